@@ -5,7 +5,7 @@ use alloc::{
 use common::ux::TagValue;
 
 use crate::{
-    comm::MessageError,
+    comm::{MessageError, MessageReader},
     ux::{has_page_api, step_pos},
     ux_generated,
 };
@@ -14,6 +14,21 @@ use crate::{
 /// returns the app's response.
 pub type Handler<S = ()> = fn(&mut App<S>, &[u8]) -> Vec<u8>;
 
+/// A StreamHandler is like a [`Handler`], but it reads the message from the host incrementally
+/// with a [`MessageReader`], instead of receiving it whole. The rest of the message, if not read,
+/// is discarded before the response is sent.
+///
+/// The handler returns `None` if it sent the response itself, for example to stream it with a
+/// [`MessageWriter`](crate::comm::MessageWriter); it must then call
+/// [`MessageReader::skip_remaining`] first, as the host sends the whole message before waiting for
+/// the response.
+pub type StreamHandler<S = ()> = fn(&mut App<S>, &mut MessageReader) -> Option<Vec<u8>>;
+
+enum AnyHandler<S> {
+    Message(Handler<S>),
+    Stream(StreamHandler<S>),
+}
+
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 enum View {
     None,
@@ -29,7 +44,7 @@ enum View {
 
 /// The AppBuilder is used to configure the App during the building phase.
 pub struct AppBuilder<S = ()> {
-    handler: Handler<S>,
+    handler: AnyHandler<S>,
     app_name: &'static str,
     version: &'static str,
     description: Option<String>,
@@ -48,6 +63,26 @@ where
     /// * `version` - The version of the application.
     /// * `handler` - The function to handle incoming messages.
     pub fn new(app_name: &'static str, version: &'static str, handler: Handler<S>) -> Self {
+        Self::with_handler(app_name, version, AnyHandler::Message(handler))
+    }
+
+    /// Creates a new AppBuilder instance with a handler that reads the incoming messages
+    /// incrementally, for V-Apps that process large messages.
+    ///
+    /// # Arguments
+    ///
+    /// * `app_name` - The name of the application.
+    /// * `version` - The version of the application.
+    /// * `handler` - The function to handle incoming messages.
+    pub fn new_streaming(
+        app_name: &'static str,
+        version: &'static str,
+        handler: StreamHandler<S>,
+    ) -> Self {
+        Self::with_handler(app_name, version, AnyHandler::Stream(handler))
+    }
+
+    fn with_handler(app_name: &'static str, version: &'static str, handler: AnyHandler<S>) -> Self {
         Self {
             handler,
             app_name,
@@ -93,9 +128,18 @@ where
     }
 }
 
+// Returns if the request was aborted, or if there was none; panics on communication errors
+fn check_receive_error(e: MessageError) {
+    match e {
+        MessageError::NoMessage => {} // TODO: should we wait before retrying, to avoid spamming the channel?
+        MessageError::Aborted => {}
+        e => panic!("Communication error: {}", e),
+    }
+}
+
 /// The App struct represents the context of the application.
 pub struct App<S = ()> {
-    handler: Handler<S>,
+    handler: AnyHandler<S>,
     app_name: &'static str,
     version: &'static str,
     description: Option<String>,
@@ -138,7 +182,13 @@ where
     ///
     /// This method enters a loop where it continuously processes user interface events and checks for incoming messages.
     /// It will not return until a message is received or an error occurs.
+    ///
+    /// If the host aborted the request being processed, it returns `MessageError::Aborted`, and the
+    /// message is not sent.
     pub fn exchange(&mut self, msg: &[u8]) -> Result<Vec<u8>, MessageError> {
+        if crate::comm::is_aborted() {
+            return Err(MessageError::Aborted);
+        }
         crate::comm::send_message(msg);
         loop {
             self.process_ux_events(false);
@@ -158,7 +208,7 @@ where
 
     fn process_ux_events(&mut self, show_dashboard: bool) {
         use common::ux::Action::*;
-        use common::ux::Event::{Action, Ticker};
+        use common::ux::Event::{Abort, Action, Ticker};
 
         if show_dashboard && self.ux_dirty && (self.cleanup_ticks == 0) {
             if has_page_api() {
@@ -202,6 +252,8 @@ where
                     self.cleanup_ticks -= 1;
                 }
             }
+            // the flag is checked by the callers
+            (_, Abort) => {}
             (view, ev) => {
                 crate::println!("Unhandled event {:?} in view {:?}", ev, view);
             }
@@ -283,17 +335,47 @@ where
     /// This function shows the dashboard, then enters the core loop of the app.
     /// It never returns, as it keeps the app running until sdk::exit() is called,
     /// or a fatal error occurs.
+    ///
+    /// If the host aborts a request, the UX flows of the handler are rejected, and its response is
+    /// discarded; the dashboard is then shown again.
     fn run_loop(&mut self) -> ! {
         loop {
             self.process_ux_events(true);
-
-            let req_msg = match crate::comm::receive_message() {
-                Ok(msg) => msg,
-                Err(crate::comm::MessageError::NoMessage) => continue, // TODO: should we wait before retrying, to avoid spamming the channel?
-                Err(e) => panic!("Communication error: {}", e),
+            // the request, if any, is over; the host knows that its response was discarded
+            crate::comm::take_aborted();
+
+            let resp_msg = match self.handler {
+                AnyHandler::Message(handler) => match crate::comm::receive_message() {
+                    Ok(req_msg) => Some(handler(self, &req_msg)),
+                    Err(e) => {
+                        check_receive_error(e);
+                        continue;
+                    }
+                },
+                AnyHandler::Stream(handler) => match MessageReader::new() {
+                    Ok(mut reader) => {
+                        let resp_msg = handler(self, &mut reader);
+                        // the host sends the whole message before waiting for the response
+                        if let Err(e) = reader.skip_remaining() {
+                            check_receive_error(e);
+                        }
+                        resp_msg
+                    }
+                    Err(e) => {
+                        check_receive_error(e);
+                        continue;
+                    }
+                },
             };
-            let resp_msg = (self.handler)(self, &req_msg);
-            crate::comm::send_message(&resp_msg);
+            if crate::comm::take_aborted() {
+                // the host does not expect a response anymore
+                self.set_ux_dirty();
+                continue;
+            }
+            // if there is no response, the handler already sent it
+            if let Some(resp_msg) = resp_msg {
+                crate::comm::send_message(&resp_msg);
+            }
         }
     }
 
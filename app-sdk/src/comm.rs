@@ -13,14 +13,18 @@
 //!   transmission and reception without requiring large buffers.
 //! - **Length-Prefixing**: Messages are prefixed with their length, enabling dynamic buffer allocation
 //!   only when necessary.
-//!
-//! Note: This module is not thread-safe. It is designed for single-threaded execution due to the use of
-//! a static mutable buffer for chunk reuse.
+//! - **Streaming**: With a [`MessageReader`] or a [`MessageWriter`], a message is processed one chunk
+//!   at a time, without buffering it whole in the memory of the V-App.
 
+#[cfg(not(test))]
 use crate::{xrecv_to, xsend};
-use alloc::{vec, vec::Vec};
+#[cfg(test)]
+use tests::{xrecv_to, xsend};
+
+use alloc::vec::Vec;
 use core::cmp::min;
 use core::convert::TryInto;
+use core::sync::atomic::{AtomicBool, Ordering};
 
 use common::comm::{ACK, CHUNK_LENGTH};
 
@@ -37,6 +41,8 @@ pub enum MessageError {
     FailedToReadMessage,
     /// Error when the message length cannot be determined due to insufficient bytes.
     FailedToReadLength,
+    /// The host aborted the request being processed, and will not answer.
+    Aborted,
 }
 
 impl core::fmt::Display for MessageError {
@@ -47,78 +53,319 @@ impl core::fmt::Display for MessageError {
             MessageError::TooManyBytesReceived => write!(f, "Too many bytes received"),
             MessageError::FailedToReadMessage => write!(f, "Failed to read message"),
             MessageError::FailedToReadLength => write!(f, "Failed to read message length"),
+            MessageError::Aborted => write!(f, "Request aborted by the host"),
         }
     }
 }
 
 impl core::error::Error for MessageError {}
 
-// Define a static mutable buffer for chunk reuse, in order to avoid unnecessary allocations.
-static mut CHUNK_BUFFER: [u8; CHUNK_LENGTH] = [0u8; CHUNK_LENGTH];
+// Set when the host aborts the request being processed, until the App discards its response
+static ABORTED: AtomicBool = AtomicBool::new(false);
 
-/// Receives a message, handling chunked data reception and error management.
-///
-/// The function starts by attempting to read a fixed-size chunk to extract the message length.
-/// It then continues reading in chunks until the entire message is received, sending an
-/// acknowledgment (`ACK`) byte for each chunk received. Errors occur if any unexpected
-/// conditions are encountered, such as insufficient bytes or extra bytes in a chunk.
-///
-/// # Errors
-///
-/// - Returns `MessageError::NoMessage` if the initial chunk has length 0.
-/// - Returns `MessageError::FailedToReadLength` if the initial chunk is too small to contain the
-///   message length.
-/// - Returns `MessageError::TooManyBytesReceived` if unexpected extra bytes are received.
-/// - Returns `MessageError::FailedToReadMessage` if a chunk is empty or fails to be read.
+/// Returns true if the host aborted the request that the V-App is processing.
 ///
-/// # Returns
-///
-/// - On success, returns `Ok(Vec<u8>)` with the received message data.
-///
-/// # Safety
+/// Once a request is aborted, no message is sent or received until the `App` is back to waiting
+/// for the next request.
+pub fn is_aborted() -> bool {
+    ABORTED.load(Ordering::Relaxed)
+}
+
+pub(crate) fn set_aborted() {
+    ABORTED.store(true, Ordering::Relaxed);
+}
+
+// Returns true if the host aborted the request, and clears the flag
+pub(crate) fn take_aborted() -> bool {
+    let aborted = ABORTED.load(Ordering::Relaxed);
+    ABORTED.store(false, Ordering::Relaxed);
+    aborted
+}
+
+/// Reads a message from the host one chunk at a time, so that the V-App can process large messages
+/// incrementally instead of buffering them whole.
 ///
-/// This function is only safe in single-threaded execution due to the use of a static mutable buffer.
-pub fn receive_message() -> Result<Vec<u8>, MessageError> {
-    let chunk = &raw mut CHUNK_BUFFER;
+/// The acknowledgment of each chunk is only sent when the next chunk is needed. If the V-App does
+/// not need the end of the message, it must call [`MessageReader::skip_remaining`] before sending
+/// its response, as the host sends the whole message first.
+pub struct MessageReader {
+    // total length of the message
+    length: usize,
+    // number of bytes of the message received so far
+    received: usize,
+    chunk: [u8; CHUNK_LENGTH],
+    // range of the bytes of the chunk that were not read yet
+    pos: usize,
+    end: usize,
+}
 
-    let first_chunk_len = xrecv_to(unsafe { &mut *chunk });
+impl MessageReader {
+    /// Receives the first chunk of a message, which contains its length.
+    ///
+    /// # Errors
+    ///
+    /// - Returns `MessageError::NoMessage` if the initial chunk has length 0.
+    /// - Returns `MessageError::Aborted` if the host aborted the request being processed.
+    /// - Returns `MessageError::FailedToReadLength` if the initial chunk is too small to contain the
+    ///   message length.
+    /// - Returns `MessageError::TooManyBytesReceived` if unexpected extra bytes are received.
+    pub fn new() -> Result<Self, MessageError> {
+        if is_aborted() {
+            return Err(MessageError::Aborted);
+        }
 
-    if first_chunk_len == 0 {
-        return Err(MessageError::NoMessage);
-    }
+        let mut chunk = [0u8; CHUNK_LENGTH];
+        let first_chunk_len = xrecv_to(&mut chunk);
+
+        if first_chunk_len == 0 {
+            if is_aborted() {
+                return Err(MessageError::Aborted);
+            }
+            return Err(MessageError::NoMessage);
+        }
+
+        // Ensure we have at least 4 bytes for the length.
+        if first_chunk_len < 4 {
+            return Err(MessageError::FailedToReadLength);
+        }
+
+        // Extract the message length.
+        let length = u32::from_be_bytes(chunk[0..4].try_into().unwrap()) as usize;
 
-    // Ensure we have at least 4 bytes for the length.
-    if first_chunk_len < 4 {
-        return Err(MessageError::FailedToReadLength);
+        // Check for unexpected extra bytes.
+        if first_chunk_len > 4 + length {
+            return Err(MessageError::TooManyBytesReceived);
+        }
+
+        Ok(Self {
+            length,
+            received: first_chunk_len - 4,
+            chunk,
+            pos: 4,
+            end: first_chunk_len,
+        })
     }
 
-    // Extract the message length.
-    let length = u32::from_be_bytes(unsafe { &*chunk }[0..4].try_into().unwrap()) as usize;
+    /// Returns the total length of the message.
+    pub fn len(&self) -> usize {
+        self.length
+    }
 
-    // Check for unexpected extra bytes.
-    if first_chunk_len > 4 + length {
-        return Err(MessageError::TooManyBytesReceived);
+    /// Returns true if the message is empty.
+    pub fn is_empty(&self) -> bool {
+        self.length == 0
     }
 
-    // Initialize the result with the data from the first chunk.
-    let mut result = Vec::with_capacity(length);
-    result.extend_from_slice(&unsafe { &*chunk }[4..first_chunk_len]);
+    /// Returns the number of bytes of the message that were not read yet.
+    pub fn remaining(&self) -> usize {
+        self.length - self.received + (self.end - self.pos)
+    }
 
-    while result.len() < length {
+    // Acknowledges the current chunk, and receives the next one
+    fn receive_chunk(&mut self) -> Result<(), MessageError> {
         // Send ACK to maintain the alternating protocol.
         xsend(&ACK);
 
-        let chunk_len = xrecv_to(unsafe { &mut *chunk });
+        let chunk_len = xrecv_to(&mut self.chunk);
 
         if chunk_len == 0 {
+            if is_aborted() {
+                return Err(MessageError::Aborted);
+            }
             return Err(MessageError::FailedToReadMessage);
         }
 
-        if chunk_len > length - result.len() {
+        if chunk_len > self.length - self.received {
             return Err(MessageError::TooManyBytesReceived);
         }
 
-        result.extend_from_slice(&unsafe { &*chunk }[0..chunk_len]);
+        self.received += chunk_len;
+        self.pos = 0;
+        self.end = chunk_len;
+        Ok(())
+    }
+
+    /// Returns the bytes of the current chunk that were not read yet, receiving the next chunk if
+    /// they were all read. Returns `None` at the end of the message.
+    ///
+    /// # Errors
+    ///
+    /// - Returns `MessageError::Aborted` if the host aborted the request being processed.
+    /// - Returns `MessageError::TooManyBytesReceived` if unexpected extra bytes are received.
+    /// - Returns `MessageError::FailedToReadMessage` if a chunk is empty or fails to be read.
+    pub fn next_chunk(&mut self) -> Result<Option<&[u8]>, MessageError> {
+        if self.pos == self.end {
+            if self.received == self.length {
+                return Ok(None);
+            }
+            self.receive_chunk()?;
+        }
+        let data = &self.chunk[self.pos..self.end];
+        self.pos = self.end;
+        Ok(Some(data))
+    }
+
+    /// Reads up to `buf.len()` bytes of the message, and returns the number of bytes read, which
+    /// is only 0 at the end of the message, or if `buf` is empty.
+    ///
+    /// The errors are the same as for [`MessageReader::next_chunk`].
+    pub fn read(&mut self, buf: &mut [u8]) -> Result<usize, MessageError> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        if self.pos == self.end {
+            if self.received == self.length {
+                return Ok(0);
+            }
+            self.receive_chunk()?;
+        }
+        let n = min(buf.len(), self.end - self.pos);
+        buf[..n].copy_from_slice(&self.chunk[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+
+    /// Reads exactly `buf.len()` bytes of the message.
+    ///
+    /// Returns `MessageError::InvalidLength` if the message ends before, or the same errors as for
+    /// [`MessageReader::next_chunk`].
+    pub fn read_exact(&mut self, buf: &mut [u8]) -> Result<(), MessageError> {
+        if buf.len() > self.remaining() {
+            return Err(MessageError::InvalidLength);
+        }
+        let mut filled = 0;
+        while filled < buf.len() {
+            filled += self.read(&mut buf[filled..])?;
+        }
+        Ok(())
+    }
+
+    /// Receives and discards the rest of the message.
+    pub fn skip_remaining(&mut self) -> Result<(), MessageError> {
+        while self.next_chunk()?.is_some() {}
+        Ok(())
+    }
+}
+
+/// Writes a message to the host one chunk at a time, so that the V-App does not need to buffer
+/// it whole. The length of the message must be known in advance, as it is sent first.
+///
+/// The data is sent whenever a full chunk is written, after waiting for the acknowledgment of the
+/// previous chunk; [`MessageWriter::finish`] sends the last chunk.
+///
+/// On native execution, the writer will panic if the underlying calls to `xsend` or `xrecv` panic,
+/// or if the host does not acknowledge a chunk.
+pub struct MessageWriter {
+    // total length of the message
+    length: usize,
+    // number of bytes of the message written so far
+    written: usize,
+    chunk: [u8; CHUNK_LENGTH],
+    chunk_len: usize,
+    // true once the first chunk is sent, as the next ones must be acknowledged first
+    started: bool,
+}
+
+impl MessageWriter {
+    /// Starts a message of `length` bytes.
+    pub fn new(length: usize) -> Self {
+        let mut chunk = [0u8; CHUNK_LENGTH];
+        // Encode the message length in big-endian format.
+        chunk[0..4].copy_from_slice(&(length as u32).to_be_bytes());
+        Self {
+            length,
+            written: 0,
+            chunk,
+            chunk_len: 4,
+            started: false,
+        }
+    }
+
+    // Sends the current chunk, after the acknowledgment of the previous one
+    fn flush(&mut self) -> Result<(), MessageError> {
+        if is_aborted() {
+            return Err(MessageError::Aborted);
+        }
+        if self.started {
+            // Wait for ACK to maintain the alternating protocol.
+            let mut ack = [0u8; 1];
+            let ack_len = xrecv_to(&mut ack);
+            if ack_len == 0 && is_aborted() {
+                return Err(MessageError::Aborted);
+            }
+            if ack_len != 1 || ack != ACK {
+                panic!("Unexpected byte received: {}", ack[0]);
+            }
+        }
+        xsend(&self.chunk[..self.chunk_len]);
+        self.chunk_len = 0;
+        self.started = true;
+        Ok(())
+    }
+
+    /// Writes the next bytes of the message, sending each chunk once it is full.
+    ///
+    /// Returns `MessageError::InvalidLength` if the data exceeds the length of the message, or
+    /// `MessageError::Aborted` if the host aborted the request being processed.
+    pub fn write(&mut self, mut data: &[u8]) -> Result<(), MessageError> {
+        if data.len() > self.length - self.written {
+            return Err(MessageError::InvalidLength);
+        }
+        while !data.is_empty() {
+            let n = min(CHUNK_LENGTH - self.chunk_len, data.len());
+            self.chunk[self.chunk_len..self.chunk_len + n].copy_from_slice(&data[..n]);
+            self.chunk_len += n;
+            self.written += n;
+            data = &data[n..];
+            if self.chunk_len == CHUNK_LENGTH {
+                self.flush()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Sends the last chunk of the message.
+    ///
+    /// Returns `MessageError::InvalidLength` if fewer bytes than the length of the message were
+    /// written, or `MessageError::Aborted` if the host aborted the request being processed.
+    pub fn finish(mut self) -> Result<(), MessageError> {
+        if self.written != self.length {
+            return Err(MessageError::InvalidLength);
+        }
+        if self.chunk_len > 0 || !self.started {
+            self.flush()?;
+        }
+        Ok(())
+    }
+}
+
+/// Receives a message, handling chunked data reception and error management.
+///
+/// The function starts by attempting to read a fixed-size chunk to extract the message length.
+/// It then continues reading in chunks until the entire message is received, sending an
+/// acknowledgment (`ACK`) byte for each chunk received. Errors occur if any unexpected
+/// conditions are encountered, such as insufficient bytes or extra bytes in a chunk.
+///
+/// For large messages, a [`MessageReader`] avoids buffering the whole message.
+///
+/// # Errors
+///
+/// - Returns `MessageError::NoMessage` if the initial chunk has length 0.
+/// - Returns `MessageError::Aborted` if the host aborted the request being processed.
+/// - Returns `MessageError::FailedToReadLength` if the initial chunk is too small to contain the
+///   message length.
+/// - Returns `MessageError::TooManyBytesReceived` if unexpected extra bytes are received.
+/// - Returns `MessageError::FailedToReadMessage` if a chunk is empty or fails to be read.
+///
+/// # Returns
+///
+/// - On success, returns `Ok(Vec<u8>)` with the received message data.
+pub fn receive_message() -> Result<Vec<u8>, MessageError> {
+    let mut reader = MessageReader::new()?;
+
+    let mut result = Vec::with_capacity(reader.len());
+    while let Some(chunk) = reader.next_chunk()? {
+        result.extend_from_slice(chunk);
     }
 
     Ok(result)
@@ -135,34 +382,150 @@ pub fn receive_message() -> Result<Vec<u8>, MessageError> {
 ///
 /// - `msg`: A reference to the message bytes (`&[u8]`) that should be sent.
 ///
-/// The function does not return a value, nor any error.
+/// The function does not return a value, nor any error. If the host aborted the request being
+/// processed, the message is not sent, or only partially.
 /// On native execution, the function will panic if the underlying calls to `xsend` or `xrecv` panic.
 /// On Risc-V targets, communication failure causes the ECALL to fail, which will arrest the execution of the VM.
 pub fn send_message(msg: &[u8]) {
-    // Encode the message length in big-endian format.
-    let length_be = (msg.len() as u32).to_be_bytes();
+    let mut writer = MessageWriter::new(msg.len());
+    // the only possible error is the abort of the request, that stops the transmission
+    let _ = writer.write(msg).and_then(|()| writer.finish());
+}
 
-    // Calculate how much of the message fits in the first chunk.
-    let first_chunk_msg_bytes = min(CHUNK_LENGTH - 4, msg.len());
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{cell::RefCell, collections::VecDeque, vec};
 
-    // Send the initial chunk containing the length and part of the message.
-    xsend(&[&length_be, &msg[..first_chunk_msg_bytes]].concat());
+    // The host of the tests: the buffers that the V-App will receive, and the ones it sent
+    thread_local! {
+        static TO_VAPP: RefCell<VecDeque<Vec<u8>>> = const { RefCell::new(VecDeque::new()) };
+        static FROM_VAPP: RefCell<Vec<Vec<u8>>> = const { RefCell::new(Vec::new()) };
+    }
 
-    let mut total_bytes_sent = first_chunk_msg_bytes;
+    pub(super) fn xrecv_to(buf: &mut [u8]) -> usize {
+        let data = TO_VAPP
+            .with(|queue| queue.borrow_mut().pop_front())
+            .expect("The V-App expects data that the host did not send");
+        buf[..data.len()].copy_from_slice(&data);
+        data.len()
+    }
 
-    let mut acc_chunk = vec![0u8];
-    // Send the remaining chunks.
-    while total_bytes_sent < msg.len() {
-        // Wait for ACK to maintain the alternating protocol.
-        let acc_len = xrecv_to(&mut acc_chunk);
-        if acc_len != 1 || acc_chunk != ACK {
-            panic!("Unexpected byte received: {}", acc_chunk[0]);
+    pub(super) fn xsend(buffer: &[u8]) {
+        FROM_VAPP.with(|sent| sent.borrow_mut().push(buffer.to_vec()));
+    }
+
+    fn host_send(data: &[u8]) {
+        TO_VAPP.with(|queue| queue.borrow_mut().push_back(data.to_vec()));
+    }
+
+    // Queues the chunks of a message, as the host sends them
+    fn host_send_message(msg: &[u8]) {
+        let mut data = (msg.len() as u32).to_be_bytes().to_vec();
+        data.extend_from_slice(msg);
+        for chunk in data.chunks(CHUNK_LENGTH) {
+            host_send(chunk);
         }
+    }
+
+    // Returns the buffers sent by the V-App, and checks that it received all the queued ones
+    fn host_received() -> Vec<Vec<u8>> {
+        assert!(TO_VAPP.with(|queue| queue.borrow().is_empty()));
+        FROM_VAPP.with(|sent| sent.take())
+    }
+
+    fn message(len: usize) -> Vec<u8> {
+        (0..len).map(|i| i as u8).collect()
+    }
+
+    #[test]
+    fn test_reader_partial_read_and_skip() {
+        // 3 chunks: 252 bytes after the length, then 256 and 92 bytes
+        let msg = message(600);
+        host_send_message(&msg);
+
+        let mut reader = MessageReader::new().unwrap();
+        assert_eq!(reader.len(), 600);
+        let mut buf = [0u8; 10];
+        reader.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, msg[..10]);
+        assert_eq!(reader.remaining(), 590);
+
+        // crosses the end of the first chunk, which is then acknowledged
+        let mut buf = [0u8; 300];
+        reader.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, msg[10..310]);
+        assert_eq!(reader.remaining(), 290);
+
+        reader.skip_remaining().unwrap();
+        assert_eq!(reader.remaining(), 0);
+        assert_eq!(reader.read(&mut buf).unwrap(), 0);
+        assert!(reader.next_chunk().unwrap().is_none());
+        assert_eq!(host_received(), vec![ACK.to_vec(), ACK.to_vec()]);
+
+        // reading past the end of the message
+        host_send_message(&msg[..5]);
+        let mut reader = MessageReader::new().unwrap();
+        assert!(matches!(
+            reader.read_exact(&mut [0u8; 6]),
+            Err(MessageError::InvalidLength)
+        ));
+        reader.skip_remaining().unwrap();
+        assert!(host_received().is_empty());
+    }
+
+    #[test]
+    fn test_writer_chunk_boundary() {
+        // fills exactly two chunks, the first one starting with the length
+        let msg = message(2 * CHUNK_LENGTH - 4);
+        host_send(&ACK);
 
-        let end_idx = min(total_bytes_sent + CHUNK_LENGTH, msg.len());
-        let chunk = &msg[total_bytes_sent..end_idx];
+        let mut writer = MessageWriter::new(msg.len());
+        writer.write(&msg[..100]).unwrap();
+        writer.write(&msg[100..]).unwrap();
+        assert!(matches!(
+            writer.write(&[0]),
+            Err(MessageError::InvalidLength)
+        ));
+        writer.finish().unwrap();
 
-        xsend(chunk);
-        total_bytes_sent = end_idx;
+        // finish has nothing left to send
+        let sent = host_received();
+        assert_eq!(sent.len(), 2);
+        assert!(sent.iter().all(|chunk| chunk.len() == CHUNK_LENGTH));
+        assert_eq!(sent[0][..4], (msg.len() as u32).to_be_bytes());
+        assert_eq!(sent.concat()[4..], msg[..]);
+    }
+
+    #[test]
+    fn test_writer_finish() {
+        // an empty message is only its length
+        MessageWriter::new(0).finish().unwrap();
+        assert_eq!(host_received(), vec![vec![0, 0, 0, 0]]);
+
+        let mut writer = MessageWriter::new(3);
+        writer.write(&[1, 2]).unwrap();
+        assert!(matches!(writer.finish(), Err(MessageError::InvalidLength)));
+        assert!(host_received().is_empty());
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        for len in [0, 1, CHUNK_LENGTH - 4, CHUNK_LENGTH - 3, 1000] {
+            let msg = message(len);
+            host_send_message(&msg);
+            assert_eq!(receive_message().unwrap(), msg);
+            // the V-App acknowledges each chunk but the last one
+            let chunks = (len + 4).div_ceil(CHUNK_LENGTH);
+            assert_eq!(host_received().len(), chunks - 1);
+
+            for _ in 1..chunks {
+                host_send(&ACK);
+            }
+            send_message(&msg);
+            let sent = host_received();
+            assert_eq!(sent.len(), chunks);
+            assert_eq!(sent.concat()[4..], msg[..]);
+        }
     }
 }
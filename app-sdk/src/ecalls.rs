@@ -59,7 +59,8 @@ forward_to_ecall! {
     /// - `max_size`: Maximum size of the buffer.
     ///
     /// # Returns
-    /// The number of bytes received.
+    /// The number of bytes received, or `XRECV_ABORTED` if the host aborted the request that the
+    /// V-App is processing.
     pub fn xrecv(buffer: *mut u8, max_size: usize) -> usize;
 
     /// Sends a buffer to print to the host.
@@ -3,7 +3,10 @@ use rand::TryRngCore;
 use std::{
     io::{self, Write},
     net::{TcpListener, TcpStream},
-    sync::Mutex,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
     thread::sleep,
     time::Duration,
 };
@@ -11,6 +14,9 @@ use std::{
 #[cfg(not(feature = "test-mode"))]
 use std::io::Read;
 
+#[cfg(not(feature = "test-mode"))]
+use common::{comm::NATIVE_ABORT_REQUEST, ecall_constants::XRECV_ABORTED};
+
 use hmac::{Hmac, Mac};
 use sha2::Sha512;
 
@@ -125,6 +131,7 @@ fn wait_for_client() -> TcpStream {
                     Ok((stream, remote)) => {
                         eprintln!("Client {remote} connected");
                         let _ = stream.set_nodelay(true);
+                        CLIENT_CONNECTED.store(true, Ordering::Relaxed);
                         return stream;
                     }
                     Err(err) => {
@@ -141,6 +148,13 @@ fn wait_for_client() -> TcpStream {
     }
 }
 
+// Set once the client is connected; until then, there is no request to abort
+static CLIENT_CONNECTED: AtomicBool = AtomicBool::new(false);
+// Set when the client aborts a request, until the V-App acknowledges it once it waits for the next
+// request
+#[cfg(not(feature = "test-mode"))]
+static ABORT_UNACKNOWLEDGED: AtomicBool = AtomicBool::new(false);
+
 lazy_static! {
     static ref LAST_EVENT: Mutex<Option<(common::ux::EventCode, common::ux::EventData)>> =
         Mutex::new(None);
@@ -216,9 +230,23 @@ pub fn xrecv(_buffer: *mut u8, _max_size: usize) -> usize {
 pub fn xrecv(buffer: *mut u8, max_size: usize) -> usize {
     let mut stream = TCP_CONN.lock().expect("TCP mutex poisoned");
 
+    // After an abort, the first message received is the next request; the client waits for the
+    // acknowledgment before sending it.
+    if ABORT_UNACKNOWLEDGED.swap(false, Ordering::Relaxed) {
+        stream
+            .write_all(&[BufferType::Aborted as u8])
+            .and_then(|_| stream.write_all(&0u32.to_be_bytes()))
+            .and_then(|_| stream.flush())
+            .expect("TCP write failed");
+    }
+
     // Read the 4-byte length header first.
     let mut len_buf = [0u8; 4];
     stream.read_exact(&mut len_buf).expect("TCP read failed");
+    if u32::from_be_bytes(len_buf) == NATIVE_ABORT_REQUEST {
+        ABORT_UNACKNOWLEDGED.store(true, Ordering::Relaxed);
+        return XRECV_ABORTED;
+    }
     let expected = u32::from_be_bytes(len_buf) as usize;
 
     if expected > max_size {
@@ -271,12 +299,46 @@ pub fn get_event(data: *mut EventData) -> u32 {
         }
     }
 
-    // for now there is no other type of event than the ticker.
-    // We wait for TICKER_MS milliseconds and return a Ticker event.
+    // We wait for TICKER_MS milliseconds and return a Ticker event, unless the client aborted
+    // the request in the meantime.
     std::thread::sleep(std::time::Duration::from_millis(TICKER_MS));
+    if poll_abort_request() {
+        return EventCode::Abort as u32;
+    }
     return EventCode::Ticker as u32;
 }
 
+// Consumes the abort request of the client, if it is the next data in the stream
+#[cfg(not(feature = "test-mode"))]
+fn poll_abort_request() -> bool {
+    if !CLIENT_CONNECTED.load(Ordering::Relaxed) {
+        return false;
+    }
+    let mut stream = TCP_CONN.lock().expect("TCP mutex poisoned");
+    let mut buf = [0u8; 4];
+    stream
+        .set_nonblocking(true)
+        .expect("TCP configuration failed");
+    let peeked = stream.peek(&mut buf);
+    stream
+        .set_nonblocking(false)
+        .expect("TCP configuration failed");
+
+    match peeked {
+        Ok(4) if u32::from_be_bytes(buf) == NATIVE_ABORT_REQUEST => {
+            stream.read_exact(&mut buf).expect("TCP read failed");
+            ABORT_UNACKNOWLEDGED.store(true, Ordering::Relaxed);
+            true
+        }
+        _ => false,
+    }
+}
+
+#[cfg(feature = "test-mode")]
+fn poll_abort_request() -> bool {
+    false
+}
+
 pub fn show_page(page_desc: *const u8, page_desc_len: usize) -> u32 {
     // make a slice from page_desc and page_desc_len
     let page_desc_slice = unsafe { std::slice::from_raw_parts(page_desc, page_desc_len) };
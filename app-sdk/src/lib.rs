@@ -6,6 +6,7 @@ extern crate alloc;
 extern crate lazy_static;
 
 use alloc::vec::Vec;
+use common::ecall_constants::XRECV_ABORTED;
 
 pub mod app;
 pub mod bignum;
@@ -17,6 +18,7 @@ pub mod slip21;
 pub mod ux;
 
 pub use app::{App, AppBuilder};
+pub use common::rpc;
 
 mod ecalls;
 
@@ -34,21 +36,16 @@ mod ux_generated {
 #[cfg(target_arch = "riscv32")]
 use embedded_alloc::Heap;
 
-#[cfg(target_arch = "riscv32")]
-include!(concat!(env!("OUT_DIR"), "/heap_config.rs"));
-
-#[cfg(target_arch = "riscv32")]
-static mut HEAP_MEM: [u8; VAPP_HEAP_SIZE] = [0; VAPP_HEAP_SIZE];
-
 #[cfg(target_arch = "riscv32")]
 #[global_allocator]
 static HEAP: Heap = Heap::empty();
 
+// The heap is the heap segment of the manifest, whose pages are only allocated by the client
+// once they are written to
 #[cfg(target_arch = "riscv32")]
-fn init_heap() {
+fn init_heap(heap_start: usize, heap_size: usize) {
     unsafe {
-        #[allow(static_mut_refs)]
-        HEAP.init(HEAP_MEM.as_mut_ptr() as usize, VAPP_HEAP_SIZE);
+        HEAP.init(heap_start, heap_size);
     }
 }
 
@@ -67,8 +64,8 @@ unsafe impl critical_section::Impl for CriticalSection {
 // Allocator initialization for riscv32 targets
 #[cfg(target_arch = "riscv32")]
 #[no_mangle]
-pub extern "C" fn rust_init_heap() {
-    init_heap();
+pub extern "C" fn rust_init_heap(heap_start: usize, heap_size: usize) {
+    init_heap(heap_start, heap_size);
 }
 
 pub fn fatal(msg: &str) -> ! {
@@ -104,12 +101,20 @@ pub fn xrecv(size: usize) -> Vec<u8> {
         buffer.set_len(size);
     }
 
-    let recv_size = ecalls::xrecv(buffer.as_mut_ptr(), buffer.len());
+    let recv_size = xrecv_to(&mut buffer);
     buffer[0..recv_size].to_vec()
 }
 
+/// Receives a buffer from the host, and returns its length. If the host aborted the request that
+/// the V-App is processing instead, returns 0, and [`comm::is_aborted`] returns true.
 pub fn xrecv_to(buf: &mut [u8]) -> usize {
-    ecalls::xrecv(buf.as_mut_ptr(), buf.len())
+    match ecalls::xrecv(buf.as_mut_ptr(), buf.len()) {
+        XRECV_ABORTED => {
+            comm::set_aborted();
+            0
+        }
+        recv_size => recv_size,
+    }
 }
 
 pub fn xsend(buffer: &[u8]) {
@@ -144,14 +149,14 @@ macro_rules! println {
 }
 
 /// Initialization boilerplate for the application that is called before the main function, for
-/// targets that need it.
+/// targets that need it. The VM passes the start and the size of the heap as arguments.
 #[macro_export]
 macro_rules! bootstrap {
     () => {
         #[cfg(target_arch = "riscv32")]
         #[no_mangle]
-        pub fn _start() {
-            $crate::rust_init_heap();
+        pub extern "C" fn _start(heap_start: usize, heap_size: usize) {
+            $crate::rust_init_heap(heap_start, heap_size);
             main()
         }
 
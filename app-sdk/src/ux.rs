@@ -42,6 +42,9 @@ pub(crate) fn show_step_raw(step: &[u8]) {
 }
 
 /// Blocks until an event is received, then returns it.
+///
+/// If the host aborts the request that the V-App is processing, an [`Event::Abort`] is returned,
+/// and [`crate::comm::is_aborted`] returns true until the request is over.
 pub fn get_event() -> Event {
     loop {
         let mut event_data = EventData::default();
@@ -54,6 +57,10 @@ pub fn get_event() -> Event {
                 let action = unsafe { event_data.action };
                 return Event::Action(action);
             }
+            EventCode::Abort => {
+                crate::comm::set_aborted();
+                return Event::Abort;
+            }
             EventCode::Unknown => {
                 let data = unsafe { event_data.raw };
                 return Event::Unknown(data);
@@ -62,19 +69,18 @@ pub fn get_event() -> Event {
     }
 }
 
-// waits for a number of ticker events
+// waits for a number of ticker events, or until the request is aborted
 pub fn wait(n: u32) {
     let mut n_tickers = 0u32;
     loop {
-        let mut event_data = EventData::default();
-        let event_code = EventCode::from(ecalls::get_event(&mut event_data));
-        match event_code {
-            EventCode::Ticker => {
+        match get_event() {
+            Event::Ticker => {
                 n_tickers += 1;
                 if n_tickers >= n {
                     return;
                 }
             }
+            Event::Abort => return,
             _ => {}
         }
     }
@@ -170,6 +176,9 @@ fn __page_review_pairs(
                 Event::Action(Action::Confirm) => {
                     return true;
                 }
+                Event::Abort => {
+                    return false;
+                }
                 _ => {} // Ignore other events
             }
         }
@@ -238,6 +247,7 @@ fn __step_review_pairs(
                         }
                     }
                 }
+                Event::Abort => return false,
                 _ => {}
             }
         }
@@ -314,6 +324,7 @@ pub fn show_confirm_reject(title: &str, text: &str, confirm: &str, reject: &str)
                         return true;
                     }
                 }
+                Event::Abort => return false,
                 _ => {}
             }
         }
@@ -350,6 +361,7 @@ pub fn show_confirm_reject(title: &str, text: &str, confirm: &str, reject: &str)
                             }
                         }
                     }
+                    Event::Abort => return false,
                     _ => {}
                 }
             }
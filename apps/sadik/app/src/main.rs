@@ -256,6 +256,7 @@ fn process_message(_app: &mut App, msg: &[u8]) -> Vec<u8> {
                             break;
                         }
                     }
+                    sdk::ux::Event::Abort => break,
                     _ => (), // ignore any other type of event
                 }
             }
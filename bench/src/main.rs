@@ -1,11 +1,14 @@
 use client::BenchClient;
 use hidapi::HidApi;
+use sdk::linewriter::Sink;
 use sdk::transport::{TransportHID, TransportWrapper};
 use sdk::transport_native_hid::TransportNativeHID;
 use sdk::vanadium_client::VanadiumAppClient;
 use std::env;
+use std::path::Path;
 use std::sync::Arc;
 use std::time::Instant;
+use vanadium_emulator::TransportEmulator;
 
 mod client;
 
@@ -19,17 +22,26 @@ const TEST_CASES: &[(&str, u64)] = &[
 ];
 
 // Helper function to run a benchmark case and return (total_ms, avg_ms)
+// If no transport is given, the case is run in the emulator.
 async fn run_bench_case(
     case: &str,
     repetitions: u64,
-    transport: Arc<TransportWrapper>,
+    transport: Option<Arc<TransportWrapper>>,
 ) -> Result<f64, Box<dyn std::error::Error>> {
     let crate_name = format!("vndbench-{}", case);
     let app_path_str = format!(
         "cases/{}/target/riscv32imc-unknown-none-elf/release/{}",
         case, crate_name
     );
-    let (client_raw, _) = VanadiumAppClient::new(&app_path_str, transport, None)
+    let transport = match transport {
+        Some(transport) => transport,
+        None => {
+            let emulator = TransportEmulator::new(Path::new(&app_path_str))
+                .map_err(|_| "Failed to load the V-App in the emulator")?;
+            Arc::new(TransportWrapper::new(Arc::new(emulator)))
+        }
+    };
+    let (client_raw, _) = VanadiumAppClient::new(&app_path_str, transport, None, Box::new(Sink))
         .await
         .map_err(|_| "Failed to create client")?;
     let mut client = BenchClient::new(Box::new(client_raw));
@@ -42,12 +54,21 @@ async fn run_bench_case(
 
 #[tokio::main(flavor = "multi_thread")]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let args: Vec<String> = env::args().skip(1).collect();
-    let transport_raw = Arc::new(TransportHID::new(
-        TransportNativeHID::new(&HidApi::new().expect("Unable to get connect to the device"))
-            .unwrap(),
-    ));
-    let transport = Arc::new(TransportWrapper::new(transport_raw.clone()));
+    let mut args: Vec<String> = env::args().skip(1).collect();
+
+    // With --emulator, the V-Apps are executed on the host instead of on a device
+    let use_emulator = args.iter().any(|arg| arg == "--emulator");
+    args.retain(|arg| arg != "--emulator");
+
+    let transport = if use_emulator {
+        None
+    } else {
+        let transport_raw = Arc::new(TransportHID::new(
+            TransportNativeHID::new(&HidApi::new().expect("Unable to get connect to the device"))
+                .unwrap(),
+        ));
+        Some(Arc::new(TransportWrapper::new(transport_raw.clone())))
+    };
 
     let testcases: Vec<_> = if args.is_empty() {
         TEST_CASES.iter().collect()
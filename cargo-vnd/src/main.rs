@@ -10,9 +10,9 @@ It can be called with no arguments if called from the folder containing the Carg
 use anyhow::{Context, Result};
 use cargo_generate::{GenerateArgs, TemplatePath};
 use clap::{Parser, Subcommand};
-use client_sdk::elf::{VAppElfFile, get_app_metadata};
-use client_sdk::memory::MemorySegment;
+use client_sdk::elf::{COMPRESSED_PAGES_SECTION_NAME, VAppElfFile, get_app_metadata};
 use common::constants;
+use common::evict::EvictionPolicy;
 use common::manifest::Manifest;
 use std::path::PathBuf;
 use std::process::Command;
@@ -136,30 +136,6 @@ fn main() -> Result<()> {
     Ok(())
 }
 
-fn compute_merkle_roots(
-    elf_file: &VAppElfFile,
-    stack_start: u32,
-    stack_size: u32,
-) -> Result<([u8; 32], [u8; 32], [u8; 32])> {
-    let code_merkle_root: [u8; 32] =
-        MemorySegment::new(elf_file.code_segment.start, &elf_file.code_segment.data)
-            .get_content_root()
-            .clone()
-            .into();
-    let data_merkle_root: [u8; 32] =
-        MemorySegment::new(elf_file.data_segment.start, &elf_file.data_segment.data)
-            .get_content_root()
-            .clone()
-            .into();
-    let stack_merkle_root: [u8; 32] =
-        MemorySegment::new(stack_start, &vec![0u8; stack_size as usize])
-            .get_content_root()
-            .clone()
-            .into();
-
-    Ok((code_merkle_root, data_merkle_root, stack_merkle_root))
-}
-
 fn create_vapp_package(
     app_version: &str,
     app_metadata: &client_sdk::cargo_toml::Value,
@@ -186,20 +162,22 @@ fn create_vapp_package(
         .as_integer()
         .context("Stack size is not a number")?;
 
-    if stack_size < constants::MIN_STACK_SIZE as i64
-        || stack_size > constants::MAX_STACK_SIZE as i64
-    {
-        return Err(anyhow::anyhow!(
-            "Stack size must be between {} and {} bytes",
-            constants::MIN_STACK_SIZE,
-            constants::MAX_STACK_SIZE
-        ));
-    }
-    let stack_size = stack_size as u32;
+    let stack_size = constants::check_stack_size(stack_size).map_err(|e| anyhow::anyhow!(e))?;
+
+    // The heap is optional in the metadata.
+    let heap_size = match app_metadata.get("heap_size") {
+        Some(heap_size) => heap_size
+            .as_integer()
+            .context("Heap size is not a number")?,
+        None => constants::DEFAULT_HEAP_SIZE as i64,
+    };
+    let heap_size = constants::check_heap_size(heap_size).map_err(|e| anyhow::anyhow!(e))?;
 
-    // we might make it configurable in the future; for now, use a fixed value
+    // we might make them configurable in the future; for now, use fixed values
     let stack_start = constants::DEFAULT_STACK_START;
     let stack_end = stack_start + stack_size;
+    let heap_start = constants::DEFAULT_HEAP_START;
+    let heap_end = heap_start + heap_size;
 
     // Create a 4KB file filled with zeros for the empty .manifest section
     let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis();
@@ -225,9 +203,52 @@ fn create_vapp_package(
     // Parse the temporary ELF file with the empty .manifest section
     let elf_file_with_manifest = VAppElfFile::new(&temp_elf)?;
 
-    // Compute Merkle roots based on the ELF file with the empty section
-    let (code_merkle_root, data_merkle_root, stack_merkle_root) =
-        compute_merkle_roots(&elf_file_with_manifest, stack_start, stack_size)?;
+    // Compute the segment table with the Merkle roots based on the ELF file with the empty section
+    let mut segments =
+        elf_file_with_manifest.segment_table(stack_start, stack_end, heap_start, heap_end);
+    let stack_segment = (segments.len() - 1) as u8;
+    let heap_segment = (segments.len() - 2) as u8;
+
+    // The eviction policies are optional in the metadata, and can be chosen separately for the
+    // code, the other segments of the binary, the heap and the stack
+    if let Some(policies) = app_metadata.get("eviction_policy") {
+        let policies = policies
+            .as_table()
+            .context("Eviction policies must be a table")?;
+        if let Some(kind) = policies
+            .keys()
+            .find(|kind| !["code", "data", "heap", "stack"].contains(&kind.as_str()))
+        {
+            return Err(anyhow::anyhow!(
+                "Unknown segment kind for the eviction policy: {}; expected code, data, heap or stack",
+                kind
+            ));
+        }
+        for (index, segment) in segments.iter_mut().enumerate() {
+            let kind = if index == stack_segment as usize {
+                "stack"
+            } else if index == heap_segment as usize {
+                "heap"
+            } else if segment.permissions.is_executable() {
+                "code"
+            } else {
+                "data"
+            };
+            if let Some(policy) = policies.get(kind) {
+                let policy = policy
+                    .as_str()
+                    .and_then(EvictionPolicy::from_name)
+                    .with_context(|| {
+                        format!(
+                            "Invalid eviction policy for {}; expected one of: {}",
+                            kind,
+                            EvictionPolicy::ALL.map(|p| p.name()).join(", ")
+                        )
+                    })?;
+                segment.eviction_policy = Some(policy);
+            }
+        }
+    }
 
     // Create the manifest with the computed Merkle roots
     let manifest = Manifest::new(
@@ -235,15 +256,9 @@ fn create_vapp_package(
         app_name,
         app_version,
         elf_file_with_manifest.entrypoint,
-        elf_file_with_manifest.code_segment.start,
-        elf_file_with_manifest.code_segment.end,
-        code_merkle_root,
-        elf_file_with_manifest.data_segment.start,
-        elf_file_with_manifest.data_segment.end,
-        data_merkle_root,
-        stack_start,
-        stack_end,
-        stack_merkle_root,
+        segments,
+        stack_segment,
+        heap_segment,
     )
     .map_err(|e| anyhow::anyhow!(e))
     .context("Failed to create VApp manifest")?;
@@ -264,7 +279,24 @@ fn create_vapp_package(
     std::fs::write(&padded_manifest_file, &padded_manifest)
         .context("Failed to write padded manifest")?;
 
-    // Update the .manifest section in the temporary ELF file with the padded manifest
+    // Compress the code pages, so that the client can send them compressed to the VM. The section
+    // is not loaded in memory, therefore it does not change the segments nor the V-App hash.
+    let compressed_pages = elf_file_with_manifest.compress_code_pages();
+    let (n_compressed, n_pages) = compressed_pages
+        .values()
+        .flatten()
+        .fold((0, 0), |(c, n), p| (c + p.is_some() as usize, n + 1));
+    println!("Compressed {} of {} code pages", n_compressed, n_pages);
+    let compressed_pages_file =
+        std::env::temp_dir().join(format!("compressed_pages_{}_{}.bin", pid, now));
+    std::fs::write(
+        &compressed_pages_file,
+        VAppElfFile::serialize_compressed_pages(&compressed_pages),
+    )
+    .context("Failed to write compressed pages")?;
+
+    // Update the .manifest section in the temporary ELF file with the padded manifest, and add the
+    // section with the compressed pages
     let status = Command::new(OBJCOPY_BINARY)
         .arg("--update-section")
         .arg(format!(
@@ -272,6 +304,12 @@ fn create_vapp_package(
             section_name,
             padded_manifest_file.display()
         ))
+        .arg("--add-section")
+        .arg(format!(
+            "{}={}",
+            COMPRESSED_PAGES_SECTION_NAME,
+            compressed_pages_file.display()
+        ))
         .arg(&temp_elf)
         .arg(output)
         .status()
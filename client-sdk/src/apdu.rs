@@ -30,6 +30,16 @@ pub enum StatusWord {
     /// The V-App panicked
     VAppPanic = 0xB021,
 
+    /// The V-App exceeded the number of instructions it can execute without interacting with
+    /// the client or the user
+    InstructionBudgetExceeded = 0xB022,
+
+    /// The V-App was aborted because of a trap; the response contains the serialized `Trap`
+    VAppTrap = 0xB023,
+
+    /// The V-App was suspended; the response contains the snapshot needed to resume it
+    VAppSuspended = 0xB024,
+
     /// Unknown
     Unknown,
 }
@@ -50,6 +60,9 @@ impl TryFrom<u16> for StatusWord {
             0xB008 => Ok(StatusWord::SignatureFail),
             0xB020 => Ok(StatusWord::VMRuntimeError),
             0xB021 => Ok(StatusWord::VAppPanic),
+            0xB022 => Ok(StatusWord::InstructionBudgetExceeded),
+            0xB023 => Ok(StatusWord::VAppTrap),
+            0xB024 => Ok(StatusWord::VAppSuspended),
             0x9000 => Ok(StatusWord::OK),
             0xEEEE => Ok(StatusWord::InterruptedExecution),
             _ => Err(()),
@@ -92,6 +105,29 @@ pub fn apdu_continue(data: Vec<u8>) -> APDUCommand {
     }
 }
 
+/// Response to a ReceiveBuffer request asking the VM to suspend the V-App instead.
+pub fn apdu_suspend_vapp() -> APDUCommand {
+    APDUCommand {
+        cla: 0xE0,
+        ins: 0xff,
+        p1: 1,
+        p2: 0,
+        data: vec![],
+    }
+}
+
+/// Response to a ReceiveBuffer or CheckAbort request asking the VM to abort the request that the
+/// V-App is processing.
+pub fn apdu_abort_request() -> APDUCommand {
+    APDUCommand {
+        cla: 0xE0,
+        ins: 0xff,
+        p1: 2,
+        p2: 0,
+        data: vec![],
+    }
+}
+
 pub fn apdu_register_vapp(serialized_manifest: Vec<u8>) -> APDUCommand {
     APDUCommand {
         cla: 0xE0,
@@ -113,3 +149,24 @@ pub fn apdu_run_vapp(serialized_manifest: Vec<u8>, app_hmac: [u8; 32]) -> APDUCo
         data,
     }
 }
+
+pub fn apdu_resume_vapp(
+    serialized_manifest: Vec<u8>,
+    app_hmac: [u8; 32],
+    snapshot: &[u8],
+    segment_roots: &[[u8; 32]],
+) -> APDUCommand {
+    let mut data = serialized_manifest;
+    data.extend_from_slice(&app_hmac);
+    data.extend_from_slice(snapshot);
+    for root in segment_roots {
+        data.extend_from_slice(root);
+    }
+    APDUCommand {
+        cla: 0xE0,
+        ins: 4,
+        p1: 0,
+        p2: 0,
+        data,
+    }
+}
@@ -2,9 +2,15 @@
 //! according to a specific communication protocol.
 //! See the documentation of the V-App SDK's `comm` module for more details.
 
-use crate::vanadium_client::{VAppExecutionError, VAppTransport};
+use std::cmp::min;
+use std::future::Future;
+use std::io::{Read, Write};
+use std::time::Instant;
+
+use crate::vanadium_client::{SendOptions, VAppExecutionError, VAppTransport};
 
 use common::comm::{ACK, CHUNK_LENGTH};
+use common::rpc::RpcTransport;
 
 /// Error types that can occur during message transmission.
 #[derive(Debug)]
@@ -15,6 +21,8 @@ pub enum SendMessageError {
     VAppExecutionError(VAppExecutionError),
     /// Error returned when the response is less than the expected 4 bytes.
     ResponseTooShort,
+    /// Error when reading the message or writing the response of a streamed exchange.
+    Io(std::io::Error),
 }
 
 impl core::fmt::Display for SendMessageError {
@@ -23,6 +31,7 @@ impl core::fmt::Display for SendMessageError {
             SendMessageError::NotAckReceived => write!(f, "ACK was expected but not received"),
             SendMessageError::ResponseTooShort => write!(f, "Response shorter than 4 bytes"),
             SendMessageError::VAppExecutionError(v) => write!(f, "Error from the VM: {}", v),
+            SendMessageError::Io(e) => write!(f, "I/O error: {}", e),
         }
     }
 }
@@ -59,21 +68,67 @@ pub async fn send_message(
     transport: &mut Box<dyn VAppTransport + Send>,
     message: &[u8],
 ) -> Result<Vec<u8>, SendMessageError> {
-    // concatenate the length of the message (as a 4-byte big-endian) and the message itself
-    let mut full_message: Vec<u8> = Vec::with_capacity(message.len() + 4);
-    full_message.extend_from_slice(&(message.len() as u32).to_be_bytes());
-    full_message.extend_from_slice(message);
+    send_message_with(transport, message, SendOptions::default()).await
+}
+
+/// Like [`send_message`], but with a timeout or a cancellation handle in `options`. The timeout
+/// applies to the whole exchange, and not to each chunk.
+///
+/// If the exchange is interrupted, the V-App aborts the request, and the error is
+/// `SendMessageError::VAppExecutionError` with [`VAppExecutionError::TimedOut`] or
+/// [`VAppExecutionError::Cancelled`].
+pub async fn send_message_with(
+    transport: &mut Box<dyn VAppTransport + Send>,
+    message: &[u8],
+    options: SendOptions,
+) -> Result<Vec<u8>, SendMessageError> {
+    let mut response = Vec::new();
+    send_message_streaming(transport, message.len(), message, &mut response, options).await?;
+    Ok(response)
+}
+
+/// Like [`send_message_with`], but the message is read from `message` and the response is written
+/// to `response` while the chunks are exchanged, so that neither needs to be buffered whole.
+///
+/// `message` must provide at least `length` bytes; only the first `length` bytes are sent.
+/// Returns the length of the response.
+///
+/// The V-App can process the message incrementally with the `MessageReader` of the V-App SDK.
+pub async fn send_message_streaming<R: Read, W: Write>(
+    transport: &mut Box<dyn VAppTransport + Send>,
+    length: usize,
+    mut message: R,
+    mut response: W,
+    options: SendOptions,
+) -> Result<usize, SendMessageError> {
+    let start = Instant::now();
+
+    // the first chunk starts with the length of the message (as a 4-byte big-endian)
+    let mut chunk: Vec<u8> = Vec::with_capacity(CHUNK_LENGTH);
+    chunk.extend_from_slice(&(length as u32).to_be_bytes());
+    let mut remaining = length;
+
+    let resp = loop {
+        let chunk_start = chunk.len();
+        let n = min(CHUNK_LENGTH - chunk_start, remaining);
+        chunk.resize(chunk_start + n, 0);
+        message
+            .read_exact(&mut chunk[chunk_start..])
+            .map_err(SendMessageError::Io)?;
+        remaining -= n;
 
-    let mut resp = ACK.to_vec();
-    for chunk in full_message.chunks(CHUNK_LENGTH) {
+        let resp = transport
+            .send_message_with(&chunk, options.since(start))
+            .await
+            .map_err(SendMessageError::VAppExecutionError)?;
+        if remaining == 0 {
+            break resp;
+        }
         if resp != ACK {
             return Err(SendMessageError::NotAckReceived);
         }
-        resp = transport
-            .send_message(chunk)
-            .await
-            .map_err(SendMessageError::VAppExecutionError)?;
-    }
+        chunk.clear();
+    };
 
     // The first 4 bytes contain the length of the data in the response.
     // All the remaining data is the response data.
@@ -86,13 +141,136 @@ pub async fn send_message(
             .map_err(|_| SendMessageError::ResponseTooShort)?,
     ) as usize;
 
-    let mut response_data = resp[4..].to_vec();
-    while response_data.len() < response_data_len {
+    let mut received = resp.len() - 4;
+    response
+        .write_all(&resp[4..])
+        .map_err(SendMessageError::Io)?;
+    while received < response_data_len {
         let resp = transport
-            .send_message(&ACK)
+            .send_message_with(&ACK, options.since(start))
             .await
             .map_err(SendMessageError::VAppExecutionError)?;
-        response_data.extend_from_slice(&resp);
+        received += resp.len();
+        response.write_all(&resp).map_err(SendMessageError::Io)?;
+    }
+    Ok(response_data_len)
+}
+
+/// Clients generated by the `#[rpc]` attribute exchange messages with [`send_message`].
+impl RpcTransport for Box<dyn VAppTransport + Send> {
+    type Error = SendMessageError;
+
+    fn call(
+        &mut self,
+        request: &[u8],
+    ) -> impl Future<Output = Result<Vec<u8>, Self::Error>> + Send {
+        send_message(self, request)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_trait::async_trait;
+    use std::collections::VecDeque;
+    use std::sync::{Arc, Mutex};
+
+    // A V-App that responds with the message reversed, recording the length of the chunks it
+    // receives
+    #[derive(Default)]
+    struct ReversingVApp {
+        request: Vec<u8>,
+        request_len: usize,
+        response: VecDeque<Vec<u8>>,
+        chunk_lengths: Arc<Mutex<Vec<usize>>>,
+    }
+
+    #[async_trait]
+    impl VAppTransport for ReversingVApp {
+        async fn send_message_with(
+            &mut self,
+            msg: &[u8],
+            _options: SendOptions,
+        ) -> Result<Vec<u8>, VAppExecutionError> {
+            if let Some(chunk) = self.response.pop_front() {
+                assert_eq!(msg, ACK);
+                return Ok(chunk);
+            }
+
+            let mut chunk_lengths = self.chunk_lengths.lock().unwrap();
+            if chunk_lengths.is_empty() {
+                self.request_len = u32::from_be_bytes(msg[0..4].try_into().unwrap()) as usize;
+                self.request.extend_from_slice(&msg[4..]);
+            } else {
+                self.request.extend_from_slice(msg);
+            }
+            chunk_lengths.push(msg.len());
+            if self.request.len() < self.request_len {
+                return Ok(ACK.to_vec());
+            }
+
+            let mut response = (self.request_len as u32).to_be_bytes().to_vec();
+            response.extend(self.request.iter().rev());
+            self.response = response.chunks(CHUNK_LENGTH).map(|c| c.to_vec()).collect();
+            Ok(self.response.pop_front().unwrap())
+        }
+    }
+
+    fn reversing_vapp() -> (Box<dyn VAppTransport + Send>, Arc<Mutex<Vec<usize>>>) {
+        let vapp = ReversingVApp::default();
+        let chunk_lengths = vapp.chunk_lengths.clone();
+        (Box::new(vapp), chunk_lengths)
+    }
+
+    #[tokio::test]
+    async fn test_send_message() {
+        let message: Vec<u8> = (0..1000).map(|i| i as u8).collect();
+        let reversed: Vec<u8> = message.iter().rev().copied().collect();
+
+        let (mut transport, chunk_lengths) = reversing_vapp();
+        assert_eq!(
+            send_message(&mut transport, &message).await.unwrap(),
+            reversed
+        );
+        assert_eq!(*chunk_lengths.lock().unwrap(), [256, 256, 256, 236]);
+
+        let (mut transport, chunk_lengths) = reversing_vapp();
+        assert_eq!(send_message(&mut transport, &[]).await.unwrap(), b"");
+        assert_eq!(*chunk_lengths.lock().unwrap(), [4]);
+    }
+
+    #[tokio::test]
+    async fn test_send_message_streaming() {
+        let message: Vec<u8> = (0..1000).map(|i| i as u8).collect();
+
+        // only the first `length` bytes of the reader are sent
+        let (mut transport, chunk_lengths) = reversing_vapp();
+        let mut response = Vec::new();
+        let len = send_message_streaming(
+            &mut transport,
+            600,
+            &message[..],
+            &mut response,
+            SendOptions::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(len, 600);
+        assert!(response.iter().eq(message[..600].iter().rev()));
+        assert_eq!(*chunk_lengths.lock().unwrap(), [256, 256, 92]);
+
+        // the reader ends before the length of the message
+        let (mut transport, _) = reversing_vapp();
+        assert!(matches!(
+            send_message_streaming(
+                &mut transport,
+                2000,
+                &message[..],
+                Vec::new(),
+                SendOptions::default()
+            )
+            .await,
+            Err(SendMessageError::Io(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof
+        ));
     }
-    Ok(response_data)
 }
@@ -1,25 +1,43 @@
-use common::manifest::Manifest;
+use common::manifest::{Manifest, SegmentDescriptor, MAX_SEGMENTS};
+use common::vm::SegmentPermissions;
 use goblin::elf::program_header::{PF_R, PF_W, PF_X, PT_LOAD};
 use goblin::elf::{Elf, ProgramHeader};
 
 use core::panic;
+use std::collections::BTreeMap;
 use std::fs::File;
 use std::io;
 use std::io::Read;
 use std::path::Path;
 
+use crate::memory::MemorySegment;
+
 // The section name where the V-App manifest is stored in a packaged ELF binary.
 const MANIFEST_SECTION_NAME: &str = ".manifest";
 
+// The section name where the compressed pages of the code segments are stored in a packaged ELF
+// binary.
+pub const COMPRESSED_PAGES_SECTION_NAME: &str = ".vnd_pages";
+
+/// The compressed content of the pages of some segments, by start address of the segment. For each
+/// page, it's `None` if the page is not smaller once compressed.
+pub type CompressedPages = BTreeMap<u32, Vec<Option<Vec<u8>>>>;
+
 #[derive(Debug)]
 pub struct Segment {
     pub data: Vec<u8>,
     pub start: u32,
     pub end: u32,
+    pub permissions: SegmentPermissions,
 }
 
 impl Segment {
-    fn new(segment: &ProgramHeader, data: &[u8], memsize: usize) -> Self {
+    fn new(
+        segment: &ProgramHeader,
+        data: &[u8],
+        memsize: usize,
+        permissions: SegmentPermissions,
+    ) -> Self {
         if (memsize as u64) < segment.p_filesz {
             panic!("memsize cannot be smaller than p_filesz");
         }
@@ -33,17 +51,20 @@ impl Segment {
             data,
             start,
             end: start + memsize as u32,
+            permissions,
         }
     }
 }
 
 #[derive(Debug)]
 pub struct VAppElfFile {
-    pub code_segment: Segment,
-    pub data_segment: Segment,
+    // The loadable segments, sorted by address
+    pub segments: Vec<Segment>,
     pub entrypoint: u32,
     // If the elf file has a .manifest section, the Manifest is parsed from it and stored here
     pub manifest: Option<Manifest>,
+    // The pages compressed when the V-App was packaged, if any
+    pub compressed_pages: CompressedPages,
 }
 
 impl VAppElfFile {
@@ -55,7 +76,7 @@ impl VAppElfFile {
         let elf = Elf::parse(&buffer).unwrap();
         assert_eq!(elf.header.e_machine, goblin::elf::header::EM_RISCV);
 
-        let (code_segment, data_segment) = Self::parse_segments(&elf, &buffer)?;
+        let segments = Self::parse_segments(&elf, &buffer)?;
         let entrypoint = elf.header.e_entry as u32;
 
         // extract the content of the .manifest section
@@ -90,64 +111,174 @@ impl VAppElfFile {
             None
         };
 
+        let compressed_pages = match elf.section_headers.iter().find(|section| {
+            elf.shdr_strtab.get_at(section.sh_name).unwrap_or("") == COMPRESSED_PAGES_SECTION_NAME
+        }) {
+            Some(section) => {
+                let start = section.sh_offset as usize;
+                let size = section.sh_size as usize;
+                Self::parse_compressed_pages(&buffer[start..start + size])?
+            }
+            None => CompressedPages::new(),
+        };
+
         Ok(Self {
-            code_segment,
-            data_segment,
+            segments,
             entrypoint,
             manifest,
+            compressed_pages,
         })
     }
 
-    // Parses the Elf, extracting the code and data segments.
-    // Fails if there are not exactly two loadable segments, one read-execute and one read-write.
-    fn parse_segments(elf: &Elf, data: &[u8]) -> io::Result<(Segment, Segment)> {
-        let mut segments: Vec<_> = elf
-            .program_headers
+    /// Compresses the pages of the executable segments, that are usually very compressible.
+    pub fn compress_code_pages(&self) -> CompressedPages {
+        self.segments
             .iter()
-            .filter(|segment| segment.p_type == PT_LOAD)
-            .collect();
+            .filter(|segment| segment.permissions.is_executable())
+            .map(|segment| {
+                let pages = MemorySegment::new(segment.start, &segment.data)
+                    .compress_pages()
+                    .expect("the in-memory store never fails");
+                (segment.start, pages)
+            })
+            .collect()
+    }
+
+    /// Serializes compressed pages for the compressed pages section. For each segment, that is the
+    /// start address and the number of pages, followed by each page as its length (0 if it's not
+    /// compressed) and its compressed content. The integers are big-endian.
+    pub fn serialize_compressed_pages(compressed_pages: &CompressedPages) -> Vec<u8> {
+        let mut result = Vec::new();
+        for (start, pages) in compressed_pages {
+            result.extend_from_slice(&start.to_be_bytes());
+            result.extend_from_slice(&(pages.len() as u32).to_be_bytes());
+            for page in pages {
+                let page = page.as_deref().unwrap_or(&[]);
+                result.extend_from_slice(&(page.len() as u16).to_be_bytes());
+                result.extend_from_slice(page);
+            }
+        }
+        result
+    }
 
-        segments.sort_by_key(|segment| segment.p_flags);
+    fn parse_compressed_pages(mut data: &[u8]) -> io::Result<CompressedPages> {
+        fn take<'a>(data: &mut &'a [u8], len: usize) -> io::Result<&'a [u8]> {
+            if data.len() < len {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "Truncated compressed pages section",
+                ));
+            }
+            let (head, tail) = data.split_at(len);
+            *data = tail;
+            Ok(head)
+        }
 
-        if segments.len() != 2 {
-            return Err(io::Error::new(
-                io::ErrorKind::Other,
-                "Expected exactly 2 loadable segments",
-            ));
+        let mut compressed_pages = CompressedPages::new();
+        while !data.is_empty() {
+            let start = u32::from_be_bytes(take(&mut data, 4)?.try_into().unwrap());
+            let n_pages = u32::from_be_bytes(take(&mut data, 4)?.try_into().unwrap());
+            let mut pages = Vec::new();
+            for _ in 0..n_pages {
+                let len = u16::from_be_bytes(take(&mut data, 2)?.try_into().unwrap()) as usize;
+                let page = take(&mut data, len)?;
+                pages.push((len > 0).then(|| page.to_vec()));
+            }
+            compressed_pages.insert(start, pages);
+        }
+        Ok(compressed_pages)
+    }
+
+    /// Returns the segment table for the manifest of the V-App: the loadable segments of the ELF,
+    /// followed by a zero-initialized read-write heap segment between `heap_start` and `heap_end`,
+    /// and a zero-initialized read-write stack segment between `stack_start` and `stack_end`.
+    /// The indices of the heap and stack segments are therefore the last two.
+    ///
+    /// The zeros at the end of each segment, like the .bss section, are marked as such, so that the
+    /// VM does not need to fetch those pages until they are written.
+    pub fn segment_table(
+        &self,
+        stack_start: u32,
+        stack_end: u32,
+        heap_start: u32,
+        heap_end: u32,
+    ) -> Vec<SegmentDescriptor> {
+        let mut table: Vec<SegmentDescriptor> = self
+            .segments
+            .iter()
+            .map(|segment| {
+                let merkle_root = MemorySegment::new(segment.start, &segment.data)
+                    .get_content_root()
+                    .clone()
+                    .into();
+                let zero_start = segment
+                    .data
+                    .iter()
+                    .rposition(|&b| b != 0)
+                    .map_or(0, |i| i + 1);
+                SegmentDescriptor::new(segment.start, segment.end, segment.permissions, merkle_root)
+                    .with_zero_start(segment.start + zero_start as u32)
+            })
+            .collect();
+
+        // The heap and the stack are not allocated, as they might be large
+        for (start, end) in [(heap_start, heap_end), (stack_start, stack_end)] {
+            let merkle_root = MemorySegment::new_zeroed(start, end)
+                .get_content_root()
+                .clone()
+                .into();
+            table.push(
+                SegmentDescriptor::new(start, end, SegmentPermissions::RW, merkle_root)
+                    .with_zero_start(start),
+            );
         }
+        table
+    }
 
-        let flags: Vec<_> = segments.iter().map(|segment| segment.p_flags).collect();
+    // Parses the Elf, extracting each loadable segment, sorted by address.
+    // Fails if a segment is not read-only, read-write or read-execute, or if there are too many.
+    fn parse_segments(elf: &Elf, data: &[u8]) -> io::Result<Vec<Segment>> {
+        let mut program_headers: Vec<_> = elf
+            .program_headers
+            .iter()
+            .filter(|segment| segment.p_type == PT_LOAD && segment.p_memsz > 0)
+            .collect();
+        program_headers.sort_by_key(|segment| segment.p_vaddr);
 
-        let rx = PF_R | PF_X;
-        let rw = PF_R | PF_W;
-        if flags != vec![rx, rw] {
+        if program_headers.is_empty() || program_headers.len() > MAX_SEGMENTS - 2 {
+            // two more segments are needed for the heap and the stack
             return Err(io::Error::new(
                 io::ErrorKind::Other,
-                "Expected exactly one read-execute and one read-write segment",
+                "Unexpected number of loadable segments",
             ));
         }
 
-        let code_start = segments[0].p_offset as usize;
-        let code_size = segments[0].p_filesz as usize;
-
-        // For the code section, we expect the memory size and the file size to be the same
-        assert_eq!(segments[0].p_memsz, segments[0].p_filesz);
+        let mut segments = Vec::with_capacity(program_headers.len());
+        for segment in program_headers {
+            let permissions = match segment.p_flags & (PF_R | PF_W | PF_X) {
+                flags if flags == PF_R => SegmentPermissions::R,
+                flags if flags == PF_R | PF_W => SegmentPermissions::RW,
+                flags if flags == PF_R | PF_X => SegmentPermissions::RX,
+                _ => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::Other,
+                        "Loadable segments must be read-only, read-write or read-execute",
+                    ))
+                }
+            };
 
-        let code_seg = Segment::new(
-            segments[0],
-            &data[code_start..code_start + code_size],
-            code_size,
-        );
-        let data_start = segments[1].p_offset as usize;
-        let data_filesize = segments[1].p_filesz as usize;
-        let data_memsize = segments[1].p_memsz as usize;
-        let data_seg = Segment::new(
-            segments[1],
-            &data[data_start..data_start + data_filesize],
-            data_memsize,
-        );
+            let start = segment.p_offset as usize;
+            let filesize = segment.p_filesz as usize;
+            let memsize = segment.p_memsz as usize;
+            segments.push(Segment::new(
+                segment,
+                &data[start..start + filesize],
+                memsize,
+                permissions,
+            ));
+        }
 
-        Ok((code_seg, data_seg))
+        Ok(segments)
     }
 }
 
@@ -175,3 +306,51 @@ pub fn get_app_metadata(
             .ok_or("VApp metadata missing in Cargo.toml (add [package.metadata.vapp] section)")?,
     ))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Builds a 32-bit ELF file whose only content is `n` read-only loadable segments, one page
+    // each, without any data in the file
+    fn elf_with_segments(n: usize) -> Vec<u8> {
+        let mut elf = Vec::new();
+        elf.extend_from_slice(b"\x7fELF");
+        elf.extend_from_slice(&[1, 1, 1, 0]); // 32 bits, little-endian, version 1, System V
+        elf.extend_from_slice(&[0; 8]);
+        for half in [2u16, 0xF3] {
+            elf.extend_from_slice(&half.to_le_bytes()); // executable, RISC-V
+        }
+        for word in [1u32, 0x1000, 52, 0, 0] {
+            elf.extend_from_slice(&word.to_le_bytes()); // version, entry, phoff, shoff, flags
+        }
+        for half in [52u16, 32, n as u16, 40, 0, 0] {
+            elf.extend_from_slice(&half.to_le_bytes()); // sizes and numbers of headers
+        }
+        for i in 0..n as u32 {
+            let vaddr = 0x1000 * (i + 1);
+            // type, offset, vaddr, paddr, filesz, memsz, flags, align
+            for word in [PT_LOAD, 0, vaddr, vaddr, 0, 0x100, PF_R, 0x1000] {
+                elf.extend_from_slice(&word.to_le_bytes());
+            }
+        }
+        elf
+    }
+
+    #[test]
+    fn test_number_of_segments() {
+        // the heap and the stack segments must fit in the manifest too
+        let data = elf_with_segments(MAX_SEGMENTS - 2);
+        let elf = Elf::parse(&data).unwrap();
+        assert_eq!(
+            VAppElfFile::parse_segments(&elf, &data).unwrap().len(),
+            MAX_SEGMENTS - 2
+        );
+
+        for n in [0, MAX_SEGMENTS - 1] {
+            let data = elf_with_segments(n);
+            let elf = Elf::parse(&data).unwrap();
+            assert!(VAppElfFile::parse_segments(&elf, &data).is_err());
+        }
+    }
+}
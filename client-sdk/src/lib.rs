@@ -3,9 +3,10 @@ pub use app_sdk::hash;
 
 pub mod elf;
 pub mod memory;
+pub mod page_store;
 
 #[cfg(feature = "transport")]
-mod apdu;
+pub mod apdu;
 #[cfg(feature = "transport")]
 pub mod comm;
 #[cfg(feature = "transport")]
@@ -21,6 +22,7 @@ pub mod vanadium_client;
 pub mod test_utils;
 
 pub use common::manifest;
+pub use common::rpc;
 
 // re-export if using the cargo_toml feature
 #[cfg(feature = "cargo_toml")]
@@ -1,17 +1,23 @@
+use common::client_commands::PageContent;
+use common::compression::{compress_page, decompress};
 use common::constants::page_start;
 use common::constants::PAGE_SIZE;
 use common::vm::MemoryError;
 use std::cmp::min;
 
-use common::accumulator::{AccumulatorError, HashOutput, MerkleAccumulator, VectorAccumulator};
+use common::accumulator::{
+    range_proof_positions, top_nodes_count, truncated_range_proof_positions, AccumulatorError,
+    HashOutput, MerkleAccumulator, SparseMerkleAccumulator,
+};
 
 use crate::hash::Sha256;
+use crate::page_store::{MemoryPageStore, PageStore, SERIALIZED_PAGE_SIZE};
 
 // Serializes a page in the format expected for the content of the leaf in the MerkleAccumulator, as follows:
 // - Clear-text pages are serialized as a 0 byte, followed by 12 0 bytes, followed by PAGE_SIZE bytes (page plaintext).
 // - Encrypted pages are serialized as a 1 byte, followed by 12 bytes for the nonce, followed by PAGE_SIZE bytes (page ciphertext).
 fn get_serialized_page(data: &[u8], nonce: Option<&[u8; 12]>) -> Vec<u8> {
-    let mut serialized_page = Vec::<u8>::with_capacity(1 + 12 + PAGE_SIZE);
+    let mut serialized_page = Vec::<u8>::with_capacity(SERIALIZED_PAGE_SIZE);
     if let Some(nonce) = nonce {
         serialized_page.push(1); // is_encrypted
         serialized_page.extend_from_slice(nonce);
@@ -26,8 +32,10 @@ fn get_serialized_page(data: &[u8], nonce: Option<&[u8; 12]>) -> Vec<u8> {
 pub enum MemorySegmentError {
     PageNotFound,
     InvalidPageSize,
+    InvalidCompressedPage,
     MemoryError(MemoryError),
     AccumulatorError(AccumulatorError),
+    StoreError(std::io::Error),
 }
 
 impl From<MemoryError> for MemorySegmentError {
@@ -42,13 +50,21 @@ impl From<AccumulatorError> for MemorySegmentError {
     }
 }
 
+impl From<std::io::Error> for MemorySegmentError {
+    fn from(e: std::io::Error) -> Self {
+        MemorySegmentError::StoreError(e)
+    }
+}
+
 impl std::fmt::Display for MemorySegmentError {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         match self {
             MemorySegmentError::PageNotFound => write!(f, "Page not found"),
             MemorySegmentError::InvalidPageSize => write!(f, "Invalid page size"),
+            MemorySegmentError::InvalidCompressedPage => write!(f, "Invalid compressed page"),
             MemorySegmentError::MemoryError(e) => write!(f, "Memory error: {}", e),
             MemorySegmentError::AccumulatorError(e) => write!(f, "Accumulator error: {}", e),
+            MemorySegmentError::StoreError(e) => write!(f, "Store error: {}", e),
         }
     }
 }
@@ -58,18 +74,49 @@ impl std::error::Error for MemorySegmentError {
         match self {
             MemorySegmentError::MemoryError(e) => Some(e),
             MemorySegmentError::AccumulatorError(e) => Some(e),
+            MemorySegmentError::StoreError(e) => Some(e),
             _ => None,
         }
     }
 }
 
-// Represents a memory segment stored by the client, using a MerkleAccumulator to provide proofs of integrity.
+type Tree = MerkleAccumulator<Sha256, Vec<u8>, 32>;
+type ZeroTree = SparseMerkleAccumulator<Sha256, Vec<u8>, 32>;
+
+// Number of nodes at the top of the Merkle tree that are always kept in memory, as they are part
+// of most proofs: the first 12 levels
+const N_CACHED_NODES: usize = (1 << 12) - 1;
+
+// Represents a memory segment stored by the client, using a Merkle tree to provide proofs of
+// integrity. The pages and the nodes of the tree are kept in a PageStore, except for the top levels
+// of the tree.
+//
+// The tree is laid out like the one of a MerkleAccumulator, with the same root and proofs.
 pub struct MemorySegment {
-    content: MerkleAccumulator<Sha256, Vec<u8>, 32>,
+    n_pages: u32,
+    store: Box<dyn PageStore>,
+    // The nodes at the first positions of the tree, that are its top levels
+    cached_nodes: Vec<HashOutput<32>>,
+    // For zero-initialized segments, the tree of the all-zero segment. The nodes and the pages are
+    // then only written once they differ from the zero ones, which makes very large segments cheap.
+    zero_tree: Option<ZeroTree>,
+    // The compressed content of the plaintext pages that were compressed when the V-App was
+    // packaged, if any. A page is removed from here when it's stored again.
+    compressed_pages: Vec<Option<Vec<u8>>>,
 }
 
 impl MemorySegment {
     pub fn new(start: u32, data: &[u8]) -> Self {
+        Self::new_with_store(start, data, Box::new(MemoryPageStore::default()))
+            .expect("the in-memory store never fails")
+    }
+
+    // Creates a segment starting at `start` with the content `data`, kept in the given store.
+    pub fn new_with_store(
+        start: u32,
+        data: &[u8],
+        store: Box<dyn PageStore>,
+    ) -> Result<Self, MemorySegmentError> {
         let end = start + data.len() as u32;
 
         let mut pages: Vec<Vec<u8>> = Vec::new();
@@ -103,22 +150,142 @@ impl MemorySegment {
             pages.push(serialized_page);
         }
 
-        Self {
-            content: MerkleAccumulator::<Sha256, Vec<u8>, 32>::new(pages),
+        let n = pages.len();
+        let mut segment = Self {
+            n_pages: n as u32,
+            store,
+            cached_nodes: vec![HashOutput([0; 32]); min(2 * n - 1, N_CACHED_NODES)],
+            zero_tree: None,
+            compressed_pages: Vec::new(),
+        };
+
+        // build the tree bottom-up, like MerkleAccumulator::new
+        let mut tree = vec![HashOutput([0; 32]); 2 * n - 1];
+        for (i, page) in pages.iter().enumerate() {
+            tree[n - 1 + i] = Tree::hash_leaf(page);
+            segment.store.write_page(i as u32, page)?;
+        }
+        for i in (0..n - 1).rev() {
+            tree[i] = Tree::hash_internal_node(&tree[2 * i + 1], &tree[2 * i + 2]);
         }
+        for (pos, node) in tree.into_iter().enumerate() {
+            segment.set_node(pos, node)?;
+        }
+        Ok(segment)
+    }
+
+    // Creates a zero-initialized segment from `start` to `end`. The pages are not allocated nor
+    // hashed until they are written, which makes very large segments cheap.
+    pub fn new_zeroed(start: u32, end: u32) -> Self {
+        Self::new_zeroed_with_store(start, end, Box::new(MemoryPageStore::default()))
+            .expect("the in-memory store never fails")
+    }
+
+    // Like new_zeroed, but the segment is kept in the given store.
+    pub fn new_zeroed_with_store(
+        start: u32,
+        end: u32,
+        store: Box<dyn PageStore>,
+    ) -> Result<Self, MemorySegmentError> {
+        let n_pages = Self::n_pages(start, end);
+        let zero_tree = ZeroTree::new(n_pages as usize, zero_page()).expect("n_pages > 0");
+        let n_cached_nodes = min(2 * n_pages as usize - 1, N_CACHED_NODES);
+        let cached_nodes = (0..n_cached_nodes)
+            .map(|pos| zero_tree.default_node(pos).clone())
+            .collect();
+        Ok(Self {
+            n_pages,
+            store,
+            cached_nodes,
+            zero_tree: Some(zero_tree),
+            compressed_pages: Vec::new(),
+        })
+    }
+
+    // Returns the number of pages of a segment from `start` to `end`
+    pub fn n_pages(start: u32, end: u32) -> u32 {
+        1 + (page_start(end - 1) - page_start(start)) / PAGE_SIZE as u32
+    }
+
+    // Returns the hash of the node at the given position
+    fn node(&mut self, pos: usize) -> Result<HashOutput<32>, MemorySegmentError> {
+        if let Some(node) = self.cached_nodes.get(pos) {
+            return Ok(node.clone());
+        }
+        if let Some(node) = self.store.read_node(pos)? {
+            return Ok(node);
+        }
+        match &self.zero_tree {
+            Some(zero_tree) => Ok(zero_tree.default_node(pos).clone()),
+            None => Err(MemorySegmentError::StoreError(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "missing Merkle tree node",
+            ))),
+        }
+    }
+
+    fn set_node(&mut self, pos: usize, hash: HashOutput<32>) -> Result<(), MemorySegmentError> {
+        match self.cached_nodes.get_mut(pos) {
+            Some(node) => *node = hash,
+            None => self.store.write_node(pos, &hash)?,
+        }
+        Ok(())
+    }
+
+    fn read_page(&mut self, page_index: u32) -> Result<Vec<u8>, MemorySegmentError> {
+        if page_index >= self.n_pages {
+            return Err(MemorySegmentError::PageNotFound);
+        }
+        match self.store.read_page(page_index)? {
+            Some(page) => Ok(page),
+            None if self.zero_tree.is_some() => Ok(zero_page()),
+            None => Err(MemorySegmentError::PageNotFound),
+        }
+    }
+
+    fn prove(&mut self, page_index: u32) -> Result<Vec<HashOutput<32>>, MemorySegmentError> {
+        let mut proof = Vec::new();
+        let mut pos = self.n_pages as usize - 1 + page_index as usize;
+        while pos > 0 {
+            let sibling = if pos.is_multiple_of(2) {
+                pos - 1
+            } else {
+                pos + 1
+            };
+            proof.push(self.node(sibling)?);
+            pos = (pos - 1) / 2;
+        }
+        Ok(proof)
     }
 
     pub fn get_page(
-        &self,
+        &mut self,
         page_index: u32,
     ) -> Result<(Vec<u8>, Vec<HashOutput<32>>), MemorySegmentError> {
-        let content = self
-            .content
-            .get(page_index as usize)
-            .ok_or(MemorySegmentError::PageNotFound)?
-            .clone();
+        let content = self.read_page(page_index)?;
+        let proof = self.prove(page_index)?;
+        Ok((content, proof))
+    }
 
-        let proof = self.content.prove(page_index as usize)?;
+    // Returns the concatenation of the serialized content of the `n_pages` pages starting at
+    // `page_index`, and the Merkle multiproof for all of them.
+    pub fn get_pages(
+        &mut self,
+        page_index: u32,
+        n_pages: u32,
+    ) -> Result<(Vec<u8>, Vec<HashOutput<32>>), MemorySegmentError> {
+        let positions =
+            range_proof_positions(self.n_pages as usize, page_index as usize, n_pages as usize)
+                .map_err(|_| MemorySegmentError::PageNotFound)?;
+
+        let mut content = Vec::with_capacity(n_pages as usize * SERIALIZED_PAGE_SIZE);
+        for i in page_index..page_index + n_pages {
+            content.extend_from_slice(&self.read_page(i)?);
+        }
+        let proof = positions
+            .into_iter()
+            .map(|pos| self.node(pos))
+            .collect::<Result<_, _>>()?;
 
         Ok((content, proof))
     }
@@ -128,14 +295,110 @@ impl MemorySegment {
         page_index: u32,
         content: &[u8],
     ) -> Result<(Vec<HashOutput<32>>, HashOutput<32>), MemorySegmentError> {
-        if content.len() != 1 + 12 + PAGE_SIZE {
+        if PageContent::from_serialized_page(content).is_err() {
             return Err(MemorySegmentError::InvalidPageSize);
         }
-        let proof = self.content.update(page_index as usize, content.to_vec())?;
-        Ok(proof)
+        if page_index >= self.n_pages {
+            return Err(MemorySegmentError::PageNotFound);
+        }
+        let proof = self.prove(page_index)?; // Capture proof before update
+
+        self.store.write_page(page_index, content)?;
+        if let Some(compressed) = self.compressed_pages.get_mut(page_index as usize) {
+            *compressed = None;
+        }
+        let mut pos = self.n_pages as usize - 1 + page_index as usize;
+        self.set_node(pos, Tree::hash_leaf(&content))?;
+        while pos > 0 {
+            pos = (pos - 1) / 2;
+            let hash = Tree::hash_internal_node(&self.node(2 * pos + 1)?, &self.node(2 * pos + 2)?);
+            self.set_node(pos, hash)?;
+        }
+
+        Ok((proof, self.get_content_root().clone()))
+    }
+
+    // Returns the length of the multiproof for the `n_pages` pages starting at `page_index`, for a
+    // verifier that caches the `cached_levels` levels of the Merkle tree below the root. Such a
+    // proof is a prefix of the full one.
+    pub fn truncated_proof_len(
+        &self,
+        page_index: u32,
+        n_pages: u32,
+        cached_levels: u8,
+    ) -> Result<usize, MemorySegmentError> {
+        let size = self.n_pages as usize;
+        Ok(truncated_range_proof_positions(
+            size,
+            page_index as usize,
+            n_pages as usize,
+            top_nodes_count(size, cached_levels),
+        )?
+        .len())
+    }
+
+    // Returns the compressed content of each plaintext page that is smaller once compressed
+    pub fn compress_pages(&mut self) -> Result<Vec<Option<Vec<u8>>>, MemorySegmentError> {
+        (0..self.n_pages)
+            .map(|page_index| {
+                let page = self.read_page(page_index)?;
+                let is_encrypted = page[0] != 0;
+                Ok((!is_encrypted)
+                    .then(|| compress_page(page[13..].try_into().unwrap()))
+                    .flatten())
+            })
+            .collect()
+    }
+
+    // Sets the compressed content of the pages, as returned by compress_pages, to be sent to the
+    // VM instead of the pages. Each of them must decompress to the current content of the page.
+    pub fn set_compressed_pages(
+        &mut self,
+        compressed_pages: Vec<Option<Vec<u8>>>,
+    ) -> Result<(), MemorySegmentError> {
+        if compressed_pages.len() != self.n_pages as usize {
+            return Err(MemorySegmentError::InvalidCompressedPage);
+        }
+        for (page_index, compressed) in compressed_pages.iter().enumerate() {
+            if let Some(compressed) = compressed {
+                let page = self.read_page(page_index as u32)?;
+                let mut data = [0u8; PAGE_SIZE];
+                if page[0] != 0 || decompress(compressed, &mut data).is_err() || data != page[13..]
+                {
+                    return Err(MemorySegmentError::InvalidCompressedPage);
+                }
+            }
+        }
+        self.compressed_pages = compressed_pages;
+        Ok(())
+    }
+
+    // Returns the compressed content of a page, if it's known
+    pub fn get_compressed_page(&self, page_index: u32) -> Option<&[u8]> {
+        self.compressed_pages
+            .get(page_index as usize)
+            .and_then(|compressed| compressed.as_deref())
+    }
+
+    // Returns the `count` nodes of the Merkle tree starting at position `start`
+    pub fn get_nodes(
+        &mut self,
+        start: usize,
+        count: usize,
+    ) -> Result<Vec<HashOutput<32>>, MemorySegmentError> {
+        if start + count > 2 * self.n_pages as usize - 1 {
+            return Err(MemorySegmentError::AccumulatorError(
+                AccumulatorError::IndexOutOfBounds,
+            ));
+        }
+        (start..start + count).map(|pos| self.node(pos)).collect()
     }
 
     pub fn get_content_root(&self) -> &HashOutput<32> {
-        self.content.root()
+        &self.cached_nodes[0]
     }
 }
+
+fn zero_page() -> Vec<u8> {
+    get_serialized_page(&[0; PAGE_SIZE], None)
+}
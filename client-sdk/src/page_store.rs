@@ -0,0 +1,412 @@
+//! Backing stores for the memory segments of a V-App, that are kept by the client.
+//!
+//! A store holds the serialized pages of a segment, and the nodes of its Merkle tree. The
+//! [`MemorySegment`](crate::memory::MemorySegment) decides what to write; the store only needs
+//! to give back what was written.
+
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use common::accumulator::HashOutput;
+use common::client_commands::PageContent;
+use common::constants::PAGE_SIZE;
+
+/// Size of a serialized page: 1 byte for is_encrypted, 12 bytes for the nonce, and the content.
+pub const SERIALIZED_PAGE_SIZE: usize = 1 + 12 + PAGE_SIZE;
+
+/// Size of the largest serialized page, that is an authenticated page, that also has a version and
+/// a tag.
+pub const MAX_SERIALIZED_PAGE_SIZE: usize = PageContent::SERIALIZED_AUTHENTICATED_PAGE_SIZE;
+
+// Returns the size of the serialized page, if its content is valid
+fn serialized_page_size(page: &[u8]) -> io::Result<usize> {
+    page.first()
+        .and_then(|flag| PageContent::serialized_page_size(*flag))
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "invalid page format"))
+}
+
+const NODE_SIZE: usize = 32;
+
+// Size of the slot of a page in a `FilePageStore`: a marker byte, and the largest serialized page
+const PAGE_SLOT_SIZE: usize = 1 + MAX_SERIALIZED_PAGE_SIZE;
+
+/// Storage for the serialized pages and the Merkle tree nodes of a memory segment.
+pub trait PageStore: Send {
+    /// Returns the serialized page with the given index, or `None` if it was never written.
+    fn read_page(&mut self, index: u32) -> io::Result<Option<Vec<u8>>>;
+
+    fn write_page(&mut self, index: u32, page: &[u8]) -> io::Result<()>;
+
+    /// Returns the node at the given position of the Merkle tree, or `None` if it was never
+    /// written.
+    fn read_node(&mut self, pos: usize) -> io::Result<Option<HashOutput<32>>>;
+
+    fn write_node(&mut self, pos: usize, hash: &HashOutput<32>) -> io::Result<()>;
+}
+
+/// A store that keeps everything in memory.
+#[derive(Default)]
+pub struct MemoryPageStore {
+    pages: HashMap<u32, Vec<u8>>,
+    nodes: HashMap<usize, HashOutput<32>>,
+}
+
+impl PageStore for MemoryPageStore {
+    fn read_page(&mut self, index: u32) -> io::Result<Option<Vec<u8>>> {
+        Ok(self.pages.get(&index).cloned())
+    }
+
+    fn write_page(&mut self, index: u32, page: &[u8]) -> io::Result<()> {
+        self.pages.insert(index, page.to_vec());
+        Ok(())
+    }
+
+    fn read_node(&mut self, pos: usize) -> io::Result<Option<HashOutput<32>>> {
+        Ok(self.nodes.get(&pos).cloned())
+    }
+
+    fn write_node(&mut self, pos: usize, hash: &HashOutput<32>) -> io::Result<()> {
+        self.nodes.insert(pos, hash.clone());
+        Ok(())
+    }
+}
+
+// Used to give a different name to the file of each store created by this process
+static FILE_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// A store that keeps everything in a single file, that is deleted when the store is dropped.
+///
+/// The file contains all the pages, each in a slot of the size of the largest serialized page
+/// preceded by a marker byte, followed by all the nodes of the tree. It is created with its full
+/// size, but as a sparse file where supported, so that the parts that are never written do not use
+/// any disk space. Those parts read as zeros: the marker byte of a page is set when it is written,
+/// as a serialized page can be all zeros, and an all-zero node is treated as never written.
+pub struct FilePageStore {
+    file: File,
+    path: PathBuf,
+    n_pages: u32,
+}
+
+impl FilePageStore {
+    /// Creates a store for a segment of `n_pages` pages, in a new file in the directory `dir`.
+    pub fn create(dir: &Path, n_pages: u32) -> io::Result<Self> {
+        let path = dir.join(format!(
+            "vanadium-{}-{}.pages",
+            std::process::id(),
+            FILE_COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(&path)?;
+        let n_nodes = 2 * n_pages as u64 - 1;
+        file.set_len(n_pages as u64 * PAGE_SLOT_SIZE as u64 + n_nodes * NODE_SIZE as u64)?;
+        Ok(Self {
+            file,
+            path,
+            n_pages,
+        })
+    }
+
+    /// The path of the file of the store.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn page_offset(&self, index: u32) -> io::Result<u64> {
+        if index >= self.n_pages {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "page index out of bounds",
+            ));
+        }
+        Ok(index as u64 * PAGE_SLOT_SIZE as u64)
+    }
+
+    fn node_offset(&self, pos: usize) -> io::Result<u64> {
+        if pos >= 2 * self.n_pages as usize - 1 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "node position out of bounds",
+            ));
+        }
+        Ok(self.n_pages as u64 * PAGE_SLOT_SIZE as u64 + pos as u64 * NODE_SIZE as u64)
+    }
+
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        self.file.seek(SeekFrom::Start(offset))?;
+        self.file.read_exact(buf)
+    }
+
+    fn write_at(&mut self, offset: u64, buf: &[u8]) -> io::Result<()> {
+        self.file.seek(SeekFrom::Start(offset))?;
+        self.file.write_all(buf)
+    }
+}
+
+impl PageStore for FilePageStore {
+    fn read_page(&mut self, index: u32) -> io::Result<Option<Vec<u8>>> {
+        let offset = self.page_offset(index)?;
+        let mut slot = vec![0u8; PAGE_SLOT_SIZE];
+        self.read_at(offset, &mut slot)?;
+        if slot[0] == 0 {
+            return Ok(None);
+        }
+        let mut page = slot.split_off(1);
+        // the rest of the slot is unused, and might contain a previous page
+        page.truncate(serialized_page_size(&page)?);
+        Ok(Some(page))
+    }
+
+    fn write_page(&mut self, index: u32, page: &[u8]) -> io::Result<()> {
+        if serialized_page_size(page).ok() != Some(page.len()) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid page size",
+            ));
+        }
+        let offset = self.page_offset(index)?;
+        self.write_at(offset, &[&[1], page].concat())
+    }
+
+    fn read_node(&mut self, pos: usize) -> io::Result<Option<HashOutput<32>>> {
+        let offset = self.node_offset(pos)?;
+        let mut node = [0u8; NODE_SIZE];
+        self.read_at(offset, &mut node)?;
+        Ok((node != [0u8; NODE_SIZE]).then_some(HashOutput(node)))
+    }
+
+    fn write_node(&mut self, pos: usize, hash: &HashOutput<32>) -> io::Result<()> {
+        let offset = self.node_offset(pos)?;
+        self.write_at(offset, &hash.0)
+    }
+}
+
+impl Drop for FilePageStore {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// The kind of store used for the memory segments of a V-App.
+#[derive(Debug, Clone, Default)]
+pub enum PageStoreKind {
+    /// Everything is kept in memory.
+    #[default]
+    Memory,
+    /// Each segment is kept in its own file in the given directory, and only the top levels of
+    /// its Merkle tree are kept in memory.
+    File(PathBuf),
+}
+
+impl PageStoreKind {
+    /// Creates an empty store for a segment of `n_pages` pages.
+    pub fn create(&self, n_pages: u32) -> io::Result<Box<dyn PageStore>> {
+        Ok(match self {
+            PageStoreKind::Memory => Box::new(MemoryPageStore::default()),
+            PageStoreKind::File(dir) => Box::new(FilePageStore::create(dir, n_pages)?),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hash::Sha256;
+    use crate::memory::MemorySegment;
+    use common::accumulator::{MerkleAccumulator, VectorAccumulator};
+
+    fn page(byte: u8) -> Vec<u8> {
+        let mut page = vec![byte; SERIALIZED_PAGE_SIZE];
+        page[0] = 1;
+        page
+    }
+
+    #[test]
+    fn test_file_page_store() {
+        let mut store = FilePageStore::create(&std::env::temp_dir(), 4).unwrap();
+        let path = store.path().to_path_buf();
+        assert!(path.exists());
+
+        assert_eq!(store.read_page(3).unwrap(), None);
+        assert_eq!(store.read_node(6).unwrap(), None);
+        store.write_page(3, &page(7)).unwrap();
+        store.write_node(6, &HashOutput([5; 32])).unwrap();
+        assert_eq!(store.read_page(3).unwrap(), Some(page(7)));
+        assert_eq!(store.read_node(6).unwrap(), Some(HashOutput([5; 32])));
+        assert_eq!(store.read_page(2).unwrap(), None);
+
+        // an authenticated page is larger, and a smaller page can overwrite it
+        let mut authenticated = vec![3u8; MAX_SERIALIZED_PAGE_SIZE];
+        authenticated[1] = 8;
+        store.write_page(1, &authenticated).unwrap();
+        assert_eq!(store.read_page(1).unwrap(), Some(authenticated));
+        store.write_page(1, &page(6)).unwrap();
+        assert_eq!(store.read_page(1).unwrap(), Some(page(6)));
+        assert_eq!(store.read_page(2).unwrap(), None);
+
+        assert!(store.read_page(4).is_err());
+        assert!(store.read_node(7).is_err());
+        assert!(store.write_page(0, &[0; 10]).is_err());
+
+        drop(store);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_segment_stores() {
+        let data: Vec<u8> = (0..5 * PAGE_SIZE + 100).map(|i| i as u8).collect();
+        let kinds = [
+            PageStoreKind::Memory,
+            PageStoreKind::File(std::env::temp_dir()),
+        ];
+        for kind in kinds.iter() {
+            let n_pages = MemorySegment::n_pages(0x1000, 0x1000 + data.len() as u32);
+            assert_eq!(n_pages, 6);
+            let mut segment =
+                MemorySegment::new_with_store(0x1000, &data, kind.create(n_pages).unwrap())
+                    .unwrap();
+
+            // same tree as a MerkleAccumulator with the same pages
+            let mut pages = Vec::new();
+            for i in 0..n_pages {
+                pages.push(segment.get_page(i).unwrap().0);
+            }
+            let mut accumulator = MerkleAccumulator::<Sha256, Vec<u8>, 32>::new(pages);
+            assert_eq!(segment.get_content_root(), accumulator.root());
+            assert_eq!(
+                segment.get_page(4).unwrap().1,
+                accumulator.prove(4).unwrap()
+            );
+            assert_eq!(
+                segment.get_pages(1, 3).unwrap().1,
+                accumulator.prove_range(1, 3).unwrap()
+            );
+
+            // the nodes of the tree, and the proofs for a verifier caching some of them
+            assert_eq!(
+                segment.get_nodes(0, 1).unwrap(),
+                vec![accumulator.root().clone()]
+            );
+            assert_eq!(segment.get_nodes(0, 11).unwrap().len(), 11);
+            assert!(segment.get_nodes(5, 7).is_err());
+            assert_eq!(segment.truncated_proof_len(4, 1, 0).unwrap(), 3);
+            assert_eq!(segment.truncated_proof_len(4, 1, 2).unwrap(), 1);
+            assert_eq!(segment.truncated_proof_len(0, 1, 2).unwrap(), 0);
+
+            let (proof, root) = segment.store_page(2, &page(9)).unwrap();
+            let (expected_proof, expected_root) = accumulator.update(2, page(9)).unwrap();
+            assert_eq!((proof, root), (expected_proof, expected_root));
+            assert_eq!(segment.get_page(2).unwrap().0, page(9));
+        }
+    }
+
+    #[test]
+    fn test_zeroed_segments() {
+        // sparse segments have the same tree as a dense one, whatever their number of pages
+        for n_pages in [1u32, 2, 3, 5, 8, 11] {
+            let (start, end) = (0x1000, 0x1000 + n_pages * PAGE_SIZE as u32);
+            let mut segment = MemorySegment::new_zeroed(start, end);
+            let mut pages: Vec<Vec<u8>> = (0..n_pages)
+                .map(|i| segment.get_page(i).unwrap().0)
+                .collect();
+            assert_eq!(
+                segment.get_content_root(),
+                MerkleAccumulator::<Sha256, Vec<u8>, 32>::new(pages.clone()).root()
+            );
+
+            for (i, index) in [n_pages - 1, 0, n_pages / 2].into_iter().enumerate() {
+                let mut accumulator = MerkleAccumulator::<Sha256, Vec<u8>, 32>::new(pages.clone());
+                assert_eq!(
+                    segment.store_page(index, &page(i as u8)).unwrap(),
+                    accumulator.update(index as usize, page(i as u8)).unwrap()
+                );
+                pages[index as usize] = page(i as u8);
+                for j in 0..n_pages {
+                    assert_eq!(
+                        segment.get_page(j).unwrap(),
+                        (
+                            pages[j as usize].clone(),
+                            accumulator.prove(j as usize).unwrap()
+                        )
+                    );
+                }
+                let (first, count) = (n_pages / 2, n_pages - n_pages / 2);
+                assert_eq!(
+                    segment.get_pages(first, count).unwrap().1,
+                    accumulator
+                        .prove_range(first as usize, count as usize)
+                        .unwrap()
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_zero_page_in_file() {
+        // a dense segment, whose second page is all zeros, like its serialization
+        let mut data = vec![1u8; PAGE_SIZE];
+        data.extend_from_slice(&[0u8; PAGE_SIZE]);
+        let kind = PageStoreKind::File(std::env::temp_dir());
+        let mut segment =
+            MemorySegment::new_with_store(0x1000, &data, kind.create(2).unwrap()).unwrap();
+        let mut in_memory = MemorySegment::new(0x1000, &data);
+
+        let zero_page = segment.get_page(1).unwrap();
+        assert!(zero_page.0.iter().all(|b| *b == 0));
+        assert_eq!(zero_page, in_memory.get_page(1).unwrap());
+    }
+
+    #[test]
+    fn test_compressed_pages() {
+        // a page of zeros, and an incompressible page
+        let mut data = vec![0u8; PAGE_SIZE];
+        data.extend((0..PAGE_SIZE).map(|i| (i * 7) as u8));
+        let mut segment = MemorySegment::new(0x1000, &data);
+        let compressed = segment.compress_pages().unwrap();
+        assert!(compressed[0].as_ref().is_some_and(|c| c.len() < 16));
+        assert_eq!(compressed[1], None);
+
+        assert!(segment.set_compressed_pages(vec![None]).is_err());
+        assert!(segment
+            .set_compressed_pages(vec![compressed[0].clone(), compressed[0].clone()])
+            .is_err());
+        segment.set_compressed_pages(compressed.clone()).unwrap();
+        assert_eq!(segment.get_compressed_page(0), compressed[0].as_deref());
+        assert_eq!(segment.get_compressed_page(1), None);
+
+        // the compressed content is outdated once the page is modified
+        segment.store_page(0, &page(9)).unwrap();
+        assert_eq!(segment.get_compressed_page(0), None);
+    }
+
+    #[test]
+    fn test_large_zeroed_segment_in_file() {
+        // 256 MiB, that is only allocated where written
+        let (start, end) = (0x80000000, 0x90000000);
+        let n_pages = MemorySegment::n_pages(start, end);
+        let kind = PageStoreKind::File(std::env::temp_dir());
+        let mut segment =
+            MemorySegment::new_zeroed_with_store(start, end, kind.create(n_pages).unwrap())
+                .unwrap();
+        let mut in_memory = MemorySegment::new_zeroed(start, end);
+        assert_eq!(segment.get_content_root(), in_memory.get_content_root());
+
+        for index in [0, 12345, n_pages - 1] {
+            let result = segment.store_page(index, &page(index as u8)).unwrap();
+            assert_eq!(
+                result,
+                in_memory.store_page(index, &page(index as u8)).unwrap()
+            );
+        }
+        assert_eq!(segment.get_page(12345).unwrap().0, page(12345u32 as u8));
+        assert_eq!(
+            segment.get_page(12346).unwrap(),
+            in_memory.get_page(12346).unwrap()
+        );
+    }
+}
@@ -6,9 +6,13 @@ use std::sync::Arc;
 use tokio::time::{sleep, Duration};
 
 use crate::linewriter::FileLineWriter;
-use crate::transport::{TransportTcp, TransportWrapper};
+use crate::transport::{RecordingTransport, TransportTcp, TransportWrapper};
 use crate::vanadium_client::{VAppTransport, VanadiumAppClient};
 
+/// Environment variable with a directory where the APDUs exchanged by each test are recorded, in a
+/// file named after the test. The recordings can be replayed with `ReplayTransport`.
+pub const RECORD_DIR_ENV: &str = "VANADIUM_RECORD_APDUS";
+
 pub struct TestSetup<C> {
     pub client: C,
     pub transport_tcp: Arc<TransportTcp>,
@@ -24,7 +28,19 @@ impl<C> TestSetup<C> {
     {
         let (child, transport_tcp) = spawn_speculos_and_transport(speculos_binary).await;
 
-        let transport = Arc::new(TransportWrapper::new(transport_tcp.clone()));
+        let test_name = std::thread::current()
+            .name()
+            .unwrap_or("unknown_test")
+            .to_string();
+
+        let mut transport = Arc::new(TransportWrapper::new(transport_tcp.clone()));
+        if let Ok(dir) = std::env::var(RECORD_DIR_ENV) {
+            let path =
+                std::path::Path::new(&dir).join(format!("{}.apdu", test_name.replace("::", "-")));
+            let recorder = RecordingTransport::new(TransportWrapper::new(transport), &path)
+                .expect("Failed to create the APDU recording");
+            transport = Arc::new(TransportWrapper::new(Arc::new(recorder)));
+        }
 
         let client = create_client(transport).await;
 
@@ -35,12 +51,7 @@ impl<C> TestSetup<C> {
             .open("test.log")
             .expect("Failed to open test.log");
 
-        writeln!(
-            log_file,
-            "=== Test: {} ===",
-            std::thread::current().name().unwrap_or("unknown_test")
-        )
-        .unwrap();
+        writeln!(log_file, "=== Test: {} ===", test_name).unwrap();
 
         TestSetup {
             client,
@@ -1,10 +1,13 @@
 use std::convert::TryFrom;
 use std::error::Error;
 use std::fmt::Debug;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
 use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::Path;
 use std::sync::{
     atomic::{AtomicU64, Ordering},
-    Arc,
+    Arc, Mutex as StdMutex,
 };
 
 use async_trait::async_trait;
@@ -24,6 +27,13 @@ use crate::apdu::{APDUCommand, StatusWord};
 pub trait Transport: Send + Sync {
     type Error: Debug + Send + Sync;
     async fn exchange(&self, command: &APDUCommand) -> Result<(StatusWord, Vec<u8>), Self::Error>;
+
+    /// Same as [`Transport::exchange`], but returns the status word as sent by the device, even if
+    /// it's not one of the known [`StatusWord`]s.
+    async fn exchange_raw(&self, command: &APDUCommand) -> Result<(u16, Vec<u8>), Self::Error> {
+        let (status, response) = self.exchange(command).await?;
+        Ok((status as u16, response))
+    }
 }
 
 /// Transport with the Ledger device.
@@ -39,6 +49,14 @@ impl TransportHID {
 impl Transport for TransportHID {
     type Error = Box<dyn Error + Send + Sync>;
     async fn exchange(&self, cmd: &APDUCommand) -> Result<(StatusWord, Vec<u8>), Self::Error> {
+        let (status, response) = self.exchange_raw(cmd).await?;
+        Ok((
+            StatusWord::try_from(status).unwrap_or(StatusWord::Unknown),
+            response,
+        ))
+    }
+
+    async fn exchange_raw(&self, cmd: &APDUCommand) -> Result<(u16, Vec<u8>), Self::Error> {
         self.0
             .exchange(&APDUCommand {
                 ins: cmd.ins,
@@ -47,12 +65,7 @@ impl Transport for TransportHID {
                 p2: cmd.p2,
                 data: cmd.data.clone(),
             })
-            .map(|answer| {
-                (
-                    StatusWord::try_from(answer.retcode()).unwrap_or(StatusWord::Unknown),
-                    answer.data().to_vec(),
-                )
-            })
+            .map(|answer| (answer.retcode(), answer.data().to_vec()))
             .map_err(|e| e.into())
     }
 }
@@ -104,6 +117,14 @@ impl TransportTcp {
 impl Transport for TransportTcp {
     type Error = Box<dyn Error + Send + Sync>;
     async fn exchange(&self, command: &APDUCommand) -> Result<(StatusWord, Vec<u8>), Self::Error> {
+        let (status, response) = self.exchange_raw(command).await?;
+        Ok((
+            StatusWord::try_from(status).unwrap_or(StatusWord::Unknown),
+            response,
+        ))
+    }
+
+    async fn exchange_raw(&self, command: &APDUCommand) -> Result<(u16, Vec<u8>), Self::Error> {
         // Count every call to exchange.
         self.total_exchanges.fetch_add(1, Ordering::Relaxed);
 
@@ -131,10 +152,7 @@ impl Transport for TransportTcp {
             .fetch_add(resp.len() as u64, Ordering::Relaxed);
 
         let answer = APDUAnswer::from_answer(resp).map_err(|_| "Invalid Answer")?;
-        Ok((
-            StatusWord::try_from(answer.retcode()).unwrap_or(StatusWord::Unknown),
-            answer.data().to_vec(),
-        ))
+        Ok((answer.retcode(), answer.data().to_vec()))
     }
 }
 
@@ -153,4 +171,318 @@ impl Transport for TransportWrapper {
     async fn exchange(&self, command: &APDUCommand) -> Result<(StatusWord, Vec<u8>), Self::Error> {
         self.0.exchange(command).await
     }
+
+    async fn exchange_raw(&self, command: &APDUCommand) -> Result<(u16, Vec<u8>), Self::Error> {
+        self.0.exchange_raw(command).await
+    }
+}
+
+// Header of the files written by RecordingTransport, followed by the version of the format
+const RECORDING_MAGIC: &[u8; 8] = b"VNDAPDU\0";
+const RECORDING_VERSION: u8 = 1;
+
+/// An exchange recorded by [`RecordingTransport`]: the encoded command, and the answer. The status
+/// word is kept as sent by the device, so that the replay reproduces it even if it's not a known
+/// [`StatusWord`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RecordedExchange {
+    pub command: Vec<u8>,
+    pub status: u16,
+    pub response: Vec<u8>,
+}
+
+impl RecordedExchange {
+    fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        writer.write_all(&(self.command.len() as u32).to_be_bytes())?;
+        writer.write_all(&self.command)?;
+        writer.write_all(&self.status.to_be_bytes())?;
+        writer.write_all(&(self.response.len() as u32).to_be_bytes())?;
+        writer.write_all(&self.response)
+    }
+
+    // Reads the next exchange, or returns None at the end of the file
+    fn read_from<R: Read>(reader: &mut R) -> io::Result<Option<Self>> {
+        let mut len = [0u8; 4];
+        match reader.read_exact(&mut len) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e),
+        }
+        let mut command = vec![0u8; u32::from_be_bytes(len) as usize];
+        reader.read_exact(&mut command)?;
+        let mut status = [0u8; 2];
+        reader.read_exact(&mut status)?;
+        reader.read_exact(&mut len)?;
+        let mut response = vec![0u8; u32::from_be_bytes(len) as usize];
+        reader.read_exact(&mut response)?;
+        Ok(Some(Self {
+            command,
+            status: u16::from_be_bytes(status),
+            response,
+        }))
+    }
+}
+
+/// Transport that forwards the commands to another transport, and records every exchange to a
+/// file, to be replayed later with [`ReplayTransport`]. The exchanges that fail are not recorded;
+/// if an exchange cannot be written to the file, it fails with the I/O error.
+pub struct RecordingTransport<T: Transport> {
+    inner: T,
+    writer: StdMutex<BufWriter<File>>,
+}
+
+impl<T: Transport> RecordingTransport<T> {
+    /// Creates the recording file at `path`, replacing any existing file.
+    pub fn new(inner: T, path: impl AsRef<Path>) -> io::Result<Self> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        writer.write_all(RECORDING_MAGIC)?;
+        writer.write_all(&[RECORDING_VERSION])?;
+        writer.flush()?;
+        Ok(Self {
+            inner,
+            writer: StdMutex::new(writer),
+        })
+    }
+}
+
+#[async_trait]
+impl<T> Transport for RecordingTransport<T>
+where
+    T: Transport,
+    T::Error: Into<Box<dyn Error + Send + Sync>>,
+{
+    type Error = Box<dyn Error + Send + Sync>;
+    async fn exchange(&self, command: &APDUCommand) -> Result<(StatusWord, Vec<u8>), Self::Error> {
+        let (status, response) = self.exchange_raw(command).await?;
+        Ok((
+            StatusWord::try_from(status).unwrap_or(StatusWord::Unknown),
+            response,
+        ))
+    }
+
+    async fn exchange_raw(&self, command: &APDUCommand) -> Result<(u16, Vec<u8>), Self::Error> {
+        let (status, response) = self.inner.exchange_raw(command).await.map_err(Into::into)?;
+
+        let exchange = RecordedExchange {
+            command: command.encode(),
+            status,
+            response,
+        };
+        // Each exchange is flushed, so that the recording is usable even if the process is killed
+        let mut writer = self.writer.lock().unwrap();
+        exchange
+            .write_to(&mut *writer)
+            .and_then(|()| writer.flush())?;
+        Ok((exchange.status, exchange.response))
+    }
+}
+
+/// Error returned by [`ReplayTransport`] when the session diverges from the recording.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ReplayError {
+    /// The command of the exchange with the given index is not the recorded one
+    UnexpectedCommand {
+        index: usize,
+        expected: Vec<u8>,
+        actual: Vec<u8>,
+    },
+    /// All the recorded exchanges were already replayed
+    EndOfRecording { index: usize },
+}
+
+impl std::fmt::Display for ReplayError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ReplayError::UnexpectedCommand {
+                index,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "Exchange {} diverges from the recording: expected command {}, got {}",
+                index,
+                hex::encode(expected),
+                hex::encode(actual)
+            ),
+            ReplayError::EndOfRecording { index } => {
+                write!(f, "Exchange {} is past the end of the recording", index)
+            }
+        }
+    }
+}
+
+impl Error for ReplayError {}
+
+/// Transport that answers the commands with the responses of a session recorded by
+/// [`RecordingTransport`], without any device. Each command must be the same as in the recording,
+/// otherwise the exchange fails with a [`ReplayError`]; the replay does not continue after that.
+pub struct ReplayTransport {
+    exchanges: Vec<RecordedExchange>,
+    next: StdMutex<usize>,
+}
+
+impl ReplayTransport {
+    /// Creates a transport replaying the given exchanges, in order.
+    pub fn new(exchanges: Vec<RecordedExchange>) -> Self {
+        Self {
+            exchanges,
+            next: StdMutex::new(0),
+        }
+    }
+
+    /// Opens a recording created by [`RecordingTransport`].
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let mut reader = BufReader::new(File::open(path)?);
+        let mut header = [0u8; RECORDING_MAGIC.len() + 1];
+        reader.read_exact(&mut header)?;
+        if &header[..RECORDING_MAGIC.len()] != RECORDING_MAGIC
+            || header[RECORDING_MAGIC.len()] != RECORDING_VERSION
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "not an APDU recording",
+            ));
+        }
+        let mut exchanges = Vec::new();
+        while let Some(exchange) = RecordedExchange::read_from(&mut reader)? {
+            exchanges.push(exchange);
+        }
+        Ok(Self::new(exchanges))
+    }
+
+    /// The recorded exchanges.
+    pub fn exchanges(&self) -> &[RecordedExchange] {
+        &self.exchanges
+    }
+
+    /// Number of recorded exchanges that were not replayed yet. It's 0 once the whole session was
+    /// replayed.
+    pub fn remaining(&self) -> usize {
+        self.exchanges.len() - *self.next.lock().unwrap()
+    }
+}
+
+#[async_trait]
+impl Transport for ReplayTransport {
+    type Error = Box<dyn Error + Send + Sync>;
+    async fn exchange(&self, command: &APDUCommand) -> Result<(StatusWord, Vec<u8>), Self::Error> {
+        let (status, response) = self.exchange_raw(command).await?;
+        Ok((
+            StatusWord::try_from(status).unwrap_or(StatusWord::Unknown),
+            response,
+        ))
+    }
+
+    async fn exchange_raw(&self, command: &APDUCommand) -> Result<(u16, Vec<u8>), Self::Error> {
+        let mut next = self.next.lock().unwrap();
+        let index = *next;
+        let exchange = self
+            .exchanges
+            .get(index)
+            .ok_or(ReplayError::EndOfRecording { index })?;
+        let actual = command.encode();
+        if actual != exchange.command {
+            // stay on the diverging exchange, so that all the following ones fail too
+            return Err(ReplayError::UnexpectedCommand {
+                index,
+                expected: exchange.command.clone(),
+                actual,
+            }
+            .into());
+        }
+        *next += 1;
+        Ok((exchange.status, exchange.response.clone()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Answers each command with its data reversed, and fails on the commands without data. The
+    // status word of the commands with INS 3 is not a known one.
+    struct EchoTransport;
+
+    #[async_trait]
+    impl Transport for EchoTransport {
+        type Error = Box<dyn Error + Send + Sync>;
+        async fn exchange(
+            &self,
+            command: &APDUCommand,
+        ) -> Result<(StatusWord, Vec<u8>), Self::Error> {
+            let (status, data) = self.exchange_raw(command).await?;
+            Ok((
+                StatusWord::try_from(status).unwrap_or(StatusWord::Unknown),
+                data,
+            ))
+        }
+
+        async fn exchange_raw(&self, command: &APDUCommand) -> Result<(u16, Vec<u8>), Self::Error> {
+            if command.data.is_empty() {
+                return Err("no data".into());
+            }
+            let mut data = command.data.clone();
+            data.reverse();
+            let status = if command.ins == 3 { 0x6F42 } else { 0x9000 };
+            Ok((status, data))
+        }
+    }
+
+    fn command(ins: u8, data: &[u8]) -> APDUCommand {
+        APDUCommand {
+            cla: 0xE0,
+            ins,
+            p1: 0,
+            p2: 0,
+            data: data.to_vec(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_record_and_replay() {
+        let path = std::env::temp_dir().join(format!("vanadium-{}.apdu", std::process::id()));
+        let recorder = RecordingTransport::new(EchoTransport, &path).unwrap();
+        let large = vec![7u8; 300];
+        assert_eq!(
+            recorder.exchange(&command(1, b"abc")).await.unwrap(),
+            (StatusWord::OK, b"cba".to_vec())
+        );
+        assert!(recorder.exchange(&command(2, b"")).await.is_err());
+        recorder.exchange(&command(3, &large)).await.unwrap();
+        drop(recorder);
+
+        let replay = ReplayTransport::open(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(replay.exchanges().len(), 2);
+        assert_eq!(replay.remaining(), 2);
+        assert_eq!(
+            replay.exchange(&command(1, b"abc")).await.unwrap(),
+            (StatusWord::OK, b"cba".to_vec())
+        );
+
+        // a different command is flagged, and the replay does not go past it
+        let err = replay.exchange(&command(3, b"xyz")).await.unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<ReplayError>(),
+            Some(&ReplayError::UnexpectedCommand {
+                index: 1,
+                expected: command(3, &large).encode(),
+                actual: command(3, b"xyz").encode(),
+            })
+        );
+        assert_eq!(replay.remaining(), 1);
+
+        // the unknown status word is replayed as sent by the device
+        assert_eq!(replay.exchanges()[1].status, 0x6F42);
+        assert_eq!(
+            replay.exchange_raw(&command(3, &large)).await.unwrap(),
+            (0x6F42, large)
+        );
+        assert_eq!(replay.remaining(), 0);
+        let err = replay.exchange(&command(1, b"abc")).await.unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<ReplayError>(),
+            Some(&ReplayError::EndOfRecording { index: 2 })
+        );
+    }
 }
@@ -4,31 +4,45 @@ use log::debug;
 use async_trait::async_trait;
 use std::{
     cmp::min,
+    future::Future,
     path::{Path, PathBuf},
-    sync::Arc,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
 };
 use tokio::{
     io::{AsyncReadExt, AsyncWriteExt},
     net::TcpStream,
     sync::{
         mpsc::{self, error::TryRecvError},
-        Mutex,
+        Mutex, Notify,
     },
     task::JoinHandle,
 };
 
 use common::client_commands::{
-    BufferType, ClientCommandCode, CommitPageMessage, CommitPageProofContinuedMessage,
-    CommitPageProofContinuedResponse, CommitPageProofResponse, GetPageMessage,
-    GetPageProofContinuedMessage, GetPageProofContinuedResponse, GetPageResponse, Message,
-    MessageDeserializationError, ReceiveBufferMessage, ReceiveBufferResponse, SectionKind,
-    SendBufferContinuedMessage, SendBufferMessage,
+    BufferType, CheckAbortMessage, ClientCommandCode, CommitPageMessage,
+    CommitPageProofContinuedMessage, CommitPageProofContinuedResponse, CommitPageProofResponse,
+    GetPageMessage, GetPageProofContinuedMessage, GetPageProofContinuedResponse, GetPageResponse,
+    GetPagesContinuedMessage, GetPagesMessage, GetPagesResponse, GetTreeNodesMessage,
+    GetTreeNodesResponse, Message, MessageDeserializationError, PageContent, ReceiveBufferMessage,
+    ReceiveBufferResponse, SendBufferContinuedMessage, SendBufferMessage,
+};
+use common::comm::NATIVE_ABORT_REQUEST;
+use common::constants::{
+    check_heap_size, check_stack_size, DEFAULT_HEAP_SIZE, DEFAULT_HEAP_START, DEFAULT_STACK_START,
 };
-use common::constants::{DEFAULT_STACK_START, PAGE_SIZE};
 use common::manifest::Manifest;
+use common::vm::Trap;
 
-use crate::apdu::{apdu_continue, apdu_register_vapp, apdu_run_vapp, APDUCommand, StatusWord};
+use crate::apdu::{
+    apdu_abort_request, apdu_continue, apdu_register_vapp, apdu_resume_vapp, apdu_run_vapp,
+    apdu_suspend_vapp, APDUCommand, StatusWord,
+};
 use crate::memory::{MemorySegment, MemorySegmentError};
+use crate::page_store::PageStoreKind;
 use crate::transport::Transport;
 use crate::{
     elf::{self, VAppElfFile},
@@ -39,10 +53,117 @@ enum VAppMessage {
     SendBuffer(Vec<u8>),
     SendPanicBuffer(String),
     VAppExited { status: i32 },
+    // the V-App is waiting for the next request after aborting the previous one
+    Aborted,
 }
 
 enum ClientMessage {
     ReceiveBuffer(Vec<u8>),
+    Suspend,
+    // abort the request that the V-App is processing
+    Abort,
+}
+
+// Everything the client needs to resume a suspended V-App. The VM only keeps the snapshot of the
+// CPU state, while the content of the memory stays on the client.
+struct SuspendedVApp {
+    manifest: Manifest,
+    app_hmac: [u8; 32],
+    snapshot: Vec<u8>,
+    segments: Vec<MemorySegment>,
+    print_writer: Box<dyn std::io::Write + Send>,
+}
+
+// A print writer shared by all the runs of a V-App, as it's restarted after reconnecting
+struct SharedWriter(Arc<std::sync::Mutex<Box<dyn std::io::Write + Send>>>);
+
+impl std::io::Write for SharedWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0
+            .lock()
+            .map_err(|_| std::io::Error::new(std::io::ErrorKind::Other, "Lock poisoned"))?
+            .write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.0
+            .lock()
+            .map_err(|_| std::io::Error::new(std::io::ErrorKind::Other, "Lock poisoned"))?
+            .flush()
+    }
+}
+
+// Everything the client needs to start the V-App again from the beginning, after the transport
+// was lost.
+struct VAppSession {
+    manifest: Manifest,
+    app_hmac: [u8; 32],
+    elf: Arc<VAppElfFile>,
+    print_writer: Arc<std::sync::Mutex<Box<dyn std::io::Write + Send>>>,
+}
+
+/// Opens a new transport to the device, to reconnect after the previous one was lost.
+///
+/// It is implemented for the closures returning a future that opens the transport.
+#[async_trait]
+pub trait TransportConnector<E>: Send + Sync {
+    async fn connect(
+        &self,
+    ) -> Result<Arc<dyn Transport<Error = E>>, Box<dyn std::error::Error + Send + Sync>>;
+}
+
+#[async_trait]
+impl<E, F, Fut> TransportConnector<E> for F
+where
+    F: Fn() -> Fut + Send + Sync,
+    Fut: Future<
+            Output = Result<
+                Arc<dyn Transport<Error = E>>,
+                Box<dyn std::error::Error + Send + Sync>,
+            >,
+        > + Send,
+{
+    async fn connect(
+        &self,
+    ) -> Result<Arc<dyn Transport<Error = E>>, Box<dyn std::error::Error + Send + Sync>> {
+        self().await
+    }
+}
+
+/// How a [`VanadiumAppClient`] reconnects to the device if the transport is lost, for example if
+/// the device is unplugged or the TCP connection to Speculos drops.
+///
+/// The state of the V-App is lost with the connection: after reconnecting, the V-App is started
+/// again from the beginning, and the message being sent fails with
+/// [`VAppExecutionError::SessionRestarted`].
+pub struct ReconnectStrategy<E> {
+    connector: Box<dyn TransportConnector<E>>,
+    max_attempts: u32,
+    delay: Duration,
+}
+
+impl<E> ReconnectStrategy<E> {
+    /// Creates a strategy that opens the new transport with `connector`, trying up to 5 times, one
+    /// second apart.
+    pub fn new(connector: impl TransportConnector<E> + 'static) -> Self {
+        Self {
+            connector: Box::new(connector),
+            max_attempts: 5,
+            delay: Duration::from_secs(1),
+        }
+    }
+
+    /// Sets the number of attempts to open the transport before giving up.
+    pub fn max_attempts(mut self, max_attempts: u32) -> Self {
+        self.max_attempts = max_attempts.max(1);
+        self
+    }
+
+    /// Sets the delay between two attempts to open the transport.
+    pub fn delay(mut self, delay: Duration) -> Self {
+        self.delay = delay;
+        self
+    }
 }
 
 #[derive(Debug)]
@@ -55,6 +176,8 @@ pub enum VAppEngineError<E: std::fmt::Debug + Send + Sync + 'static> {
     ResponseError(&'static str),
     VMRuntimeError,
     VAppPanic,
+    InstructionBudgetExceeded,
+    VAppTrap(Trap),
     GenericError(Box<dyn std::error::Error + Send + Sync>),
 }
 
@@ -73,6 +196,10 @@ impl<E: std::fmt::Debug + Send + Sync + 'static> std::fmt::Display for VAppEngin
             VAppEngineError::ResponseError(e) => write!(f, "Invalid response: {}", e),
             VAppEngineError::VMRuntimeError => write!(f, "VM runtime error"),
             VAppEngineError::VAppPanic => write!(f, "V-App panicked"),
+            VAppEngineError::InstructionBudgetExceeded => {
+                write!(f, "V-App exceeded its execution budget")
+            }
+            VAppEngineError::VAppTrap(trap) => write!(f, "V-App trap: {}", trap),
             VAppEngineError::GenericError(e) => write!(f, "Generic error: {}", e),
         }
     }
@@ -89,6 +216,8 @@ impl<E: std::fmt::Debug + Send + Sync + 'static> std::error::Error for VAppEngin
             VAppEngineError::ResponseError(_) => None,
             VAppEngineError::VMRuntimeError => None,
             VAppEngineError::VAppPanic => None,
+            VAppEngineError::InstructionBudgetExceeded => None,
+            VAppEngineError::VAppTrap(_) => None,
             VAppEngineError::GenericError(e) => Some(&**e),
         }
     }
@@ -124,26 +253,59 @@ impl<E: std::fmt::Debug + Send + Sync + 'static> From<Box<dyn std::error::Error
 
 struct VAppEngine<E: std::fmt::Debug + Send + Sync + 'static> {
     manifest: Manifest,
-    code_seg: MemorySegment,
-    data_seg: MemorySegment,
-    stack_seg: MemorySegment,
+    // The content of each segment of the manifest, in the same order
+    segments: Vec<MemorySegment>,
     transport: Arc<dyn Transport<Error = E>>,
     engine_to_client_sender: mpsc::Sender<VAppMessage>,
     client_to_engine_receiver: mpsc::Receiver<ClientMessage>,
     print_writer: Box<dyn std::io::Write + Send>,
+    // a message received from the client while checking for an abort request, not processed yet
+    pending_message: Option<ClientMessage>,
+    // set once the VM was asked to abort the current request, until the V-App waits for the next one
+    abort_delivered: bool,
 }
 
 impl<E: std::fmt::Debug + Send + Sync + 'static> VAppEngine<E> {
-    pub async fn run(mut self, app_hmac: [u8; 32]) -> Result<(), VAppEngineError<E>> {
+    // Starts the V-App, or resumes it if a snapshot is given. Returns the state of the V-App if it
+    // was suspended, or None if it exited.
+    pub async fn run(
+        mut self,
+        app_hmac: [u8; 32],
+        snapshot: Option<&[u8]>,
+    ) -> Result<Option<SuspendedVApp>, VAppEngineError<E>> {
         let serialized_manifest = postcard::to_allocvec(&self.manifest)?;
 
+        let apdu = match snapshot {
+            Some(snapshot) => {
+                // the VM checks the Merkle roots of the writable segments against the snapshot
+                let roots: Vec<[u8; 32]> = self
+                    .manifest
+                    .segments
+                    .iter()
+                    .zip(self.segments.iter())
+                    .filter(|(descriptor, _)| descriptor.permissions.is_writable())
+                    .map(|(_, segment)| segment.get_content_root().clone().into())
+                    .collect();
+                apdu_resume_vapp(serialized_manifest, app_hmac, snapshot, &roots)
+            }
+            None => apdu_run_vapp(serialized_manifest, app_hmac),
+        };
         let (status, result) = self
             .transport
-            .exchange(&apdu_run_vapp(serialized_manifest, app_hmac))
+            .exchange(&apdu)
             .await
             .map_err(VAppEngineError::TransportError)?;
 
-        self.busy_loop(status, result).await
+        Ok(self
+            .busy_loop(status, result)
+            .await?
+            .map(|snapshot| SuspendedVApp {
+                manifest: self.manifest,
+                app_hmac,
+                snapshot,
+                segments: self.segments,
+                print_writer: self.print_writer,
+            }))
     }
 
     // Sends and APDU and repeatedly processes the response if it's a GetPage or CommitPage client command.
@@ -168,7 +330,9 @@ impl<E: std::fmt::Debug + Send + Sync + 'static> VAppEngine<E> {
 
             (status, result) = match client_command_code {
                 ClientCommandCode::GetPage => self.process_get_page(&result).await?,
+                ClientCommandCode::GetPages => self.process_get_pages(&result).await?,
                 ClientCommandCode::CommitPage => self.process_commit_page(&result).await?,
+                ClientCommandCode::GetTreeNodes => self.process_get_tree_nodes(&result).await?,
                 _ => return Ok((status, result)),
             }
         }
@@ -180,49 +344,41 @@ impl<E: std::fmt::Debug + Send + Sync + 'static> VAppEngine<E> {
     ) -> Result<(StatusWord, Vec<u8>), VAppEngineError<E>> {
         let GetPageMessage {
             command_code: _,
-            section_kind,
+            segment_index,
             page_index,
+            cached_levels,
         } = GetPageMessage::deserialize(command)?;
 
         #[cfg(feature = "debug")]
         debug!(
-            "<- GetPageMessage(section_kind = {:?}, page_index = {})",
-            section_kind, page_index
+            "<- GetPageMessage(segment_index = {}, page_index = {}, cached_levels = {})",
+            segment_index, page_index, cached_levels
         );
 
-        let segment = match section_kind {
-            SectionKind::Code => &self.code_seg,
-            SectionKind::Data => &self.data_seg,
-            SectionKind::Stack => &self.stack_seg,
-        };
-
-        // Get the serialized page content and its proof
-        let (mut serialized_page, proof) = segment.get_page(page_index)?;
-
-        assert!(serialized_page.len() == 1 + 12 + PAGE_SIZE);
+        let segment = self
+            .segments
+            .get_mut(segment_index as usize)
+            .ok_or(VAppEngineError::AccessViolation)?;
 
-        // split the first 13 bytes from the actual page data:
-        let (header, data) = serialized_page.split_at_mut(13);
+        // Get the serialized page content and its proof, up to the levels cached by the VM
+        let (serialized_page, mut proof) = segment.get_page(page_index)?;
+        proof.truncate(segment.truncated_proof_len(page_index, 1, cached_levels)?);
 
-        let is_encrypted = header[0] != 0;
-        let nonce: [u8; 12] = header[1..13].try_into().unwrap();
+        // The page is sent compressed if it was compressed when the V-App was packaged
+        let page = match segment.get_compressed_page(page_index) {
+            Some(compressed) => PageContent::Compressed(compressed),
+            None => PageContent::from_serialized_page(&serialized_page)?,
+        };
 
         // Convert HashOutput<32> to [u8; 32]
         let proof: Vec<[u8; 32]> = proof.into_iter().map(|h| h.0).collect();
 
         // Calculate how many proof elements we can send in one message
-        let t = min(proof.len(), GetPageResponse::max_proof_size()) as u8;
+        let t = min(proof.len(), GetPageResponse::max_proof_size(&page)) as u8;
 
         // Create the page response
-        let response = GetPageResponse::new(
-            (*data).try_into().unwrap(),
-            is_encrypted,
-            nonce,
-            proof.len() as u8,
-            t,
-            &proof[0..t as usize],
-        )
-        .serialize();
+        let response =
+            GetPageResponse::new(proof.len() as u8, t, page, &proof[0..t as usize]).serialize();
 
         let (status, result) = self
             .transport
@@ -282,35 +438,190 @@ impl<E: std::fmt::Debug + Send + Sync + 'static> VAppEngine<E> {
         Ok((status, result))
     }
 
-    async fn process_commit_page(
+    async fn process_get_pages(
         &mut self,
         command: &[u8],
     ) -> Result<(StatusWord, Vec<u8>), VAppEngineError<E>> {
-        let msg = CommitPageMessage::deserialize(command)?;
+        let GetPagesMessage {
+            command_code: _,
+            segment_index,
+            page_index,
+            n_pages,
+            cached_levels,
+        } = GetPagesMessage::deserialize(command)?;
 
         #[cfg(feature = "debug")]
         debug!(
-            "<- CommitPageMessage(section_kind = {:?}, page_index = {})",
-            msg.section_kind, msg.page_index,
+            "<- GetPagesMessage(segment_index = {}, page_index = {}, n_pages = {}, cached_levels = {})",
+            segment_index, page_index, n_pages, cached_levels
         );
 
-        let segment = match msg.section_kind {
-            SectionKind::Code => {
-                return Err(VAppEngineError::AccessViolation);
+        let segment = self
+            .segments
+            .get_mut(segment_index as usize)
+            .ok_or(VAppEngineError::AccessViolation)?;
+
+        // Get the serialized content of the pages and their multiproof, up to the levels cached by
+        // the VM
+        let (pages, mut proof) = segment.get_pages(page_index, n_pages as u32)?;
+        proof.truncate(segment.truncated_proof_len(page_index, n_pages as u32, cached_levels)?);
+
+        // The pages that were compressed when the V-App was packaged are sent compressed
+        let page_size = PageContent::SERIALIZED_RAW_PAGE_SIZE;
+        let pages = (0..n_pages as usize)
+            .map(|i| {
+                let mut serialized = Vec::new();
+                match segment.get_compressed_page(page_index + i as u32) {
+                    Some(compressed) => PageContent::Compressed(compressed),
+                    None => PageContent::from_serialized_page(
+                        &pages[i * page_size..(i + 1) * page_size],
+                    )?,
+                }
+                .serialize_with(|data| serialized.extend_from_slice(data));
+                Ok(serialized)
+            })
+            .collect::<Result<Vec<_>, MessageDeserializationError>>()?;
+
+        // Convert HashOutput<32> to [u8; 32]
+        let proof: Vec<[u8; 32]> = proof.into_iter().map(|h| h.0).collect();
+
+        #[cfg(feature = "debug")]
+        debug!("Multiproof length: {}", proof.len());
+
+        // Send the pages first, then the proof, in as many messages as needed
+        let mut pages_offset = 0;
+        let mut proof_offset = 0;
+        loop {
+            // as many pages as fit in the message, then as many proof elements as fit
+            let mut size = 0;
+            let mut n = 0;
+            while pages_offset + n < pages.len()
+                && size + pages[pages_offset + n].len() <= GetPagesResponse::max_content_size()
+            {
+                size += pages[pages_offset + n].len();
+                n += 1;
             }
-            SectionKind::Data => &mut self.data_seg,
-            SectionKind::Stack => &mut self.stack_seg,
-        };
+            let t = if pages_offset + n == pages.len() {
+                min(
+                    proof.len() - proof_offset,
+                    (GetPagesResponse::max_content_size() - size) / 32,
+                )
+            } else {
+                0
+            };
+
+            let response = GetPagesResponse::new(
+                proof.len() as u8,
+                n as u8,
+                t as u8,
+                &pages[pages_offset..pages_offset + n].concat(),
+                &proof[proof_offset..proof_offset + t],
+            )
+            .serialize();
+
+            let (status, result) = self
+                .transport
+                .exchange(&apdu_continue(response))
+                .await
+                .map_err(VAppEngineError::TransportError)?;
+
+            pages_offset += n;
+            proof_offset += t;
 
-        assert!(msg.is_encrypted == true); // the VM should always commit to encrypted pages
+            if pages_offset == n_pages as usize && proof_offset == proof.len() {
+                return Ok((status, result));
+            }
+
+            // Otherwise, expect a GetPagesContinuedMessage
+            if status != StatusWord::InterruptedExecution {
+                return Err(VAppEngineError::InterruptedExecutionExpected);
+            }
+
+            GetPagesContinuedMessage::deserialize(&result)?;
 
-        let mut serialized_page = Vec::<u8>::with_capacity(1 + 12 + PAGE_SIZE);
-        serialized_page.push(msg.is_encrypted as u8);
-        serialized_page.extend_from_slice(&msg.nonce);
-        serialized_page.extend_from_slice(msg.data);
+            #[cfg(feature = "debug")]
+            debug!("<- GetPagesContinuedMessage()");
+        }
+    }
 
-        // Store page and get proof
-        let (proof, new_root) = segment.store_page(msg.page_index, &serialized_page)?;
+    async fn process_get_tree_nodes(
+        &mut self,
+        command: &[u8],
+    ) -> Result<(StatusWord, Vec<u8>), VAppEngineError<E>> {
+        let GetTreeNodesMessage {
+            command_code: _,
+            segment_index,
+            start,
+            count,
+        } = GetTreeNodesMessage::deserialize(command)?;
+
+        #[cfg(feature = "debug")]
+        debug!(
+            "<- GetTreeNodesMessage(segment_index = {}, start = {}, count = {})",
+            segment_index, start, count
+        );
+
+        if count as usize > GetTreeNodesResponse::max_nodes() {
+            return Err(VAppEngineError::ResponseError(
+                "Too many tree nodes requested",
+            ));
+        }
+
+        let segment = self
+            .segments
+            .get_mut(segment_index as usize)
+            .ok_or(VAppEngineError::AccessViolation)?;
+
+        // Convert HashOutput<32> to [u8; 32]
+        let nodes: Vec<[u8; 32]> = segment
+            .get_nodes(start as usize, count as usize)?
+            .into_iter()
+            .map(|h| h.0)
+            .collect();
+
+        let response = GetTreeNodesResponse::new(count, &nodes).serialize();
+        self.transport
+            .exchange(&apdu_continue(response))
+            .await
+            .map_err(VAppEngineError::TransportError)
+    }
+
+    async fn process_commit_page(
+        &mut self,
+        command: &[u8],
+    ) -> Result<(StatusWord, Vec<u8>), VAppEngineError<E>> {
+        let msg = CommitPageMessage::deserialize(command)?;
+
+        #[cfg(feature = "debug")]
+        debug!(
+            "<- CommitPageMessage(segment_index = {}, page_index = {})",
+            msg.segment_index, msg.page_index,
+        );
+
+        // only the writable segments can be committed
+        let index = msg.segment_index as usize;
+        match self.manifest.segments.get(index) {
+            Some(descriptor) if descriptor.permissions.is_writable() => {}
+            _ => return Err(VAppEngineError::AccessViolation),
+        }
+        let segment = &mut self.segments[index];
+
+        // the VM should always commit to encrypted pages
+        assert!(matches!(
+            msg.page,
+            PageContent::Raw {
+                is_encrypted: true,
+                ..
+            } | PageContent::Authenticated { .. }
+        ));
+
+        let mut serialized_page = Vec::<u8>::with_capacity(msg.page.serialized_size());
+        msg.page
+            .serialize_with(|chunk| serialized_page.extend_from_slice(chunk));
+
+        // Store page and get proof, up to the levels cached by the VM
+        let (mut proof, new_root) = segment.store_page(msg.page_index, &serialized_page)?;
+        proof.truncate(segment.truncated_proof_len(msg.page_index, 1, msg.cached_levels)?);
 
         // Convert HashOutput<32> to [u8; 32]
         let proof: Vec<[u8; 32]> = proof.into_iter().map(|h| h.into()).collect();
@@ -449,6 +760,9 @@ impl<E: std::fmt::Debug + Send + Sync + 'static> VAppEngine<E> {
         let (buffer_type, buf) = self.process_send_buffer_generic(command).await?;
 
         match buffer_type {
+            BufferType::VAppMessage if self.abort_delivered => {
+                // the client does not expect a response to the aborted request
+            }
             BufferType::VAppMessage => {
                 // Send the buffer back to the client via engine_to_client_sender
                 self.engine_to_client_sender
@@ -471,6 +785,9 @@ impl<E: std::fmt::Debug + Send + Sync + 'static> VAppEngine<E> {
                     .write(&buf)
                     .map_err(|e| VAppEngineError::GenericError(Box::new(e)))?;
             }
+            BufferType::Aborted => {
+                return Err(VAppEngineError::ResponseError("Unexpected buffer type"));
+            }
         }
 
         // Continue processing
@@ -488,9 +805,40 @@ impl<E: std::fmt::Debug + Send + Sync + 'static> VAppEngine<E> {
         #[cfg(feature = "debug")]
         debug!("<- ReceiveBufferMessage()");
 
-        let msg = self.client_to_engine_receiver.try_recv();
+        if self.abort_delivered {
+            // Once the request is aborted, the V-App does not receive messages until it waits for
+            // the next request. The client may have stopped waiting for the acknowledgment, for
+            // example if the V-App is being suspended.
+            self.abort_delivered = false;
+            let _ = self
+                .engine_to_client_sender
+                .send(VAppMessage::Aborted)
+                .await;
+        }
+
+        let msg = match self.pending_message.take() {
+            Some(msg) => Ok(msg),
+            None => self.client_to_engine_receiver.try_recv(),
+        };
         let bytes: Vec<u8> = match msg {
             Ok(ClientMessage::ReceiveBuffer(b)) => b,
+            Ok(ClientMessage::Abort) => {
+                #[cfg(feature = "debug")]
+                debug!("-> Abort");
+
+                self.abort_delivered = true;
+                return self
+                    .exchange_and_process_page_requests(&apdu_abort_request())
+                    .await;
+            }
+            Ok(ClientMessage::Suspend) => {
+                #[cfg(feature = "debug")]
+                debug!("-> Suspend");
+
+                return self
+                    .exchange_and_process_page_requests(&apdu_suspend_vapp())
+                    .await;
+            }
             Err(TryRecvError::Empty) => {
                 // if there is no data to send to the V-App, respond with an empty buffer
                 let data = ReceiveBufferResponse::new(0, &[]).serialize();
@@ -543,11 +891,42 @@ impl<E: std::fmt::Debug + Send + Sync + 'static> VAppEngine<E> {
         }
     }
 
+    // the V-App is waiting for UX events; abort its request if the client asked to
+    async fn process_check_abort(
+        &mut self,
+        command: &[u8],
+    ) -> Result<(StatusWord, Vec<u8>), VAppEngineError<E>> {
+        CheckAbortMessage::deserialize(command)?;
+
+        #[cfg(feature = "debug")]
+        debug!("<- CheckAbortMessage()");
+
+        if self.pending_message.is_none() {
+            self.pending_message = self.client_to_engine_receiver.try_recv().ok();
+        }
+        if matches!(self.pending_message, Some(ClientMessage::Abort)) {
+            #[cfg(feature = "debug")]
+            debug!("-> Abort");
+
+            self.pending_message = None;
+            self.abort_delivered = true;
+            return self
+                .exchange_and_process_page_requests(&apdu_abort_request())
+                .await;
+        }
+
+        // any other message is processed when the V-App asks for it
+        self.exchange_and_process_page_requests(&apdu_continue(vec![]))
+            .await
+    }
+
+    // Processes the requests of the VM until the V-App exits or is suspended. Returns the snapshot
+    // if the V-App was suspended.
     async fn busy_loop(
         &mut self,
         first_sw: StatusWord,
         first_result: Vec<u8>,
-    ) -> Result<(), VAppEngineError<E>> {
+    ) -> Result<Option<Vec<u8>>, VAppEngineError<E>> {
         let mut status = first_sw;
         let mut result = first_result;
 
@@ -563,7 +942,11 @@ impl<E: std::fmt::Debug + Send + Sync + 'static> VAppEngine<E> {
                     .send(VAppMessage::VAppExited { status: st })
                     .await
                     .map_err(|e| VAppEngineError::GenericError(Box::new(e)))?;
-                return Ok(());
+                return Ok(None);
+            }
+
+            if status == StatusWord::VAppSuspended {
+                return Ok(Some(result));
             }
 
             if status == StatusWord::VMRuntimeError {
@@ -574,6 +957,16 @@ impl<E: std::fmt::Debug + Send + Sync + 'static> VAppEngine<E> {
                 return Err(VAppEngineError::VAppPanic);
             }
 
+            if status == StatusWord::InstructionBudgetExceeded {
+                return Err(VAppEngineError::InstructionBudgetExceeded);
+            }
+
+            if status == StatusWord::VAppTrap {
+                let trap = postcard::from_bytes::<Trap>(&result)
+                    .map_err(|_| VAppEngineError::ResponseError("Invalid trap response"))?;
+                return Err(VAppEngineError::VAppTrap(trap));
+            }
+
             if status != StatusWord::InterruptedExecution {
                 return Err(VAppEngineError::InterruptedExecutionExpected);
             }
@@ -588,11 +981,15 @@ impl<E: std::fmt::Debug + Send + Sync + 'static> VAppEngine<E> {
 
             (status, result) = match client_command_code {
                 ClientCommandCode::GetPage => self.process_get_page(&result).await?,
+                ClientCommandCode::GetPages => self.process_get_pages(&result).await?,
                 ClientCommandCode::CommitPage => self.process_commit_page(&result).await?,
+                ClientCommandCode::GetTreeNodes => self.process_get_tree_nodes(&result).await?,
                 ClientCommandCode::SendBuffer => self.process_send_buffer(&result).await?,
                 ClientCommandCode::ReceiveBuffer => self.process_receive_buffer(&result).await?,
+                ClientCommandCode::CheckAbort => self.process_check_abort(&result).await?,
                 ClientCommandCode::SendBufferContinued
                 | ClientCommandCode::GetPageProofContinued
+                | ClientCommandCode::GetPagesContinued
                 | ClientCommandCode::CommitPageProofContinued => {
                     // not a top-level command, part of the handling of some other command
                     return Err(VAppEngineError::ResponseError("Unexpected command"));
@@ -605,13 +1002,24 @@ impl<E: std::fmt::Debug + Send + Sync + 'static> VAppEngine<E> {
 struct GenericVanadiumClient<E: std::fmt::Debug + Send + Sync + 'static> {
     client_to_engine_sender: Option<mpsc::Sender<ClientMessage>>,
     engine_to_client_receiver: Option<Mutex<mpsc::Receiver<VAppMessage>>>,
-    vapp_engine_handle: Option<JoinHandle<Result<(), VAppEngineError<E>>>>,
+    vapp_engine_handle: Option<JoinHandle<Result<Option<SuspendedVApp>, VAppEngineError<E>>>>,
+    // behind a Mutex, as the print writer is not Sync
+    suspended_vapp: Mutex<Option<SuspendedVApp>>,
+    // where the memory segments of the V-App are kept
+    page_store: PageStoreKind,
+    // set once the V-App is started, to restart it after reconnecting
+    session: Option<VAppSession>,
+    reconnect: Option<ReconnectStrategy<E>>,
+    // set after a request is aborted, until the V-App acknowledges it
+    aborting: bool,
 }
 
 #[derive(Debug)]
 enum VanadiumClientError {
     VAppPanicked(String),
     VAppExited(i32),
+    SessionRestarted,
+    Interrupted(Interruption),
     GenericError(String),
 }
 
@@ -626,6 +1034,15 @@ impl std::fmt::Display for VanadiumClientError {
         match self {
             VanadiumClientError::VAppPanicked(msg) => write!(f, "VApp panicked: {}", msg),
             VanadiumClientError::VAppExited(code) => write!(f, "VApp exited with code: {}", code),
+            VanadiumClientError::SessionRestarted => {
+                write!(f, "The connection was lost, and the VApp was restarted")
+            }
+            VanadiumClientError::Interrupted(Interruption::TimedOut) => {
+                write!(f, "Timed out waiting for the VApp")
+            }
+            VanadiumClientError::Interrupted(Interruption::Cancelled) => {
+                write!(f, "Cancelled while waiting for the VApp")
+            }
             VanadiumClientError::GenericError(msg) => write!(f, "Generic error: {}", msg),
         }
     }
@@ -643,9 +1060,24 @@ impl<E: std::fmt::Debug + Send + Sync + 'static> GenericVanadiumClient<E> {
             client_to_engine_sender: None,
             engine_to_client_receiver: None,
             vapp_engine_handle: None,
+            suspended_vapp: Mutex::new(None),
+            page_store: PageStoreKind::default(),
+            session: None,
+            reconnect: None,
+            aborting: false,
         }
     }
 
+    pub fn with_page_store(mut self, page_store: PageStoreKind) -> Self {
+        self.page_store = page_store;
+        self
+    }
+
+    pub fn with_reconnect(mut self, reconnect: Option<ReconnectStrategy<E>>) -> Self {
+        self.reconnect = reconnect;
+        self
+    }
+
     pub async fn register_vapp(
         &self,
         transport: Arc<dyn Transport<Error = E>>,
@@ -677,39 +1109,104 @@ impl<E: std::fmt::Debug + Send + Sync + 'static> GenericVanadiumClient<E> {
         transport: Arc<dyn Transport<Error = E>>,
         manifest: &Manifest,
         app_hmac: &[u8; 32],
-        elf: &VAppElfFile,
+        elf: Arc<VAppElfFile>,
         print_writer: Box<dyn std::io::Write + Send>,
     ) -> Result<(), VAppEngineError<E>> {
-        let mut data = postcard::to_allocvec(manifest)?;
-        data.extend_from_slice(app_hmac);
-
-        // Create the memory segments for the code, data, and stack sections
-        let code_seg = MemorySegment::new(elf.code_segment.start, &elf.code_segment.data);
-        let data_seg = MemorySegment::new(elf.data_segment.start, &elf.data_segment.data);
-        let stack_seg = MemorySegment::new(
-            manifest.stack_start,
-            &vec![0; (manifest.stack_end - manifest.stack_start) as usize],
+        let segments = self.create_segments(manifest, &elf)?;
+        let print_writer = Arc::new(std::sync::Mutex::new(print_writer));
+
+        self.start_engine(
+            SuspendedVApp {
+                manifest: manifest.clone(),
+                app_hmac: *app_hmac,
+                snapshot: vec![],
+                segments,
+                print_writer: Box::new(SharedWriter(print_writer.clone())),
+            },
+            transport,
+            false,
         );
+        self.session = Some(VAppSession {
+            manifest: manifest.clone(),
+            app_hmac: *app_hmac,
+            elf,
+            print_writer,
+        });
+
+        Ok(())
+    }
+
+    // Creates the memory segments of the manifest, with the content of the ELF segment starting
+    // at the same address, if any, or zero-initialized otherwise
+    fn create_segments(
+        &self,
+        manifest: &Manifest,
+        elf: &VAppElfFile,
+    ) -> Result<Vec<MemorySegment>, VAppEngineError<E>> {
+        let segments = manifest
+            .segments
+            .iter()
+            .map(
+                |descriptor| match elf.segments.iter().find(|s| s.start == descriptor.start) {
+                    Some(elf_segment) => {
+                        let end = elf_segment.start + elf_segment.data.len() as u32;
+                        let store = self
+                            .page_store
+                            .create(MemorySegment::n_pages(elf_segment.start, end))?;
+                        let mut segment = MemorySegment::new_with_store(
+                            descriptor.start,
+                            &elf_segment.data,
+                            store,
+                        )?;
+                        if let Some(pages) = elf.compressed_pages.get(&elf_segment.start) {
+                            segment.set_compressed_pages(pages.clone())?;
+                        }
+                        Ok(segment)
+                    }
+                    None => {
+                        let store = self
+                            .page_store
+                            .create(MemorySegment::n_pages(descriptor.start, descriptor.end))?;
+                        MemorySegment::new_zeroed_with_store(
+                            descriptor.start,
+                            descriptor.end,
+                            store,
+                        )
+                    }
+                },
+            )
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(segments)
+    }
 
+    // Starts the VAppEngine in a task, either to run the V-App from the beginning, or to resume it
+    fn start_engine(
+        &mut self,
+        state: SuspendedVApp,
+        transport: Arc<dyn Transport<Error = E>>,
+        resume: bool,
+    ) {
         let (client_to_engine_sender, client_to_engine_receiver) =
             mpsc::channel::<ClientMessage>(10);
         let (engine_to_client_sender, engine_to_client_receiver) = mpsc::channel::<VAppMessage>(10);
 
         let vapp_engine = VAppEngine {
-            manifest: manifest.clone(),
-            code_seg,
-            data_seg,
-            stack_seg,
+            manifest: state.manifest,
+            segments: state.segments,
             transport,
             engine_to_client_sender,
             client_to_engine_receiver,
-            print_writer,
+            print_writer: state.print_writer,
+            pending_message: None,
+            abort_delivered: false,
         };
 
-        // Start the VAppEngine in a task
-        let app_hmac_clone = *app_hmac;
+        let app_hmac = state.app_hmac;
+        let snapshot = state.snapshot;
         let vapp_engine_handle = tokio::spawn(async move {
-            let res = vapp_engine.run(app_hmac_clone).await;
+            let res = vapp_engine
+                .run(app_hmac, resume.then_some(snapshot.as_slice()))
+                .await;
             if let Err(e) = &res {
                 println!("VAppEngine error: {:?}", e);
             }
@@ -718,38 +1215,186 @@ impl<E: std::fmt::Debug + Send + Sync + 'static> GenericVanadiumClient<E> {
 
         // Store the senders and receivers
         self.client_to_engine_sender = Some(client_to_engine_sender);
+        // the V-App is waiting for a request when it is started or resumed
+        self.aborting = false;
         self.engine_to_client_receiver = Some(Mutex::new(engine_to_client_receiver));
         self.vapp_engine_handle = Some(vapp_engine_handle);
+    }
+
+    // Suspends the V-App the next time it waits for a message, and keeps its state until resume
+    // is called.
+    pub async fn suspend(&mut self) -> Result<(), VAppEngineError<E>> {
+        let sender = self
+            .client_to_engine_sender
+            .take()
+            .ok_or(VAppEngineError::GenericError(
+                "VAppEngine not running".into(),
+            ))?;
+        self.engine_to_client_receiver = None;
+        let handle = self
+            .vapp_engine_handle
+            .take()
+            .ok_or(VAppEngineError::GenericError(
+                "VAppEngine not running".into(),
+            ))?;
+
+        sender
+            .send(ClientMessage::Suspend)
+            .await
+            .map_err(|_| VAppEngineError::GenericError("VAppEngine stopped".into()))?;
 
+        let result = handle
+            .await
+            .map_err(|e| VAppEngineError::GenericError(Box::new(e)))??;
+        match result {
+            Some(suspended_vapp) => {
+                *self.suspended_vapp.get_mut() = Some(suspended_vapp);
+                Ok(())
+            }
+            None => Err(VAppEngineError::GenericError(
+                "The V-App exited instead of suspending".into(),
+            )),
+        }
+    }
+
+    // Resumes the suspended V-App. The transport can be different from the one used before
+    // suspending, for example after reconnecting to the device.
+    pub fn resume(
+        &mut self,
+        transport: Arc<dyn Transport<Error = E>>,
+    ) -> Result<(), VAppEngineError<E>> {
+        let state = self
+            .suspended_vapp
+            .get_mut()
+            .take()
+            .ok_or(VAppEngineError::GenericError("No suspended V-App".into()))?;
+        self.start_engine(state, transport, true);
         Ok(())
     }
 
-    pub async fn send_message(&mut self, message: &[u8]) -> Result<Vec<u8>, VanadiumClientError> {
+    // Opens a new transport after the previous one was lost, and starts the V-App again from the
+    // beginning.
+    async fn restart(&mut self) -> Result<(), VanadiumClientError> {
+        let reconnect = self.reconnect.as_ref().ok_or("No reconnect strategy")?;
+        let session = self.session.as_ref().ok_or("No V-App to restart")?;
+
+        let mut attempt = 1;
+        let transport = loop {
+            match reconnect.connector.connect().await {
+                Ok(transport) => break transport,
+                Err(e) if attempt >= reconnect.max_attempts => {
+                    return Err(VanadiumClientError::GenericError(format!(
+                        "Failed to reconnect: {}",
+                        e
+                    )));
+                }
+                Err(_) => {
+                    attempt += 1;
+                    tokio::time::sleep(reconnect.delay).await;
+                }
+            }
+        };
+
+        let segments = self
+            .create_segments(&session.manifest, &session.elf)
+            .map_err(|e| VanadiumClientError::GenericError(e.to_string()))?;
+        let state = SuspendedVApp {
+            manifest: session.manifest.clone(),
+            app_hmac: session.app_hmac,
+            snapshot: vec![],
+            segments,
+            print_writer: Box::new(SharedWriter(session.print_writer.clone())),
+        };
+        self.start_engine(state, transport, false);
+        Ok(())
+    }
+
+    // Called when the VAppEngine stopped while processing a message. If it stopped because the
+    // transport was lost, and a reconnect strategy is set, the V-App is restarted. Otherwise,
+    // returns `error`.
+    async fn on_engine_stopped(&mut self, error: &str) -> VanadiumClientError {
+        let transport_lost = match self.vapp_engine_handle.take() {
+            Some(handle) => matches!(handle.await, Ok(Err(VAppEngineError::TransportError(_)))),
+            None => false,
+        };
+        if !transport_lost || self.reconnect.is_none() {
+            return error.into();
+        }
+
+        self.client_to_engine_sender = None;
+        self.engine_to_client_receiver = None;
+        match self.restart().await {
+            Ok(()) => VanadiumClientError::SessionRestarted,
+            Err(e) => e,
+        }
+    }
+
+    // Waits for the next message of the VAppEngine
+    async fn receive_from_engine(&mut self) -> Result<VAppMessage, VanadiumClientError> {
+        let response = match self.engine_to_client_receiver.as_mut() {
+            Some(engine_to_client_receiver) => engine_to_client_receiver.lock().await.recv().await,
+            None => return Err("VAppEngine not running".into()),
+        };
+        match response {
+            Some(VAppMessage::SendPanicBuffer(panic_msg)) => {
+                Err(VanadiumClientError::VAppPanicked(panic_msg))
+            }
+            Some(VAppMessage::VAppExited { status }) => {
+                Err(VanadiumClientError::VAppExited(status))
+            }
+            Some(msg) => Ok(msg),
+            None => Err(self.on_engine_stopped("VAppEngine stopped").await),
+        }
+    }
+
+    pub async fn send_message(
+        &mut self,
+        message: &[u8],
+        options: &SendOptions,
+    ) -> Result<Vec<u8>, VanadiumClientError> {
+        if options.is_cancelled() {
+            return Err(VanadiumClientError::Interrupted(Interruption::Cancelled));
+        }
+
+        // The response to an aborted request might still arrive before the V-App acknowledges
+        // the abort; it is discarded.
+        while self.aborting {
+            if let VAppMessage::Aborted = self.receive_from_engine().await? {
+                self.aborting = false;
+            }
+        }
+
         // Send the message to VAppEngine when receive_buffer is called
-        self.client_to_engine_sender
+        let sent = self
+            .client_to_engine_sender
             .as_ref()
             .ok_or("VAppEngine not running")?
             .send(ClientMessage::ReceiveBuffer(message.to_vec()))
-            .await
-            .map_err(|_| "Failed to send message to VAppEngine")?;
-
-        // Wait for the response from VAppEngine
-        match self.engine_to_client_receiver.as_mut() {
-            Some(engine_to_client_receiver) => {
-                let mut receiver = engine_to_client_receiver.lock().await;
-                match receiver.recv().await {
-                    Some(VAppMessage::SendBuffer(buf)) => Ok(buf),
-                    Some(VAppMessage::SendPanicBuffer(panic_msg)) => {
-                        Err(VanadiumClientError::VAppPanicked(panic_msg))
-                    }
-                    Some(VAppMessage::VAppExited { status }) => {
-                        Err(VanadiumClientError::VAppExited(status))
-                    }
-                    None => Err("VAppEngine stopped".into()),
-                }
+            .await;
+        if sent.is_err() {
+            return Err(self
+                .on_engine_stopped("Failed to send message to VAppEngine")
+                .await);
+        }
+
+        // Wait for the response from VAppEngine, unless the caller stops waiting first
+        let interruption = tokio::select! {
+            response = self.receive_from_engine() => {
+                return match response? {
+                    VAppMessage::SendBuffer(buf) => Ok(buf),
+                    _ => Err("Unexpected message from VAppEngine".into()),
+                };
+            }
+            interruption = options.interrupted() => interruption,
+        };
+
+        // The V-App is still processing the message; abort it, so that it's ready for the next one
+        if let Some(sender) = self.client_to_engine_sender.as_ref() {
+            if sender.send(ClientMessage::Abort).await.is_ok() {
+                self.aborting = true;
             }
-            None => Err("VAppEngine not running".into()),
         }
+        Err(VanadiumClientError::Interrupted(interruption))
     }
 }
 
@@ -759,6 +1404,17 @@ pub enum VAppExecutionError {
     /// Indicates that the V-App has exited with the specific status code.
     /// Useful to handle a graceful exit of the V-App.
     AppExited(i32),
+    /// Indicates that the connection to the device was lost, and that the V-App was started again
+    /// from the beginning after reconnecting. The response to the message was lost, if the V-App
+    /// processed it at all; the message can be sent again if it is idempotent.
+    SessionRestarted,
+    /// Indicates that the timeout of [`SendOptions`] expired before the response was received.
+    /// The V-App is asked to abort the request, and is ready for the next message afterwards; the
+    /// request might have been executed anyway.
+    TimedOut,
+    /// Indicates that the [`CancellationHandle`] of [`SendOptions`] was cancelled before the
+    /// response was received. Like for [`VAppExecutionError::TimedOut`], the request is aborted.
+    Cancelled,
     /// Any other error.
     Other(Box<dyn std::error::Error>),
 }
@@ -767,6 +1423,11 @@ impl std::fmt::Display for VAppExecutionError {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         match self {
             VAppExecutionError::AppExited(code) => write!(f, "V-App exited with status {}", code),
+            VAppExecutionError::SessionRestarted => {
+                write!(f, "Connection lost, the V-App was restarted")
+            }
+            VAppExecutionError::TimedOut => write!(f, "Timed out waiting for the V-App"),
+            VAppExecutionError::Cancelled => write!(f, "Cancelled while waiting for the V-App"),
             VAppExecutionError::Other(e) => write!(f, "{}", e),
         }
     }
@@ -781,6 +1442,129 @@ impl std::error::Error for VAppExecutionError {
     }
 }
 
+impl From<Interruption> for VAppExecutionError {
+    fn from(interruption: Interruption) -> Self {
+        match interruption {
+            Interruption::TimedOut => VAppExecutionError::TimedOut,
+            Interruption::Cancelled => VAppExecutionError::Cancelled,
+        }
+    }
+}
+
+/// A handle to cancel the calls to [`VAppTransport::send_message_with`] from another task.
+///
+/// Clones of a handle share its state. Cancelling is permanent: the calls using the handle after
+/// [`CancellationHandle::cancel`] fail immediately, so a new handle is needed for each operation
+/// that can be cancelled separately.
+#[derive(Debug, Clone, Default)]
+pub struct CancellationHandle {
+    state: Arc<CancellationState>,
+}
+
+#[derive(Debug, Default)]
+struct CancellationState {
+    cancelled: AtomicBool,
+    notify: Notify,
+}
+
+impl CancellationHandle {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Cancels the calls using this handle, both the pending ones and the future ones.
+    pub fn cancel(&self) {
+        self.state.cancelled.store(true, Ordering::SeqCst);
+        self.state.notify.notify_waiters();
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.state.cancelled.load(Ordering::SeqCst)
+    }
+
+    /// Completes once the handle is cancelled.
+    pub async fn cancelled(&self) {
+        loop {
+            // created before checking the flag, in order not to miss a notification in between
+            let notified = self.state.notify.notified();
+            if self.is_cancelled() {
+                return;
+            }
+            notified.await;
+        }
+    }
+}
+
+// Why the caller stopped waiting for the response of the V-App
+#[derive(Debug, Clone, Copy)]
+enum Interruption {
+    TimedOut,
+    Cancelled,
+}
+
+/// Options for [`VAppTransport::send_message_with`]. By default, the call waits for the response
+/// for as long as needed, for example while the user reviews a transaction.
+#[derive(Debug, Clone, Default)]
+pub struct SendOptions {
+    timeout: Option<Duration>,
+    cancel: Option<CancellationHandle>,
+}
+
+impl SendOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets how long to wait for the response before failing with
+    /// [`VAppExecutionError::TimedOut`].
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Sets a handle to stop waiting for the response, failing with
+    /// [`VAppExecutionError::Cancelled`].
+    pub fn cancel(mut self, cancel: CancellationHandle) -> Self {
+        self.cancel = Some(cancel);
+        self
+    }
+
+    fn is_cancelled(&self) -> bool {
+        self.cancel.as_ref().is_some_and(|c| c.is_cancelled())
+    }
+
+    // The same options, for a call that is part of an operation started at `start`: the timeout
+    // applies to the whole operation
+    pub(crate) fn since(&self, start: std::time::Instant) -> Self {
+        Self {
+            timeout: self
+                .timeout
+                .map(|timeout| timeout.saturating_sub(start.elapsed())),
+            cancel: self.cancel.clone(),
+        }
+    }
+
+    // Completes when the caller stops waiting for the response, if ever
+    async fn interrupted(&self) -> Interruption {
+        let timeout = async {
+            match self.timeout {
+                Some(timeout) => tokio::time::sleep(timeout).await,
+                None => std::future::pending().await,
+            }
+        };
+        let cancelled = async {
+            match &self.cancel {
+                Some(cancel) => cancel.cancelled().await,
+                None => std::future::pending().await,
+            }
+        };
+        tokio::select! {
+            _ = timeout => Interruption::TimedOut,
+            _ = cancelled => Interruption::Cancelled,
+        }
+    }
+}
+
 /// A trait representing an application that can send messages asynchronously.
 ///
 /// This trait defines the behavior for sending messages to an application and
@@ -798,7 +1582,24 @@ pub trait VAppTransport {
     /// A `Result` containing the response message as a `Vec<u8>` if the operation is successful,
     /// or a `VAppExecutionError` if an error occurs.
 
-    async fn send_message(&mut self, msg: &[u8]) -> Result<Vec<u8>, VAppExecutionError>;
+    async fn send_message(&mut self, msg: &[u8]) -> Result<Vec<u8>, VAppExecutionError>
+    where
+        Self: Send,
+    {
+        self.send_message_with(msg, SendOptions::default()).await
+    }
+
+    /// Like [`VAppTransport::send_message`], but stops waiting for the response if the timeout of
+    /// `options` expires, or if its cancellation handle is cancelled.
+    ///
+    /// In that case, the V-App is asked to abort the request: its UX flows are rejected, its
+    /// response is discarded, and it goes back to the dashboard. The next message is only sent
+    /// once the V-App is done with the aborted request.
+    async fn send_message_with(
+        &mut self,
+        msg: &[u8],
+        options: SendOptions,
+    ) -> Result<Vec<u8>, VAppExecutionError>;
 }
 
 /// Implementation of a VAppTransport using the Vanadium VM.
@@ -853,12 +1654,100 @@ fn get_cargo_toml_path(elf_path: &str) -> Option<PathBuf> {
 }
 
 impl<E: std::fmt::Debug + Send + Sync + 'static> VanadiumAppClient<E> {
+    /// Suspends the V-App the next time it waits for a message.
+    ///
+    /// The VM returns an encrypted snapshot of the state of the CPU, and the client keeps the
+    /// content of the memory. The V-App can then be resumed with [`VanadiumAppClient::resume`],
+    /// even after the Vanadium app was closed on the device. A snapshot can only be resumed once,
+    /// and only the most recently suspended V-App can be resumed.
+    pub async fn suspend(&mut self) -> Result<(), VanadiumAppClientError<E>> {
+        Ok(self.client.suspend().await?)
+    }
+
+    /// Resumes the V-App that was suspended with [`VanadiumAppClient::suspend`], using the given
+    /// transport.
+    pub fn resume(
+        &mut self,
+        transport: Arc<dyn Transport<Error = E>>,
+    ) -> Result<(), VanadiumAppClientError<E>> {
+        Ok(self.client.resume(transport)?)
+    }
+
     pub async fn new(
         elf_path: &str,
         transport: Arc<dyn Transport<Error = E>>,
         app_hmac: Option<[u8; 32]>,
         print_writer: Box<dyn std::io::Write + Send>,
     ) -> Result<(Self, [u8; 32]), Box<dyn std::error::Error + Send + Sync>> {
+        let mut builder = Self::builder(elf_path, transport, print_writer);
+        if let Some(app_hmac) = app_hmac {
+            builder = builder.app_hmac(app_hmac);
+        }
+        builder.build().await
+    }
+
+    /// Returns a builder to run the V-App at `elf_path` with non-default options.
+    pub fn builder(
+        elf_path: &str,
+        transport: Arc<dyn Transport<Error = E>>,
+        print_writer: Box<dyn std::io::Write + Send>,
+    ) -> VanadiumAppClientBuilder<E> {
+        VanadiumAppClientBuilder {
+            elf_path: elf_path.to_string(),
+            transport,
+            app_hmac: None,
+            print_writer,
+            page_store: PageStoreKind::default(),
+            reconnect: None,
+        }
+    }
+}
+
+/// Builder for a [`VanadiumAppClient`], created with [`VanadiumAppClient::builder`].
+pub struct VanadiumAppClientBuilder<E: std::fmt::Debug + Send + Sync + 'static> {
+    elf_path: String,
+    transport: Arc<dyn Transport<Error = E>>,
+    app_hmac: Option<[u8; 32]>,
+    print_writer: Box<dyn std::io::Write + Send>,
+    page_store: PageStoreKind,
+    reconnect: Option<ReconnectStrategy<E>>,
+}
+
+impl<E: std::fmt::Debug + Send + Sync + 'static> VanadiumAppClientBuilder<E> {
+    /// Sets the HMAC of the V-App, if it was already registered. Otherwise, the V-App is
+    /// registered when the client is built.
+    pub fn app_hmac(mut self, app_hmac: [u8; 32]) -> Self {
+        self.app_hmac = Some(app_hmac);
+        self
+    }
+
+    /// Sets where the client keeps the memory of the V-App. By default, it is kept in memory.
+    pub fn page_store(mut self, page_store: PageStoreKind) -> Self {
+        self.page_store = page_store;
+        self
+    }
+
+    /// Sets how the client reconnects if the transport is lost. By default, it doesn't, and the
+    /// client stops working.
+    pub fn reconnect(mut self, reconnect: ReconnectStrategy<E>) -> Self {
+        self.reconnect = Some(reconnect);
+        self
+    }
+
+    /// Starts the V-App, and returns the client and the HMAC of the V-App.
+    pub async fn build(
+        self,
+    ) -> Result<(VanadiumAppClient<E>, [u8; 32]), Box<dyn std::error::Error + Send + Sync>> {
+        let VanadiumAppClientBuilder {
+            elf_path,
+            transport,
+            app_hmac,
+            print_writer,
+            page_store,
+            reconnect,
+        } = self;
+        let elf_path = elf_path.as_str();
+
         // Create ELF file and manifest
         let elf_file = VAppElfFile::new(Path::new(&elf_path))
             .map_err(|e| format!("Failed to create ELF file from path '{}': {}", elf_path, e))?;
@@ -897,66 +1786,70 @@ impl<E: std::fmt::Debug + Send + Sync + 'static> VanadiumAppClient<E> {
                     .ok_or("Stack size missing in metadata")?
                     .as_integer()
                     .ok_or("Stack size is not a number")?;
-                let stack_size = stack_size as u32;
+                let stack_size = check_stack_size(stack_size)?;
+
+                let heap_size = match app_metadata.get("heap_size") {
+                    Some(heap_size) => heap_size.as_integer().ok_or("Heap size is not a number")?,
+                    None => DEFAULT_HEAP_SIZE as i64,
+                };
+                let heap_size = check_heap_size(heap_size)?;
 
                 let stack_start = DEFAULT_STACK_START;
                 let stack_end = stack_start + stack_size;
+                let heap_start = DEFAULT_HEAP_START;
+                let heap_end = heap_start + heap_size;
 
-                let code_merkle_root: [u8; 32] =
-                    MemorySegment::new(elf_file.code_segment.start, &elf_file.code_segment.data)
-                        .get_content_root()
-                        .clone()
-                        .into();
-                let data_merkle_root: [u8; 32] =
-                    MemorySegment::new(elf_file.data_segment.start, &elf_file.data_segment.data)
-                        .get_content_root()
-                        .clone()
-                        .into();
-                let stack_merkle_root: [u8; 32] =
-                    MemorySegment::new(stack_start, &vec![0u8; (stack_end - stack_start) as usize])
-                        .get_content_root()
-                        .clone()
-                        .into();
+                let segments = elf_file.segment_table(stack_start, stack_end, heap_start, heap_end);
+                let stack_segment = (segments.len() - 1) as u8;
+                let heap_segment = (segments.len() - 2) as u8;
 
                 Manifest::new(
                     0,
                     app_name,
                     &app_version,
                     elf_file.entrypoint,
-                    elf_file.code_segment.start,
-                    elf_file.code_segment.end,
-                    code_merkle_root,
-                    elf_file.data_segment.start,
-                    elf_file.data_segment.end,
-                    data_merkle_root,
-                    stack_start,
-                    stack_end,
-                    stack_merkle_root,
+                    segments,
+                    stack_segment,
+                    heap_segment,
                 )?
             }
         };
 
-        let mut client = GenericVanadiumClient::new();
+        let mut client = GenericVanadiumClient::new()
+            .with_page_store(page_store)
+            .with_reconnect(reconnect);
 
         // Register the V-App if the hmac was not given
         let app_hmac =
             app_hmac.unwrap_or(client.register_vapp(transport.clone(), &manifest).await?);
 
         // run the V-App
-        client.run_vapp(transport, &manifest, &app_hmac, &elf_file, print_writer)?;
+        client.run_vapp(
+            transport,
+            &manifest,
+            &app_hmac,
+            Arc::new(elf_file),
+            print_writer,
+        )?;
 
-        Ok((Self { client }, app_hmac))
+        Ok((VanadiumAppClient { client }, app_hmac))
     }
 }
 
 #[async_trait]
 impl<E: std::fmt::Debug + Send + Sync + 'static> VAppTransport for VanadiumAppClient<E> {
-    async fn send_message(&mut self, msg: &[u8]) -> Result<Vec<u8>, VAppExecutionError> {
-        match self.client.send_message(msg).await {
+    async fn send_message_with(
+        &mut self,
+        msg: &[u8],
+        options: SendOptions,
+    ) -> Result<Vec<u8>, VAppExecutionError> {
+        match self.client.send_message(msg, &options).await {
             Ok(response) => Ok(response),
             Err(VanadiumClientError::VAppExited(status)) => {
                 Err(VAppExecutionError::AppExited(status))
             }
+            Err(VanadiumClientError::SessionRestarted) => Err(VAppExecutionError::SessionRestarted),
+            Err(VanadiumClientError::Interrupted(interruption)) => Err(interruption.into()),
             Err(e) => Err(VAppExecutionError::Other(Box::new(e))),
         }
     }
@@ -966,6 +1859,10 @@ impl<E: std::fmt::Debug + Send + Sync + 'static> VAppTransport for VanadiumAppCl
 pub struct NativeAppClient {
     stream: TcpStream,
     print_writer: Box<dyn std::io::Write + Send>,
+    // bytes read from the stream that are not part of a complete buffer yet
+    read_buffer: Vec<u8>,
+    // set after a request is aborted, until the V-App acknowledges it
+    aborting: bool,
 }
 
 impl NativeAppClient {
@@ -979,6 +1876,8 @@ impl NativeAppClient {
         Ok(Self {
             stream,
             print_writer,
+            read_buffer: Vec::new(),
+            aborting: false,
         })
     }
 
@@ -990,11 +1889,79 @@ impl NativeAppClient {
             _ => VAppExecutionError::Other(Box::new(e)),
         }
     }
+
+    // Reads the next buffer sent by the V-App, printing the Print buffers. Cancel-safe: if the
+    // future is dropped, the bytes already read are kept for the next call.
+    async fn next_buffer(&mut self) -> Result<(BufferType, Vec<u8>), VAppExecutionError> {
+        loop {
+            // 1 byte buffer type, 4-byte big-endian length, then raw payload.
+            if self.read_buffer.len() >= 5 {
+                let resp_len = u32::from_be_bytes(self.read_buffer[1..5].try_into().unwrap());
+                let end = 5 + resp_len as usize;
+                if self.read_buffer.len() >= end {
+                    let buffer_type: BufferType = self.read_buffer[0]
+                        .try_into()
+                        .map_err(|_| VAppExecutionError::Other("Invalid buffer type".into()))?;
+                    let resp = self.read_buffer[5..end].to_vec();
+                    self.read_buffer.drain(..end);
+
+                    match buffer_type {
+                        BufferType::Print => {
+                            // Print the message to the print writer
+                            self.print_writer
+                                .write_all(&resp)
+                                .map_err(|e| VAppExecutionError::Other(Box::new(e)))?;
+                            self.print_writer
+                                .flush()
+                                .map_err(|e| VAppExecutionError::Other(Box::new(e)))?;
+                            continue; // Wait for the next message
+                        }
+                        BufferType::Panic => {
+                            panic!("V-App panicked: {}", String::from_utf8_lossy(&resp));
+                        }
+                        BufferType::VAppMessage | BufferType::Aborted => {
+                            return Ok((buffer_type, resp))
+                        }
+                    }
+                }
+            }
+
+            let mut chunk = [0u8; 4096];
+            let n = self.stream.read(&mut chunk).await.map_err(Self::map_err)?;
+            if n == 0 {
+                return Err(VAppExecutionError::AppExited(-1));
+            }
+            self.read_buffer.extend_from_slice(&chunk[..n]);
+        }
+    }
+
+    async fn read_response(&mut self) -> Result<Vec<u8>, VAppExecutionError> {
+        match self.next_buffer().await? {
+            (BufferType::VAppMessage, resp) => Ok(resp),
+            _ => Err(VAppExecutionError::Other("Unexpected buffer type".into())),
+        }
+    }
 }
 
 #[async_trait]
 impl VAppTransport for NativeAppClient {
-    async fn send_message(&mut self, msg: &[u8]) -> Result<Vec<u8>, VAppExecutionError> {
+    async fn send_message_with(
+        &mut self,
+        msg: &[u8],
+        options: SendOptions,
+    ) -> Result<Vec<u8>, VAppExecutionError> {
+        if options.is_cancelled() {
+            return Err(VAppExecutionError::Cancelled);
+        }
+
+        // The response to an aborted request might still arrive before the V-App acknowledges
+        // the abort; it is discarded.
+        while self.aborting {
+            if let (BufferType::Aborted, _) = self.next_buffer().await? {
+                self.aborting = false;
+            }
+        }
+
         // ---------- WRITE ----------
         let len = msg.len() as u32;
         self.stream
@@ -1004,43 +1971,20 @@ impl VAppTransport for NativeAppClient {
         self.stream.write_all(msg).await.map_err(Self::map_err)?;
         self.stream.flush().await.map_err(Self::map_err)?;
 
-        loop {
-            // ---------- READ ----------
-            let mut len_buf = [0u8; 5];
-            self.stream
-                .read_exact(&mut len_buf)
-                .await
-                .map_err(Self::map_err)?;
-
-            let buffer_type: BufferType = len_buf[0]
-                .try_into()
-                .map_err(|_| VAppExecutionError::Other("Invalid buffer type".into()))?;
-            let resp_len =
-                u32::from_be_bytes([len_buf[1], len_buf[2], len_buf[3], len_buf[4]]) as usize;
+        // ---------- READ ----------
+        let interruption = tokio::select! {
+            response = self.read_response() => return response,
+            interruption = options.interrupted() => interruption,
+        };
 
-            let mut resp = vec![0u8; resp_len];
-            self.stream
-                .read_exact(&mut resp)
-                .await
-                .map_err(Self::map_err)?;
-
-            match buffer_type {
-                BufferType::Print => {
-                    // Print the message to the print writer
-                    self.print_writer
-                        .write_all(&resp)
-                        .map_err(|e| VAppExecutionError::Other(Box::new(e)))?;
-                    self.print_writer
-                        .flush()
-                        .map_err(|e| VAppExecutionError::Other(Box::new(e)))?;
-                    continue; // Wait for the next message
-                }
-                BufferType::VAppMessage => return Ok(resp),
-                BufferType::Panic => {
-                    panic!("V-App panicked: {}", String::from_utf8_lossy(&resp));
-                }
-            }
-        }
+        // The V-App is still processing the message; abort it, so that it's ready for the next one
+        self.stream
+            .write_all(&NATIVE_ABORT_REQUEST.to_be_bytes())
+            .await
+            .map_err(Self::map_err)?;
+        self.stream.flush().await.map_err(Self::map_err)?;
+        self.aborting = true;
+        Err(interruption.into())
     }
 }
 
@@ -1054,24 +1998,6 @@ pub mod client_utils {
     use crate::transport::{TransportHID, TransportTcp, TransportWrapper};
     use crate::transport_native_hid::TransportNativeHID;
 
-    struct SharedWriter(Arc<std::sync::Mutex<Box<dyn std::io::Write + Send>>>);
-
-    impl std::io::Write for SharedWriter {
-        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
-            self.0
-                .lock()
-                .map_err(|_| std::io::Error::new(std::io::ErrorKind::Other, "Lock poisoned"))?
-                .write(buf)
-        }
-
-        fn flush(&mut self) -> std::io::Result<()> {
-            self.0
-                .lock()
-                .map_err(|_| std::io::Error::new(std::io::ErrorKind::Other, "Lock poisoned"))?
-                .flush()
-        }
-    }
-
     #[derive(Debug, Clone)]
     pub enum ClientUtilsError {
         /// Failed to connect to native app
@@ -1138,7 +2064,47 @@ pub mod client_utils {
         Ok(Box::new(client))
     }
 
-    /// Creates a Vanadium client using TCP transport (for Speculos)
+    type BoxedTransport = Arc<dyn Transport<Error = Box<dyn std::error::Error + Send + Sync>>>;
+
+    async fn connect_tcp() -> Result<BoxedTransport, Box<dyn std::error::Error + Send + Sync>> {
+        let transport_raw = Arc::new(
+            TransportTcp::new_default()
+                .await
+                .map_err(|e| e.to_string())?,
+        );
+        Ok(Arc::new(TransportWrapper::new(transport_raw)))
+    }
+
+    async fn connect_hid() -> Result<BoxedTransport, Box<dyn std::error::Error + Send + Sync>> {
+        let hid_api = hidapi::HidApi::new()?;
+        let transport_raw = Arc::new(TransportHID::new(TransportNativeHID::new(&hid_api)?));
+        Ok(Arc::new(TransportWrapper::new(transport_raw)))
+    }
+
+    // Starts the V-App, restarting it if the connection to the device is lost
+    async fn create_vanadium_client(
+        app_path: &str,
+        transport: BoxedTransport,
+        reconnect: ReconnectStrategy<Box<dyn std::error::Error + Send + Sync>>,
+        app_hmac: Option<[u8; 32]>,
+        print_writer: Option<Box<dyn std::io::Write + Send>>,
+    ) -> Result<(Box<dyn VAppTransport + Send>, [u8; 32]), ClientUtilsError> {
+        // if no print_writer is provided, default to Sink
+        let print_writer = print_writer.unwrap_or_else(|| Box::new(Sink::default()));
+        let mut builder =
+            VanadiumAppClient::builder(app_path, transport, print_writer).reconnect(reconnect);
+        if let Some(app_hmac) = app_hmac {
+            builder = builder.app_hmac(app_hmac);
+        }
+        let (client, hmac) = builder
+            .build()
+            .await
+            .map_err(|e| ClientUtilsError::VanadiumClientFailed(e.to_string()))?;
+        Ok((Box::new(client), hmac))
+    }
+
+    /// Creates a Vanadium client using TCP transport (for Speculos). The V-App is restarted if the
+    /// connection to Speculos is lost.
     pub async fn create_tcp_client(
         app_path: &str,
         app_hmac: Option<[u8; 32]>,
@@ -1152,16 +2118,18 @@ pub mod client_utils {
         })?);
         let transport = TransportWrapper::new(transport_raw);
 
-        // if no print_writer is provided, default to Sink
-        let print_writer = print_writer.unwrap_or_else(|| Box::new(Sink::default()));
-        let (client, hmac) =
-            VanadiumAppClient::new(app_path, Arc::new(transport), app_hmac, print_writer)
-                .await
-                .map_err(|e| ClientUtilsError::VanadiumClientFailed(e.to_string()))?;
-        Ok((Box::new(client), hmac))
+        create_vanadium_client(
+            app_path,
+            Arc::new(transport),
+            ReconnectStrategy::new(connect_tcp),
+            app_hmac,
+            print_writer,
+        )
+        .await
     }
 
-    /// Creates a Vanadium client using HID transport (for real device)
+    /// Creates a Vanadium client using HID transport (for real device). The V-App is restarted if
+    /// the device is disconnected and connected again.
     pub async fn create_hid_client(
         app_path: &str,
         app_hmac: Option<[u8; 32]>,
@@ -1180,13 +2148,14 @@ pub mod client_utils {
         ));
         let transport = TransportWrapper::new(transport_raw);
 
-        // if no print_writer is provided, default to Sink
-        let print_writer = print_writer.unwrap_or_else(|| Box::new(Sink::default()));
-        let (client, hmac) =
-            VanadiumAppClient::new(app_path, Arc::new(transport), app_hmac, print_writer)
-                .await
-                .map_err(|e| ClientUtilsError::VanadiumClientFailed(e.to_string()))?;
-        Ok((Box::new(client), hmac))
+        create_vanadium_client(
+            app_path,
+            Arc::new(transport),
+            ReconnectStrategy::new(connect_hid),
+            app_hmac,
+            print_writer,
+        )
+        .await
     }
 
     pub enum ClientType {
@@ -1262,3 +2231,259 @@ pub mod client_utils {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::elf::Segment;
+    use common::constants::PAGE_SIZE;
+    use common::vm::SegmentPermissions;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    type BoxedError = Box<dyn std::error::Error + Send + Sync>;
+
+    // A transport to a device that was disconnected
+    struct LostTransport;
+
+    #[async_trait]
+    impl Transport for LostTransport {
+        type Error = BoxedError;
+        async fn exchange(&self, _: &APDUCommand) -> Result<(StatusWord, Vec<u8>), Self::Error> {
+            Err("device disconnected".into())
+        }
+    }
+
+    // Records the commands, and answers as if the V-App exited immediately
+    #[derive(Default)]
+    struct ExitingTransport {
+        commands: std::sync::Mutex<Vec<APDUCommand>>,
+    }
+
+    #[async_trait]
+    impl Transport for ExitingTransport {
+        type Error = BoxedError;
+        async fn exchange(
+            &self,
+            command: &APDUCommand,
+        ) -> Result<(StatusWord, Vec<u8>), Self::Error> {
+            self.commands.lock().unwrap().push(command.clone());
+            Ok((StatusWord::OK, 0i32.to_be_bytes().to_vec()))
+        }
+    }
+
+    fn test_app() -> (VAppElfFile, Manifest) {
+        let elf = VAppElfFile {
+            segments: vec![Segment {
+                data: vec![0x13, 0, 0, 0],
+                start: 0x10000,
+                end: 0x10004,
+                permissions: SegmentPermissions::RX,
+            }],
+            entrypoint: 0x10000,
+            manifest: None,
+            compressed_pages: Default::default(),
+        };
+        let segments = elf.segment_table(
+            DEFAULT_STACK_START,
+            DEFAULT_STACK_START + PAGE_SIZE as u32,
+            DEFAULT_HEAP_START,
+            DEFAULT_HEAP_START + PAGE_SIZE as u32,
+        );
+        let stack_segment = (segments.len() - 1) as u8;
+        let heap_segment = (segments.len() - 2) as u8;
+        let manifest = Manifest::new(
+            0,
+            "Test",
+            "0.1.0",
+            elf.entrypoint,
+            segments,
+            stack_segment,
+            heap_segment,
+        )
+        .unwrap();
+        (elf, manifest)
+    }
+
+    #[tokio::test]
+    async fn test_restart_after_transport_lost() {
+        let (elf, manifest) = test_app();
+        let app_hmac = [42u8; 32];
+        let device = Arc::new(ExitingTransport::default());
+
+        // the first attempt to reconnect fails
+        let attempts = Arc::new(AtomicUsize::new(0));
+        let connector = {
+            let device = device.clone();
+            let attempts = attempts.clone();
+            move || {
+                let device = device.clone();
+                let attempt = attempts.fetch_add(1, Ordering::SeqCst);
+                async move {
+                    if attempt == 0 {
+                        return Err::<Arc<dyn Transport<Error = BoxedError>>, BoxedError>(
+                            "device not found".into(),
+                        );
+                    }
+                    Ok(device as Arc<dyn Transport<Error = BoxedError>>)
+                }
+            }
+        };
+        let reconnect = ReconnectStrategy::new(connector).delay(Duration::ZERO);
+
+        let mut client = GenericVanadiumClient::new().with_reconnect(Some(reconnect));
+        client
+            .run_vapp(
+                Arc::new(LostTransport),
+                &manifest,
+                &app_hmac,
+                Arc::new(elf),
+                Box::new(crate::linewriter::Sink),
+            )
+            .unwrap();
+
+        assert!(matches!(
+            client.send_message(b"hello", &SendOptions::default()).await,
+            Err(VanadiumClientError::SessionRestarted)
+        ));
+        assert_eq!(attempts.load(Ordering::SeqCst), 2);
+
+        // the V-App is started again with the same manifest and HMAC
+        while device.commands.lock().unwrap().is_empty() {
+            tokio::task::yield_now().await;
+        }
+        let commands = device.commands.lock().unwrap().clone();
+        let expected = apdu_run_vapp(postcard::to_allocvec(&manifest).unwrap(), app_hmac);
+        assert_eq!(commands.len(), 1);
+        assert_eq!(commands[0].encode(), expected.encode());
+    }
+
+    // A V-App that echoes the messages, except "confirm", for which it waits for the user until
+    // the request is aborted
+    #[derive(Default)]
+    struct ReviewingTransport {
+        state: std::sync::Mutex<ReviewingState>,
+    }
+
+    #[derive(Default)]
+    struct ReviewingState {
+        reviewing: bool,
+        aborts: usize,
+    }
+
+    #[async_trait]
+    impl Transport for ReviewingTransport {
+        type Error = BoxedError;
+        async fn exchange(
+            &self,
+            command: &APDUCommand,
+        ) -> Result<(StatusWord, Vec<u8>), Self::Error> {
+            // let the client run, as the engine keeps polling the V-App
+            tokio::task::yield_now().await;
+
+            let receive_buffer = vec![ClientCommandCode::ReceiveBuffer as u8];
+            let check_abort = vec![ClientCommandCode::CheckAbort as u8];
+            let mut state = self.state.lock().unwrap();
+            let response = match (command.ins, command.p1) {
+                (0xff, 2) => {
+                    state.reviewing = false;
+                    state.aborts += 1;
+                    receive_buffer
+                }
+                (0xff, 0) if state.reviewing => check_abort,
+                // after sending the response
+                (0xff, 0) if command.data.is_empty() => receive_buffer,
+                (0xff, 0) => {
+                    let msg = ReceiveBufferResponse::deserialize(&command.data)?;
+                    match msg.content {
+                        [] => receive_buffer,
+                        b"confirm" => {
+                            state.reviewing = true;
+                            check_abort
+                        }
+                        content => SendBufferMessage::new(
+                            content.len() as u32,
+                            BufferType::VAppMessage,
+                            content,
+                        )
+                        .serialize(),
+                    }
+                }
+                _ => receive_buffer,
+            };
+            Ok((StatusWord::InterruptedExecution, response))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_timeout_and_cancel() {
+        let (elf, manifest) = test_app();
+        let device = Arc::new(ReviewingTransport::default());
+        let mut client = GenericVanadiumClient::new();
+        client
+            .run_vapp(
+                device.clone(),
+                &manifest,
+                &[0u8; 32],
+                Arc::new(elf),
+                Box::new(crate::linewriter::Sink),
+            )
+            .unwrap();
+        let mut client = VanadiumAppClient { client };
+
+        let options = SendOptions::new().timeout(Duration::from_millis(50));
+        assert_eq!(
+            client
+                .send_message_with(b"hello", options.clone())
+                .await
+                .unwrap(),
+            b"hello"
+        );
+        assert!(matches!(
+            client.send_message_with(b"confirm", options).await,
+            Err(VAppExecutionError::TimedOut)
+        ));
+
+        // the next message is sent once the V-App is done with the aborted request
+        assert_eq!(client.send_message(b"hello").await.unwrap(), b"hello");
+        assert_eq!(device.state.lock().unwrap().aborts, 1);
+
+        let cancel = CancellationHandle::new();
+        let options = SendOptions::new().cancel(cancel.clone());
+        let (result, _) = tokio::join!(
+            client.send_message_with(b"confirm", options.clone()),
+            async {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+                cancel.cancel();
+            }
+        );
+        assert!(matches!(result, Err(VAppExecutionError::Cancelled)));
+
+        // a cancelled handle stays cancelled, and nothing is sent
+        assert!(matches!(
+            client.send_message_with(b"hello", options).await,
+            Err(VAppExecutionError::Cancelled)
+        ));
+        assert_eq!(client.send_message(b"world").await.unwrap(), b"world");
+        assert_eq!(device.state.lock().unwrap().aborts, 2);
+    }
+
+    #[tokio::test]
+    async fn test_no_reconnect_strategy() {
+        let (elf, manifest) = test_app();
+        let mut client = GenericVanadiumClient::new();
+        client
+            .run_vapp(
+                Arc::new(LostTransport),
+                &manifest,
+                &[0u8; 32],
+                Arc::new(elf),
+                Box::new(crate::linewriter::Sink),
+            )
+            .unwrap();
+
+        assert!(matches!(
+            client.send_message(b"hello", &SendOptions::default()).await,
+            Err(VanadiumClientError::GenericError(_))
+        ));
+    }
+}
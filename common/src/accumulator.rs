@@ -6,7 +6,7 @@
 //! Each retrieval or update operation is guaranteed by an accompanied proof, that is
 //! produced by the prover.
 
-use alloc::{vec, vec::Vec};
+use alloc::{collections::BTreeMap, vec, vec::Vec};
 use core::{error::Error, fmt, marker::PhantomData, mem::MaybeUninit, ops::Deref};
 use serde::{de::DeserializeOwned, Deserialize, Deserializer, Serialize, Serializer};
 
@@ -363,11 +363,15 @@ impl<
 }
 
 /// Verifier for streaming inclusion proof verification in a Merkle tree.
+///
+/// The proof is verified up to the node at position `target`, that is the root unless the top
+/// levels of the tree are known to the verifier (see [`MerkleTreeTop`]).
 pub struct MerkleInclusionProofVerifier<H: ResettableHasher<OUTPUT_SIZE>, const OUTPUT_SIZE: usize>
 {
     current_hash: HashOutput<OUTPUT_SIZE>, // Current computed hash
     pos: usize,                            // Current position in the tree
-    root: HashOutput<OUTPUT_SIZE>,         // Expected root hash
+    target: usize,                         // Position where the verification stops
+    root: HashOutput<OUTPUT_SIZE>,         // Expected hash of the node at position target
     verified: bool,                        // Whether the proof has been verified
     _marker: PhantomData<H>,
 }
@@ -376,7 +380,7 @@ impl<H: ResettableHasher<OUTPUT_SIZE>, const OUTPUT_SIZE: usize>
     InclusionProofVerifier<OUTPUT_SIZE, H> for MerkleInclusionProofVerifier<H, OUTPUT_SIZE>
 {
     fn feed(&mut self, hasher: &mut H, sibling_hash: &HashOutput<OUTPUT_SIZE>) {
-        if self.pos == 0 {
+        if self.pos == self.target {
             // Verification already completed; extra elements make the proof invalid
 
             self.verified = false;
@@ -400,8 +404,8 @@ impl<H: ResettableHasher<OUTPUT_SIZE>, const OUTPUT_SIZE: usize>
         // Move up the tree
         self.pos = (self.pos - 1) / 2;
 
-        // If at the root, check if the computed hash matches
-        if self.pos == 0 {
+        // If at the target, check if the computed hash matches
+        if self.pos == self.target {
             self.verified = &self.current_hash == &self.root;
         }
     }
@@ -432,6 +436,138 @@ impl<H: ResettableHasher<OUTPUT_SIZE>, const OUTPUT_SIZE: usize> UpdateProofVeri
     }
 }
 
+/// Verifier for streaming verification of a multiproof of a contiguous range of elements in a
+/// Merkle tree, as produced by [`MerkleAccumulator::prove_range`].
+///
+/// The verifier keeps the nodes whose hash is known, and combines them whenever both children of a
+/// node are known. Each element of the proof is the hash of the sibling of the known node with the
+/// largest position, when that sibling is not known.
+///
+/// The nodes are combined until they are all at a position smaller than `frontier`, where they are
+/// compared with the expected ones: that is only the root, unless the top levels of the tree are
+/// known to the verifier (see [`MerkleTreeTop`]).
+pub struct MerkleMultiProofVerifier<H: ResettableHasher<OUTPUT_SIZE>, const OUTPUT_SIZE: usize> {
+    nodes: Vec<(usize, HashOutput<OUTPUT_SIZE>)>, // Known nodes, sorted by decreasing position
+    frontier: usize,                              // Position where the verification stops
+    expected: Vec<(usize, HashOutput<OUTPUT_SIZE>)>, // Expected nodes, sorted like `nodes`
+    verified: bool,                               // Whether the proof has been verified
+    _marker: PhantomData<H>,
+}
+
+impl<H: ResettableHasher<OUTPUT_SIZE>, const OUTPUT_SIZE: usize>
+    MerkleMultiProofVerifier<H, OUTPUT_SIZE>
+{
+    fn is_complete(&self) -> bool {
+        self.nodes[0].0 < self.frontier
+    }
+
+    // Replaces the two children of a node with the node itself, keeping the nodes sorted
+    fn push_parent(
+        &mut self,
+        hasher: &mut H,
+        pos: usize,
+        left: &HashOutput<OUTPUT_SIZE>,
+        right: &HashOutput<OUTPUT_SIZE>,
+    ) {
+        let mut parent_hash = HashOutput([0u8; OUTPUT_SIZE]);
+        hasher.reset();
+        hasher.update(&[0x01]); // Internal node prefix
+        hasher.update(&left.0);
+        hasher.update(&right.0);
+        hasher.digest_inplace(&mut parent_hash.0);
+
+        let parent = (pos - 1) / 2;
+        let index = self.nodes.partition_point(|(p, _)| *p > parent);
+        self.nodes.insert(index, (parent, parent_hash));
+    }
+
+    // Combines the known nodes until an element of the proof is needed, or the root is reached
+    fn advance(&mut self, hasher: &mut H) {
+        while !self.is_complete() {
+            let pos = self.nodes[0].0;
+            if !pos.is_multiple_of(2) || self.nodes.len() < 2 || self.nodes[1].0 != pos - 1 {
+                return;
+            }
+            let (_, right) = self.nodes.remove(0);
+            let (_, left) = self.nodes.remove(0);
+            self.push_parent(hasher, pos, &left, &right);
+        }
+        self.verified = self.nodes == self.expected;
+    }
+}
+
+impl<H: ResettableHasher<OUTPUT_SIZE>, const OUTPUT_SIZE: usize>
+    InclusionProofVerifier<OUTPUT_SIZE, H> for MerkleMultiProofVerifier<H, OUTPUT_SIZE>
+{
+    fn feed(&mut self, hasher: &mut H, sibling_hash: &HashOutput<OUTPUT_SIZE>) {
+        if self.is_complete() {
+            // Verification already completed; extra elements make the proof invalid
+            self.verified = false;
+            return;
+        }
+
+        let (pos, current_hash) = self.nodes.remove(0);
+        if pos.is_multiple_of(2) {
+            // Even pos: right child
+            self.push_parent(hasher, pos, sibling_hash, &current_hash);
+        } else {
+            // Odd pos: left child
+            self.push_parent(hasher, pos, &current_hash, sibling_hash);
+        }
+        self.advance(hasher);
+    }
+
+    fn verified(&self) -> bool {
+        self.verified
+    }
+}
+
+/// Returns the positions of the nodes of the multiproof for the `count` consecutive elements
+/// starting at index `start`, in a Merkle tree of `size` elements, in the order in which
+/// [`MerkleMultiProofVerifier`] consumes them.
+///
+/// The tree is laid out like a binary heap: the element `i` is at position `size - 1 + i`, and the
+/// children of the node at position `p` are at positions `2p + 1` and `2p + 2`.
+pub fn range_proof_positions(
+    size: usize,
+    start: usize,
+    count: usize,
+) -> Result<Vec<usize>, AccumulatorError> {
+    truncated_range_proof_positions(size, start, count, 1)
+}
+
+/// Like [`range_proof_positions`], for a verifier that knows the `n_top_nodes` nodes of the top
+/// levels of the tree, as returned by [`top_nodes_count`]. The proof stops at the known nodes, and
+/// it's always a prefix of the full one.
+pub fn truncated_range_proof_positions(
+    size: usize,
+    start: usize,
+    count: usize,
+    n_top_nodes: usize,
+) -> Result<Vec<usize>, AccumulatorError> {
+    if count == 0 || start >= size || count > size - start {
+        return Err(AccumulatorError::IndexOutOfBounds);
+    }
+
+    // positions of the known nodes, in decreasing order
+    let mut known: Vec<usize> = (size - 1 + start..size - 1 + start + count).rev().collect();
+    let mut positions = Vec::new();
+    while known[0] >= n_top_nodes {
+        let pos = known.remove(0);
+        if pos.is_multiple_of(2) && known.first() == Some(&(pos - 1)) {
+            known.remove(0);
+        } else if pos.is_multiple_of(2) {
+            positions.push(pos - 1);
+        } else {
+            positions.push(pos + 1);
+        }
+        let parent = (pos - 1) / 2;
+        let index = known.partition_point(|p| *p > parent);
+        known.insert(index, parent);
+    }
+    Ok(positions)
+}
+
 /// A Merkle tree-based implementation of the `VectorAccumulator` trait.
 pub struct MerkleAccumulator<
     H: Hasher<OUTPUT_SIZE>,
@@ -462,6 +598,7 @@ impl<
         MerkleInclusionProofVerifier {
             current_hash: value_hash.clone(),
             pos,
+            target: 0,
             root: root.clone(),
             // a zero-length proof (for a single-element tree) is only valid if the value hash is equal to the root
             verified: size == 1 && value_hash == root,
@@ -622,7 +759,7 @@ impl<
     }
 
     /// Computes the hash for a leaf node. A 0x00 byte is prepended to the data before hashing the element.
-    fn hash_leaf<T_: AsRef<[u8]>>(data: &T_) -> HashOutput<OUTPUT_SIZE> {
+    pub fn hash_leaf<T_: AsRef<[u8]>>(data: &T_) -> HashOutput<OUTPUT_SIZE> {
         let mut hasher = H::new();
         hasher.update(&[0x00]);
         hasher.update(data.as_ref());
@@ -630,7 +767,7 @@ impl<
     }
 
     /// Computes the hash for an internal node. A 0x01 byte is prepended to the data before hashing the child nodes.
-    fn hash_internal_node(
+    pub fn hash_internal_node(
         left: &HashOutput<OUTPUT_SIZE>,
         right: &HashOutput<OUTPUT_SIZE>,
     ) -> HashOutput<OUTPUT_SIZE> {
@@ -641,6 +778,469 @@ impl<
         hasher.update(&right.0);
         HashOutput(hasher.finalize())
     }
+
+    /// Generates a multiproof for the `count` consecutive elements starting at index `start`.
+    ///
+    /// The proof contains the hashes of the nodes that are needed to compute the root from the
+    /// hashes of the elements in the range, in the order in which [`MerkleMultiProofVerifier`]
+    /// consumes them. It is never longer than the concatenation of the inclusion proofs of the
+    /// elements, and it's much shorter for large ranges.
+    pub fn prove_range(
+        &self,
+        start: usize,
+        count: usize,
+    ) -> Result<Vec<HashOutput<OUTPUT_SIZE>>, AccumulatorError> {
+        Ok(range_proof_positions(self.data.len(), start, count)?
+            .into_iter()
+            .map(|pos| self.tree[pos].clone())
+            .collect())
+    }
+}
+
+impl<
+        H: ResettableHasher<OUTPUT_SIZE>,
+        T: AsRef<[u8]> + Clone + Serialize + DeserializeOwned,
+        const OUTPUT_SIZE: usize,
+    > MerkleAccumulator<H, T, OUTPUT_SIZE>
+{
+    /// Starts the streaming verification of a multiproof for the consecutive elements starting at
+    /// index `start`, whose hashes are `value_hashes`, in a vector of length `size`.
+    ///
+    /// The range must be non-empty and within the bounds of the vector.
+    pub fn begin_range_proof(
+        root: &HashOutput<OUTPUT_SIZE>,
+        value_hashes: &[HashOutput<OUTPUT_SIZE>],
+        start: usize,
+        size: usize,
+    ) -> MerkleMultiProofVerifier<H, OUTPUT_SIZE> {
+        assert!(!value_hashes.is_empty() && start + value_hashes.len() <= size);
+
+        let first = size - 1 + start;
+        let mut verifier = MerkleMultiProofVerifier {
+            nodes: value_hashes
+                .iter()
+                .enumerate()
+                .rev()
+                .map(|(i, hash)| (first + i, hash.clone()))
+                .collect(),
+            frontier: 1,
+            expected: vec![(0, root.clone())],
+            verified: false,
+            _marker: PhantomData,
+        };
+        // the range might not need any element of the proof, if it contains the whole vector
+        verifier.advance(&mut H::new());
+        verifier
+    }
+
+    /// Verifies a multiproof produced by [`MerkleAccumulator::prove_range`].
+    pub fn verify_range_proof(
+        root: &HashOutput<OUTPUT_SIZE>,
+        proof: &[HashOutput<OUTPUT_SIZE>],
+        value_hashes: &[HashOutput<OUTPUT_SIZE>],
+        start: usize,
+        size: usize,
+    ) -> bool {
+        let mut hasher = H::new();
+        let mut verifier = Self::begin_range_proof(root, value_hashes, start, size);
+        for el in proof.iter() {
+            verifier.feed(&mut hasher, el);
+        }
+        verifier.verified()
+    }
+}
+
+/// Returns the number of nodes in the `levels` levels below the root of a Merkle tree of `size`
+/// elements, root included. These are the nodes at positions from 0 to the returned value
+/// (excluded); it's the whole tree if it has no more than `levels` levels below the root.
+pub fn top_nodes_count(size: usize, levels: u8) -> usize {
+    let top_levels_size = 1usize
+        .checked_shl(levels as u32 + 1)
+        .map_or(usize::MAX, |n| n - 1);
+    top_levels_size.min(2 * size - 1)
+}
+
+/// The nodes of the top levels of a Merkle tree of a [`MerkleAccumulator`], kept by a verifier in
+/// addition to the root in order to shorten the proofs.
+///
+/// The proofs only go up to the cached levels: the verifier compares the hash that it computes for
+/// the deepest cached ancestor of an element with the cached node, rather than going all the way
+/// to the root. The prover therefore omits the last elements of each proof, as computed by
+/// [`truncated_range_proof_positions`]. With no levels cached below the root, the proofs are the
+/// same as for a [`MerkleAccumulator`].
+pub struct MerkleTreeTop<H: ResettableHasher<OUTPUT_SIZE>, const OUTPUT_SIZE: usize> {
+    size: usize,
+    levels: u8,
+    nodes: Vec<HashOutput<OUTPUT_SIZE>>, // nodes at positions 0..top_nodes_count(size, levels)
+    _marker: PhantomData<H>,
+}
+
+impl<H: ResettableHasher<OUTPUT_SIZE>, const OUTPUT_SIZE: usize> MerkleTreeTop<H, OUTPUT_SIZE> {
+    /// Creates the top of the tree of a vector of length `size`, with only the root cached.
+    pub fn new(root: HashOutput<OUTPUT_SIZE>, size: usize) -> Self {
+        assert!(size > 0);
+        Self {
+            size,
+            levels: 0,
+            nodes: vec![root],
+            _marker: PhantomData,
+        }
+    }
+
+    /// Returns the number of levels cached below the root.
+    pub fn levels(&self) -> u8 {
+        self.levels
+    }
+
+    /// Returns the number of cached nodes, root included.
+    pub fn n_nodes(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn root(&self) -> &HashOutput<OUTPUT_SIZE> {
+        &self.nodes[0]
+    }
+
+    /// Replaces the cached nodes with the `nodes` of the `levels` levels below the root, as given
+    /// by the prover. They are only accepted if they are consistent with the current root;
+    /// otherwise, `false` is returned and the cache is not changed.
+    pub fn set_nodes(&mut self, levels: u8, nodes: Vec<HashOutput<OUTPUT_SIZE>>) -> bool {
+        if nodes.len() != top_nodes_count(self.size, levels) || nodes[0] != self.nodes[0] {
+            return false;
+        }
+        // every cached node whose children are cached must be the hash of its children
+        let mut hasher = H::new();
+        let mut hash = HashOutput([0u8; OUTPUT_SIZE]);
+        for pos in 0..nodes.len() / 2 {
+            hasher.reset();
+            hasher.update(&[0x01]); // Internal node prefix
+            hasher.update(&nodes[2 * pos + 1].0);
+            hasher.update(&nodes[2 * pos + 2].0);
+            hasher.digest_inplace(&mut hash.0);
+            if hash != nodes[pos] {
+                return false;
+            }
+        }
+        self.levels = levels;
+        self.nodes = nodes;
+        true
+    }
+
+    // Returns the position of the deepest cached ancestor of the node at position `pos`, that is
+    // the node itself if it's cached
+    fn cached_ancestor(&self, mut pos: usize) -> usize {
+        while pos >= self.nodes.len() {
+            pos = (pos - 1) / 2;
+        }
+        pos
+    }
+
+    /// Starts the streaming verification of a truncated inclusion proof for the element with hash
+    /// `value_hash` at the given index.
+    pub fn begin_inclusion_proof(
+        &self,
+        value_hash: &HashOutput<OUTPUT_SIZE>,
+        index: usize,
+    ) -> MerkleInclusionProofVerifier<H, OUTPUT_SIZE> {
+        assert!(index < self.size);
+        let pos = self.size - 1 + index;
+        let target = self.cached_ancestor(pos);
+        MerkleInclusionProofVerifier {
+            current_hash: value_hash.clone(),
+            pos,
+            target,
+            root: self.nodes[target].clone(),
+            // a zero-length proof is only valid if the element itself is cached
+            verified: pos == target && value_hash == &self.nodes[target],
+            _marker: PhantomData,
+        }
+    }
+
+    /// Starts the streaming verification of a truncated multiproof for the consecutive elements
+    /// starting at index `start`, whose hashes are `value_hashes`.
+    pub fn begin_range_proof(
+        &self,
+        value_hashes: &[HashOutput<OUTPUT_SIZE>],
+        start: usize,
+    ) -> MerkleMultiProofVerifier<H, OUTPUT_SIZE> {
+        assert!(!value_hashes.is_empty() && start + value_hashes.len() <= self.size);
+
+        let first = self.size - 1 + start;
+        let nodes: Vec<_> = value_hashes
+            .iter()
+            .enumerate()
+            .rev()
+            .map(|(i, hash)| (first + i, hash.clone()))
+            .collect();
+        let mut expected: Vec<_> = nodes
+            .iter()
+            .map(|(pos, _)| {
+                let ancestor = self.cached_ancestor(*pos);
+                (ancestor, self.nodes[ancestor].clone())
+            })
+            .collect();
+        expected.sort_by_key(|(pos, _)| core::cmp::Reverse(*pos));
+        expected.dedup_by_key(|(pos, _)| *pos);
+
+        let mut verifier = MerkleMultiProofVerifier {
+            nodes,
+            frontier: self.nodes.len(),
+            expected,
+            verified: false,
+            _marker: PhantomData,
+        };
+        verifier.advance(&mut H::new());
+        verifier
+    }
+
+    /// Starts the streaming verification of a truncated update proof, for the element at the
+    /// given index whose hash changes from `old_value_hash` to `new_value_hash`. The new root is
+    /// not needed: once verified, the cached nodes are updated with [`MerkleTreeTop::apply_update`].
+    pub fn begin_update_proof(
+        &self,
+        old_value_hash: &HashOutput<OUTPUT_SIZE>,
+        new_value_hash: &HashOutput<OUTPUT_SIZE>,
+        index: usize,
+    ) -> MerkleTreeTopUpdateVerifier<H, OUTPUT_SIZE> {
+        MerkleTreeTopUpdateVerifier {
+            old_verifier: self.begin_inclusion_proof(old_value_hash, index),
+            new_verifier: self.begin_inclusion_proof(new_value_hash, index),
+        }
+    }
+
+    /// Updates the cached nodes after a verified update proof. Returns `false` and leaves the
+    /// cache unchanged if the proof was not verified.
+    pub fn apply_update(&mut self, verifier: &MerkleTreeTopUpdateVerifier<H, OUTPUT_SIZE>) -> bool {
+        if !verifier.verified() {
+            return false;
+        }
+        let mut pos = verifier.new_verifier.target;
+        self.nodes[pos] = verifier.new_verifier.current_hash.clone();
+
+        let mut hasher = H::new();
+        while pos > 0 {
+            pos = (pos - 1) / 2;
+            hasher.reset();
+            hasher.update(&[0x01]); // Internal node prefix
+            hasher.update(&self.nodes[2 * pos + 1].0);
+            hasher.update(&self.nodes[2 * pos + 2].0);
+            hasher.digest_inplace(&mut self.nodes[pos].0);
+        }
+        true
+    }
+}
+
+/// Verifier for streaming verification of a truncated update proof, started with
+/// [`MerkleTreeTop::begin_update_proof`].
+///
+/// Only the old value is checked against the cached nodes; for the new value, the verifier only
+/// computes the new hash of the deepest cached ancestor, using the same proof.
+pub struct MerkleTreeTopUpdateVerifier<H: ResettableHasher<OUTPUT_SIZE>, const OUTPUT_SIZE: usize> {
+    old_verifier: MerkleInclusionProofVerifier<H, OUTPUT_SIZE>,
+    new_verifier: MerkleInclusionProofVerifier<H, OUTPUT_SIZE>,
+}
+
+impl<H: ResettableHasher<OUTPUT_SIZE>, const OUTPUT_SIZE: usize> UpdateProofVerifier<OUTPUT_SIZE, H>
+    for MerkleTreeTopUpdateVerifier<H, OUTPUT_SIZE>
+{
+    fn feed(&mut self, hasher: &mut H, sibling_hash: &HashOutput<OUTPUT_SIZE>) {
+        self.old_verifier.feed(hasher, sibling_hash);
+        self.new_verifier.feed(hasher, sibling_hash);
+    }
+
+    fn verified(&self) -> bool {
+        self.old_verifier.verified() && self.new_verifier.pos == self.new_verifier.target
+    }
+}
+
+// Returns the heights of the leftmost and of the rightmost leaf below the node at position `pos`,
+// in a Merkle tree of `size` elements laid out like a binary heap. They are equal if the subtree
+// of the node is perfect.
+fn subtree_heights(size: usize, pos: usize) -> (usize, usize) {
+    // the leftmost descendants are at the positions (pos + 1) * 2^k - 1, and the rightmost ones at
+    // (pos + 2) * 2^k - 2, for the heights k that are still in the 2 * size - 1 nodes of the tree
+    let left = ((2 * size - 1) / (pos + 1)).ilog2() as usize;
+    let right = ((2 * size) / (pos + 2)).ilog2() as usize;
+    (left, right)
+}
+
+/// A Merkle tree over a vector whose elements are all equal to a default value, except for the
+/// ones that are explicitly updated. Only the updated elements and the nodes that differ from the
+/// ones of the all-default tree are stored, so that very large vectors are cheap to create.
+///
+/// The tree is the same as the one of a [`MerkleAccumulator`] with the same content, therefore it
+/// has the same root, and its proofs are verified in the same way.
+pub struct SparseMerkleAccumulator<
+    H: Hasher<OUTPUT_SIZE>,
+    T: AsRef<[u8]> + Clone + Serialize + DeserializeOwned,
+    const OUTPUT_SIZE: usize,
+> {
+    size: usize,
+    default: T,
+    data: BTreeMap<usize, T>,
+    // the nodes that differ from the default ones, by position in the tree
+    nodes: BTreeMap<usize, HashOutput<OUTPUT_SIZE>>,
+    // the root of a perfect all-default subtree, for each height from the leaves
+    default_hashes: Vec<HashOutput<OUTPUT_SIZE>>,
+    // the default nodes whose subtree is not perfect, as their leaves are not all at the same
+    // depth; there is at most one per level of the tree
+    unbalanced_default_nodes: BTreeMap<usize, HashOutput<OUTPUT_SIZE>>,
+    _marker: PhantomData<H>,
+}
+
+impl<
+        H: Hasher<OUTPUT_SIZE>,
+        T: AsRef<[u8]> + Clone + Serialize + DeserializeOwned,
+        const OUTPUT_SIZE: usize,
+    > SparseMerkleAccumulator<H, T, OUTPUT_SIZE>
+{
+    /// Creates a new `SparseMerkleAccumulator` for a vector of `size` elements, all equal to
+    /// `default`. Returns `None` if `size` is 0.
+    pub fn new(size: usize, default: T) -> Option<Self> {
+        if size == 0 {
+            return None;
+        }
+        let mut default_hashes = vec![MerkleAccumulator::<H, T, OUTPUT_SIZE>::hash_leaf(&default)];
+        for height in 0..subtree_heights(size, 0).0 {
+            let hash = &default_hashes[height];
+            default_hashes.push(MerkleAccumulator::<H, T, OUTPUT_SIZE>::hash_internal_node(
+                hash, hash,
+            ));
+        }
+        let mut accumulator = Self {
+            size,
+            default,
+            data: BTreeMap::new(),
+            nodes: BTreeMap::new(),
+            default_hashes,
+            unbalanced_default_nodes: BTreeMap::new(),
+            _marker: PhantomData,
+        };
+
+        // The tree is complete: below an unbalanced node, at most one child is unbalanced too
+        let mut unbalanced = Vec::new();
+        let mut pos = 0;
+        loop {
+            let (left, right) = subtree_heights(size, pos);
+            if left == right {
+                break;
+            }
+            unbalanced.push(pos);
+            let (left, right) = subtree_heights(size, 2 * pos + 1);
+            pos = if left != right {
+                2 * pos + 1
+            } else {
+                2 * pos + 2
+            };
+        }
+        for pos in unbalanced.into_iter().rev() {
+            let hash = MerkleAccumulator::<H, T, OUTPUT_SIZE>::hash_internal_node(
+                accumulator.default_node(2 * pos + 1),
+                accumulator.default_node(2 * pos + 2),
+            );
+            accumulator.unbalanced_default_nodes.insert(pos, hash);
+        }
+        Some(accumulator)
+    }
+
+    pub fn get(&self, index: usize) -> Option<&T> {
+        if index >= self.size {
+            return None;
+        }
+        Some(self.data.get(&index).unwrap_or(&self.default))
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Returns the root hash of the Merkle tree.
+    pub fn root(&self) -> &HashOutput<OUTPUT_SIZE> {
+        self.node(0)
+    }
+
+    /// Returns the hash of the node at the given position when all the elements are equal to the
+    /// default value.
+    pub fn default_node(&self, pos: usize) -> &HashOutput<OUTPUT_SIZE> {
+        self.unbalanced_default_nodes
+            .get(&pos)
+            .unwrap_or_else(|| &self.default_hashes[subtree_heights(self.size, pos).0])
+    }
+
+    // Returns the hash of the node at the given position
+    fn node(&self, pos: usize) -> &HashOutput<OUTPUT_SIZE> {
+        self.nodes
+            .get(&pos)
+            .unwrap_or_else(|| self.default_node(pos))
+    }
+
+    // Sets the hash of the node at the given position, only storing it if it's not the default
+    fn set_node(&mut self, pos: usize, hash: HashOutput<OUTPUT_SIZE>) {
+        if hash == *self.default_node(pos) {
+            self.nodes.remove(&pos);
+        } else {
+            self.nodes.insert(pos, hash);
+        }
+    }
+
+    /// Generates a proof of inclusion for an element at the given index, like
+    /// [`MerkleAccumulator::prove`].
+    pub fn prove(&self, index: usize) -> Result<Vec<HashOutput<OUTPUT_SIZE>>, AccumulatorError> {
+        if index >= self.size {
+            return Err(AccumulatorError::IndexOutOfBounds);
+        }
+
+        let mut proof = Vec::new();
+        let mut pos = self.size - 1 + index;
+        while pos > 0 {
+            if pos.is_multiple_of(2) {
+                proof.push(self.node(pos - 1).clone());
+            } else {
+                proof.push(self.node(pos + 1).clone());
+            }
+            pos = (pos - 1) / 2;
+        }
+        Ok(proof)
+    }
+
+    /// Generates a multiproof for the `count` consecutive elements starting at index `start`, like
+    /// [`MerkleAccumulator::prove_range`].
+    pub fn prove_range(
+        &self,
+        start: usize,
+        count: usize,
+    ) -> Result<Vec<HashOutput<OUTPUT_SIZE>>, AccumulatorError> {
+        Ok(range_proof_positions(self.size, start, count)?
+            .into_iter()
+            .map(|pos| self.node(pos).clone())
+            .collect())
+    }
+
+    /// Replaces the element at the given index, and returns the update proof and the new root,
+    /// like [`MerkleAccumulator::update`].
+    pub fn update(
+        &mut self,
+        index: usize,
+        value: T,
+    ) -> Result<(Vec<HashOutput<OUTPUT_SIZE>>, HashOutput<OUTPUT_SIZE>), AccumulatorError> {
+        let merkle_proof = self.prove(index)?; // Capture proof before update
+
+        let mut pos = self.size - 1 + index;
+        let leaf_hash = MerkleAccumulator::<H, T, OUTPUT_SIZE>::hash_leaf(&value);
+        self.data.insert(index, value);
+        self.set_node(pos, leaf_hash);
+
+        while pos > 0 {
+            pos = (pos - 1) / 2;
+            let hash = MerkleAccumulator::<H, T, OUTPUT_SIZE>::hash_internal_node(
+                self.node(2 * pos + 1),
+                self.node(2 * pos + 2),
+            );
+            self.set_node(pos, hash);
+        }
+
+        Ok((merkle_proof, self.root().clone()))
+    }
 }
 
 #[cfg(test)]
@@ -907,4 +1507,269 @@ mod tests {
             )
         );
     }
+
+    #[test]
+    fn test_range_proof() {
+        type MA = MerkleAccumulator<Sha256Hasher, Vec<u8>, 32>;
+
+        for size in 1..=9 {
+            let data = generate_test_data(size);
+            let ma = MA::new(data.clone());
+            let hashes: Vec<_> = data.iter().map(MA::hash_leaf).collect();
+
+            for start in 0..size {
+                for count in 1..=size - start {
+                    let range = &hashes[start..start + count];
+                    let proof = ma.prove_range(start, count).unwrap();
+                    assert!(MA::verify_range_proof(
+                        ma.root(),
+                        &proof,
+                        range,
+                        start,
+                        size
+                    ));
+
+                    // never longer than the individual proofs
+                    let single_proofs_len: usize = (start..start + count)
+                        .map(|i| ma.prove(i).unwrap().len())
+                        .sum();
+                    assert!(proof.len() <= single_proofs_len);
+                    if count == 1 {
+                        assert_eq!(proof, ma.prove(start).unwrap());
+                    }
+
+                    // wrong position, truncated or extended proofs are rejected
+                    if start + count < size {
+                        assert!(!MA::verify_range_proof(
+                            ma.root(),
+                            &proof,
+                            range,
+                            start + 1,
+                            size
+                        ));
+                    }
+                    if !proof.is_empty() {
+                        let truncated = &proof[..proof.len() - 1];
+                        assert!(!MA::verify_range_proof(
+                            ma.root(),
+                            truncated,
+                            range,
+                            start,
+                            size
+                        ));
+                    }
+                    let mut extended = proof.clone();
+                    extended.push(HashOutput([0u8; 32]));
+                    assert!(!MA::verify_range_proof(
+                        ma.root(),
+                        &extended,
+                        range,
+                        start,
+                        size
+                    ));
+
+                    // a wrong element is rejected
+                    let mut wrong = range.to_vec();
+                    wrong[count - 1] = MA::hash_leaf(&b"wrong".to_vec());
+                    assert!(!MA::verify_range_proof(
+                        ma.root(),
+                        &proof,
+                        &wrong,
+                        start,
+                        size
+                    ));
+                }
+            }
+        }
+
+        let ma = MA::new(generate_test_data(4));
+        assert!(ma.prove_range(0, 0).is_err());
+        assert!(ma.prove_range(2, 3).is_err());
+        assert!(ma.prove_range(4, 1).is_err());
+        assert!(ma.prove_range(0, 4).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_sparse_merkle_accumulator() {
+        type MA = MerkleAccumulator<Sha256Hasher, Vec<u8>, 32>;
+        type Sma = SparseMerkleAccumulator<Sha256Hasher, Vec<u8>, 32>;
+
+        assert!(Sma::new(0, vec![]).is_none());
+
+        let default = b"default".to_vec();
+        for size in [1, 2, 3, 5, 6, 8, 11] {
+            let mut data = vec![default.clone(); size];
+            let mut sparse = Sma::new(size, default.clone()).unwrap();
+            let dense = MA::new(data.clone());
+            assert_eq!(sparse.root(), dense.root());
+            for pos in 0..2 * size - 1 {
+                assert_eq!(sparse.default_node(pos), &dense.tree[pos]);
+            }
+            assert_eq!(sparse.get(size - 1), Some(&default));
+            assert_eq!(sparse.get(size), None);
+
+            for (i, index) in [size - 1, 0, size / 2, size - 1].into_iter().enumerate() {
+                let value = format!("value{}", i).into_bytes();
+                let mut dense = MA::new(data.clone());
+                assert_eq!(
+                    sparse.update(index, value.clone()).unwrap(),
+                    dense.update(index, value.clone()).unwrap()
+                );
+                data[index] = value;
+                assert_eq!(sparse.get(index), Some(&data[index]));
+
+                for j in 0..size {
+                    assert_eq!(sparse.prove(j).unwrap(), dense.prove(j).unwrap());
+                    for count in 1..=size - j {
+                        assert_eq!(
+                            sparse.prove_range(j, count).unwrap(),
+                            dense.prove_range(j, count).unwrap()
+                        );
+                    }
+                }
+            }
+
+            // setting an element back to the default value only keeps the nodes that differ
+            let mut sparse = Sma::new(size, default.clone()).unwrap();
+            let root = sparse.root().clone();
+            sparse.update(size - 1, b"other".to_vec()).unwrap();
+            sparse.update(size - 1, default.clone()).unwrap();
+            assert_eq!(sparse.root(), &root);
+            assert!(sparse.nodes.is_empty());
+        }
+
+        assert!(Sma::new(4, default.clone()).unwrap().prove(4).is_err());
+        assert!(Sma::new(4, default.clone())
+            .unwrap()
+            .update(4, vec![])
+            .is_err());
+    }
+
+    #[test]
+    fn test_large_sparse_merkle_accumulator() {
+        type Sma = SparseMerkleAccumulator<Sha256Hasher, Vec<u8>, 32>;
+
+        // creating and updating a huge vector only touches the path to the updated element
+        let size = 1usize << 30;
+        let mut sparse = Sma::new(size, vec![0u8; 16]).unwrap();
+        let old_root = sparse.root().clone();
+        let old_hash = MerkleAccumulator::<Sha256Hasher, Vec<u8>, 32>::hash_leaf(&vec![0u8; 16]);
+        let value = b"value".to_vec();
+        let (proof, new_root) = sparse.update(size - 3, value.clone()).unwrap();
+        assert_eq!(proof.len(), 30);
+        assert_eq!(sparse.nodes.len(), 31);
+        assert!(
+            MerkleAccumulator::<Sha256Hasher, Vec<u8>, 32>::verify_update_proof(
+                &old_root,
+                &new_root,
+                &proof,
+                &old_hash,
+                &MerkleAccumulator::<Sha256Hasher, Vec<u8>, 32>::hash_leaf(&value),
+                size - 3,
+                size
+            )
+        );
+
+        // the same for a size that is not a power of 2
+        let size = (3usize << 28) + 5;
+        let mut sparse = Sma::new(size, vec![0u8; 16]).unwrap();
+        assert!(sparse.unbalanced_default_nodes.len() <= 31);
+        let old_root = sparse.root().clone();
+        let (proof, new_root) = sparse.update(size - 3, value.clone()).unwrap();
+        assert!(
+            MerkleAccumulator::<Sha256Hasher, Vec<u8>, 32>::verify_update_proof(
+                &old_root,
+                &new_root,
+                &proof,
+                &old_hash,
+                &MerkleAccumulator::<Sha256Hasher, Vec<u8>, 32>::hash_leaf(&value),
+                size - 3,
+                size
+            )
+        );
+    }
+
+    #[test]
+    fn test_merkle_tree_top() {
+        type MA = MerkleAccumulator<Sha256Hasher, Vec<u8>, 32>;
+        type Top = MerkleTreeTop<Sha256Hasher, 32>;
+
+        assert_eq!(top_nodes_count(1, 3), 1);
+        assert_eq!(top_nodes_count(6, 0), 1);
+        assert_eq!(top_nodes_count(6, 2), 7);
+        assert_eq!(top_nodes_count(6, 3), 11);
+        assert_eq!(top_nodes_count(6, 255), 11);
+
+        for size in 1..=9 {
+            for levels in 0..=4 {
+                let mut ma = MA::new(generate_test_data(size));
+                let mut top = Top::new(ma.root().clone(), size);
+                let n_nodes = top_nodes_count(size, levels);
+
+                // the nodes must be consistent with the root
+                let mut wrong = ma.tree[..n_nodes].to_vec();
+                wrong[n_nodes - 1] = HashOutput([0u8; 32]);
+                assert!(!top.set_nodes(levels, wrong));
+                assert!(!top.set_nodes(levels, ma.tree[..n_nodes / 2].to_vec()));
+                assert!(top.set_nodes(levels, ma.tree[..n_nodes].to_vec()));
+                assert_eq!(top.n_nodes(), n_nodes);
+
+                for start in 0..size {
+                    for count in 1..=size - start {
+                        // the truncated proof is a prefix of the full one
+                        let proof = ma.prove_range(start, count).unwrap();
+                        let len = truncated_range_proof_positions(size, start, count, n_nodes)
+                            .unwrap()
+                            .len();
+                        let truncated = &proof[..len];
+
+                        let hashes: Vec<_> = (start..start + count)
+                            .map(|i| MA::hash_leaf(ma.get(i).unwrap()))
+                            .collect();
+                        let mut hasher = Sha256Hasher::new();
+                        let mut verifier = top.begin_range_proof(&hashes, start);
+                        for el in truncated {
+                            verifier.feed(&mut hasher, el);
+                        }
+                        assert!(verifier.verified());
+                        if len < proof.len() {
+                            verifier.feed(&mut hasher, &proof[len]);
+                            assert!(!verifier.verified());
+                        }
+                        if count == 1 {
+                            let mut verifier = top.begin_inclusion_proof(&hashes[0], start);
+                            for el in truncated {
+                                verifier.feed(&mut hasher, el);
+                            }
+                            assert!(verifier.verified());
+                        }
+                    }
+                }
+
+                // updates keep the cached nodes in sync with the tree
+                for (i, index) in [size - 1, 0, size / 2].into_iter().enumerate() {
+                    let old_hash = MA::hash_leaf(ma.get(index).unwrap());
+                    let value = format!("new{}", i).into_bytes();
+                    let (proof, _) = ma.update(index, value.clone()).unwrap();
+                    let len = truncated_range_proof_positions(size, index, 1, n_nodes)
+                        .unwrap()
+                        .len();
+
+                    let mut hasher = Sha256Hasher::new();
+                    let mut wrong_verifier =
+                        top.begin_update_proof(&MA::hash_leaf(&value), &old_hash, index);
+                    let mut verifier =
+                        top.begin_update_proof(&old_hash, &MA::hash_leaf(&value), index);
+                    for el in &proof[..len] {
+                        verifier.feed(&mut hasher, el);
+                        wrong_verifier.feed(&mut hasher, el);
+                    }
+                    assert!(!top.apply_update(&wrong_verifier));
+                    assert!(top.apply_update(&verifier));
+                    assert_eq!(top.root(), ma.root());
+                    assert_eq!(top.nodes, ma.tree[..n_nodes]);
+                }
+            }
+        }
+    }
 }
@@ -10,7 +10,6 @@ const MAX_APDU_DATA_SIZE: usize = 590;
 pub enum MessageDeserializationError {
     InvalidClientCommandCode,
     MismatchingClientCommandCode,
-    InvalidSectionKind,
     InvalidDataLength,
     UnexpectedCommandCode,
     InvalidBufferType,
@@ -25,7 +24,6 @@ impl fmt::Display for MessageDeserializationError {
             MessageDeserializationError::MismatchingClientCommandCode => {
                 write!(f, "Mismatching client command code")
             }
-            MessageDeserializationError::InvalidSectionKind => write!(f, "Invalid section kind"),
             MessageDeserializationError::InvalidDataLength => write!(f, "Invalid data length"),
             MessageDeserializationError::UnexpectedCommandCode => {
                 write!(f, "Unexpected command code")
@@ -62,6 +60,10 @@ pub enum ClientCommandCode {
     SendBuffer = 4,
     SendBufferContinued = 5,
     ReceiveBuffer = 6,
+    GetPages = 7,
+    GetPagesContinued = 8,
+    GetTreeNodes = 9,
+    CheckAbort = 10,
 }
 
 impl TryFrom<u8> for ClientCommandCode {
@@ -76,49 +78,34 @@ impl TryFrom<u8> for ClientCommandCode {
             4 => Ok(ClientCommandCode::SendBuffer),
             5 => Ok(ClientCommandCode::SendBufferContinued),
             6 => Ok(ClientCommandCode::ReceiveBuffer),
+            7 => Ok(ClientCommandCode::GetPages),
+            8 => Ok(ClientCommandCode::GetPagesContinued),
+            9 => Ok(ClientCommandCode::GetTreeNodes),
+            10 => Ok(ClientCommandCode::CheckAbort),
             _ => Err("Invalid value for ClientCommandCode"),
         }
     }
 }
 
-#[derive(Debug, Clone, Copy)]
-#[repr(u8)]
-pub enum SectionKind {
-    Code = 0,
-    Data = 1,
-    Stack = 2,
-}
-
-impl TryFrom<u8> for SectionKind {
-    type Error = &'static str;
-
-    fn try_from(value: u8) -> Result<Self, Self::Error> {
-        match value {
-            0 => Ok(SectionKind::Code),
-            1 => Ok(SectionKind::Data),
-            2 => Ok(SectionKind::Stack),
-            _ => Err("Invalid section kind"),
-        }
-    }
-}
-
 // We use the _Message ending for messages from the VM to the host, and the _Response ending for messages from the host to the VM.
 
 /// Message sent by the VM to request a page from the host
 #[derive(Debug, Clone)]
 pub struct GetPageMessage {
     pub command_code: ClientCommandCode,
-    pub section_kind: SectionKind,
+    pub segment_index: u8, // index of the segment in the segment table of the manifest
     pub page_index: u32,
+    pub cached_levels: u8, // levels of the Merkle tree below the root known to the VM
 }
 
 impl GetPageMessage {
     #[inline]
-    pub fn new(section_kind: SectionKind, page_index: u32) -> Self {
+    pub fn new(segment_index: u8, page_index: u32, cached_levels: u8) -> Self {
         GetPageMessage {
             command_code: ClientCommandCode::GetPage,
-            section_kind,
+            segment_index,
             page_index,
+            cached_levels,
         }
     }
 }
@@ -127,12 +114,13 @@ impl<'a> Message<'a> for GetPageMessage {
     #[inline]
     fn serialize_with<F: FnMut(&[u8])>(&self, mut f: F) {
         f(&[self.command_code as u8]);
-        f(&[self.section_kind as u8]);
+        f(&[self.segment_index]);
         f(&self.page_index.to_be_bytes());
+        f(&[self.cached_levels]);
     }
 
     fn deserialize(data: &[u8]) -> Result<Self, MessageDeserializationError> {
-        if data.len() != 6 {
+        if data.len() != 7 {
             return Err(MessageDeserializationError::InvalidDataLength);
         }
         let command_code = ClientCommandCode::try_from(data[0])
@@ -140,101 +128,206 @@ impl<'a> Message<'a> for GetPageMessage {
         if !matches!(command_code, ClientCommandCode::GetPage) {
             return Err(MessageDeserializationError::MismatchingClientCommandCode);
         }
-        let section_kind = SectionKind::try_from(data[1])
-            .map_err(|_| MessageDeserializationError::InvalidSectionKind)?;
+        let segment_index = data[1];
         let page_index = u32::from_be_bytes([data[2], data[3], data[4], data[5]]);
+        let cached_levels = data[6];
 
         Ok(GetPageMessage {
             command_code,
-            section_kind,
+            segment_index,
             page_index,
+            cached_levels,
         })
     }
 }
 
-/// Message sent by client in response to the VM's GetPageProofMessage
-/// It contains the page's metadata, and the merkle proof of the page (or part of it)
+/// The content of a page exchanged between the client and the VM.
+///
+/// It's serialized as a flag, followed either by the nonce (all zeros if not encrypted) and the
+/// content of the page; for authenticated pages, by the nonce, the version, the tag and the
+/// encrypted content; or, for compressed pages, by the length of the compressed content and the
+/// compressed content. Except for compressed pages, that is also how the client stores them, and
+/// the content of their leaf in the Merkle tree.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PageContent<'a> {
+    /// The content of the page, possibly encrypted
+    Raw {
+        is_encrypted: bool,
+        nonce: [u8; 12], // nonce of the page encryption (all zeros if not encrypted)
+        data: &'a [u8; PAGE_SIZE],
+    },
+    /// The plaintext content of the page, compressed with [`crate::compression::compress`]
+    Compressed(&'a [u8]),
+    /// The content of the page encrypted with an AEAD, whose tag also authenticates the position
+    /// of the page and its version, incremented each time that the page is committed
+    Authenticated {
+        nonce: [u8; 12],
+        version: u32,
+        tag: [u8; 16],
+        data: &'a [u8; PAGE_SIZE],
+    },
+}
+
+impl<'a> PageContent<'a> {
+    /// Size of a serialized raw page: the flag, the nonce, and the content of the page
+    pub const SERIALIZED_RAW_PAGE_SIZE: usize = 1 + 12 + PAGE_SIZE;
+    /// Size of a serialized authenticated page: the flag, the nonce, the version, the tag, and the
+    /// content of the page
+    pub const SERIALIZED_AUTHENTICATED_PAGE_SIZE: usize = 1 + 12 + 4 + 16 + PAGE_SIZE;
+
+    const COMPRESSED_FLAG: u8 = 2;
+    const AUTHENTICATED_FLAG: u8 = 3;
+
+    /// Returns the size of a page serialized like in the client's storage, from its first byte,
+    /// or None if the byte is not valid.
+    pub fn serialized_page_size(flag: u8) -> Option<usize> {
+        match flag {
+            0 | 1 => Some(Self::SERIALIZED_RAW_PAGE_SIZE),
+            Self::AUTHENTICATED_FLAG => Some(Self::SERIALIZED_AUTHENTICATED_PAGE_SIZE),
+            _ => None,
+        }
+    }
+
+    /// Returns the content of a page serialized like in the client's storage, that is also how a
+    /// raw or authenticated page is serialized.
+    pub fn from_serialized_page(page: &'a [u8]) -> Result<Self, MessageDeserializationError> {
+        let flag = *page
+            .first()
+            .ok_or(MessageDeserializationError::InvalidDataLength)?;
+        if Self::serialized_page_size(flag) != Some(page.len()) {
+            return Err(MessageDeserializationError::InvalidDataLength);
+        }
+        if flag == Self::AUTHENTICATED_FLAG {
+            return Ok(PageContent::Authenticated {
+                nonce: page[1..13].try_into().unwrap(),
+                version: u32::from_be_bytes(page[13..17].try_into().unwrap()),
+                tag: page[17..33].try_into().unwrap(),
+                data: page[33..].try_into().unwrap(),
+            });
+        }
+        Ok(PageContent::Raw {
+            is_encrypted: flag == 1,
+            nonce: page[1..13].try_into().unwrap(),
+            data: page[13..].try_into().unwrap(),
+        })
+    }
+
+    pub fn serialized_size(&self) -> usize {
+        match self {
+            PageContent::Raw { .. } => Self::SERIALIZED_RAW_PAGE_SIZE,
+            PageContent::Compressed(data) => 1 + 2 + data.len(),
+            PageContent::Authenticated { .. } => Self::SERIALIZED_AUTHENTICATED_PAGE_SIZE,
+        }
+    }
+
+    #[inline]
+    pub fn serialize_with<F: FnMut(&[u8])>(&self, mut f: F) {
+        match self {
+            PageContent::Raw {
+                is_encrypted,
+                nonce,
+                data,
+            } => {
+                f(&[*is_encrypted as u8]);
+                f(nonce);
+                f(*data);
+            }
+            PageContent::Compressed(data) => {
+                f(&[Self::COMPRESSED_FLAG]);
+                f(&(data.len() as u16).to_be_bytes());
+                f(data);
+            }
+            PageContent::Authenticated {
+                nonce,
+                version,
+                tag,
+                data,
+            } => {
+                f(&[Self::AUTHENTICATED_FLAG]);
+                f(nonce);
+                f(&version.to_be_bytes());
+                f(tag);
+                f(*data);
+            }
+        }
+    }
+
+    /// Deserializes the page at the beginning of `data`, and returns it with its serialized size.
+    pub fn deserialize(data: &'a [u8]) -> Result<(Self, usize), MessageDeserializationError> {
+        match data.first() {
+            Some(&Self::COMPRESSED_FLAG) => {
+                if data.len() < 3 {
+                    return Err(MessageDeserializationError::InvalidDataLength);
+                }
+                let len = u16::from_be_bytes([data[1], data[2]]) as usize;
+                let compressed = data
+                    .get(3..3 + len)
+                    .ok_or(MessageDeserializationError::InvalidDataLength)?;
+                Ok((PageContent::Compressed(compressed), 3 + len))
+            }
+            Some(&flag) => {
+                let size = Self::serialized_page_size(flag)
+                    .ok_or(MessageDeserializationError::InvalidDataLength)?;
+                let page = data
+                    .get(..size)
+                    .ok_or(MessageDeserializationError::InvalidDataLength)?;
+                Ok((Self::from_serialized_page(page)?, size))
+            }
+            None => Err(MessageDeserializationError::InvalidDataLength),
+        }
+    }
+}
+
+/// Message sent by client in response to the VM's GetPageMessage
+/// It contains the page, and the merkle proof of the page (or part of it)
 #[derive(Debug, Clone)]
 pub struct GetPageResponse<'a> {
-    pub page_data: &'a [u8; PAGE_SIZE],
-    pub is_encrypted: bool,    // whether the page is encrypted
-    pub nonce: [u8; 12],       // nonce of the page encryption (all zeros if not encrypted)
     pub n: u8,                 // number of element in the proof
     pub t: u8,                 // number of proof elements in this message
+    pub page: PageContent<'a>, // content of the page
     pub proof: &'a [[u8; 32]], // hashes of the proof
 }
 
 impl<'a> GetPageResponse<'a> {
     #[inline]
-    pub fn new(
-        page_data: &'a [u8; PAGE_SIZE],
-        is_encrypted: bool,
-        nonce: [u8; 12],
-        n: u8,
-        t: u8,
-        proof: &'a [[u8; 32]],
-    ) -> Self {
-        GetPageResponse {
-            page_data,
-            is_encrypted,
-            nonce,
-            n,
-            t,
-            proof,
-        }
+    pub fn new(n: u8, t: u8, page: PageContent<'a>, proof: &'a [[u8; 32]]) -> Self {
+        GetPageResponse { n, t, page, proof }
     }
 
-    pub const fn max_proof_size() -> usize {
-        (MAX_APDU_DATA_SIZE - PAGE_SIZE - 1 - 12 - 1 - 1) / 32
+    /// Returns the maximum number of proof elements in a response with the given page.
+    pub fn max_proof_size(page: &PageContent) -> usize {
+        (MAX_APDU_DATA_SIZE - 1 - 1 - page.serialized_size()) / 32
     }
 }
 
 impl<'a> Message<'a> for GetPageResponse<'a> {
     #[inline]
     fn serialize_with<F: FnMut(&[u8])>(&self, mut f: F) {
-        f(self.page_data);
         f(&[self.n]);
         f(&[self.t]);
-        f(&[self.is_encrypted as u8]);
-        f(&self.nonce);
+        self.page.serialize_with(&mut f);
         for p in self.proof {
             f(p);
         }
     }
 
     fn deserialize(data: &'a [u8]) -> Result<Self, MessageDeserializationError> {
-        if data.len() < PAGE_SIZE + 1 + 1 + 1 + 12 {
+        if data.len() < 2 {
             return Err(MessageDeserializationError::InvalidDataLength);
         }
-        let page_data = data[0..PAGE_SIZE].try_into().unwrap();
-        let n = data[PAGE_SIZE];
-        let t = data[PAGE_SIZE + 1];
-        let is_encrypted = data[PAGE_SIZE + 2] == 1;
-        let nonce = if is_encrypted {
-            let mut arr = [0; 12];
-            arr.copy_from_slice(&data[PAGE_SIZE + 3..PAGE_SIZE + 15]);
-            arr
-        } else {
-            [0; 12]
-        };
-        let proof_len = data.len() - (PAGE_SIZE + 1 + 1 + 1 + 12);
-        if proof_len % 32 != 0 {
+        let n = data[0];
+        let t = data[1];
+        let (page, page_len) = PageContent::deserialize(&data[2..])?;
+        let proof_len = data.len() - 2 - page_len;
+        if proof_len != t as usize * 32 {
             return Err(MessageDeserializationError::InvalidDataLength);
         }
-        let slice_len = proof_len / 32;
         let proof = unsafe {
-            let ptr = data.as_ptr().add(PAGE_SIZE + 1 + 1 + 1 + 12) as *const [u8; 32];
-            core::slice::from_raw_parts(ptr, slice_len)
+            let ptr = data.as_ptr().add(2 + page_len) as *const [u8; 32];
+            core::slice::from_raw_parts(ptr, t as usize)
         };
 
-        Ok(GetPageResponse {
-            page_data,
-            is_encrypted,
-            nonce,
-            n,
-            t,
-            proof,
-        })
+        Ok(GetPageResponse { n, t, page, proof })
     }
 }
 
@@ -319,33 +412,209 @@ impl<'a> Message<'a> for GetPageProofContinuedResponse<'a> {
     }
 }
 
+/// Message sent by the VM to request a run of consecutive pages from the host, with a single
+/// Merkle multiproof for all of them
+#[derive(Debug, Clone)]
+pub struct GetPagesMessage {
+    pub command_code: ClientCommandCode,
+    pub segment_index: u8, // index of the segment in the segment table of the manifest
+    pub page_index: u32,   // index of the first page
+    pub n_pages: u8,       // number of pages
+    pub cached_levels: u8, // levels of the Merkle tree below the root known to the VM
+}
+
+impl GetPagesMessage {
+    #[inline]
+    pub fn new(segment_index: u8, page_index: u32, n_pages: u8, cached_levels: u8) -> Self {
+        GetPagesMessage {
+            command_code: ClientCommandCode::GetPages,
+            segment_index,
+            page_index,
+            n_pages,
+            cached_levels,
+        }
+    }
+}
+
+impl<'a> Message<'a> for GetPagesMessage {
+    #[inline]
+    fn serialize_with<F: FnMut(&[u8])>(&self, mut f: F) {
+        f(&[self.command_code as u8]);
+        f(&[self.segment_index]);
+        f(&self.page_index.to_be_bytes());
+        f(&[self.n_pages]);
+        f(&[self.cached_levels]);
+    }
+
+    fn deserialize(data: &[u8]) -> Result<Self, MessageDeserializationError> {
+        if data.len() != 8 {
+            return Err(MessageDeserializationError::InvalidDataLength);
+        }
+        let command_code = ClientCommandCode::try_from(data[0])
+            .map_err(|_| MessageDeserializationError::InvalidClientCommandCode)?;
+        if !matches!(command_code, ClientCommandCode::GetPages) {
+            return Err(MessageDeserializationError::MismatchingClientCommandCode);
+        }
+        let segment_index = data[1];
+        let page_index = u32::from_be_bytes([data[2], data[3], data[4], data[5]]);
+        let n_pages = data[6];
+        let cached_levels = data[7];
+
+        Ok(GetPagesMessage {
+            command_code,
+            segment_index,
+            page_index,
+            n_pages,
+            cached_levels,
+        })
+    }
+}
+
+/// Message sent by client in response to the VM's GetPagesMessage or GetPagesContinuedMessage.
+/// It contains some of the requested pages, in order, followed by part of the multiproof once all
+/// the pages are sent. Each page is serialized as a [`PageContent`].
+#[derive(Debug, Clone)]
+pub struct GetPagesResponse<'a> {
+    pub n: u8,                 // number of elements in the multiproof
+    pub n_pages: u8,           // number of pages in this message
+    pub t: u8,                 // number of proof elements in this message
+    pub pages: &'a [u8],       // serialized pages
+    pub proof: &'a [[u8; 32]], // hashes of the proof
+}
+
+impl<'a> GetPagesResponse<'a> {
+    #[inline]
+    pub fn new(n: u8, n_pages: u8, t: u8, pages: &'a [u8], proof: &'a [[u8; 32]]) -> Self {
+        GetPagesResponse {
+            n,
+            n_pages,
+            t,
+            pages,
+            proof,
+        }
+    }
+
+    /// Maximum total size of the serialized pages and of the proof elements in a message
+    pub const fn max_content_size() -> usize {
+        MAX_APDU_DATA_SIZE - 1 - 1 - 1
+    }
+
+    /// Returns the pages in this message, in order.
+    pub fn pages(&self) -> impl Iterator<Item = PageContent<'a>> {
+        let mut pages = self.pages;
+        (0..self.n_pages).map(move |_| {
+            let (page, len) =
+                PageContent::deserialize(pages).expect("The pages are checked when deserialized");
+            pages = &pages[len..];
+            page
+        })
+    }
+}
+
+impl<'a> Message<'a> for GetPagesResponse<'a> {
+    #[inline]
+    fn serialize_with<F: FnMut(&[u8])>(&self, mut f: F) {
+        f(&[self.n]);
+        f(&[self.n_pages]);
+        f(&[self.t]);
+        f(self.pages);
+        for p in self.proof {
+            f(p);
+        }
+    }
+
+    fn deserialize(data: &'a [u8]) -> Result<Self, MessageDeserializationError> {
+        if data.len() < 3 {
+            return Err(MessageDeserializationError::InvalidDataLength);
+        }
+        let n = data[0];
+        let n_pages = data[1];
+        let t = data[2];
+        let mut pages_len = 0;
+        for _ in 0..n_pages {
+            let (_, len) = PageContent::deserialize(&data[3 + pages_len..])?;
+            pages_len += len;
+        }
+        let pages = &data[3..3 + pages_len];
+        let proof_len = data.len() - 3 - pages_len;
+        if proof_len != t as usize * 32 {
+            return Err(MessageDeserializationError::InvalidDataLength);
+        }
+        let proof = unsafe {
+            let ptr = data.as_ptr().add(3 + pages_len) as *const [u8; 32];
+            core::slice::from_raw_parts(ptr, t as usize)
+        };
+
+        Ok(GetPagesResponse {
+            n,
+            n_pages,
+            t,
+            pages,
+            proof,
+        })
+    }
+}
+
+/// Message sent by the VM to request the rest of the pages and of the multiproof, if they didn't
+/// fit in the previous GetPagesResponse
+#[derive(Debug, Clone)]
+pub struct GetPagesContinuedMessage {
+    pub command_code: ClientCommandCode,
+}
+
+impl GetPagesContinuedMessage {
+    #[inline]
+    pub fn new() -> Self {
+        GetPagesContinuedMessage {
+            command_code: ClientCommandCode::GetPagesContinued,
+        }
+    }
+}
+
+impl<'a> Message<'a> for GetPagesContinuedMessage {
+    #[inline]
+    fn serialize_with<F: FnMut(&[u8])>(&self, mut f: F) {
+        f(&[self.command_code as u8]);
+    }
+
+    fn deserialize(data: &[u8]) -> Result<Self, MessageDeserializationError> {
+        if data.len() != 1 {
+            return Err(MessageDeserializationError::InvalidDataLength);
+        }
+        let command_code = ClientCommandCode::try_from(data[0])
+            .map_err(|_| MessageDeserializationError::InvalidClientCommandCode)?;
+        if !matches!(command_code, ClientCommandCode::GetPagesContinued) {
+            return Err(MessageDeserializationError::MismatchingClientCommandCode);
+        }
+
+        Ok(GetPagesContinuedMessage { command_code })
+    }
+}
+
 /// Message sent by the VM to commit a page to the host
 #[derive(Debug, Clone)]
 pub struct CommitPageMessage<'a> {
     pub command_code: ClientCommandCode,
-    pub section_kind: SectionKind,
+    pub segment_index: u8, // index of the segment in the segment table of the manifest
     pub page_index: u32,
-    pub is_encrypted: bool,        // whether the page is encrypted
-    pub nonce: [u8; 12],           // nonce of the page encryption (all zeros if not encrypted)
-    pub data: &'a [u8; PAGE_SIZE], // content of the page
+    pub cached_levels: u8, // levels of the Merkle tree below the root known to the VM
+    pub page: PageContent<'a>, // content of the page, never compressed
 }
 
 impl<'a> CommitPageMessage<'a> {
     #[inline]
     pub fn new(
-        section_kind: SectionKind,
+        segment_index: u8,
         page_index: u32,
-        is_encrypted: bool,
-        nonce: [u8; 12],
-        data: &'a [u8; PAGE_SIZE],
+        cached_levels: u8,
+        page: PageContent<'a>,
     ) -> Self {
         CommitPageMessage {
             command_code: ClientCommandCode::CommitPage,
-            section_kind,
+            segment_index,
             page_index,
-            is_encrypted,
-            nonce,
-            data,
+            cached_levels,
+            page,
         }
     }
 }
@@ -354,19 +623,14 @@ impl<'a> Message<'a> for CommitPageMessage<'a> {
     #[inline]
     fn serialize_with<F: FnMut(&[u8])>(&self, mut f: F) {
         f(&[self.command_code as u8]);
-        f(&[self.section_kind as u8]);
+        f(&[self.segment_index]);
         f(&self.page_index.to_be_bytes());
-        if self.is_encrypted {
-            f(&[1]);
-            f(&self.nonce);
-        } else {
-            f(&[0; 13]); // 0 byte, followed by 12 zero bytes for the (unused) nonce
-        }
-        f(self.data);
+        f(&[self.cached_levels]);
+        self.page.serialize_with(f);
     }
 
     fn deserialize(data: &'a [u8]) -> Result<Self, MessageDeserializationError> {
-        if data.len() != 1 + 1 + 4 + 1 + 12 + PAGE_SIZE {
+        if data.len() < 1 + 1 + 4 + 1 {
             return Err(MessageDeserializationError::InvalidDataLength);
         }
         let command_code = ClientCommandCode::try_from(data[0])
@@ -375,31 +639,19 @@ impl<'a> Message<'a> for CommitPageMessage<'a> {
             return Err(MessageDeserializationError::MismatchingClientCommandCode);
         }
 
-        let section_kind = SectionKind::try_from(data[1])
-            .map_err(|_| MessageDeserializationError::InvalidSectionKind)?;
+        let segment_index = data[1];
         let page_index = u32::from_be_bytes([data[2], data[3], data[4], data[5]]);
+        let cached_levels = data[6];
 
-        let is_encrypted = data[6] == 1;
-        let nonce = if is_encrypted {
-            let mut arr = [0; 12];
-            arr.copy_from_slice(&data[7..19]);
-            arr
-        } else {
-            [0; 12]
-        };
-
-        // Safe extraction of the page data (starts after 1+1+4+1+12 = 19 bytes)
-        let data: &'a [u8; PAGE_SIZE] = data[1 + 1 + 4 + 1 + 12..1 + 1 + 4 + 1 + 12 + PAGE_SIZE]
-            .try_into()
-            .map_err(|_| MessageDeserializationError::InvalidDataLength)?;
+        // the page is serialized like in the client's storage, and takes the rest of the message
+        let page = PageContent::from_serialized_page(&data[7..])?;
 
         Ok(CommitPageMessage {
             command_code,
-            section_kind,
+            segment_index,
             page_index,
-            is_encrypted,
-            nonce,
-            data,
+            cached_levels,
+            page,
         })
     }
 }
@@ -545,12 +797,110 @@ impl<'a> Message<'a> for CommitPageProofContinuedResponse<'a> {
     }
 }
 
+/// Message sent by the VM to request some of the nodes of the top levels of the Merkle tree of a
+/// segment, in order to cache them
+#[derive(Debug, Clone)]
+pub struct GetTreeNodesMessage {
+    pub command_code: ClientCommandCode,
+    pub segment_index: u8, // index of the segment in the segment table of the manifest
+    pub start: u32,        // position of the first node in the tree
+    pub count: u8,         // number of nodes
+}
+
+impl GetTreeNodesMessage {
+    #[inline]
+    pub fn new(segment_index: u8, start: u32, count: u8) -> Self {
+        GetTreeNodesMessage {
+            command_code: ClientCommandCode::GetTreeNodes,
+            segment_index,
+            start,
+            count,
+        }
+    }
+}
+
+impl<'a> Message<'a> for GetTreeNodesMessage {
+    #[inline]
+    fn serialize_with<F: FnMut(&[u8])>(&self, mut f: F) {
+        f(&[self.command_code as u8]);
+        f(&[self.segment_index]);
+        f(&self.start.to_be_bytes());
+        f(&[self.count]);
+    }
+
+    fn deserialize(data: &[u8]) -> Result<Self, MessageDeserializationError> {
+        if data.len() != 7 {
+            return Err(MessageDeserializationError::InvalidDataLength);
+        }
+        let command_code = ClientCommandCode::try_from(data[0])
+            .map_err(|_| MessageDeserializationError::InvalidClientCommandCode)?;
+        if !matches!(command_code, ClientCommandCode::GetTreeNodes) {
+            return Err(MessageDeserializationError::MismatchingClientCommandCode);
+        }
+        let segment_index = data[1];
+        let start = u32::from_be_bytes([data[2], data[3], data[4], data[5]]);
+        let count = data[6];
+
+        Ok(GetTreeNodesMessage {
+            command_code,
+            segment_index,
+            start,
+            count,
+        })
+    }
+}
+
+/// Message sent by client in response to the VM's GetTreeNodesMessage, with the requested nodes
+#[derive(Debug, Clone)]
+pub struct GetTreeNodesResponse<'a> {
+    pub t: u8,                 // number of nodes in this message
+    pub nodes: &'a [[u8; 32]], // hashes of the nodes
+}
+
+impl<'a> GetTreeNodesResponse<'a> {
+    #[inline]
+    pub fn new(t: u8, nodes: &'a [[u8; 32]]) -> Self {
+        GetTreeNodesResponse { t, nodes }
+    }
+
+    pub const fn max_nodes() -> usize {
+        (MAX_APDU_DATA_SIZE - 1) / 32
+    }
+}
+
+impl<'a> Message<'a> for GetTreeNodesResponse<'a> {
+    #[inline]
+    fn serialize_with<F: FnMut(&[u8])>(&self, mut f: F) {
+        f(&[self.t]);
+        for node in self.nodes {
+            f(node);
+        }
+    }
+
+    fn deserialize(data: &'a [u8]) -> Result<Self, MessageDeserializationError> {
+        if data.is_empty() {
+            return Err(MessageDeserializationError::InvalidDataLength);
+        }
+        let t = data[0];
+        if data.len() - 1 != t as usize * 32 {
+            return Err(MessageDeserializationError::InvalidDataLength);
+        }
+        let nodes = unsafe {
+            let ptr = data.as_ptr().add(1) as *const [u8; 32];
+            core::slice::from_raw_parts(ptr, t as usize)
+        };
+
+        Ok(GetTreeNodesResponse { t, nodes })
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(u8)]
 pub enum BufferType {
     VAppMessage = 0, // data buffer sent from the VApp to the host
     Panic = 1,       // the VApp panicked
     Print = 2,       // the VApp printed a message
+    Aborted = 3,     // the VApp aborted a request; only sent by V-Apps running natively
 }
 
 impl TryFrom<u8> for BufferType {
@@ -561,6 +911,7 @@ impl TryFrom<u8> for BufferType {
             0 => Ok(BufferType::VAppMessage),
             1 => Ok(BufferType::Panic),
             2 => Ok(BufferType::Print),
+            3 => Ok(BufferType::Aborted),
             _ => Err("Invalid buffer type"),
         }
     }
@@ -697,6 +1048,42 @@ impl<'a> Message<'a> for ReceiveBufferMessage {
     }
 }
 
+/// Message sent by the VM while the V-App waits for UX events, to ask the host whether the request
+/// that the V-App is processing should be aborted. The host answers with an empty Continue APDU,
+/// whose P1 asks to abort.
+#[derive(Debug, Clone)]
+pub struct CheckAbortMessage {
+    pub command_code: ClientCommandCode,
+}
+
+impl CheckAbortMessage {
+    #[inline]
+    pub fn new() -> Self {
+        CheckAbortMessage {
+            command_code: ClientCommandCode::CheckAbort,
+        }
+    }
+}
+
+impl<'a> Message<'a> for CheckAbortMessage {
+    #[inline]
+    fn serialize_with<F: FnMut(&[u8])>(&self, mut f: F) {
+        f(&[self.command_code as u8]);
+    }
+    fn deserialize(data: &[u8]) -> Result<Self, MessageDeserializationError> {
+        if data.len() != 1 {
+            return Err(MessageDeserializationError::InvalidDataLength);
+        }
+        let command_code = ClientCommandCode::try_from(data[0])
+            .map_err(|_| MessageDeserializationError::InvalidClientCommandCode)?;
+        if !matches!(command_code, ClientCommandCode::CheckAbort) {
+            return Err(MessageDeserializationError::MismatchingClientCommandCode);
+        }
+
+        Ok(CheckAbortMessage { command_code })
+    }
+}
+
 /// The host's response to a ReceiveBufferMessage.
 #[derive(Debug, Clone)]
 pub struct ReceiveBufferResponse<'a> {
@@ -9,3 +9,7 @@ pub const ACK: [u8; 1] = [0x42];
 
 /// The length of each chunk of data to be sent or received when calling xrecv/xsend.
 pub const CHUNK_LENGTH: usize = 256;
+
+/// For V-Apps running natively, the client sends this value instead of the length of a message
+/// in order to abort the request that the V-App is processing.
+pub const NATIVE_ABORT_REQUEST: u32 = u32::MAX;
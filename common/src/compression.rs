@@ -0,0 +1,260 @@
+//! Compression of the pages sent by the client to the VM, in the LZ4 block format.
+//!
+//! The compressor is a simple greedy one, as the pages are small; the compressed data can be
+//! decompressed by any LZ4 block decoder. Pages are compressed once, when the V-App is packaged,
+//! and decompressed by the VM, that hashes the plaintext pages as usual.
+
+use alloc::vec::Vec;
+use core::fmt;
+
+use crate::constants::PAGE_SIZE;
+
+const MIN_MATCH: usize = 4;
+// The last match must start at least 12 bytes before the end of the block...
+const MF_LIMIT: usize = 12;
+// ...and the last 5 bytes are always literals
+const LAST_LITERALS: usize = 5;
+const MAX_OFFSET: usize = u16::MAX as usize;
+
+const HASH_BITS: u32 = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecompressionError {
+    /// The compressed data ends in the middle of a sequence.
+    Truncated,
+    /// A match refers to data before the start of the output.
+    InvalidOffset,
+    /// The decompressed data does not have the expected size.
+    InvalidSize,
+}
+
+impl fmt::Display for DecompressionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecompressionError::Truncated => write!(f, "Truncated compressed data"),
+            DecompressionError::InvalidOffset => write!(f, "Invalid match offset"),
+            DecompressionError::InvalidSize => write!(f, "Invalid decompressed size"),
+        }
+    }
+}
+
+impl core::error::Error for DecompressionError {}
+
+fn hash(sequence: u32) -> usize {
+    (sequence.wrapping_mul(2654435761) >> (32 - HASH_BITS)) as usize
+}
+
+fn read_u32(data: &[u8], pos: usize) -> u32 {
+    u32::from_le_bytes([data[pos], data[pos + 1], data[pos + 2], data[pos + 3]])
+}
+
+// Appends the bytes of a length that doesn't fit in the 4 bits of the token
+fn push_length(out: &mut Vec<u8>, len: usize) {
+    if len >= 15 {
+        let mut rest = len - 15;
+        while rest >= 255 {
+            out.push(255);
+            rest -= 255;
+        }
+        out.push(rest as u8);
+    }
+}
+
+fn push_sequence(out: &mut Vec<u8>, literals: &[u8], offset: usize, match_len: usize) {
+    let literal_token = literals.len().min(15) as u8;
+    let match_token = (match_len - MIN_MATCH).min(15) as u8;
+    out.push((literal_token << 4) | match_token);
+    push_length(out, literals.len());
+    out.extend_from_slice(literals);
+    out.extend_from_slice(&(offset as u16).to_le_bytes());
+    push_length(out, match_len - MIN_MATCH);
+}
+
+/// Compresses `data` in the LZ4 block format.
+pub fn compress(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len() + data.len() / 255 + 16);
+    let mut table = [usize::MAX; 1 << HASH_BITS];
+    let mut anchor = 0;
+    let mut pos = 0;
+
+    while pos + MF_LIMIT <= data.len() {
+        let sequence = read_u32(data, pos);
+        let h = hash(sequence);
+        let candidate = table[h];
+        table[h] = pos;
+
+        if candidate == usize::MAX
+            || pos - candidate > MAX_OFFSET
+            || read_u32(data, candidate) != sequence
+        {
+            pos += 1;
+            continue;
+        }
+
+        let match_limit = data.len() - LAST_LITERALS;
+        let mut match_len = MIN_MATCH;
+        while pos + match_len < match_limit && data[candidate + match_len] == data[pos + match_len]
+        {
+            match_len += 1;
+        }
+        push_sequence(&mut out, &data[anchor..pos], pos - candidate, match_len);
+        pos += match_len;
+        anchor = pos;
+    }
+
+    // the last sequence only has literals
+    let literals = &data[anchor..];
+    out.push((literals.len().min(15) as u8) << 4);
+    push_length(&mut out, literals.len());
+    out.extend_from_slice(literals);
+    out
+}
+
+// Reads a length whose 4 bits in the token are `token_len`
+fn read_length(data: &[u8], pos: &mut usize, token_len: u8) -> Result<usize, DecompressionError> {
+    let mut len = token_len as usize;
+    if token_len == 15 {
+        loop {
+            let byte = *data.get(*pos).ok_or(DecompressionError::Truncated)?;
+            *pos += 1;
+            len += byte as usize;
+            if byte != 255 {
+                break;
+            }
+        }
+    }
+    Ok(len)
+}
+
+/// Decompresses `data`, compressed in the LZ4 block format, into `out`. The decompressed data must
+/// have exactly the size of `out`.
+pub fn decompress(data: &[u8], out: &mut [u8]) -> Result<(), DecompressionError> {
+    let mut pos = 0;
+    let mut out_pos = 0;
+    loop {
+        let token = *data.get(pos).ok_or(DecompressionError::Truncated)?;
+        pos += 1;
+
+        let literals_len = read_length(data, &mut pos, token >> 4)?;
+        let literals = data
+            .get(pos..pos + literals_len)
+            .ok_or(DecompressionError::Truncated)?;
+        out.get_mut(out_pos..out_pos + literals_len)
+            .ok_or(DecompressionError::InvalidSize)?
+            .copy_from_slice(literals);
+        pos += literals_len;
+        out_pos += literals_len;
+
+        if pos == data.len() {
+            // the last sequence has no match
+            break;
+        }
+
+        let offset = data
+            .get(pos..pos + 2)
+            .map(|b| u16::from_le_bytes([b[0], b[1]]) as usize)
+            .ok_or(DecompressionError::Truncated)?;
+        pos += 2;
+        if offset == 0 || offset > out_pos {
+            return Err(DecompressionError::InvalidOffset);
+        }
+        let match_len = read_length(data, &mut pos, token & 0x0f)? + MIN_MATCH;
+        if out_pos + match_len > out.len() {
+            return Err(DecompressionError::InvalidSize);
+        }
+        // the match can overlap with the bytes that it produces
+        for i in out_pos..out_pos + match_len {
+            out[i] = out[i - offset];
+        }
+        out_pos += match_len;
+    }
+
+    if out_pos != out.len() {
+        return Err(DecompressionError::InvalidSize);
+    }
+    Ok(())
+}
+
+/// Compresses a page, if that makes it smaller.
+pub fn compress_page(page: &[u8; PAGE_SIZE]) -> Option<Vec<u8>> {
+    let compressed = compress(page);
+    (compressed.len() < PAGE_SIZE).then_some(compressed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    fn roundtrip(data: &[u8]) -> Vec<u8> {
+        let compressed = compress(data);
+        let mut out = vec![0u8; data.len()];
+        decompress(&compressed, &mut out).unwrap();
+        assert_eq!(out, data);
+        compressed
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        assert_eq!(roundtrip(&[]), vec![0]);
+        roundtrip(b"abc");
+        roundtrip(&[7u8; 1000]);
+
+        // pseudo-random data doesn't compress, but is expanded by a few bytes at most
+        let mut x = 1u32;
+        let random: Vec<u8> = (0..PAGE_SIZE)
+            .map(|_| {
+                x = x.wrapping_mul(1103515245).wrapping_add(12345);
+                (x >> 16) as u8
+            })
+            .collect();
+        assert!(roundtrip(&random).len() <= PAGE_SIZE + 3);
+        assert_eq!(compress_page(random.as_slice().try_into().unwrap()), None);
+
+        // repetitive data, like code, compresses well
+        let code: Vec<u8> = (0..PAGE_SIZE / 8)
+            .flat_map(|i| [0x93, 0x05, 0x01, i as u8 & 3, 0x13, 0x01, 0x81, 0x00])
+            .collect();
+        let compressed = compress_page(code.as_slice().try_into().unwrap()).unwrap();
+        assert!(compressed.len() < PAGE_SIZE / 4);
+        assert_eq!(roundtrip(&code), compressed);
+        assert!(roundtrip(&[0u8; PAGE_SIZE]).len() < 16);
+    }
+
+    #[test]
+    fn test_decompress() {
+        // 3 literals, a match of 9 bytes at offset 3, and 5 final literals
+        let data = [
+            0x35, b'a', b'b', b'c', 3, 0, 0x50, b'x', b'y', b'z', b'1', b'2',
+        ];
+        let mut out = [0u8; 17];
+        decompress(&data, &mut out).unwrap();
+        assert_eq!(&out, b"abcabcabcabcxyz12");
+
+        let mut short = [0u8; 16];
+        assert_eq!(
+            decompress(&data, &mut short),
+            Err(DecompressionError::InvalidSize)
+        );
+        let mut long = [0u8; 18];
+        assert_eq!(
+            decompress(&data, &mut long),
+            Err(DecompressionError::InvalidSize)
+        );
+        assert_eq!(
+            decompress(&data[..5], &mut out),
+            Err(DecompressionError::Truncated)
+        );
+        assert_eq!(
+            decompress(&[], &mut out),
+            Err(DecompressionError::Truncated)
+        );
+
+        // a match before the start of the output
+        let data = [0x15, b'a', 2, 0, 0x00];
+        assert_eq!(
+            decompress(&data, &mut [0u8; 10]),
+            Err(DecompressionError::InvalidOffset)
+        );
+    }
+}
@@ -1,3 +1,5 @@
+use alloc::{format, string::String};
+
 /// The size of each memory page of the V-app. The starting address of the page must be a multiple
 /// of this value.
 pub const PAGE_SIZE: usize = 256;
@@ -19,6 +21,36 @@ pub const MAX_STACK_SIZE: usize = 1 << 27; // 128 MiB
 /// acceptable address on the stack.
 pub const DEFAULT_STACK_START: u32 = 0xf0000000;
 
+pub const DEFAULT_HEAP_SIZE: usize = 1 << 16; // 64 KiB
+pub const MAX_HEAP_SIZE: usize = 1 << 29; // 512 MiB
+
+/// Memory address where the heap begins by default.
+/// The heap is a zero-initialized segment, whose pages are only stored by the client once they are
+/// written.
+pub const DEFAULT_HEAP_START: u32 = 0x80000000;
+
+/// Checks the stack size given in the metadata of a V-App.
+pub fn check_stack_size(stack_size: i64) -> Result<u32, String> {
+    if stack_size < MIN_STACK_SIZE as i64 || stack_size > MAX_STACK_SIZE as i64 {
+        return Err(format!(
+            "Stack size must be between {} and {} bytes",
+            MIN_STACK_SIZE, MAX_STACK_SIZE
+        ));
+    }
+    Ok(stack_size as u32)
+}
+
+/// Checks the heap size given in the metadata of a V-App.
+pub fn check_heap_size(heap_size: i64) -> Result<u32, String> {
+    if heap_size < PAGE_SIZE as i64 || heap_size > MAX_HEAP_SIZE as i64 {
+        return Err(format!(
+            "Heap size must be between {} and {} bytes",
+            PAGE_SIZE, MAX_HEAP_SIZE
+        ));
+    }
+    Ok(heap_size as u32)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -33,4 +65,35 @@ mod tests {
             "Stack extends beyond 32-bit address space"
         );
     }
+
+    #[test]
+    fn test_default_heap_constants() {
+        assert!(PAGE_SIZE <= DEFAULT_HEAP_SIZE && DEFAULT_HEAP_SIZE <= MAX_HEAP_SIZE);
+        assert_eq!(DEFAULT_HEAP_START % PAGE_SIZE as u32, 0);
+        assert!(
+            (DEFAULT_HEAP_START as u64) + (MAX_HEAP_SIZE as u64) <= DEFAULT_STACK_START as u64,
+            "Heap overlaps with the stack"
+        );
+    }
+
+    #[test]
+    fn test_check_sizes() {
+        assert_eq!(
+            check_stack_size(MIN_STACK_SIZE as i64),
+            Ok(MIN_STACK_SIZE as u32)
+        );
+        assert!(check_stack_size(MIN_STACK_SIZE as i64 - 1).is_err());
+        assert!(check_stack_size(MAX_STACK_SIZE as i64 + 1).is_err());
+        assert!(check_stack_size(-1).is_err());
+
+        assert_eq!(check_heap_size(1 << 20), Ok(1 << 20));
+        assert_eq!(check_heap_size(PAGE_SIZE as i64), Ok(PAGE_SIZE as u32));
+        assert_eq!(
+            check_heap_size(3 * PAGE_SIZE as i64),
+            Ok(3 * PAGE_SIZE as u32)
+        );
+        assert!(check_heap_size(2 * MAX_HEAP_SIZE as i64).is_err());
+        assert!(check_heap_size(1 << 32).is_err());
+        assert!(check_heap_size(-(1 << 20)).is_err());
+    }
 }
@@ -4,6 +4,9 @@ pub const ECALL_XRECV: u32 = 3;
 pub const ECALL_EXIT: u32 = 4;
 pub const ECALL_PRINT: u32 = 5;
 
+// Returned by XRECV instead of a length when the host aborts the request being processed
+pub const XRECV_ABORTED: usize = usize::MAX;
+
 // device handling, events, and UX
 
 pub const ECALL_GET_EVENT: u32 = 10;
@@ -0,0 +1,852 @@
+//! This module provides traits and implementations for page eviction strategies,
+//! to be used in the `OutsourcedMemory` structure of the VM, and by the cache simulator in `tools/`.
+
+use alloc::{boxed::Box, collections::VecDeque, vec, vec::Vec};
+use serde::{Deserialize, Serialize};
+
+pub trait PageEvictionStrategy {
+    /// Called when a page that is not in the cache is requested, before any slot is reserved for
+    /// it. Strategies that adapt to the misses can use it to prepare the choice of the victims.
+    fn on_miss(&mut self, _page_index: u32) {}
+
+    /// Called when a page is accessed in a given slot.
+    fn on_access(&mut self, slot_index: usize, page_index: u32);
+
+    /// Called when a new page is loaded into a slot.
+    fn on_load(&mut self, slot_index: usize, page_index: u32);
+
+    /// Choose a victim slot to evict.
+    fn choose_victim(&mut self) -> usize;
+
+    /// Called when a slot is invalidated.
+    fn on_invalidate(&mut self, slot_index: usize, page_index: u32);
+}
+
+/// A simple LRU (Least Recently Used) eviction strategy.
+pub struct LruEvictionStrategy {
+    // For each slot, None if it's free, or the time of the last use of the page
+    usage_counters: Vec<Option<u32>>,
+    global_counter: u32,
+}
+
+impl LruEvictionStrategy {
+    pub fn new(num_slots: usize) -> Self {
+        Self {
+            usage_counters: vec![None; num_slots],
+            global_counter: 0,
+        }
+    }
+
+    // estimates how much space this struct uses for each additional page slot
+    pub const fn size_per_page() -> usize {
+        core::mem::size_of::<Option<u32>>()
+    }
+}
+
+impl PageEvictionStrategy for LruEvictionStrategy {
+    fn on_access(&mut self, slot_index: usize, _page_index: u32) {
+        self.global_counter = self.global_counter.wrapping_add(1);
+        self.usage_counters[slot_index] = Some(self.global_counter);
+    }
+
+    fn on_load(&mut self, slot_index: usize, _page_index: u32) {
+        self.global_counter = self.global_counter.wrapping_add(1);
+        self.usage_counters[slot_index] = Some(self.global_counter);
+    }
+
+    fn choose_victim(&mut self) -> usize {
+        // Free slots are being reserved for pages that are not loaded yet, and are never chosen
+        self.usage_counters
+            .iter()
+            .enumerate()
+            .filter_map(|(i, counter)| counter.map(|counter| (counter, i)))
+            .min()
+            .map(|(_, i)| i)
+            .expect("This never happens, as there is always a page to evict")
+    }
+
+    fn on_invalidate(&mut self, slot_index: usize, _page_index: u32) {
+        self.usage_counters[slot_index] = None;
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PageState {
+    Free,
+    A1(u32), // In A1 queue, with a timestamp for FIFO
+    Am(u32), // In Am queue, with a timestamp for LRU
+}
+
+/// 2Q: A Low Overhead High Performance Buffer Management Replacement Algorithm
+/// https://www.vldb.org/conf/1994/P439.PDF
+/// This implementation keeps the A1 and Am queues implied by using the `PageState` enum.
+/// While this implies linear scanning, this should be acceptable for the small size of
+/// the cache that we expect.
+#[derive(Debug)]
+pub struct TwoQEvictionStrategy {
+    states: Vec<PageState>,
+    global_counter: u32,
+    a1_max_size: usize,
+    a1_size: usize,
+    a1out: VecDeque<u32>,
+    a1out_max_size: usize,
+}
+
+impl TwoQEvictionStrategy {
+    pub fn new(num_slots: usize, a1_max_size: usize, a1out_max_size: usize) -> Self {
+        if a1_max_size >= num_slots {
+            panic!("A1 max size must be smaller than total slots");
+        }
+        Self {
+            states: vec![PageState::Free; num_slots],
+            global_counter: 0,
+            a1_max_size,
+            a1_size: 0,
+            a1out: VecDeque::with_capacity(a1out_max_size),
+            a1out_max_size,
+        }
+    }
+
+    // estimates how much space this struct uses for each additional page slot
+    pub const fn size_per_page() -> usize {
+        core::mem::size_of::<PageState>() + core::mem::size_of::<u32>()
+    }
+}
+
+impl PageEvictionStrategy for TwoQEvictionStrategy {
+    fn on_access(&mut self, slot_index: usize, _page_index: u32) {
+        self.global_counter = self.global_counter.wrapping_add(1);
+        match self.states[slot_index] {
+            PageState::A1(_) => {
+                // do nothing: counter is not updated in A1, as it's FIFO
+            }
+            PageState::Am(_) => {
+                // Re-accessed in Am, update LRU counter
+                self.states[slot_index] = PageState::Am(self.global_counter);
+            }
+            PageState::Free => {
+                // Should not happen on access, but handle defensively
+            }
+        }
+    }
+
+    fn on_load(&mut self, slot_index: usize, page_index: u32) {
+        self.global_counter = self.global_counter.wrapping_add(1);
+        // If the page is in A1out, move it to Am
+        if let Some(pos) = self.a1out.iter().position(|&x| x == page_index) {
+            self.a1out.remove(pos);
+            self.states[slot_index] = PageState::Am(self.global_counter);
+        } else {
+            // Otherwise, add it to A1
+            self.states[slot_index] = PageState::A1(self.global_counter);
+            self.a1_size += 1;
+        }
+    }
+
+    fn choose_victim(&mut self) -> usize {
+        if self.a1_size >= self.a1_max_size {
+            // Evict from A1 (FIFO)
+            let mut oldest_time = u32::MAX;
+            let mut victim_index = 0;
+            for (i, state) in self.states.iter().enumerate() {
+                if let PageState::A1(time) = *state {
+                    if time < oldest_time {
+                        oldest_time = time;
+                        victim_index = i;
+                    }
+                }
+            }
+
+            victim_index
+        } else {
+            // Evict from Am (LRU)
+            let mut oldest_time = u32::MAX;
+            let mut victim_index = 0;
+            let mut found_in_am = false;
+            for (i, state) in self.states.iter().enumerate() {
+                if let PageState::Am(time) = *state {
+                    if time < oldest_time {
+                        oldest_time = time;
+                        victim_index = i;
+                        found_in_am = true;
+                    }
+                }
+            }
+
+            if !found_in_am {
+                // Fallback to A1 if Am is empty
+                return self.choose_victim_from_a1_fallback();
+            }
+
+            victim_index
+        }
+    }
+
+    fn on_invalidate(&mut self, slot_index: usize, page_index: u32) {
+        if let PageState::A1(_) = self.states[slot_index] {
+            self.a1_size -= 1;
+            // Add to A1out
+            if self.a1out.len() == self.a1out_max_size {
+                self.a1out.pop_front();
+            }
+            self.a1out.push_back(page_index);
+        }
+        self.states[slot_index] = PageState::Free;
+    }
+}
+
+impl TwoQEvictionStrategy {
+    fn choose_victim_from_a1_fallback(&self) -> usize {
+        let mut oldest_time = u32::MAX;
+        let mut victim_index = 0;
+        let mut found = false;
+        for (i, state) in self.states.iter().enumerate() {
+            if let PageState::A1(time) = *state {
+                if time < oldest_time {
+                    oldest_time = time;
+                    victim_index = i;
+                    found = true;
+                }
+            }
+        }
+
+        if !found {
+            unreachable!("This never happens, as there is always a page to evict in A1 or Am")
+        }
+
+        victim_index
+    }
+}
+
+/// CLOCK, also known as second chance: an approximation of LRU that only keeps a reference bit
+/// for each slot. A hand sweeps the slots circularly, clearing the reference bits, and evicts the
+/// first page that was not referenced since the hand last passed it.
+#[derive(Debug)]
+pub struct ClockEvictionStrategy {
+    // For each slot, None if it's free, or whether the page was referenced
+    referenced: Vec<Option<bool>>,
+    hand: usize,
+}
+
+impl ClockEvictionStrategy {
+    pub fn new(num_slots: usize) -> Self {
+        Self {
+            referenced: vec![None; num_slots],
+            hand: 0,
+        }
+    }
+
+    // estimates how much space this struct uses for each additional page slot
+    pub const fn size_per_page() -> usize {
+        core::mem::size_of::<Option<bool>>()
+    }
+}
+
+impl PageEvictionStrategy for ClockEvictionStrategy {
+    fn on_access(&mut self, slot_index: usize, _page_index: u32) {
+        if let Some(referenced) = self.referenced[slot_index].as_mut() {
+            *referenced = true;
+        }
+    }
+
+    fn on_load(&mut self, slot_index: usize, _page_index: u32) {
+        // A new page gets a full sweep of the hand to be referenced
+        self.referenced[slot_index] = Some(false);
+    }
+
+    fn choose_victim(&mut self) -> usize {
+        // After a full sweep, all the reference bits are cleared
+        let n = self.referenced.len();
+        for _ in 0..2 * n {
+            let slot = self.hand;
+            self.hand = (self.hand + 1) % n;
+            match self.referenced[slot].as_mut() {
+                Some(referenced) if *referenced => *referenced = false,
+                Some(_) => return slot,
+                None => {}
+            }
+        }
+        unreachable!("This never happens, as there is always a page to evict")
+    }
+
+    fn on_invalidate(&mut self, slot_index: usize, _page_index: u32) {
+        self.referenced[slot_index] = None;
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ClockProState {
+    Free,
+    Hot { referenced: bool },
+    Cold { referenced: bool, in_test: bool },
+}
+
+/// CLOCK-Pro: An Effective Improvement of the CLOCK Replacement
+/// https://www.usenix.org/legacy/event/usenix05/tech/general/full_papers/jiang/jiang.pdf
+/// Pages are either hot or cold, and only cold pages are evicted. A new page is cold, and starts a
+/// test period; if it is accessed again during its test period, even after being evicted, it
+/// becomes hot. The target number of cold pages adapts to the workload: it grows when pages are
+/// accessed during their test period, and shrinks when test periods expire.
+/// This implementation keeps a hand for the cold pages and one for the hot pages over the slots;
+/// instead of the third hand of the paper, the evicted pages in their test period are kept in a
+/// FIFO queue as long as the cache.
+#[derive(Debug)]
+pub struct ClockProEvictionStrategy {
+    states: Vec<ClockProState>,
+    cold_hand: usize,
+    hot_hand: usize,
+    n_hot: usize,
+    n_cold: usize,
+    cold_target: usize,
+    // evicted pages that are still in their test period, from the oldest
+    test_pages: VecDeque<u32>,
+}
+
+impl ClockProEvictionStrategy {
+    pub fn new(num_slots: usize) -> Self {
+        Self {
+            states: vec![ClockProState::Free; num_slots],
+            cold_hand: 0,
+            hot_hand: 0,
+            n_hot: 0,
+            n_cold: 0,
+            cold_target: (num_slots / 4).max(1),
+            test_pages: VecDeque::with_capacity(num_slots),
+        }
+    }
+
+    // estimates how much space this struct uses for each additional page slot
+    pub const fn size_per_page() -> usize {
+        core::mem::size_of::<ClockProState>() + core::mem::size_of::<u32>()
+    }
+
+    // There is always room for at least one cold page
+    fn increase_cold_target(&mut self) {
+        self.cold_target = (self.cold_target + 1).min(self.states.len().saturating_sub(1).max(1));
+    }
+
+    fn decrease_cold_target(&mut self) {
+        self.cold_target = self.cold_target.saturating_sub(1).max(1);
+    }
+
+    // Turns a page from hot to cold, moving the hot hand past it. The test periods of the cold
+    // pages that the hand passes are terminated.
+    fn run_hot_hand(&mut self) {
+        if self.n_hot == 0 {
+            return;
+        }
+        loop {
+            let slot = self.hot_hand;
+            self.hot_hand = (self.hot_hand + 1) % self.states.len();
+            match self.states[slot] {
+                ClockProState::Hot { referenced: true } => {
+                    self.states[slot] = ClockProState::Hot { referenced: false };
+                }
+                ClockProState::Hot { referenced: false } => {
+                    self.states[slot] = ClockProState::Cold {
+                        referenced: false,
+                        in_test: false,
+                    };
+                    self.n_hot -= 1;
+                    self.n_cold += 1;
+                    return;
+                }
+                ClockProState::Cold {
+                    referenced,
+                    in_test: true,
+                } => {
+                    self.states[slot] = ClockProState::Cold {
+                        referenced,
+                        in_test: false,
+                    };
+                    self.decrease_cold_target();
+                }
+                _ => {}
+            }
+        }
+    }
+
+    // Turns a cold page accessed during its test period into a hot one
+    fn promote(&mut self, slot_index: usize) {
+        self.states[slot_index] = ClockProState::Hot { referenced: false };
+        self.n_hot += 1;
+        self.increase_cold_target();
+        while self.n_hot > self.states.len() - self.cold_target {
+            self.run_hot_hand();
+        }
+    }
+}
+
+impl PageEvictionStrategy for ClockProEvictionStrategy {
+    fn on_access(&mut self, slot_index: usize, _page_index: u32) {
+        match &mut self.states[slot_index] {
+            ClockProState::Hot { referenced } | ClockProState::Cold { referenced, .. } => {
+                *referenced = true;
+            }
+            ClockProState::Free => {}
+        }
+    }
+
+    fn on_load(&mut self, slot_index: usize, page_index: u32) {
+        if let Some(pos) = self.test_pages.iter().position(|&x| x == page_index) {
+            self.test_pages.remove(pos);
+            self.promote(slot_index);
+        } else {
+            self.states[slot_index] = ClockProState::Cold {
+                referenced: false,
+                in_test: true,
+            };
+            self.n_cold += 1;
+        }
+    }
+
+    fn choose_victim(&mut self) -> usize {
+        loop {
+            if self.n_cold == 0 {
+                if self.n_hot == 0 {
+                    unreachable!("This never happens, as there is always a page to evict")
+                }
+                self.run_hot_hand();
+            }
+            let slot = self.cold_hand;
+            self.cold_hand = (self.cold_hand + 1) % self.states.len();
+            match self.states[slot] {
+                ClockProState::Cold {
+                    referenced: false, ..
+                } => return slot,
+                ClockProState::Cold {
+                    referenced: true,
+                    in_test: true,
+                } => {
+                    self.n_cold -= 1;
+                    self.promote(slot);
+                }
+                ClockProState::Cold {
+                    referenced: true,
+                    in_test: false,
+                } => {
+                    self.states[slot] = ClockProState::Cold {
+                        referenced: false,
+                        in_test: true,
+                    };
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn on_invalidate(&mut self, slot_index: usize, page_index: u32) {
+        match self.states[slot_index] {
+            ClockProState::Hot { .. } => self.n_hot -= 1,
+            ClockProState::Cold { in_test, .. } => {
+                self.n_cold -= 1;
+                if in_test {
+                    if self.test_pages.len() == self.states.len() {
+                        self.test_pages.pop_front();
+                        self.decrease_cold_target();
+                    }
+                    self.test_pages.push_back(page_index);
+                }
+            }
+            ClockProState::Free => {}
+        }
+        self.states[slot_index] = ClockProState::Free;
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ArcList {
+    Free,
+    T1,
+    T2,
+}
+
+// A doubly linked list of slots, from the least to the most recently used
+#[derive(Debug, Default)]
+struct SlotList {
+    head: Option<usize>,
+    tail: Option<usize>,
+    len: usize,
+}
+
+/// ARC: A Self-Tuning, Low Overhead Replacement Cache
+/// https://www.usenix.org/legacy/events/fast03/tech/full_papers/megiddo/megiddo.pdf
+/// The cached pages are split between T1, the pages accessed once recently, and T2, the pages
+/// accessed at least twice. The pages evicted from each of them are remembered in the ghost lists
+/// B1 and B2; a miss on a ghost page moves the target size of T1 towards the list it came from.
+/// T1 and T2 are linked lists over the slots, so that choosing a victim is constant time.
+#[derive(Debug)]
+pub struct ArcEvictionStrategy {
+    lists: Vec<ArcList>,
+    prev: Vec<Option<usize>>,
+    next: Vec<Option<usize>>,
+    t1: SlotList,
+    t2: SlotList,
+    // pages evicted from T1 and T2, from the least recently used
+    b1: VecDeque<u32>,
+    b2: VecDeque<u32>,
+    t1_target: usize,
+    // whether the last missed page was in B2
+    miss_in_b2: bool,
+}
+
+impl ArcEvictionStrategy {
+    pub fn new(num_slots: usize) -> Self {
+        Self {
+            lists: vec![ArcList::Free; num_slots],
+            prev: vec![None; num_slots],
+            next: vec![None; num_slots],
+            t1: SlotList::default(),
+            t2: SlotList::default(),
+            b1: VecDeque::new(),
+            b2: VecDeque::new(),
+            t1_target: 0,
+            miss_in_b2: false,
+        }
+    }
+
+    // estimates how much space this struct uses for each additional page slot
+    pub const fn size_per_page() -> usize {
+        core::mem::size_of::<ArcList>()
+            + 2 * core::mem::size_of::<Option<usize>>()
+            + 2 * core::mem::size_of::<u32>()
+    }
+
+    fn list_mut(&mut self, list: ArcList) -> &mut SlotList {
+        match list {
+            ArcList::T1 => &mut self.t1,
+            ArcList::T2 => &mut self.t2,
+            ArcList::Free => unreachable!("Free slots are not in a list"),
+        }
+    }
+
+    fn push_mru(&mut self, list: ArcList, slot_index: usize) {
+        let tail = self.list_mut(list).tail;
+        self.prev[slot_index] = tail;
+        self.next[slot_index] = None;
+        match tail {
+            Some(tail) => self.next[tail] = Some(slot_index),
+            None => self.list_mut(list).head = Some(slot_index),
+        }
+        let l = self.list_mut(list);
+        l.tail = Some(slot_index);
+        l.len += 1;
+        self.lists[slot_index] = list;
+    }
+
+    fn unlink(&mut self, slot_index: usize) {
+        let list = self.lists[slot_index];
+        if list == ArcList::Free {
+            return;
+        }
+        let (prev, next) = (self.prev[slot_index], self.next[slot_index]);
+        match prev {
+            Some(prev) => self.next[prev] = next,
+            None => self.list_mut(list).head = next,
+        }
+        match next {
+            Some(next) => self.prev[next] = prev,
+            None => self.list_mut(list).tail = prev,
+        }
+        self.list_mut(list).len -= 1;
+        self.lists[slot_index] = ArcList::Free;
+    }
+
+    // Forgets the oldest ghost pages, so that T1 and B1 together, and all the lists together, do
+    // not remember more than one and two times the size of the cache, respectively
+    fn trim_ghosts(&mut self) {
+        let c = self.lists.len();
+        while self.t1.len + self.b1.len() > c && self.b1.pop_front().is_some() {}
+        while self.t1.len + self.t2.len + self.b1.len() + self.b2.len() > 2 * c {
+            if self.b2.pop_front().is_none() {
+                self.b1.pop_front();
+            }
+        }
+    }
+}
+
+impl PageEvictionStrategy for ArcEvictionStrategy {
+    fn on_miss(&mut self, page_index: u32) {
+        let c = self.lists.len();
+        let (b1_len, b2_len) = (self.b1.len(), self.b2.len());
+        self.miss_in_b2 = false;
+        if self.b1.contains(&page_index) {
+            self.t1_target = (self.t1_target + (b2_len / b1_len).max(1)).min(c);
+        } else if self.b2.contains(&page_index) {
+            self.t1_target = self.t1_target.saturating_sub((b1_len / b2_len).max(1));
+            self.miss_in_b2 = true;
+        }
+    }
+
+    fn on_access(&mut self, slot_index: usize, _page_index: u32) {
+        if self.lists[slot_index] != ArcList::Free {
+            self.unlink(slot_index);
+            self.push_mru(ArcList::T2, slot_index);
+        }
+    }
+
+    fn on_load(&mut self, slot_index: usize, page_index: u32) {
+        // A page that was recently evicted is accessed for the second time
+        let in_ghost = |ghost: &mut VecDeque<u32>| match ghost.iter().position(|&x| x == page_index)
+        {
+            Some(pos) => ghost.remove(pos).is_some(),
+            None => false,
+        };
+        let list = if in_ghost(&mut self.b1) || in_ghost(&mut self.b2) {
+            ArcList::T2
+        } else {
+            ArcList::T1
+        };
+        self.unlink(slot_index);
+        self.push_mru(list, slot_index);
+        self.miss_in_b2 = false;
+        self.trim_ghosts();
+    }
+
+    fn choose_victim(&mut self) -> usize {
+        let t1_len = self.t1.len;
+        let from_t1 = t1_len > 0
+            && (t1_len > self.t1_target
+                || (self.miss_in_b2 && t1_len == self.t1_target)
+                || self.t2.len == 0);
+        let victim = if from_t1 { self.t1.head } else { self.t2.head };
+        victim.expect("There is always a page to evict")
+    }
+
+    fn on_invalidate(&mut self, slot_index: usize, page_index: u32) {
+        match self.lists[slot_index] {
+            ArcList::T1 => self.b1.push_back(page_index),
+            ArcList::T2 => self.b2.push_back(page_index),
+            ArcList::Free => return,
+        }
+        self.unlink(slot_index);
+        self.trim_ghosts();
+    }
+}
+
+/// The eviction strategies that can be chosen for the page cache of a segment.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[repr(u8)]
+pub enum EvictionPolicy {
+    #[serde(rename = "lru")]
+    Lru = 0,
+    #[serde(rename = "2q")]
+    TwoQ = 1,
+    #[serde(rename = "clock")]
+    Clock = 2,
+    #[serde(rename = "clock-pro")]
+    ClockPro = 3,
+    #[serde(rename = "arc")]
+    Arc = 4,
+}
+
+impl EvictionPolicy {
+    pub const ALL: [EvictionPolicy; 5] = [
+        EvictionPolicy::Lru,
+        EvictionPolicy::TwoQ,
+        EvictionPolicy::Clock,
+        EvictionPolicy::ClockPro,
+        EvictionPolicy::Arc,
+    ];
+
+    /// The name of the policy, as used in the manifest.
+    pub fn name(self) -> &'static str {
+        match self {
+            EvictionPolicy::Lru => "lru",
+            EvictionPolicy::TwoQ => "2q",
+            EvictionPolicy::Clock => "clock",
+            EvictionPolicy::ClockPro => "clock-pro",
+            EvictionPolicy::Arc => "arc",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|policy| policy.name() == name)
+    }
+
+    /// Creates the eviction strategy for a cache of `num_slots` pages. For 2Q, the A1 and A1out
+    /// queues are a quarter and a half of the cache, respectively.
+    pub fn create(self, num_slots: usize) -> Box<dyn PageEvictionStrategy> {
+        match self {
+            EvictionPolicy::Lru => Box::new(LruEvictionStrategy::new(num_slots)),
+            EvictionPolicy::TwoQ => Box::new(TwoQEvictionStrategy::new(
+                num_slots,
+                num_slots / 4,
+                num_slots / 2,
+            )),
+            EvictionPolicy::Clock => Box::new(ClockEvictionStrategy::new(num_slots)),
+            EvictionPolicy::ClockPro => Box::new(ClockProEvictionStrategy::new(num_slots)),
+            EvictionPolicy::Arc => Box::new(ArcEvictionStrategy::new(num_slots)),
+        }
+    }
+
+    /// Estimates how much space the strategy uses for each additional page slot.
+    pub const fn size_per_page(self) -> usize {
+        match self {
+            EvictionPolicy::Lru => LruEvictionStrategy::size_per_page(),
+            EvictionPolicy::TwoQ => TwoQEvictionStrategy::size_per_page(),
+            EvictionPolicy::Clock => ClockEvictionStrategy::size_per_page(),
+            EvictionPolicy::ClockPro => ClockProEvictionStrategy::size_per_page(),
+            EvictionPolicy::Arc => ArcEvictionStrategy::size_per_page(),
+        }
+    }
+
+    /// The largest [`size_per_page`](Self::size_per_page) among all the policies.
+    pub const fn max_size_per_page() -> usize {
+        let mut max = 0;
+        let mut i = 0;
+        while i < Self::ALL.len() {
+            let size = Self::ALL[i].size_per_page();
+            if size > max {
+                max = size;
+            }
+            i += 1;
+        }
+        max
+    }
+}
+
+impl core::fmt::Display for EvictionPolicy {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Replays the accesses in a cache of `num_slots` pages, like the `OutsourcedMemory` of the VM,
+    // and returns the number of misses
+    fn count_misses(policy: EvictionPolicy, num_slots: usize, accesses: &[u32]) -> usize {
+        let mut strategy = policy.create(num_slots);
+        let mut slots: Vec<Option<u32>> = vec![None; num_slots];
+        let mut misses = 0;
+        for &page_index in accesses {
+            if let Some(slot) = slots.iter().position(|&p| p == Some(page_index)) {
+                strategy.on_access(slot, page_index);
+                continue;
+            }
+            misses += 1;
+            strategy.on_miss(page_index);
+            let slot = match slots.iter().position(|p| p.is_none()) {
+                Some(slot) => slot,
+                None => {
+                    let slot = strategy.choose_victim();
+                    let evicted = slots[slot]
+                        .take()
+                        .expect("The victim must be a cached page");
+                    strategy.on_invalidate(slot, evicted);
+                    slot
+                }
+            };
+            slots[slot] = Some(page_index);
+            strategy.on_load(slot, page_index);
+        }
+        misses
+    }
+
+    // Pseudo-random accesses, mostly to a small set of pages
+    fn random_accesses(n: usize) -> Vec<u32> {
+        let mut state = 0x12345678u32;
+        (0..n)
+            .map(|_| {
+                state = state.wrapping_mul(1103515245).wrapping_add(12345);
+                let r = state >> 16;
+                if r.is_multiple_of(4) {
+                    r % 64
+                } else {
+                    r % 8
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_policy_names() {
+        for policy in EvictionPolicy::ALL {
+            assert_eq!(EvictionPolicy::from_name(policy.name()), Some(policy));
+            assert!(policy.size_per_page() <= EvictionPolicy::max_size_per_page());
+        }
+        assert_eq!(EvictionPolicy::from_name("mru"), None);
+    }
+
+    #[test]
+    fn test_victims_are_cached_pages() {
+        let accesses = random_accesses(2000);
+        for policy in EvictionPolicy::ALL {
+            for num_slots in [1, 2, 3, 8, 16] {
+                let misses = count_misses(policy, num_slots, &accesses);
+                assert!(misses <= accesses.len());
+            }
+            // no eviction if everything fits
+            let mut distinct = accesses.clone();
+            distinct.sort();
+            distinct.dedup();
+            assert_eq!(count_misses(policy, 64, &accesses), distinct.len());
+        }
+    }
+
+    #[test]
+    fn test_batch_victims_are_distinct() {
+        // Like the `OutsourcedMemory` of the VM loading a batch of pages: all the slots are
+        // reserved before any of the pages is loaded
+        for policy in EvictionPolicy::ALL {
+            for num_slots in [2, 3, 8] {
+                let mut strategy = policy.create(num_slots);
+                for slot in 0..num_slots {
+                    strategy.on_miss(slot as u32);
+                    strategy.on_load(slot, slot as u32);
+                }
+                strategy.on_access(0, 0);
+
+                let mut slots = Vec::new();
+                for i in 0..num_slots as u32 {
+                    strategy.on_miss(100 + i);
+                    let slot = strategy.choose_victim();
+                    assert!(
+                        !slots.contains(&slot),
+                        "{}: slot {} chosen twice",
+                        policy,
+                        slot
+                    );
+                    strategy.on_invalidate(slot, slot as u32);
+                    slots.push(slot);
+                }
+                for (i, &slot) in slots.iter().enumerate() {
+                    strategy.on_load(slot, 100 + i as u32);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_clock_second_chance() {
+        let mut strategy = ClockEvictionStrategy::new(3);
+        for slot in 0..3 {
+            strategy.on_load(slot, slot as u32);
+        }
+        strategy.on_access(0, 0);
+        strategy.on_access(2, 2);
+        assert_eq!(strategy.choose_victim(), 1);
+        strategy.on_invalidate(1, 1);
+        strategy.on_load(1, 3);
+        // page 2 is given a second chance again, but page 0 lost it in the previous sweep
+        assert_eq!(strategy.choose_victim(), 0);
+    }
+
+    #[test]
+    fn test_scan_resistance() {
+        // a small working set, that is accessed between scans of pages that are used only once
+        let mut accesses = Vec::new();
+        for round in 0..50 {
+            accesses.extend_from_slice(&[0, 1, 2, 0, 1, 2]);
+            accesses.extend((0..3).map(|i| 100 + 3 * round + i));
+        }
+        let lru = count_misses(EvictionPolicy::Lru, 5, &accesses);
+        for policy in [EvictionPolicy::ClockPro, EvictionPolicy::Arc] {
+            let misses = count_misses(policy, 5, &accesses);
+            assert!(misses < lru, "{}: {} misses, LRU: {}", policy, misses, lru);
+        }
+    }
+}
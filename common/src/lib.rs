@@ -5,9 +5,14 @@ extern crate alloc;
 pub mod accumulator;
 pub mod client_commands;
 pub mod comm;
+pub mod compression;
 pub mod constants;
 pub mod ecall_constants;
+pub mod evict;
 pub mod manifest;
+pub mod ranges;
+pub mod rpc;
+pub mod trace;
 pub mod ux;
 pub mod vm;
 
@@ -1,12 +1,88 @@
 use alloc::string::{String, ToString};
+use alloc::vec::Vec;
 use serde::{self, Deserialize, Serialize};
 
 use crate::accumulator::Hasher;
 use crate::constants::{page_start, PAGE_SIZE};
+use crate::evict::EvictionPolicy;
+use crate::vm::SegmentPermissions;
 
 const APP_NAME_MAX_LEN: usize = 32;
 const APP_VERSION_MAX_LEN: usize = 32;
 
+/// The maximum number of memory segments of a V-App.
+pub const MAX_SEGMENTS: usize = 8;
+
+/// An entry of the segment table of a V-App: a contiguous range of memory, with its access
+/// permissions and the Merkle root of its initial content.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct SegmentDescriptor {
+    pub start: u32,
+    pub end: u32,
+    pub permissions: SegmentPermissions,
+    pub merkle_root: [u8; 32],
+    /// The initial content of the segment is all zeros from this address to the end, like for the
+    /// .bss section or the stack. The VM serves the pages in this range without asking the client,
+    /// until they are first written.
+    pub zero_start: u32,
+    /// The eviction policy of the page cache of the segment in the VM. If not specified, the VM
+    /// chooses one depending on the permissions of the segment.
+    pub eviction_policy: Option<EvictionPolicy>,
+}
+
+impl SegmentDescriptor {
+    pub fn new(
+        start: u32,
+        end: u32,
+        permissions: SegmentPermissions,
+        merkle_root: [u8; 32],
+    ) -> Self {
+        Self {
+            start,
+            end,
+            permissions,
+            merkle_root,
+            zero_start: end,
+            eviction_policy: None,
+        }
+    }
+
+    /// Returns the same descriptor, with the initial content known to be zero from `zero_start`.
+    pub fn with_zero_start(self, zero_start: u32) -> Self {
+        Self { zero_start, ..self }
+    }
+
+    /// Returns the same descriptor, with the given eviction policy for its page cache.
+    pub fn with_eviction_policy(self, eviction_policy: EvictionPolicy) -> Self {
+        Self {
+            eviction_policy: Some(eviction_policy),
+            ..self
+        }
+    }
+
+    /// Returns the number of pages spanned by the segment.
+    #[inline]
+    pub fn n_pages(&self) -> u32 {
+        1 + (page_start(self.end - 1) - page_start(self.start)) / PAGE_SIZE as u32
+    }
+
+    /// Returns the index of the first page whose initial content is all zeros; all the following
+    /// pages are zero, too. Returns [`n_pages`](Self::n_pages) if there is no such page.
+    #[inline]
+    pub fn first_zero_page(&self) -> u32 {
+        if self.zero_start <= self.start {
+            return 0;
+        }
+        (self.zero_start - page_start(self.start)).div_ceil(PAGE_SIZE as u32)
+    }
+
+    /// Returns true if the segment contains the byte at the specified address.
+    #[inline]
+    pub fn contains(&self, address: u32) -> bool {
+        address >= self.start && address < self.end
+    }
+}
+
 /// The manifest contains all the required info that the application needs in order to execute a V-App.
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Manifest {
@@ -14,15 +90,14 @@ pub struct Manifest {
     pub app_name: String,
     pub app_version: String,
     pub entrypoint: u32,
-    pub code_start: u32,
-    pub code_end: u32,
-    pub code_merkle_root: [u8; 32],
-    pub data_start: u32,
-    pub data_end: u32,
-    pub data_merkle_root: [u8; 32],
-    pub stack_start: u32,
-    pub stack_end: u32,
-    pub stack_merkle_root: [u8; 32],
+    /// The memory segments of the V-App. The client commands identify a segment by its index in
+    /// this table.
+    pub segments: Vec<SegmentDescriptor>,
+    /// The index of the segment used for the stack; it must be read-write.
+    pub stack_segment: u8,
+    /// The index of the segment used for the heap; it must be read-write, and different from the
+    /// stack segment. The V-App receives its bounds in the a0 and a1 registers when it starts.
+    pub heap_segment: u8,
 }
 
 impl Manifest {
@@ -32,15 +107,9 @@ impl Manifest {
         app_name: &str,
         app_version: &str,
         entrypoint: u32,
-        code_start: u32,
-        code_end: u32,
-        code_merkle_root: [u8; 32],
-        data_start: u32,
-        data_end: u32,
-        data_merkle_root: [u8; 32],
-        stack_start: u32,
-        stack_end: u32,
-        stack_merkle_root: [u8; 32],
+        segments: Vec<SegmentDescriptor>,
+        stack_segment: u8,
+        heap_segment: u8,
     ) -> Result<Self, &'static str> {
         if app_name.len() > APP_NAME_MAX_LEN {
             return Err("app_name is too long");
@@ -48,8 +117,40 @@ impl Manifest {
         if app_version.len() > APP_VERSION_MAX_LEN {
             return Err("app_version is too long");
         }
-        if entrypoint < code_start || entrypoint >= code_end {
-            return Err("entrypoint must be within the code section");
+        if segments.is_empty() || segments.len() > MAX_SEGMENTS {
+            return Err("invalid number of segments");
+        }
+        for (i, segment) in segments.iter().enumerate() {
+            if segment.start >= segment.end {
+                return Err("segments must not be empty");
+            }
+            if !segment.start.is_multiple_of(2) {
+                return Err("segments must start at a 2-byte aligned address");
+            }
+            if segment.zero_start < segment.start || segment.zero_start > segment.end {
+                return Err("the zero-initialized part of a segment must be within the segment");
+            }
+            if segments[..i]
+                .iter()
+                .any(|other| segment.start < other.end && other.start < segment.end)
+            {
+                return Err("segments must not overlap");
+            }
+        }
+        if !segments
+            .iter()
+            .any(|segment| segment.permissions.is_executable() && segment.contains(entrypoint))
+        {
+            return Err("entrypoint must be within an executable segment");
+        }
+        match segments.get(stack_segment as usize) {
+            Some(segment) if segment.permissions.is_writable() => {}
+            _ => return Err("the stack segment must be a read-write segment"),
+        }
+        match segments.get(heap_segment as usize) {
+            Some(segment) if segment.permissions.is_writable() && heap_segment != stack_segment => {
+            }
+            _ => return Err("the heap segment must be a read-write segment, other than the stack"),
         }
         if entrypoint % 2 != 0 {
             return Err("entrypoint must be 2-byte aligned");
@@ -75,15 +176,9 @@ impl Manifest {
             app_name: app_name.to_string(),
             app_version: app_version.to_string(),
             entrypoint,
-            code_start,
-            code_end,
-            code_merkle_root,
-            data_start,
-            data_end,
-            data_merkle_root,
-            stack_start,
-            stack_end,
-            stack_merkle_root,
+            segments,
+            stack_segment,
+            heap_segment,
         })
     }
 
@@ -95,24 +190,37 @@ impl Manifest {
         &self.app_version
     }
 
+    /// Returns the descriptor of the segment used for the stack.
+    ///
+    /// Panics if the index of the stack segment is invalid, which can only happen if the manifest
+    /// was not created with [`Manifest::new`]; see [`Manifest::validate`].
     #[inline]
-    fn n_pages(start: u32, end: u32) -> u32 {
-        1 + (page_start(end - 1) - page_start(start)) / PAGE_SIZE as u32
-    }
-
-    #[inline]
-    pub fn n_code_pages(&self) -> u32 {
-        Self::n_pages(self.code_start, self.code_end)
+    pub fn stack_segment(&self) -> &SegmentDescriptor {
+        &self.segments[self.stack_segment as usize]
     }
 
+    /// Returns the descriptor of the segment used for the heap.
+    ///
+    /// Panics if the index of the heap segment is invalid, which can only happen if the manifest
+    /// was not created with [`Manifest::new`]; see [`Manifest::validate`].
     #[inline]
-    pub fn n_data_pages(&self) -> u32 {
-        Self::n_pages(self.data_start, self.data_end)
+    pub fn heap_segment(&self) -> &SegmentDescriptor {
+        &self.segments[self.heap_segment as usize]
     }
 
-    #[inline]
-    pub fn n_stack_pages(&self) -> u32 {
-        Self::n_pages(self.stack_start, self.stack_end)
+    /// Checks that the manifest satisfies all the constraints enforced by [`Manifest::new`].
+    /// This must be called on manifests that are deserialized from an untrusted source.
+    pub fn validate(&self) -> Result<(), &'static str> {
+        Self::new(
+            self.manifest_version,
+            &self.app_name,
+            &self.app_version,
+            self.entrypoint,
+            self.segments.clone(),
+            self.stack_segment,
+            self.heap_segment,
+        )
+        .map(|_| ())
     }
 
     #[cfg(feature = "serde_json")]
@@ -153,21 +261,160 @@ impl Manifest {
         // Hash entrypoint
         hasher.update(&self.entrypoint.to_be_bytes());
 
-        // Hash code section information
-        hasher.update(&self.code_start.to_be_bytes());
-        hasher.update(&self.code_end.to_be_bytes());
-        hasher.update(&self.code_merkle_root);
+        // Hash the segment table (length prefixed, as it's variable length)
+        hasher.update(&[self.segments.len() as u8]);
+        for segment in self.segments.iter() {
+            hasher.update(&segment.start.to_be_bytes());
+            hasher.update(&segment.end.to_be_bytes());
+            hasher.update(&[segment.permissions as u8]);
+            hasher.update(&segment.merkle_root);
+            hasher.update(&segment.zero_start.to_be_bytes());
+            // 0 if not specified, or 1 + the policy
+            hasher.update(&[segment.eviction_policy.map_or(0, |policy| 1 + policy as u8)]);
+        }
+        hasher.update(&[self.stack_segment]);
+        hasher.update(&[self.heap_segment]);
+
+        hasher.finalize()
+    }
+}
 
-        // Hash data section information
-        hasher.update(&self.data_start.to_be_bytes());
-        hasher.update(&self.data_end.to_be_bytes());
-        hasher.update(&self.data_merkle_root);
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
 
-        // Hash stack section information
-        hasher.update(&self.stack_start.to_be_bytes());
-        hasher.update(&self.stack_end.to_be_bytes());
-        hasher.update(&self.stack_merkle_root);
+    fn segment(start: u32, end: u32, permissions: SegmentPermissions) -> SegmentDescriptor {
+        SegmentDescriptor::new(start, end, permissions, [0u8; 32])
+    }
 
-        hasher.finalize()
+    fn make_manifest(
+        entrypoint: u32,
+        segments: Vec<SegmentDescriptor>,
+        stack_segment: u8,
+        heap_segment: u8,
+    ) -> Result<Manifest, &'static str> {
+        Manifest::new(
+            0,
+            "Test",
+            "0.1.0",
+            entrypoint,
+            segments,
+            stack_segment,
+            heap_segment,
+        )
+    }
+
+    #[test]
+    fn test_segment_table() {
+        use SegmentPermissions::*;
+
+        let segments = vec![
+            segment(0x10000, 0x12000, RX),
+            segment(0x12000, 0x12800, R),
+            segment(0x13000, 0x13010, RW),
+            segment(0x20000, 0x21000, RW),
+        ];
+        let manifest = make_manifest(0x10000, segments.clone(), 3, 2).unwrap();
+        assert_eq!(manifest.stack_segment(), &segments[3]);
+        assert_eq!(manifest.heap_segment(), &segments[2]);
+        assert_eq!(manifest.segments[0].n_pages(), 0x2000 / PAGE_SIZE as u32);
+        assert_eq!(manifest.segments[2].n_pages(), 1);
+        assert!(manifest.validate().is_ok());
+
+        // the entrypoint must be in an executable segment
+        assert!(make_manifest(0x12000, segments.clone(), 3, 2).is_err());
+        // the stack must be writable
+        assert!(make_manifest(0x10000, segments.clone(), 1, 2).is_err());
+        assert!(make_manifest(0x10000, segments.clone(), 4, 2).is_err());
+        // the heap must be writable, and not the stack
+        assert!(make_manifest(0x10000, segments.clone(), 3, 1).is_err());
+        assert!(make_manifest(0x10000, segments.clone(), 3, 3).is_err());
+        assert!(make_manifest(0x10000, segments.clone(), 3, 4).is_err());
+        // segments must not overlap
+        let mut overlapping = segments.clone();
+        overlapping[1].start = 0x11ffe;
+        assert!(make_manifest(0x10000, overlapping, 3, 2).is_err());
+        // segments must not be empty
+        let mut empty = segments.clone();
+        empty[2].end = empty[2].start;
+        assert!(make_manifest(0x10000, empty, 3, 2).is_err());
+        // too many segments
+        let many: Vec<_> = (0..=MAX_SEGMENTS as u32)
+            .map(|i| segment(i * 0x1000, i * 0x1000 + 0x100, RX))
+            .collect();
+        assert!(make_manifest(0, many, 0, 1).is_err());
+        assert!(make_manifest(0x10000, vec![], 0, 1).is_err());
+
+        // a deserialized manifest might violate the constraints
+        let mut invalid = manifest.clone();
+        invalid.stack_segment = 0;
+        assert!(invalid.validate().is_err());
+        let mut invalid = manifest.clone();
+        invalid.heap_segment = 3;
+        assert!(invalid.validate().is_err());
+    }
+
+    #[test]
+    fn test_zero_pages() {
+        use SegmentPermissions::*;
+
+        let page = PAGE_SIZE as u32;
+        let data = segment(0x13010, 0x13010 + 4 * page, RW);
+        assert_eq!(data.zero_start, data.end);
+        assert_eq!(data.first_zero_page(), data.n_pages());
+        // the page that contains the start of the zeros is not entirely zero
+        assert_eq!(
+            data.clone()
+                .with_zero_start(0x13010 + page)
+                .first_zero_page(),
+            2
+        );
+        assert_eq!(
+            data.clone()
+                .with_zero_start(0x13000 + page)
+                .first_zero_page(),
+            1
+        );
+        // except for the padding before the start of the segment
+        assert_eq!(data.clone().with_zero_start(0x13010).first_zero_page(), 0);
+
+        let code = segment(0x10000, 0x12000, RX);
+        let heap = segment(0x18000, 0x19000, RW).with_zero_start(0x18000);
+        let stack = segment(0x20000, 0x21000, RW).with_zero_start(0x20000);
+        let segments = vec![code.clone(), heap.clone(), stack.clone()];
+        assert!(make_manifest(0x10000, segments, 2, 1).is_ok());
+        let outside = stack.clone().with_zero_start(0x1fff0);
+        let segments = vec![code.clone(), heap.clone(), outside];
+        assert!(make_manifest(0x10000, segments, 2, 1).is_err());
+        let outside = stack.clone().with_zero_start(0x21001);
+        assert!(make_manifest(0x10000, vec![code, heap, outside], 2, 1).is_err());
+    }
+
+    #[test]
+    fn test_eviction_policy() {
+        use SegmentPermissions::*;
+
+        let code = segment(0x10000, 0x12000, RX).with_eviction_policy(EvictionPolicy::ClockPro);
+        let heap = segment(0x18000, 0x19000, RW).with_eviction_policy(EvictionPolicy::Arc);
+        let stack = segment(0x20000, 0x21000, RW);
+        assert_eq!(stack.eviction_policy, None);
+        let manifest = make_manifest(0x10000, vec![code, heap, stack], 2, 1).unwrap();
+
+        let serialized = postcard::to_allocvec(&manifest).unwrap();
+        let deserialized: Manifest = postcard::from_bytes(&serialized).unwrap();
+        let policies: Vec<_> = deserialized
+            .segments
+            .iter()
+            .map(|segment| segment.eviction_policy)
+            .collect();
+        assert_eq!(
+            policies,
+            vec![
+                Some(EvictionPolicy::ClockPro),
+                Some(EvictionPolicy::Arc),
+                None
+            ]
+        );
     }
 }
@@ -0,0 +1,100 @@
+//! A set of page indices kept as a bounded number of ranges, for the VM to track the pages of a
+//! segment with a small and fixed amount of memory.
+
+use alloc::vec::Vec;
+
+/// A set of page indices, stored as at most `max_ranges` disjoint ranges.
+///
+/// When an insertion would need more ranges, the two closest ranges are merged, so that the set
+/// also contains the pages between them. The set is therefore a superset of the inserted pages,
+/// which is fine for the uses where a page being in the set only loses an optimization.
+#[derive(Debug, Clone)]
+pub struct PageRangeSet {
+    // sorted, disjoint and non-adjacent ranges [start, end)
+    ranges: Vec<(u32, u32)>,
+    max_ranges: usize,
+}
+
+impl PageRangeSet {
+    pub fn new(max_ranges: usize) -> Self {
+        assert!(max_ranges > 0, "There must be at least one range");
+        Self {
+            ranges: Vec::with_capacity(max_ranges + 1),
+            max_ranges,
+        }
+    }
+
+    pub fn contains(&self, page_index: u32) -> bool {
+        let i = self.ranges.partition_point(|&(_, end)| end <= page_index);
+        i < self.ranges.len() && self.ranges[i].0 <= page_index
+    }
+
+    pub fn insert(&mut self, page_index: u32) {
+        // the first range that ends at or after the page
+        let i = self.ranges.partition_point(|&(_, end)| end < page_index);
+        if i < self.ranges.len() && self.ranges[i].0 <= page_index {
+            if self.ranges[i].1 == page_index {
+                // just after the range, that might now touch the next one
+                self.ranges[i].1 += 1;
+                if i + 1 < self.ranges.len() && self.ranges[i + 1].0 == self.ranges[i].1 {
+                    self.ranges[i].1 = self.ranges.remove(i + 1).1;
+                }
+            }
+            return;
+        }
+        if i < self.ranges.len() && self.ranges[i].0 == page_index + 1 {
+            // just before the range
+            self.ranges[i].0 = page_index;
+            return;
+        }
+        self.ranges.insert(i, (page_index, page_index + 1));
+
+        if self.ranges.len() > self.max_ranges {
+            let j = (0..self.ranges.len() - 1)
+                .min_by_key(|&j| self.ranges[j + 1].0 - self.ranges[j].1)
+                .unwrap();
+            self.ranges[j].1 = self.ranges.remove(j + 1).1;
+        }
+    }
+
+    /// Returns the ranges of the set, sorted.
+    pub fn ranges(&self) -> &[(u32, u32)] {
+        &self.ranges
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_page_range_set() {
+        let mut set = PageRangeSet::new(3);
+        for page in [10, 12, 11, 9, 20] {
+            set.insert(page);
+        }
+        assert_eq!(set.ranges(), &[(9, 13), (20, 21)]);
+        set.insert(11);
+        set.insert(13);
+        assert_eq!(set.ranges(), &[(9, 14), (20, 21)]);
+        assert!(set.contains(9) && set.contains(13) && set.contains(20));
+        assert!(!set.contains(8) && !set.contains(14) && !set.contains(21));
+
+        // the closest ranges are merged, and contain the pages in between
+        set.insert(0);
+        set.insert(17);
+        assert_eq!(set.ranges(), &[(0, 1), (9, 14), (17, 21)]);
+        assert!(set.contains(18));
+        assert!(!set.contains(15));
+
+        // a bounded number of ranges, whatever the number of pages
+        for page in (100..10000).step_by(2) {
+            set.insert(page);
+        }
+        assert_eq!(set.ranges().len(), 3);
+        for page in (100..10000).step_by(2) {
+            assert!(set.contains(page));
+        }
+        assert!(set.contains(0) && set.contains(9));
+    }
+}
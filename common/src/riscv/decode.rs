@@ -330,12 +330,29 @@ fn decode_uncompressed(inst: u32) -> Op {
             0x00007013 => Op::Andi { rd: rd(inst), rs1: rs1(inst), imm: i_imm(inst) },
             0x00001013 => match inst & 0xfe00707f {
                 0x00001013 => Op::Slli { rd: rd(inst), rs1: rs1(inst), imm: i_imm(inst) },
+                0x48001013 => Op::Bclri { rd: rd(inst), rs1: rs1(inst), imm: i_imm(inst) },
+                0x68001013 => Op::Binvi { rd: rd(inst), rs1: rs1(inst), imm: i_imm(inst) },
+                0x28001013 => Op::Bseti { rd: rd(inst), rs1: rs1(inst), imm: i_imm(inst) },
+                0x60001013 => match inst & 0xfff0707f {
+                    0x60001013 => Op::Clz { rd: rd(inst), rs1: rs1(inst) },
+                    0x60101013 => Op::Ctz { rd: rd(inst), rs1: rs1(inst) },
+                    0x60201013 => Op::Cpop { rd: rd(inst), rs1: rs1(inst) },
+                    0x60401013 => Op::SextB { rd: rd(inst), rs1: rs1(inst) },
+                    0x60501013 => Op::SextH { rd: rd(inst), rs1: rs1(inst) },
+                    _ => Op::Unknown,
+                },
                 _ => Op::Unknown,
             },
             0x00005013 => match inst & 0xfe00707f {
                 0x00005013 => Op::Srli { rd: rd(inst), rs1: rs1(inst), imm: i_imm(inst) },
                 0x40005013 => Op::Srai { rd: rd(inst), rs1: rs1(inst), imm: i_imm(inst) },
-                _ => Op::Unknown,
+                0x60005013 => Op::Rori { rd: rd(inst), rs1: rs1(inst), imm: i_imm(inst) },
+                0x48005013 => Op::Bexti { rd: rd(inst), rs1: rs1(inst), imm: i_imm(inst) },
+                _ => match inst & 0xfff0707f {
+                    0x28705013 => Op::OrcB { rd: rd(inst), rs1: rs1(inst) },
+                    0x69805013 => Op::Rev8 { rd: rd(inst), rs1: rs1(inst) },
+                    _ => Op::Unknown,
+                },
             },
             _ => Op::Unknown,
         },
@@ -358,16 +375,52 @@ fn decode_uncompressed(inst: u32) -> Op {
             0x02005033 => Op::Divu { rd: rd(inst), rs1: rs1(inst), rs2: rs2(inst) },
             0x02006033 => Op::Rem { rd: rd(inst), rs1: rs1(inst), rs2: rs2(inst) },
             0x02007033 => Op::Remu { rd: rd(inst), rs1: rs1(inst), rs2: rs2(inst) },
+            0x20002033 => Op::Sh1add { rd: rd(inst), rs1: rs1(inst), rs2: rs2(inst) },
+            0x20004033 => Op::Sh2add { rd: rd(inst), rs1: rs1(inst), rs2: rs2(inst) },
+            0x20006033 => Op::Sh3add { rd: rd(inst), rs1: rs1(inst), rs2: rs2(inst) },
+            0x40007033 => Op::Andn { rd: rd(inst), rs1: rs1(inst), rs2: rs2(inst) },
+            0x40006033 => Op::Orn { rd: rd(inst), rs1: rs1(inst), rs2: rs2(inst) },
+            0x40004033 => Op::Xnor { rd: rd(inst), rs1: rs1(inst), rs2: rs2(inst) },
+            0x0a006033 => Op::Max { rd: rd(inst), rs1: rs1(inst), rs2: rs2(inst) },
+            0x0a007033 => Op::Maxu { rd: rd(inst), rs1: rs1(inst), rs2: rs2(inst) },
+            0x0a004033 => Op::Min { rd: rd(inst), rs1: rs1(inst), rs2: rs2(inst) },
+            0x0a005033 => Op::Minu { rd: rd(inst), rs1: rs1(inst), rs2: rs2(inst) },
+            0x60001033 => Op::Rol { rd: rd(inst), rs1: rs1(inst), rs2: rs2(inst) },
+            0x60005033 => Op::Ror { rd: rd(inst), rs1: rs1(inst), rs2: rs2(inst) },
+            0x48001033 => Op::Bclr { rd: rd(inst), rs1: rs1(inst), rs2: rs2(inst) },
+            0x48005033 => Op::Bext { rd: rd(inst), rs1: rs1(inst), rs2: rs2(inst) },
+            0x68001033 => Op::Binv { rd: rd(inst), rs1: rs1(inst), rs2: rs2(inst) },
+            0x28001033 => Op::Bset { rd: rd(inst), rs1: rs1(inst), rs2: rs2(inst) },
+            // zext.h is only defined for rs2 = 0 (it is an instance of the "pack" instruction)
+            0x08004033 => match rs2(inst) {
+                0 => Op::ZextH { rd: rd(inst), rs1: rs1(inst) },
+                _ => Op::Unknown,
+            },
+            _ => Op::Unknown,
+        },
+        // FENCE, FENCE.TSO and PAUSE only differ in the ordering they request, which is irrelevant
+        // for a single hart
+        0x0000000f => match inst & 0x0000707f {
+            0x0000000f => Op::Fence,
+            _ => Op::Unknown,
+        },
+        0x0000002f => match inst & 0xf800707f {
+            0x1000202f => match rs2(inst) {
+                0 => Op::LrW { rd: rd(inst), rs1: rs1(inst) },
+                _ => Op::Unknown,
+            },
+            0x1800202f => Op::ScW { rd: rd(inst), rs1: rs1(inst), rs2: rs2(inst) },
+            0x0800202f => Op::AmoswapW { rd: rd(inst), rs1: rs1(inst), rs2: rs2(inst) },
+            0x0000202f => Op::AmoaddW { rd: rd(inst), rs1: rs1(inst), rs2: rs2(inst) },
+            0x2000202f => Op::AmoxorW { rd: rd(inst), rs1: rs1(inst), rs2: rs2(inst) },
+            0x6000202f => Op::AmoandW { rd: rd(inst), rs1: rs1(inst), rs2: rs2(inst) },
+            0x4000202f => Op::AmoorW { rd: rd(inst), rs1: rs1(inst), rs2: rs2(inst) },
+            0x8000202f => Op::AmominW { rd: rd(inst), rs1: rs1(inst), rs2: rs2(inst) },
+            0xa000202f => Op::AmomaxW { rd: rd(inst), rs1: rs1(inst), rs2: rs2(inst) },
+            0xc000202f => Op::AmominuW { rd: rd(inst), rs1: rs1(inst), rs2: rs2(inst) },
+            0xe000202f => Op::AmomaxuW { rd: rd(inst), rs1: rs1(inst), rs2: rs2(inst) },
             _ => Op::Unknown,
         },
-        // 0x0000000f => match inst & 0x0000707f {
-        //     0x0000000f => match inst & 0xffffffff {
-        //         0x8330000f => Op::RV_OP_FENCE_TSO,
-        //         0x0100000f => Op::RV_OP_PAUSE,
-        //         _ => Op::RV_OP_FENCE,
-        //     },
-        //     _ => Op::Unknown,
-        // },
         0x00000073 => match inst & 0xffffffff {
             0x00000073 => Op::Ecall,
             0x00100073 => Op::Break,
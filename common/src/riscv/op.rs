@@ -56,6 +56,56 @@ pub enum Op {
 
     Xori { rd: u8, rs1: u8, imm: i32 },
 
+    Fence,
+
+    // "A" extension (atomics); the aq/rl bits are irrelevant for a single hart, and are dropped
+    LrW { rd: u8, rs1: u8 },
+    ScW { rd: u8, rs1: u8, rs2: u8 },
+    AmoswapW { rd: u8, rs1: u8, rs2: u8 },
+    AmoaddW { rd: u8, rs1: u8, rs2: u8 },
+    AmoxorW { rd: u8, rs1: u8, rs2: u8 },
+    AmoandW { rd: u8, rs1: u8, rs2: u8 },
+    AmoorW { rd: u8, rs1: u8, rs2: u8 },
+    AmominW { rd: u8, rs1: u8, rs2: u8 },
+    AmomaxW { rd: u8, rs1: u8, rs2: u8 },
+    AmominuW { rd: u8, rs1: u8, rs2: u8 },
+    AmomaxuW { rd: u8, rs1: u8, rs2: u8 },
+
+    // "Zba" extension (address generation)
+    Sh1add { rd: u8, rs1: u8, rs2: u8 },
+    Sh2add { rd: u8, rs1: u8, rs2: u8 },
+    Sh3add { rd: u8, rs1: u8, rs2: u8 },
+
+    // "Zbb" extension (basic bit-manipulation)
+    Andn { rd: u8, rs1: u8, rs2: u8 },
+    Orn { rd: u8, rs1: u8, rs2: u8 },
+    Xnor { rd: u8, rs1: u8, rs2: u8 },
+    Clz { rd: u8, rs1: u8 },
+    Ctz { rd: u8, rs1: u8 },
+    Cpop { rd: u8, rs1: u8 },
+    Max { rd: u8, rs1: u8, rs2: u8 },
+    Maxu { rd: u8, rs1: u8, rs2: u8 },
+    Min { rd: u8, rs1: u8, rs2: u8 },
+    Minu { rd: u8, rs1: u8, rs2: u8 },
+    SextB { rd: u8, rs1: u8 },
+    SextH { rd: u8, rs1: u8 },
+    ZextH { rd: u8, rs1: u8 },
+    Rol { rd: u8, rs1: u8, rs2: u8 },
+    Ror { rd: u8, rs1: u8, rs2: u8 },
+    Rori { rd: u8, rs1: u8, imm: i32 },
+    OrcB { rd: u8, rs1: u8 },
+    Rev8 { rd: u8, rs1: u8 },
+
+    // "Zbs" extension (single-bit instructions)
+    Bclr { rd: u8, rs1: u8, rs2: u8 },
+    Bclri { rd: u8, rs1: u8, imm: i32 },
+    Bext { rd: u8, rs1: u8, rs2: u8 },
+    Bexti { rd: u8, rs1: u8, imm: i32 },
+    Binv { rd: u8, rs1: u8, rs2: u8 },
+    Binvi { rd: u8, rs1: u8, imm: i32 },
+    Bset { rd: u8, rs1: u8, rs2: u8 },
+    Bseti { rd: u8, rs1: u8, imm: i32 },
+
     Ecall,
     Break,
 }
@@ -0,0 +1,273 @@
+//! Support for the typed RPC services generated by the `#[rpc]` attribute of `vanadium_macros`.
+//!
+//! A service is a trait whose methods take serializable arguments and return a
+//! `Result<T, RpcError>`. From the trait, the macro generates the enums of the requests and of the
+//! responses, a `dispatch` method that the V-App calls from its message handler, and an async
+//! client over any [`RpcTransport`].
+//!
+//! Requests are serialized with postcard as the version of the service, followed by the request.
+//! Responses are serialized as a `Result<Response, RpcError>`, so that all the services report
+//! errors in the same envelope.
+//!
+//! The trait is defined in the crate shared by the V-App and its client:
+//!
+//! ```ignore
+//! #[rpc(version = 1)]
+//! pub trait Calculator {
+//!     fn add(&mut self, a: u32, b: u32) -> Result<u64, RpcError>;
+//! }
+//! ```
+//!
+//! The V-App implements it, and dispatches the messages from the handler of its `AppBuilder`:
+//!
+//! ```ignore
+//! fn process_message(app: &mut App, msg: &[u8]) -> Vec<u8> {
+//!     CalculatorService { app }.dispatch(msg)
+//! }
+//! ```
+//!
+//! The client calls the methods of a `CalculatorClient`, over the transport of the V-App:
+//!
+//! ```ignore
+//! let mut client = CalculatorClient::new(transport);
+//! let sum = client.add(1, 2).await?;
+//! ```
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt;
+use core::future::Future;
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+/// The errors that a V-App reports to the client in the response envelope.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RpcError {
+    /// The V-App implements a different version of the service.
+    UnsupportedVersion { supported: u32 },
+    /// The request could not be deserialized.
+    InvalidRequest,
+    /// The user rejected the request.
+    UserRejected,
+    /// An error specific to the service.
+    Custom { code: u32, message: String },
+}
+
+impl fmt::Display for RpcError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RpcError::UnsupportedVersion { supported } => {
+                write!(
+                    f,
+                    "Unsupported version, the V-App supports version {}",
+                    supported
+                )
+            }
+            RpcError::InvalidRequest => write!(f, "Invalid request"),
+            RpcError::UserRejected => write!(f, "Rejected by the user"),
+            RpcError::Custom { code, message } => write!(f, "Error {}: {}", code, message),
+        }
+    }
+}
+
+impl core::error::Error for RpcError {}
+
+/// The errors of the calls of a generated client.
+#[derive(Debug)]
+pub enum RpcClientError<E> {
+    /// The transport failed to exchange the messages with the V-App.
+    Transport(E),
+    /// The V-App returned an error.
+    Remote(RpcError),
+    /// The response could not be deserialized, or does not match the request.
+    InvalidResponse,
+}
+
+impl<E: fmt::Display> fmt::Display for RpcClientError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RpcClientError::Transport(e) => write!(f, "Transport error: {}", e),
+            RpcClientError::Remote(e) => write!(f, "V-App error: {}", e),
+            RpcClientError::InvalidResponse => write!(f, "Invalid response"),
+        }
+    }
+}
+
+impl<E: fmt::Debug + fmt::Display> core::error::Error for RpcClientError<E> {}
+
+/// Sends a serialized request to a V-App, and returns its serialized response.
+pub trait RpcTransport {
+    type Error;
+
+    fn call(&mut self, request: &[u8])
+        -> impl Future<Output = Result<Vec<u8>, Self::Error>> + Send;
+}
+
+/// Serializes a request for the given version of a service.
+pub fn encode_request<R: Serialize>(version: u32, request: &R) -> Vec<u8> {
+    postcard::to_allocvec(&(version, request)).expect("Serialization failed")
+}
+
+/// Deserializes a request, checking that it is for the given version of the service.
+pub fn decode_request<R: DeserializeOwned>(version: u32, data: &[u8]) -> Result<R, RpcError> {
+    let (request_version, rest) =
+        postcard::take_from_bytes::<u32>(data).map_err(|_| RpcError::InvalidRequest)?;
+    if request_version != version {
+        return Err(RpcError::UnsupportedVersion { supported: version });
+    }
+    match postcard::take_from_bytes(rest) {
+        Ok((request, [])) => Ok(request),
+        _ => Err(RpcError::InvalidRequest),
+    }
+}
+
+/// Deserializes a request, passes it to `handler`, and returns the serialized response envelope.
+pub fn dispatch<Req, Resp>(
+    version: u32,
+    request: &[u8],
+    handler: impl FnOnce(Req) -> Result<Resp, RpcError>,
+) -> Vec<u8>
+where
+    Req: DeserializeOwned,
+    Resp: Serialize,
+{
+    let response = decode_request(version, request).and_then(handler);
+    postcard::to_allocvec(&response).expect("Serialization failed")
+}
+
+/// Sends a request with the given version of the service, and returns the response of the V-App.
+pub async fn call<T, Req, Resp>(
+    transport: &mut T,
+    version: u32,
+    request: &Req,
+) -> Result<Resp, RpcClientError<T::Error>>
+where
+    T: RpcTransport,
+    Req: Serialize,
+    Resp: DeserializeOwned,
+{
+    let response = transport
+        .call(&encode_request(version, request))
+        .await
+        .map_err(RpcClientError::Transport)?;
+    let response: Result<Resp, RpcError> =
+        postcard::from_bytes(&response).map_err(|_| RpcClientError::InvalidResponse)?;
+    response.map_err(RpcClientError::Remote)
+}
+
+// Used by the code generated by the `#[rpc]` attribute.
+#[doc(hidden)]
+pub mod __private {
+    pub use alloc::vec::Vec;
+    pub use serde;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::string::ToString;
+    use alloc::vec;
+    use vanadium_macros::rpc;
+
+    #[rpc(version = 2, crate = crate::rpc)]
+    pub trait Calculator {
+        /// Adds two numbers.
+        fn add(&mut self, a: u32, b: u32) -> Result<u64, RpcError>;
+        fn count(&self) -> Result<u32, RpcError>;
+        fn echo(&mut self, data: Vec<u8>) -> Result<Vec<u8>, RpcError>;
+    }
+
+    struct Service {
+        calls: u32,
+    }
+
+    impl Calculator for Service {
+        fn add(&mut self, a: u32, b: u32) -> Result<u64, RpcError> {
+            self.calls += 1;
+            Ok(a as u64 + b as u64)
+        }
+
+        fn count(&self) -> Result<u32, RpcError> {
+            Ok(self.calls)
+        }
+
+        fn echo(&mut self, data: Vec<u8>) -> Result<Vec<u8>, RpcError> {
+            self.calls += 1;
+            if data.is_empty() {
+                return Err(RpcError::Custom {
+                    code: 1,
+                    message: "empty".to_string(),
+                });
+            }
+            Ok(data)
+        }
+    }
+
+    // Dispatches the requests to the service, as the V-App would
+    impl RpcTransport for Service {
+        type Error = core::convert::Infallible;
+
+        fn call(
+            &mut self,
+            request: &[u8],
+        ) -> impl Future<Output = Result<Vec<u8>, Self::Error>> + Send {
+            core::future::ready(Ok(self.dispatch(request)))
+        }
+    }
+
+    // The futures of the in-memory transport are always ready
+    fn block_on<F: Future>(future: F) -> F::Output {
+        use core::pin::pin;
+        use core::task::{Context, Poll, Waker};
+
+        match pin!(future).poll(&mut Context::from_waker(Waker::noop())) {
+            Poll::Ready(output) => output,
+            Poll::Pending => panic!("The future is not ready"),
+        }
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        assert_eq!(CalculatorRequest::VERSION, 2);
+
+        let mut client = CalculatorClient::new(Service { calls: 0 });
+        assert_eq!(block_on(client.add(u32::MAX, 1)).unwrap(), 1 << 32);
+        assert_eq!(block_on(client.echo(vec![1, 2, 3])).unwrap(), vec![1, 2, 3]);
+        assert_eq!(block_on(client.count()).unwrap(), 2);
+        assert!(matches!(
+            block_on(client.echo(vec![])),
+            Err(RpcClientError::Remote(RpcError::Custom { code: 1, .. }))
+        ));
+        assert_eq!(client.into_inner().calls, 3);
+    }
+
+    #[test]
+    fn test_dispatch_errors() {
+        let mut service = Service { calls: 0 };
+        let decode = |response: Vec<u8>| {
+            postcard::from_bytes::<Result<CalculatorResponse, RpcError>>(&response)
+                .unwrap()
+                .map(|_| ())
+        };
+
+        let request = CalculatorRequest::Add { a: 1, b: 2 };
+        assert_eq!(
+            decode(service.dispatch(&encode_request(1, &request))),
+            Err(RpcError::UnsupportedVersion { supported: 2 })
+        );
+
+        let mut data = encode_request(2, &request);
+        assert_eq!(decode(service.dispatch(&data)), Ok(()));
+        data.push(0);
+        assert_eq!(
+            decode(service.dispatch(&data)),
+            Err(RpcError::InvalidRequest)
+        );
+        assert_eq!(
+            decode(service.dispatch(&[2, 42])),
+            Err(RpcError::InvalidRequest)
+        );
+        assert_eq!(decode(service.dispatch(&[])), Err(RpcError::InvalidRequest));
+        assert_eq!(service.calls, 1);
+    }
+}
@@ -0,0 +1,580 @@
+//! Compact binary format for the execution traces of V-Apps.
+//!
+//! A trace is the 5-byte header returned by [`header`], followed by a sequence of encoded
+//! [`TraceEvent`]s. Each event starts with a one-byte tag, followed by its fields in
+//! little-endian order:
+//!
+//! | tag  | event         | fields                          |
+//! |------|---------------|---------------------------------|
+//! | 0x01 | `Instruction` | pc: u32, inst: u32              |
+//! | 0x02 | `Instruction` | pc: u32, inst: u16 (compressed) |
+//! | 0x03 | `MemoryRead`  | address: u32, size: u8          |
+//! | 0x04 | `MemoryWrite` | address: u32, size: u8          |
+//! | 0x05 | `Ecall`       | code: u32                       |
+//! | 0x06 | `PageLoad`    | segment: u8, page_index: u32    |
+//! | 0x07 | `PageCommit`  | segment: u8, page_index: u32    |
+//! | 0x08 | `PageAccess`  | segment: u8, page_index: u32    |
+//! | 0x09 | `PageAccess`  | segment: u8, page_index: u32 (write) |
+//!
+//! The memory accesses of an instruction, if any, are recorded right after it.
+//!
+//! Traces of page accesses only, produced with a [`PageAccessRecorder`], are much smaller than
+//! full traces, and are enough to simulate the page caches of the VM.
+
+use alloc::vec::Vec;
+
+use crate::constants::{page_start, PAGE_SIZE};
+use crate::riscv::op::Op;
+
+/// The first bytes of every trace.
+pub const TRACE_MAGIC: [u8; 4] = *b"VTRC";
+
+/// The version of the trace format, stored after the magic.
+pub const TRACE_VERSION: u8 = 1;
+
+/// The maximum size of an encoded event.
+pub const MAX_EVENT_SIZE: usize = 9;
+
+const TAG_INSTRUCTION: u8 = 0x01;
+const TAG_COMPRESSED_INSTRUCTION: u8 = 0x02;
+const TAG_MEMORY_READ: u8 = 0x03;
+const TAG_MEMORY_WRITE: u8 = 0x04;
+const TAG_ECALL: u8 = 0x05;
+const TAG_PAGE_LOAD: u8 = 0x06;
+const TAG_PAGE_COMMIT: u8 = 0x07;
+const TAG_PAGE_ACCESS: u8 = 0x08;
+const TAG_PAGE_WRITE: u8 = 0x09;
+
+/// Returns the header that starts every trace.
+pub fn header() -> [u8; 5] {
+    let mut header = [0u8; 5];
+    header[..4].copy_from_slice(&TRACE_MAGIC);
+    header[4] = TRACE_VERSION;
+    header
+}
+
+/// An event in the execution of a V-App.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TraceEvent {
+    /// An instruction is about to be executed. `size` is 2 for compressed instructions, and only
+    /// the low 16 bits of `inst` are meaningful in that case.
+    Instruction { pc: u32, inst: u32, size: u8 },
+    /// The last instruction reads `size` bytes at `address`.
+    MemoryRead { address: u32, size: u8 },
+    /// The last instruction writes `size` bytes at `address`. Atomic memory operations, that both
+    /// read and write the memory, are recorded as writes.
+    MemoryWrite { address: u32, size: u8 },
+    /// The last instruction is an ECALL with the given code.
+    Ecall { code: u32 },
+    /// A page of the segment with the given index in the manifest is loaded from the host.
+    PageLoad { segment: u8, page_index: u32 },
+    /// A page of the segment with the given index in the manifest is committed to the host.
+    PageCommit { segment: u8, page_index: u32 },
+    /// A page of the segment with the given index in the manifest is accessed, and written if
+    /// `write` is true. See [`PageAccessRecorder`].
+    PageAccess {
+        segment: u8,
+        page_index: u32,
+        write: bool,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TraceError {
+    /// The data ends in the middle of an event.
+    Truncated,
+    InvalidTag(u8),
+}
+
+impl core::fmt::Display for TraceError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            TraceError::Truncated => write!(f, "truncated trace event"),
+            TraceError::InvalidTag(tag) => write!(f, "invalid trace event tag 0x{:02x}", tag),
+        }
+    }
+}
+
+impl TraceEvent {
+    /// Encodes the event in `buf`, and returns the number of bytes written.
+    pub fn encode(&self, buf: &mut [u8; MAX_EVENT_SIZE]) -> usize {
+        let (tag, fields_len) = match *self {
+            TraceEvent::Instruction { pc, inst, size } => {
+                buf[1..5].copy_from_slice(&pc.to_le_bytes());
+                if size == 2 {
+                    buf[5..7].copy_from_slice(&(inst as u16).to_le_bytes());
+                    (TAG_COMPRESSED_INSTRUCTION, 6)
+                } else {
+                    buf[5..9].copy_from_slice(&inst.to_le_bytes());
+                    (TAG_INSTRUCTION, 8)
+                }
+            }
+            TraceEvent::MemoryRead { address, size }
+            | TraceEvent::MemoryWrite { address, size } => {
+                buf[1..5].copy_from_slice(&address.to_le_bytes());
+                buf[5] = size;
+                let tag = if matches!(self, TraceEvent::MemoryRead { .. }) {
+                    TAG_MEMORY_READ
+                } else {
+                    TAG_MEMORY_WRITE
+                };
+                (tag, 5)
+            }
+            TraceEvent::Ecall { code } => {
+                buf[1..5].copy_from_slice(&code.to_le_bytes());
+                (TAG_ECALL, 4)
+            }
+            TraceEvent::PageLoad {
+                segment,
+                page_index,
+            }
+            | TraceEvent::PageCommit {
+                segment,
+                page_index,
+            } => {
+                buf[1] = segment;
+                buf[2..6].copy_from_slice(&page_index.to_le_bytes());
+                let tag = if matches!(self, TraceEvent::PageLoad { .. }) {
+                    TAG_PAGE_LOAD
+                } else {
+                    TAG_PAGE_COMMIT
+                };
+                (tag, 5)
+            }
+            TraceEvent::PageAccess {
+                segment,
+                page_index,
+                write,
+            } => {
+                buf[1] = segment;
+                buf[2..6].copy_from_slice(&page_index.to_le_bytes());
+                (
+                    if write {
+                        TAG_PAGE_WRITE
+                    } else {
+                        TAG_PAGE_ACCESS
+                    },
+                    5,
+                )
+            }
+        };
+        buf[0] = tag;
+        1 + fields_len
+    }
+
+    /// Decodes the event at the start of `data`, and returns it with its encoded size.
+    pub fn decode(data: &[u8]) -> Result<(Self, usize), TraceError> {
+        let tag = *data.first().ok_or(TraceError::Truncated)?;
+        let fields_len = match tag {
+            TAG_INSTRUCTION => 8,
+            TAG_COMPRESSED_INSTRUCTION => 6,
+            TAG_MEMORY_READ | TAG_MEMORY_WRITE | TAG_PAGE_LOAD | TAG_PAGE_COMMIT
+            | TAG_PAGE_ACCESS | TAG_PAGE_WRITE => 5,
+            TAG_ECALL => 4,
+            _ => return Err(TraceError::InvalidTag(tag)),
+        };
+        let fields = data.get(1..1 + fields_len).ok_or(TraceError::Truncated)?;
+        let u32_at = |i: usize| u32::from_le_bytes(fields[i..i + 4].try_into().unwrap());
+
+        let event = match tag {
+            TAG_INSTRUCTION => TraceEvent::Instruction {
+                pc: u32_at(0),
+                inst: u32_at(4),
+                size: 4,
+            },
+            TAG_COMPRESSED_INSTRUCTION => TraceEvent::Instruction {
+                pc: u32_at(0),
+                inst: u16::from_le_bytes([fields[4], fields[5]]) as u32,
+                size: 2,
+            },
+            TAG_MEMORY_READ => TraceEvent::MemoryRead {
+                address: u32_at(0),
+                size: fields[4],
+            },
+            TAG_MEMORY_WRITE => TraceEvent::MemoryWrite {
+                address: u32_at(0),
+                size: fields[4],
+            },
+            TAG_ECALL => TraceEvent::Ecall { code: u32_at(0) },
+            TAG_PAGE_LOAD => TraceEvent::PageLoad {
+                segment: fields[0],
+                page_index: u32_at(1),
+            },
+            TAG_PAGE_COMMIT => TraceEvent::PageCommit {
+                segment: fields[0],
+                page_index: u32_at(1),
+            },
+            _ => TraceEvent::PageAccess {
+                segment: fields[0],
+                page_index: u32_at(1),
+                write: tag == TAG_PAGE_WRITE,
+            },
+        };
+        Ok((event, 1 + fields_len))
+    }
+}
+
+/// Returns the memory access that `op` performs when executed with the registers `regs`, if any.
+///
+/// Must be called before executing the instruction, as it might overwrite the base register.
+pub fn memory_access(op: &Op, regs: &[u32; 32]) -> Option<TraceEvent> {
+    let addr = |rs1: &u8, imm: &i32| regs[*rs1 as usize].wrapping_add(*imm as u32);
+    let event = match op {
+        Op::Lb { rs1, imm, .. } | Op::Lbu { rs1, imm, .. } => TraceEvent::MemoryRead {
+            address: addr(rs1, imm),
+            size: 1,
+        },
+        Op::Lh { rs1, imm, .. } | Op::Lhu { rs1, imm, .. } => TraceEvent::MemoryRead {
+            address: addr(rs1, imm),
+            size: 2,
+        },
+        Op::Lw { rs1, imm, .. } => TraceEvent::MemoryRead {
+            address: addr(rs1, imm),
+            size: 4,
+        },
+        Op::Sb { rs1, imm, .. } => TraceEvent::MemoryWrite {
+            address: addr(rs1, imm),
+            size: 1,
+        },
+        Op::Sh { rs1, imm, .. } => TraceEvent::MemoryWrite {
+            address: addr(rs1, imm),
+            size: 2,
+        },
+        Op::Sw { rs1, imm, .. } => TraceEvent::MemoryWrite {
+            address: addr(rs1, imm),
+            size: 4,
+        },
+        Op::LrW { rs1, .. } => TraceEvent::MemoryRead {
+            address: regs[*rs1 as usize],
+            size: 4,
+        },
+        Op::ScW { rs1, .. }
+        | Op::AmoswapW { rs1, .. }
+        | Op::AmoaddW { rs1, .. }
+        | Op::AmoxorW { rs1, .. }
+        | Op::AmoandW { rs1, .. }
+        | Op::AmoorW { rs1, .. }
+        | Op::AmominW { rs1, .. }
+        | Op::AmomaxW { rs1, .. }
+        | Op::AmominuW { rs1, .. }
+        | Op::AmomaxuW { rs1, .. } => TraceEvent::MemoryWrite {
+            address: regs[*rs1 as usize],
+            size: 4,
+        },
+        _ => return None,
+    };
+    Some(event)
+}
+
+/// Calls `emit` with the events describing the instruction `inst` at `pc`, decoded as `op`, that
+/// is about to be executed with the registers `regs`.
+pub fn record_instruction(
+    pc: u32,
+    inst: u32,
+    op: &Op,
+    inst_size: u32,
+    regs: &[u32; 32],
+    mut emit: impl FnMut(&TraceEvent),
+) {
+    emit(&TraceEvent::Instruction {
+        pc,
+        inst,
+        size: inst_size as u8,
+    });
+    if let Some(access) = memory_access(op, regs) {
+        emit(&access);
+    }
+    if let Op::Ecall = op {
+        emit(&TraceEvent::Ecall { code: regs[5] });
+    }
+}
+
+/// Turns the instructions and the memory accesses of a trace into the page accesses of each
+/// segment, as seen by the page caches of the VM: consecutive accesses to the same page of a
+/// segment are a single access, that is a write if any of them is. Therefore, a write is recorded
+/// again for the last accessed page of a segment if it was only read so far.
+pub struct PageAccessRecorder {
+    // the start and end address of each segment
+    segments: Vec<(u32, u32)>,
+    // for each segment, the last accessed page, and whether it was written
+    last_access: Vec<Option<(u32, bool)>>,
+}
+
+impl PageAccessRecorder {
+    /// Creates a recorder for the segments with the given start and end addresses, in the order of
+    /// the segment table of the manifest.
+    pub fn new(segments: Vec<(u32, u32)>) -> Self {
+        let last_access = alloc::vec![None; segments.len()];
+        Self {
+            segments,
+            last_access,
+        }
+    }
+
+    /// Calls `emit` with the page accesses caused by `event`, if any. Accesses outside of the
+    /// segments are ignored, and page accesses are recorded as they are.
+    pub fn record(&mut self, event: &TraceEvent, mut emit: impl FnMut(&TraceEvent)) {
+        let (address, size, write) = match *event {
+            TraceEvent::Instruction { pc, size, .. } => (pc, size, false),
+            TraceEvent::MemoryRead { address, size } => (address, size, false),
+            TraceEvent::MemoryWrite { address, size } => (address, size, true),
+            TraceEvent::PageAccess {
+                segment,
+                page_index,
+                write,
+            } => {
+                self.access(segment, page_index, write, &mut emit);
+                return;
+            }
+            _ => return,
+        };
+        // an unaligned access might span two pages
+        let last_address = address.saturating_add(size.max(1) as u32 - 1);
+        self.access_address(address, write, &mut emit);
+        if page_start(last_address) != page_start(address) {
+            self.access_address(last_address, write, &mut emit);
+        }
+    }
+
+    fn access_address(&mut self, address: u32, write: bool, emit: &mut impl FnMut(&TraceEvent)) {
+        let Some(segment) = self
+            .segments
+            .iter()
+            .position(|&(start, end)| start <= address && address < end)
+        else {
+            return;
+        };
+        let page_index =
+            (page_start(address) - page_start(self.segments[segment].0)) / PAGE_SIZE as u32;
+        self.access(segment as u8, page_index, write, emit);
+    }
+
+    fn access(
+        &mut self,
+        segment: u8,
+        page_index: u32,
+        write: bool,
+        emit: &mut impl FnMut(&TraceEvent),
+    ) {
+        let Some(last_access) = self.last_access.get_mut(segment as usize) else {
+            return;
+        };
+        match last_access {
+            Some((last_page, written)) if *last_page == page_index => {
+                if !write || *written {
+                    return;
+                }
+                *written = true;
+            }
+            _ => *last_access = Some((page_index, write)),
+        }
+        emit(&TraceEvent::PageAccess {
+            segment,
+            page_index,
+            write,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode_decode() {
+        let events = [
+            TraceEvent::Instruction {
+                pc: 0x10000,
+                inst: 0x00b14603,
+                size: 4,
+            },
+            TraceEvent::Instruction {
+                pc: 0x10004,
+                inst: 0x4501,
+                size: 2,
+            },
+            TraceEvent::MemoryRead {
+                address: 0x20001,
+                size: 1,
+            },
+            TraceEvent::MemoryWrite {
+                address: 0xfffffffc,
+                size: 4,
+            },
+            TraceEvent::Ecall { code: 0x1234 },
+            TraceEvent::PageLoad {
+                segment: 0,
+                page_index: 3,
+            },
+            TraceEvent::PageCommit {
+                segment: 2,
+                page_index: 0xabcdef,
+            },
+            TraceEvent::PageAccess {
+                segment: 1,
+                page_index: 5,
+                write: false,
+            },
+            TraceEvent::PageAccess {
+                segment: 3,
+                page_index: 0x12345,
+                write: true,
+            },
+        ];
+        let expected_sizes = [9, 7, 6, 6, 5, 6, 6, 6, 6];
+
+        for (event, expected_size) in events.iter().zip(expected_sizes) {
+            let mut buf = [0u8; MAX_EVENT_SIZE];
+            let size = event.encode(&mut buf);
+            assert_eq!(size, expected_size);
+            assert_eq!(TraceEvent::decode(&buf[..size]), Ok((*event, size)));
+            assert_eq!(
+                TraceEvent::decode(&buf[..size - 1]),
+                Err(TraceError::Truncated)
+            );
+        }
+
+        assert_eq!(TraceEvent::decode(&[]), Err(TraceError::Truncated));
+        assert_eq!(
+            TraceEvent::decode(&[0x42]),
+            Err(TraceError::InvalidTag(0x42))
+        );
+    }
+
+    #[test]
+    fn test_page_access_recorder() {
+        let access = |segment, page_index, write| TraceEvent::PageAccess {
+            segment,
+            page_index,
+            write,
+        };
+        let instruction = |pc, size| TraceEvent::Instruction { pc, inst: 0, size };
+
+        // an unaligned code segment, and a data segment
+        let mut recorder =
+            PageAccessRecorder::new(alloc::vec![(0x10010, 0x10300), (0x20000, 0x20100)]);
+        let events = [
+            instruction(0x10010, 4),
+            instruction(0x100fc, 4),
+            TraceEvent::MemoryRead {
+                address: 0x20010,
+                size: 4,
+            },
+            instruction(0x10100, 2),
+            TraceEvent::MemoryWrite {
+                address: 0x20020,
+                size: 4,
+            },
+            instruction(0x10102, 4),
+            TraceEvent::MemoryWrite {
+                address: 0x20030,
+                size: 1,
+            },
+            // spans the second and third pages of the code segment
+            instruction(0x101fe, 4),
+            // outside of the segments
+            TraceEvent::MemoryRead {
+                address: 0x30000,
+                size: 4,
+            },
+            TraceEvent::Ecall { code: 1 },
+            access(1, 0, false),
+            access(1, 7, true),
+        ];
+        let mut recorded = Vec::new();
+        for event in events.iter() {
+            recorder.record(event, |e| recorded.push(*e));
+        }
+        assert_eq!(
+            recorded,
+            [
+                access(0, 0, false),
+                access(1, 0, false),
+                access(0, 1, false),
+                access(1, 0, true),
+                access(0, 2, false),
+                access(1, 7, true),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_memory_access() {
+        let mut regs = [0u32; 32];
+        regs[2] = 0x1000;
+        regs[10] = 0x2000;
+
+        assert_eq!(
+            memory_access(
+                &Op::Lw {
+                    rd: 10,
+                    rs1: 2,
+                    imm: -4
+                },
+                &regs
+            ),
+            Some(TraceEvent::MemoryRead {
+                address: 0xffc,
+                size: 4
+            })
+        );
+        assert_eq!(
+            memory_access(
+                &Op::Sb {
+                    rs1: 10,
+                    rs2: 1,
+                    imm: 3
+                },
+                &regs
+            ),
+            Some(TraceEvent::MemoryWrite {
+                address: 0x2003,
+                size: 1
+            })
+        );
+        assert_eq!(
+            memory_access(
+                &Op::AmoaddW {
+                    rd: 1,
+                    rs1: 10,
+                    rs2: 2
+                },
+                &regs
+            ),
+            Some(TraceEvent::MemoryWrite {
+                address: 0x2000,
+                size: 4
+            })
+        );
+        assert_eq!(
+            memory_access(
+                &Op::Addi {
+                    rd: 1,
+                    rs1: 2,
+                    imm: 4
+                },
+                &regs
+            ),
+            None
+        );
+    }
+
+    #[test]
+    fn test_record_instruction() {
+        let mut regs = [0u32; 32];
+        regs[5] = 0x42;
+        let mut events = alloc::vec::Vec::new();
+        record_instruction(0x100, 0x00000073, &Op::Ecall, 4, &regs, |e| events.push(*e));
+        assert_eq!(
+            events,
+            [
+                TraceEvent::Instruction {
+                    pc: 0x100,
+                    inst: 0x00000073,
+                    size: 4
+                },
+                TraceEvent::Ecall { code: 0x42 }
+            ]
+        );
+    }
+}
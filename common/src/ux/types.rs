@@ -21,6 +21,8 @@ pub enum Action {
 pub enum EventCode {
     Ticker = 0,
     Action = 1,
+    // the host aborted the request that the V-App is processing
+    Abort = 2,
     Unknown = 0xFFFFFFFF,
 }
 
@@ -29,6 +31,7 @@ impl From<u32> for EventCode {
         match value {
             0 => EventCode::Ticker,
             1 => EventCode::Action,
+            2 => EventCode::Abort,
             _ => EventCode::Unknown,
         }
     }
@@ -59,6 +62,7 @@ pub struct TickerEvent {}
 pub enum Event {
     Ticker,
     Action(Action),
+    Abort,
     Unknown([u8; 16]),
 }
 
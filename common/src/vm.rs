@@ -12,6 +12,7 @@ use crate::{
     riscv::op::Op,
 };
 use alloc::{format, vec::Vec};
+use serde::{Deserialize, Serialize};
 
 #[derive(Debug)]
 pub enum MemoryError {
@@ -57,11 +58,79 @@ pub trait PagedMemory: fmt::Debug {
 
     /// Retrieves a mutable reference to the page at the given index.
     fn get_page(&mut self, page_index: u32) -> Result<Self::PageRef<'_>, MemoryError>;
+
+    /// Returns the decoded instruction at the given offset of the page, and its size, if it was
+    /// previously stored with `store_decoded_instruction`.
+    ///
+    /// Implementations that cache decoded instructions must discard them whenever the page is
+    /// evicted or its content changes. The default implementation does not cache anything.
+    #[inline]
+    fn get_decoded_instruction(&mut self, _page_index: u32, _offset: u32) -> Option<(Op, u32)> {
+        None
+    }
+
+    /// Stores the decoded instruction at the given offset of the page, if supported.
+    #[inline]
+    fn store_decoded_instruction(&mut self, _page_index: u32, _offset: u32, _op: Op, _size: u32) {}
+}
+
+/// The decoded instructions of a page of code, indexed by their offset in the page.
+/// Instructions are 2-byte aligned, therefore there is a slot for each half-word of the page.
+#[derive(Clone, Debug)]
+pub struct DecodedPage {
+    ops: [Op; PAGE_SIZE / 2],
+    sizes: [u8; PAGE_SIZE / 2], // 0 if the instruction was not decoded yet
+}
+
+impl Default for DecodedPage {
+    fn default() -> Self {
+        Self {
+            ops: [Op::Unknown; PAGE_SIZE / 2],
+            sizes: [0; PAGE_SIZE / 2],
+        }
+    }
+}
+
+impl DecodedPage {
+    /// Creates an empty `DecodedPage`.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Discards all the decoded instructions.
+    pub fn clear(&mut self) {
+        self.sizes = [0; PAGE_SIZE / 2];
+    }
+
+    /// Returns the decoded instruction at the given offset, and its size, if present.
+    #[inline]
+    pub fn get(&self, offset: u32) -> Option<(Op, u32)> {
+        let i = (offset / 2) as usize;
+        match self.sizes.get(i) {
+            Some(&size) if size != 0 && offset.is_multiple_of(2) => {
+                Some((self.ops[i], size as u32))
+            }
+            _ => None,
+        }
+    }
+
+    /// Stores the decoded instruction at the given offset.
+    #[inline]
+    pub fn set(&mut self, offset: u32, op: Op, size: u32) {
+        let i = (offset / 2) as usize;
+        if offset.is_multiple_of(2) && i < self.sizes.len() {
+            self.ops[i] = op;
+            self.sizes[i] = size as u8;
+        }
+    }
 }
 
 /// A simple implementation of `PagedMemory` using a vector of pages.
+/// The pages are zero-initialized, and only allocated up to the last one that is accessed, so that
+/// large memories that are mostly unused (like the heap) are cheap.
 #[derive(Clone, Debug)]
 pub struct VecMemory {
+    n_pages: usize,
     pages: Vec<Page>,
 }
 
@@ -72,30 +141,65 @@ impl PagedMemory for VecMemory {
         Self: 'a;
 
     fn get_page(&mut self, page_index: u32) -> Result<Self::PageRef<'_>, MemoryError> {
-        self.pages
-            .get_mut(page_index as usize)
-            .ok_or(MemoryError::PageNotFound)
+        let page_index = page_index as usize;
+        if page_index >= self.n_pages {
+            return Err(MemoryError::PageNotFound);
+        }
+        if page_index >= self.pages.len() {
+            self.pages.resize(
+                page_index + 1,
+                Page {
+                    data: [0; PAGE_SIZE],
+                },
+            );
+        }
+        Ok(&mut self.pages[page_index])
     }
 }
 
 impl VecMemory {
     /// Creates a new `VecMemory` with the specified number of pages.
     pub fn new(n_pages: usize) -> VecMemory {
-        let mut pages = Vec::with_capacity(n_pages);
-        for _ in 0..n_pages {
-            pages.push(Page {
-                data: [0; PAGE_SIZE],
-            });
+        VecMemory {
+            n_pages,
+            pages: Vec::new(),
         }
-        VecMemory { pages }
+    }
+}
+
+/// Access permissions of a memory segment.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[repr(u8)]
+pub enum SegmentPermissions {
+    /// Read-only data.
+    R = 0,
+    /// Read-write data, like the stack.
+    RW = 1,
+    /// Executable code, that is also readable, but not writable.
+    RX = 2,
+}
+
+impl SegmentPermissions {
+    #[inline(always)]
+    pub fn is_writable(self) -> bool {
+        self == SegmentPermissions::RW
+    }
+
+    #[inline(always)]
+    pub fn is_executable(self) -> bool {
+        self == SegmentPermissions::RX
     }
 }
 
 /// Represents a contiguous region of memory, implemented via a paged memory.
+///
+/// The permissions are enforced by the [`Cpu`] for the accesses of the V-App; the segment itself
+/// allows any access.
 #[derive(Debug)]
 pub struct MemorySegment<'a, M: PagedMemory> {
     start_address: u32,
     size: u32,
+    permissions: SegmentPermissions,
     paged_memory: &'a mut M,
 }
 
@@ -121,10 +225,22 @@ impl<'a, M: PagedMemory> MemorySegment<'a, M> {
         Ok(Self {
             start_address,
             size,
+            permissions: SegmentPermissions::RW,
             paged_memory,
         })
     }
 
+    /// Sets the permissions of the segment, that are read-write by default.
+    pub fn with_permissions(mut self, permissions: SegmentPermissions) -> Self {
+        self.permissions = permissions;
+        self
+    }
+
+    #[inline(always)]
+    pub fn permissions(&self) -> SegmentPermissions {
+        self.permissions
+    }
+
     #[inline]
     /// Returns true if this segment contains the byte at the specified address.
     pub fn contains(&self, address: u32) -> bool {
@@ -196,6 +312,39 @@ impl<'a, M: PagedMemory> MemorySegment<'a, M> {
         Ok(value)
     }
 
+    /// Returns the cached decoded instruction at the specified address, and its size, if any.
+    #[inline]
+    pub fn get_decoded_instruction(&mut self, address: u32) -> Option<(Op, u32)> {
+        if !self.contains(address) {
+            return None;
+        }
+
+        let relative_address = address - page_start(self.start_address);
+        let page_index = relative_address / (PAGE_SIZE as u32);
+        let offset = relative_address % (PAGE_SIZE as u32);
+
+        self.paged_memory
+            .get_decoded_instruction(page_index, offset)
+    }
+
+    /// Stores the decoded instruction at the specified address in the cache of the paged memory,
+    /// if supported. Instructions crossing a page boundary are never cached.
+    #[inline]
+    pub fn store_decoded_instruction(&mut self, address: u32, op: Op, size: u32) {
+        if !self.contains(address) {
+            return;
+        }
+
+        let relative_address = address - page_start(self.start_address);
+        let page_index = relative_address / (PAGE_SIZE as u32);
+        let offset = relative_address % (PAGE_SIZE as u32);
+
+        if offset + size <= PAGE_SIZE as u32 {
+            self.paged_memory
+                .store_decoded_instruction(page_index, offset, op, size);
+        }
+    }
+
     /// Writes a byte to the specified address.
     #[inline]
     pub fn write_u8(&mut self, address: u32, value: u8) -> Result<(), MemoryError> {
@@ -341,14 +490,22 @@ impl<'a, M: PagedMemory> MemorySegment<'a, M> {
     }
 }
 
-/// Represents the state of the Risc-V CPU, with registers and three memory segments
-/// for code, data and stack.
+/// Represents the state of the Risc-V CPU, with registers and a table of memory segments.
 pub struct Cpu<'a, M: PagedMemory> {
     pub pc: u32,
     pub regs: [u32; 32],
-    pub code_seg: MemorySegment<'a, M>,
-    pub data_seg: MemorySegment<'a, M>,
-    pub stack_seg: MemorySegment<'a, M>,
+    /// The memory segments, that must not overlap. The memory accesses of the V-App are checked
+    /// against the permissions of each segment.
+    pub segments: Vec<MemorySegment<'a, M>>,
+    // Index of the segment of the last fetched instruction, that is checked first on each fetch
+    code_segment_hint: usize,
+    /// Address reserved by the last LR.W instruction, if any.
+    pub reservation: Option<u32>,
+    /// Maximum number of instructions that can be executed between two resets of the
+    /// instruction budget, or `None` if unlimited.
+    pub instruction_budget: Option<u64>,
+    /// Number of instructions that can still be executed before the budget is exhausted.
+    pub remaining_budget: u64,
 }
 
 pub trait EcallHandler {
@@ -358,11 +515,93 @@ pub trait EcallHandler {
     fn handle_ecall(&mut self, cpu: &mut Cpu<'_, Self::Memory>) -> Result<(), Self::Error>;
 }
 
+/// The reason why the V-App could not execute an instruction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TrapCause {
+    /// The instruction is not valid, or not supported by the VM. `inst` contains the raw bits of
+    /// the instruction; the upper half is 0 for compressed instructions.
+    IllegalInstruction { inst: u32 },
+    /// An EBREAK instruction was executed.
+    Breakpoint,
+    /// A memory access at an address that is not aligned to the size of the access.
+    MisalignedAccess { address: u32 },
+    /// A memory access that is not allowed, either because the address is not in any segment, or
+    /// because the segment does not allow it (for example, writing to the code segment).
+    /// `segment` is the index of the segment containing the address, if any.
+    AccessFault { address: u32, segment: Option<u8> },
+    /// An ECALL with a code that is not handled by the VM.
+    UnknownEcall { code: u32 },
+}
+
+impl fmt::Display for TrapCause {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TrapCause::IllegalInstruction { inst } => {
+                write!(f, "illegal instruction 0x{inst:08x}")
+            }
+            TrapCause::Breakpoint => write!(f, "breakpoint"),
+            TrapCause::MisalignedAccess { address } => {
+                write!(f, "misaligned access at address 0x{address:08x}")
+            }
+            TrapCause::AccessFault {
+                address,
+                segment: Some(segment),
+            } => write!(
+                f,
+                "access fault at address 0x{address:08x} in segment {segment}"
+            ),
+            TrapCause::AccessFault {
+                address,
+                segment: None,
+            } => write!(
+                f,
+                "access fault at address 0x{address:08x}, outside of any segment"
+            ),
+            TrapCause::UnknownEcall { code } => write!(f, "unknown ECALL {code}"),
+        }
+    }
+}
+
+/// A trap, with the address of the instruction that caused it. This is sent to the client
+/// when the execution of the V-App is aborted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Trap {
+    pub pc: u32,
+    pub cause: TrapCause,
+}
+
+impl fmt::Display for Trap {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} at pc 0x{:08x}", self.cause, self.pc)
+    }
+}
+
+/// Hooks that allow a debugger to control the execution of a `Cpu`.
+///
+/// The loop executing the V-App calls the hooks, if a debugger is attached. The hooks can inspect
+/// and modify the state of the CPU (registers, and memory with `Cpu::debug_read_memory` and
+/// `Cpu::debug_write_memory`), which allows implementing breakpoints and single-stepping.
+pub trait DebugHook {
+    type Memory: PagedMemory;
+    type Error: fmt::Debug;
+
+    /// Called before executing the instruction at `cpu.pc`. Returning an error aborts the
+    /// execution of the V-App.
+    fn before_instruction(&mut self, cpu: &mut Cpu<'_, Self::Memory>) -> Result<(), Self::Error>;
+
+    /// Called when the instruction at `cpu.pc` caused a trap, before the execution is aborted.
+    fn on_trap(&mut self, cpu: &mut Cpu<'_, Self::Memory>, cause: TrapCause);
+
+    /// Called when the V-App exits with the given status.
+    fn on_exit(&mut self, status: i32);
+}
+
 #[derive(Debug)]
 pub enum CpuError<E: fmt::Debug> {
     EcallError(E),
     MemoryError(MemoryError),
-    GenericError(&'static str), // TODO: make errors more specific
+    InstructionBudgetExhausted,
+    Trap(TrapCause),
 }
 
 impl<E: fmt::Debug> core::error::Error for CpuError<E> {
@@ -370,14 +609,15 @@ impl<E: fmt::Debug> core::error::Error for CpuError<E> {
         match self {
             CpuError::EcallError(_) => None,
             CpuError::MemoryError(err) => Some(err),
-            CpuError::GenericError(_) => None,
+            CpuError::InstructionBudgetExhausted => None,
+            CpuError::Trap(_) => None,
         }
     }
 }
 
-impl<E: fmt::Debug> From<&'static str> for CpuError<E> {
-    fn from(err: &'static str) -> Self {
-        CpuError::GenericError(err)
+impl<E: fmt::Debug> From<TrapCause> for CpuError<E> {
+    fn from(cause: TrapCause) -> Self {
+        CpuError::Trap(cause)
     }
 }
 
@@ -388,7 +628,8 @@ impl<E: fmt::Debug> fmt::Display for CpuError<E> {
                 write!(f, "Error returned from the ECALL handler")
             }
             CpuError::MemoryError(err) => write!(f, "Memory error: {err}"),
-            CpuError::GenericError(msg) => write!(f, "{msg}"),
+            CpuError::InstructionBudgetExhausted => write!(f, "Instruction budget exhausted"),
+            CpuError::Trap(cause) => write!(f, "Trap: {cause}"),
         }
     }
 }
@@ -423,85 +664,175 @@ impl<'a, M: PagedMemory> fmt::Debug for Cpu<'a, M> {
 
 impl<'a, M: PagedMemory> Cpu<'a, M> {
     /// Creates a new `Cpu` instance.
-    pub fn new(
-        entrypoint: u32,
-        code_seg: MemorySegment<'a, M>,
-        data_seg: MemorySegment<'a, M>,
-        stack_seg: MemorySegment<'a, M>,
-    ) -> Cpu<'a, M> {
+    pub fn new(entrypoint: u32, segments: Vec<MemorySegment<'a, M>>) -> Cpu<'a, M> {
         Cpu {
             pc: entrypoint,
             regs: [0; 32],
-            code_seg,
-            data_seg,
-            stack_seg,
+            segments,
+            code_segment_hint: usize::MAX,
+            reservation: None,
+            instruction_budget: None,
+            remaining_budget: 0,
+        }
+    }
+
+    /// Sets the maximum number of instructions that can be executed between two calls to
+    /// `reset_instruction_budget`, or `None` to remove the limit. The budget is also reset.
+    pub fn set_instruction_budget(&mut self, budget: Option<u64>) {
+        self.instruction_budget = budget;
+        self.reset_instruction_budget();
+    }
+
+    /// Restores the full instruction budget. This is expected to be called whenever the V-App
+    /// interacts with the outside world, for example when it exchanges a message with the client.
+    pub fn reset_instruction_budget(&mut self) {
+        self.remaining_budget = self.instruction_budget.unwrap_or(0);
+    }
+
+    #[inline(always)]
+    /// Consumes the budget for one instruction. Returns `CpuError::InstructionBudgetExhausted`
+    /// if the budget is limited and no instruction is left.
+    pub fn consume_instruction_budget<E: fmt::Debug>(&mut self) -> Result<(), CpuError<E>> {
+        if self.instruction_budget.is_some() {
+            if self.remaining_budget == 0 {
+                return Err(CpuError::InstructionBudgetExhausted);
+            }
+            self.remaining_budget -= 1;
+        }
+        Ok(())
+    }
+
+    // Returns the index of the segment containing the address, if any
+    #[inline(always)]
+    fn find_segment(&self, address: u32) -> Option<usize> {
+        self.segments.iter().position(|seg| seg.contains(address))
+    }
+
+    // Returns the index of the executable segment containing the address, if any
+    #[inline(always)]
+    fn find_code_segment(&mut self, address: u32) -> Option<usize> {
+        let hint = self.code_segment_hint;
+        if self
+            .segments
+            .get(hint)
+            .is_some_and(|seg| seg.contains(address))
+        {
+            return Some(hint);
+        }
+        let index = self
+            .segments
+            .iter()
+            .position(|seg| seg.permissions().is_executable() && seg.contains(address))?;
+        self.code_segment_hint = index;
+        Some(index)
+    }
+
+    // Converts the error of a memory access at the given address to the corresponding trap, if
+    // the access was not allowed
+    #[cold]
+    fn access_error<E: fmt::Debug>(&self, address: u32, err: MemoryError) -> CpuError<E> {
+        match err {
+            MemoryError::AddressOutOfBounds => CpuError::Trap(TrapCause::AccessFault {
+                address,
+                segment: self.find_segment(address).map(|index| index as u8),
+            }),
+            MemoryError::UnalignedAddress => {
+                CpuError::Trap(TrapCause::MisalignedAccess { address })
+            }
+            err => CpuError::MemoryError(err),
         }
     }
 
+    // Performs a memory access of the V-App, in the segment containing the address, if the
+    // segment allows it
+    #[inline(always)]
+    fn access<T, E: fmt::Debug>(
+        &mut self,
+        address: u32,
+        write: bool,
+        f: impl FnOnce(&mut MemorySegment<'a, M>) -> Result<T, MemoryError>,
+    ) -> Result<T, CpuError<E>> {
+        let result = match self.segments.iter_mut().find(|seg| seg.contains(address)) {
+            Some(seg) if !write || seg.permissions().is_writable() => f(seg),
+            _ => Err(MemoryError::AddressOutOfBounds),
+        };
+        result.map_err(|err| self.access_error(address, err))
+    }
+
     #[inline]
     fn read_u8<E: fmt::Debug>(&mut self, address: u32) -> Result<u8, CpuError<E>> {
-        if self.stack_seg.contains(address) {
-            return Ok(self.stack_seg.read_u8(address)?);
-        } else if self.data_seg.contains(address) {
-            return Ok(self.data_seg.read_u8(address)?);
-        } else if self.code_seg.contains(address) {
-            return Ok(self.code_seg.read_u8(address)?);
-        }
-        Err(MemoryError::AddressOutOfBounds.into())
+        self.access(address, false, |seg| seg.read_u8(address))
     }
 
     #[inline]
     fn read_u16<E: fmt::Debug>(&mut self, address: u32) -> Result<u16, CpuError<E>> {
-        if self.stack_seg.contains(address) {
-            return Ok(self.stack_seg.read_u16(address)?);
-        } else if self.data_seg.contains(address) {
-            return Ok(self.data_seg.read_u16(address)?);
-        } else if self.code_seg.contains(address) {
-            return Ok(self.code_seg.read_u16(address)?);
-        }
-        Err(MemoryError::AddressOutOfBounds.into())
+        self.access(address, false, |seg| seg.read_u16(address))
     }
 
     #[inline]
     fn read_u32<E: fmt::Debug>(&mut self, address: u32) -> Result<u32, CpuError<E>> {
-        if self.stack_seg.contains(address) {
-            return Ok(self.stack_seg.read_u32(address)?);
-        } else if self.data_seg.contains(address) {
-            return Ok(self.data_seg.read_u32(address)?);
-        } else if self.code_seg.contains(address) {
-            return Ok(self.code_seg.read_u32(address)?);
-        }
-        Err(MemoryError::AddressOutOfBounds.into())
+        self.access(address, false, |seg| seg.read_u32(address))
     }
 
     #[inline]
     fn write_u8<E: fmt::Debug>(&mut self, address: u32, value: u8) -> Result<(), CpuError<E>> {
-        if self.stack_seg.contains(address) {
-            return Ok(self.stack_seg.write_u8(address, value)?);
-        } else if self.data_seg.contains(address) {
-            return Ok(self.data_seg.write_u8(address, value)?);
-        }
-        Err(MemoryError::AddressOutOfBounds.into())
+        self.access(address, true, |seg| seg.write_u8(address, value))
     }
 
     #[inline]
     fn write_u16<E: fmt::Debug>(&mut self, address: u32, value: u16) -> Result<(), CpuError<E>> {
-        if self.stack_seg.contains(address) {
-            return Ok(self.stack_seg.write_u16(address, value)?);
-        } else if self.data_seg.contains(address) {
-            return Ok(self.data_seg.write_u16(address, value)?);
-        }
-        Err(MemoryError::AddressOutOfBounds.into())
+        self.access(address, true, |seg| seg.write_u16(address, value))
     }
 
     #[inline]
     fn write_u32<E: fmt::Debug>(&mut self, address: u32, value: u32) -> Result<(), CpuError<E>> {
-        if self.stack_seg.contains(address) {
-            return Ok(self.stack_seg.write_u32(address, value)?);
-        } else if self.data_seg.contains(address) {
-            return Ok(self.data_seg.write_u32(address, value)?);
+        self.access(address, true, |seg| seg.write_u32(address, value))
+    }
+
+    // Executes an atomic read-modify-write instruction: the word at the address in rs1 is replaced
+    // with f(word, rs2), and the original word is stored in rd.
+    #[inline(always)]
+    fn amo<E: fmt::Debug>(
+        &mut self,
+        rd: u8,
+        rs1: u8,
+        rs2: u8,
+        f: impl FnOnce(u32, u32) -> u32,
+    ) -> Result<(), CpuError<E>> {
+        let addr = self.regs[rs1 as usize];
+        if addr & 3 != 0 {
+            return Err(TrapCause::MisalignedAccess { address: addr }.into());
+        }
+        let value = self.read_u32(addr)?;
+        self.write_u32(addr, f(value, self.regs[rs2 as usize]))?;
+        self.regs[rd as usize] = value;
+        Ok(())
+    }
+
+    /// Reads memory on behalf of a debugger. Unlike the memory accesses of the V-App, this can
+    /// read any segment, with no alignment requirement.
+    pub fn debug_read_memory(&mut self, address: u32, buf: &mut [u8]) -> Result<(), MemoryError> {
+        for (i, byte) in buf.iter_mut().enumerate() {
+            let addr = address.checked_add(i as u32).ok_or(MemoryError::Overflow)?;
+            *byte = self
+                .get_segment::<()>(addr)
+                .map_err(|_| MemoryError::AddressOutOfBounds)?
+                .read_u8(addr)?;
+        }
+        Ok(())
+    }
+
+    /// Writes memory on behalf of a debugger. Unlike the memory accesses of the V-App, this can
+    /// also write to read-only segments; instructions decoded before the change might still be
+    /// cached by the code memory, and executed instead of the new ones.
+    pub fn debug_write_memory(&mut self, address: u32, buf: &[u8]) -> Result<(), MemoryError> {
+        for (i, byte) in buf.iter().enumerate() {
+            let addr = address.checked_add(i as u32).ok_or(MemoryError::Overflow)?;
+            self.get_segment::<()>(addr)
+                .map_err(|_| MemoryError::AddressOutOfBounds)?
+                .write_u8(addr, *byte)?;
         }
-        Err(MemoryError::AddressOutOfBounds.into())
+        Ok(())
     }
 
     #[inline(always)]
@@ -509,42 +840,94 @@ impl<'a, M: PagedMemory> Cpu<'a, M> {
         &mut self,
         address: u32,
     ) -> Result<&mut MemorySegment<'a, M>, CpuError<E>> {
-        if self.stack_seg.contains(address) {
-            return Ok(&mut self.stack_seg);
-        } else if self.data_seg.contains(address) {
-            return Ok(&mut self.data_seg);
-        } else if self.code_seg.contains(address) {
-            return Ok(&mut self.code_seg);
-        }
-        Err(MemoryError::AddressOutOfBounds.into())
+        self.segments
+            .iter_mut()
+            .find(|seg| seg.contains(address))
+            .ok_or(MemoryError::AddressOutOfBounds.into())
+    }
+
+    /// Like [`Cpu::get_segment`], but fails if the segment is not writable. This must be used
+    /// to write the output of an ECALL to the memory of the V-App.
+    #[inline(always)]
+    pub fn get_writable_segment<E: fmt::Debug>(
+        &mut self,
+        address: u32,
+    ) -> Result<&mut MemorySegment<'a, M>, CpuError<E>> {
+        self.segments
+            .iter_mut()
+            .find(|seg| seg.contains(address) && seg.permissions().is_writable())
+            .ok_or(MemoryError::AddressOutOfBounds.into())
     }
 
     #[inline(always)]
     /// Fetches the next instruction to be executed.
     pub fn fetch_instruction<E: fmt::Debug>(&mut self) -> Result<u32, CpuError<E>> {
-        if self.pc % 4 == 0 && self.code_seg.contains(self.pc + 3) {
+        let pc = self.pc;
+        let Some(index) = self.find_code_segment(pc) else {
+            return Err(self.access_error(pc, MemoryError::AddressOutOfBounds));
+        };
+        let code_seg = &mut self.segments[index];
+        let result = if pc.is_multiple_of(4) && code_seg.contains(pc + 3) {
             // if the address is aligned and within boundaries, we can read four bytes at once
-            Ok(self.code_seg.read_u32(self.pc)?)
+            code_seg.read_u32(pc).map_err(|err| (pc, err))
         } else {
             // as the address is not 4-bytes aligned, we have to read each half separately,
             // and consider the case where the second half might not be readable
             // (which is fine if the instruction is compressed)
-            let inst_lo: u16 = self.code_seg.read_u16(self.pc)?;
-            let inst_hi = if inst_lo & 0b11 != 0b11 {
-                // compressed instruction, ignore the second half
-                0u16
-            } else {
-                // not a compressed instruction, we have to read the next two bytes
-                self.code_seg.read_u16(self.pc + 2)?
-            };
-            Ok(u32::from(inst_hi) << 16 | u32::from(inst_lo))
+            code_seg
+                .read_u16(pc)
+                .map_err(|err| (pc, err))
+                .and_then(|inst_lo| {
+                    let inst_hi = if inst_lo & 0b11 != 0b11 {
+                        // compressed instruction, ignore the second half
+                        0u16
+                    } else {
+                        // not a compressed instruction, we have to read the next two bytes
+                        code_seg.read_u16(pc + 2).map_err(|err| (pc + 2, err))?
+                    };
+                    Ok(u32::from(inst_hi) << 16 | u32::from(inst_lo))
+                })
+        };
+        result.map_err(|(address, err)| self.access_error(address, err))
+    }
+
+    #[inline(always)]
+    /// Fetches and decodes the next instruction to be executed, returning the decoded instruction
+    /// and its size. Decoded instructions are cached in the code memory, if it supports it.
+    pub fn fetch_op<E: fmt::Debug>(&mut self) -> Result<(Op, u32), CpuError<E>> {
+        let pc = self.pc;
+        if let Some(index) = self.find_code_segment(pc) {
+            if let Some(decoded) = self.segments[index].get_decoded_instruction(pc) {
+                return Ok(decoded);
+            }
         }
+
+        // after a successful fetch, the hint is the segment containing pc
+        let inst = self.fetch_instruction()?;
+        let (op, inst_size) = crate::riscv::decode::decode(inst);
+        self.segments[self.code_segment_hint].store_decoded_instruction(pc, op, inst_size);
+        Ok((op, inst_size))
     }
 
-    #[rustfmt::skip]
     #[inline(always)]
-    pub fn execute<E: fmt::Debug>(&mut self, inst: u32, ecall_handler: Option<&mut dyn EcallHandler<Memory = M, Error = E>>) -> Result<(), CpuError<E>> {
+    /// Decodes and executes the instruction `inst`.
+    pub fn execute<E: fmt::Debug>(
+        &mut self,
+        inst: u32,
+        ecall_handler: Option<&mut dyn EcallHandler<Memory = M, Error = E>>,
+    ) -> Result<(), CpuError<E>> {
         let (op, inst_size) = crate::riscv::decode::decode(inst);
+        if let Op::Unknown = op {
+            return Err(TrapCause::IllegalInstruction { inst }.into());
+        }
+        self.execute_op(op, inst_size, ecall_handler)
+    }
+
+    #[rustfmt::skip]
+    #[inline(always)]
+    /// Executes an instruction that was already decoded, where `inst_size` is the size of the
+    /// encoded instruction.
+    pub fn execute_op<E: fmt::Debug>(&mut self, op: Op, inst_size: u32, ecall_handler: Option<&mut dyn EcallHandler<Memory = M, Error = E>>) -> Result<(), CpuError<E>> {
         let mut pc_inc: u32 = inst_size;
         match op {
             Op::Add { rd, rs1, rs2 } => { self.regs[rd as usize] = self.regs[rs1 as usize].wrapping_add(self.regs[rs2 as usize]); },
@@ -651,7 +1034,7 @@ impl<'a, M: PagedMemory> Cpu<'a, M> {
             Op::Lh { rd, rs1, imm } => {
                 let addr = self.regs[rs1 as usize].wrapping_add(imm as u32);
                 if addr & 1 != 0 {
-                    return Err("Unaligned 16-bit read".into());
+                    return Err(TrapCause::MisalignedAccess { address: addr }.into());
                 }
                 let value = self.read_u16(addr)?;
                 self.regs[rd as usize] = value as i16 as i32 as u32;
@@ -659,7 +1042,7 @@ impl<'a, M: PagedMemory> Cpu<'a, M> {
             Op::Lw { rd, rs1, imm } => {
                 let addr = self.regs[rs1 as usize].wrapping_add(imm as u32);
                 if addr & 3 != 0 {
-                    return Err("Unaligned 32-bit read".into());
+                    return Err(TrapCause::MisalignedAccess { address: addr }.into());
                 }
                 let value = self.read_u32(addr)?;
                 self.regs[rd as usize] = value;
@@ -672,7 +1055,7 @@ impl<'a, M: PagedMemory> Cpu<'a, M> {
             Op::Lhu { rd, rs1, imm } => {
                 let addr = self.regs[rs1 as usize].wrapping_add(imm as u32);
                 if addr & 1 != 0 {
-                    return Err("Unaligned 16-bit read".into());
+                    return Err(TrapCause::MisalignedAccess { address: addr }.into());
                 }
                 let value = self.read_u16(addr)?;
                 self.regs[rd as usize] = value as u32;
@@ -687,7 +1070,7 @@ impl<'a, M: PagedMemory> Cpu<'a, M> {
             Op::Sh { rs1, rs2, imm } => {
                 let addr = self.regs[rs1 as usize].wrapping_add(imm as u32);
                 if addr & 1 != 0 {
-                    return Err("Unaligned 16-bit write".into());
+                    return Err(TrapCause::MisalignedAccess { address: addr }.into());
                 }
                 let value = self.regs[rs2 as usize] as u16;
                 self.write_u16(addr, value)?;
@@ -695,7 +1078,7 @@ impl<'a, M: PagedMemory> Cpu<'a, M> {
             Op::Sw { rs1, rs2, imm } => {
                 let addr = self.regs[rs1 as usize].wrapping_add(imm as u32);
                 if addr & 3 != 0 {
-                    return Err("Unaligned 32-bit write".into());
+                    return Err(TrapCause::MisalignedAccess { address: addr }.into());
                 }
                 let value = self.regs[rs2 as usize];
                 self.write_u32(addr, value)?;
@@ -707,18 +1090,89 @@ impl<'a, M: PagedMemory> Cpu<'a, M> {
             Op::Srai { rd, rs1, imm } => { self.regs[rd as usize] = ((self.regs[rs1 as usize] as i32) >> (imm & 0x1f)) as u32; },
             Op::Xori { rd, rs1, imm } => { self.regs[rd as usize] = self.regs[rs1 as usize] ^ (imm as u32); },
 
+            Op::Sh1add { rd, rs1, rs2 } => { self.regs[rd as usize] = (self.regs[rs1 as usize] << 1).wrapping_add(self.regs[rs2 as usize]); },
+            Op::Sh2add { rd, rs1, rs2 } => { self.regs[rd as usize] = (self.regs[rs1 as usize] << 2).wrapping_add(self.regs[rs2 as usize]); },
+            Op::Sh3add { rd, rs1, rs2 } => { self.regs[rd as usize] = (self.regs[rs1 as usize] << 3).wrapping_add(self.regs[rs2 as usize]); },
+
+            Op::Andn { rd, rs1, rs2 } => { self.regs[rd as usize] = self.regs[rs1 as usize] & !self.regs[rs2 as usize]; },
+            Op::Orn { rd, rs1, rs2 } => { self.regs[rd as usize] = self.regs[rs1 as usize] | !self.regs[rs2 as usize]; },
+            Op::Xnor { rd, rs1, rs2 } => { self.regs[rd as usize] = !(self.regs[rs1 as usize] ^ self.regs[rs2 as usize]); },
+            Op::Clz { rd, rs1 } => { self.regs[rd as usize] = self.regs[rs1 as usize].leading_zeros(); },
+            Op::Ctz { rd, rs1 } => { self.regs[rd as usize] = self.regs[rs1 as usize].trailing_zeros(); },
+            Op::Cpop { rd, rs1 } => { self.regs[rd as usize] = self.regs[rs1 as usize].count_ones(); },
+            Op::Max { rd, rs1, rs2 } => { self.regs[rd as usize] = (self.regs[rs1 as usize] as i32).max(self.regs[rs2 as usize] as i32) as u32; },
+            Op::Maxu { rd, rs1, rs2 } => { self.regs[rd as usize] = self.regs[rs1 as usize].max(self.regs[rs2 as usize]); },
+            Op::Min { rd, rs1, rs2 } => { self.regs[rd as usize] = (self.regs[rs1 as usize] as i32).min(self.regs[rs2 as usize] as i32) as u32; },
+            Op::Minu { rd, rs1, rs2 } => { self.regs[rd as usize] = self.regs[rs1 as usize].min(self.regs[rs2 as usize]); },
+            Op::SextB { rd, rs1 } => { self.regs[rd as usize] = self.regs[rs1 as usize] as i8 as i32 as u32; },
+            Op::SextH { rd, rs1 } => { self.regs[rd as usize] = self.regs[rs1 as usize] as i16 as i32 as u32; },
+            Op::ZextH { rd, rs1 } => { self.regs[rd as usize] = self.regs[rs1 as usize] & 0xffff; },
+            Op::Rol { rd, rs1, rs2 } => { self.regs[rd as usize] = self.regs[rs1 as usize].rotate_left(self.regs[rs2 as usize] & 0x1f); },
+            Op::Ror { rd, rs1, rs2 } => { self.regs[rd as usize] = self.regs[rs1 as usize].rotate_right(self.regs[rs2 as usize] & 0x1f); },
+            Op::Rori { rd, rs1, imm } => { self.regs[rd as usize] = self.regs[rs1 as usize].rotate_right((imm & 0x1f) as u32); },
+            Op::OrcB { rd, rs1 } => {
+                let value = self.regs[rs1 as usize].to_le_bytes().map(|b| if b != 0 { 0xff } else { 0 });
+                self.regs[rd as usize] = u32::from_le_bytes(value);
+            },
+            Op::Rev8 { rd, rs1 } => { self.regs[rd as usize] = self.regs[rs1 as usize].swap_bytes(); },
+
+            Op::Bclr { rd, rs1, rs2 } => { self.regs[rd as usize] = self.regs[rs1 as usize] & !(1 << (self.regs[rs2 as usize] & 0x1f)); },
+            Op::Bclri { rd, rs1, imm } => { self.regs[rd as usize] = self.regs[rs1 as usize] & !(1 << (imm & 0x1f)); },
+            Op::Bext { rd, rs1, rs2 } => { self.regs[rd as usize] = (self.regs[rs1 as usize] >> (self.regs[rs2 as usize] & 0x1f)) & 1; },
+            Op::Bexti { rd, rs1, imm } => { self.regs[rd as usize] = (self.regs[rs1 as usize] >> (imm & 0x1f)) & 1; },
+            Op::Binv { rd, rs1, rs2 } => { self.regs[rd as usize] = self.regs[rs1 as usize] ^ (1 << (self.regs[rs2 as usize] & 0x1f)); },
+            Op::Binvi { rd, rs1, imm } => { self.regs[rd as usize] = self.regs[rs1 as usize] ^ (1 << (imm & 0x1f)); },
+            Op::Bset { rd, rs1, rs2 } => { self.regs[rd as usize] = self.regs[rs1 as usize] | (1 << (self.regs[rs2 as usize] & 0x1f)); },
+            Op::Bseti { rd, rs1, imm } => { self.regs[rd as usize] = self.regs[rs1 as usize] | (1 << (imm & 0x1f)); },
+
+            // There is a single hart, and no cache: memory accesses are always ordered
+            Op::Fence => {},
+
+            Op::LrW { rd, rs1 } => {
+                let addr = self.regs[rs1 as usize];
+                if addr & 3 != 0 {
+                    return Err(TrapCause::MisalignedAccess { address: addr }.into());
+                }
+                self.regs[rd as usize] = self.read_u32(addr)?;
+                self.reservation = Some(addr);
+            },
+            Op::ScW { rd, rs1, rs2 } => {
+                let addr = self.regs[rs1 as usize];
+                if addr & 3 != 0 {
+                    return Err(TrapCause::MisalignedAccess { address: addr }.into());
+                }
+                // Any SC.W invalidates the reservation, whether it succeeds or not
+                if self.reservation.take() == Some(addr) {
+                    self.write_u32(addr, self.regs[rs2 as usize])?;
+                    self.regs[rd as usize] = 0;
+                } else {
+                    self.regs[rd as usize] = 1;
+                }
+            },
+            Op::AmoswapW { rd, rs1, rs2 } => self.amo(rd, rs1, rs2, |_, b| b)?,
+            Op::AmoaddW { rd, rs1, rs2 } => self.amo(rd, rs1, rs2, |a, b| a.wrapping_add(b))?,
+            Op::AmoxorW { rd, rs1, rs2 } => self.amo(rd, rs1, rs2, |a, b| a ^ b)?,
+            Op::AmoandW { rd, rs1, rs2 } => self.amo(rd, rs1, rs2, |a, b| a & b)?,
+            Op::AmoorW { rd, rs1, rs2 } => self.amo(rd, rs1, rs2, |a, b| a | b)?,
+            Op::AmominW { rd, rs1, rs2 } => self.amo(rd, rs1, rs2, |a, b| (a as i32).min(b as i32) as u32)?,
+            Op::AmomaxW { rd, rs1, rs2 } => self.amo(rd, rs1, rs2, |a, b| (a as i32).max(b as i32) as u32)?,
+            Op::AmominuW { rd, rs1, rs2 } => self.amo(rd, rs1, rs2, |a, b| a.min(b))?,
+            Op::AmomaxuW { rd, rs1, rs2 } => self.amo(rd, rs1, rs2, |a, b| a.max(b))?,
+
             Op::Ecall => {
                 if let Some(ecall_handler) = ecall_handler {
                     ecall_handler.handle_ecall(self).map_err(CpuError::EcallError)?;
                 } else {
-                    return Err("No ECALL handler".into());
+                    return Err(TrapCause::UnknownEcall { code: self.regs[5] }.into());
                 }
             },
             Op::Break => {
-                return Err("BREAK instruction is not supported".into());
+                return Err(TrapCause::Breakpoint.into());
             },
             Op::Unknown => {
-                return Err("Unknown instruction".into());
+                // the raw instruction is not available if it was decoded from the cache
+                let inst = self.fetch_instruction::<E>()?;
+                return Err(TrapCause::IllegalInstruction { inst }.into());
             },
         }
 
@@ -737,9 +1191,12 @@ mod tests {
     #[test]
     fn test_vec_memory_new() {
         let n_pages = 5;
-        let vec_memory = VecMemory::new(n_pages);
+        let mut vec_memory = VecMemory::new(n_pages);
 
-        assert_eq!(vec_memory.pages.len(), n_pages);
+        // pages are only allocated when accessed
+        assert!(vec_memory.pages.is_empty());
+        assert_eq!(vec_memory.get_page(3).unwrap().data, [0; PAGE_SIZE]);
+        assert_eq!(vec_memory.pages.len(), 4);
         for page in vec_memory.pages.iter() {
             assert_eq!(page.data, [0; PAGE_SIZE]);
         }
@@ -938,4 +1395,635 @@ mod tests {
         let read_result = segment.read_buffer(0, &mut read_buffer);
         assert!(read_result.is_ok());
     }
+
+    // Encodes an instruction of the "A" extension with the given funct5
+    fn encode_amo(funct5: u32, rd: u8, rs1: u8, rs2: u8) -> u32 {
+        (funct5 << 27)
+            | ((rs2 as u32) << 20)
+            | ((rs1 as u32) << 15)
+            | (0b010 << 12)
+            | ((rd as u32) << 7)
+            | 0x2f
+    }
+
+    const LR_W: u32 = 0b00010;
+    const SC_W: u32 = 0b00011;
+    const AMOSWAP_W: u32 = 0b00001;
+    const AMOADD_W: u32 = 0b00000;
+    const AMOXOR_W: u32 = 0b00100;
+    const AMOAND_W: u32 = 0b01100;
+    const AMOOR_W: u32 = 0b01000;
+    const AMOMIN_W: u32 = 0b10000;
+    const AMOMAX_W: u32 = 0b10100;
+    const AMOMINU_W: u32 = 0b11000;
+    const AMOMAXU_W: u32 = 0b11100;
+
+    const DATA_ADDR: u32 = 0x1000;
+
+    fn with_cpu<F: FnOnce(&mut Cpu<'_, VecMemory>)>(f: F) {
+        let mut code_mem = VecMemory::new(1);
+        let mut data_mem = VecMemory::new(1);
+        let mut stack_mem = VecMemory::new(1);
+        let code_seg = MemorySegment::new(0, PAGE_SIZE as u32, &mut code_mem)
+            .unwrap()
+            .with_permissions(SegmentPermissions::RX);
+        let data_seg = MemorySegment::new(DATA_ADDR, PAGE_SIZE as u32, &mut data_mem).unwrap();
+        let stack_seg = MemorySegment::new(0x2000, PAGE_SIZE as u32, &mut stack_mem).unwrap();
+        let mut cpu = Cpu::new(0, vec![code_seg, data_seg, stack_seg]);
+        f(&mut cpu);
+    }
+
+    #[test]
+    fn test_amo() {
+        let cases: &[(u32, u32, u32, u32)] = &[
+            // (funct5, memory value, rs2 value, expected memory value after the instruction)
+            (AMOSWAP_W, 5, 3, 3),
+            (AMOADD_W, 5, 3, 8),
+            (AMOADD_W, 0xffffffff, 2, 1),
+            (AMOXOR_W, 0b1100, 0b1010, 0b0110),
+            (AMOAND_W, 0b1100, 0b1010, 0b1000),
+            (AMOOR_W, 0b1100, 0b1010, 0b1110),
+            (AMOMIN_W, (-5i32) as u32, 3, (-5i32) as u32),
+            (AMOMAX_W, (-5i32) as u32, 3, 3),
+            (AMOMINU_W, (-5i32) as u32, 3, 3),
+            (AMOMAXU_W, (-5i32) as u32, 3, (-5i32) as u32),
+        ];
+
+        for &(funct5, mem_value, rs2_value, expected) in cases {
+            with_cpu(|cpu| {
+                cpu.write_u32::<()>(DATA_ADDR + 8, mem_value).unwrap();
+                cpu.regs[10] = DATA_ADDR + 8;
+                cpu.regs[11] = rs2_value;
+                cpu.execute::<()>(encode_amo(funct5, 12, 10, 11), None)
+                    .unwrap();
+
+                assert_eq!(cpu.regs[12], mem_value);
+                assert_eq!(cpu.read_u32::<()>(DATA_ADDR + 8).unwrap(), expected);
+                assert_eq!(cpu.pc, 4);
+            });
+        }
+    }
+
+    #[test]
+    fn test_amo_same_rd_and_rs2() {
+        with_cpu(|cpu| {
+            cpu.write_u32::<()>(DATA_ADDR, 5).unwrap();
+            cpu.regs[10] = DATA_ADDR;
+            cpu.regs[11] = 3;
+            cpu.execute::<()>(encode_amo(AMOSWAP_W, 11, 10, 11), None)
+                .unwrap();
+
+            assert_eq!(cpu.regs[11], 5);
+            assert_eq!(cpu.read_u32::<()>(DATA_ADDR).unwrap(), 3);
+        });
+    }
+
+    #[test]
+    fn test_amo_errors() {
+        with_cpu(|cpu| {
+            // unaligned address
+            cpu.regs[10] = DATA_ADDR + 2;
+            assert!(matches!(
+                cpu.execute::<()>(encode_amo(AMOADD_W, 12, 10, 11), None),
+                Err(CpuError::Trap(TrapCause::MisalignedAccess { address })) if address == DATA_ADDR + 2
+            ));
+            assert!(matches!(
+                cpu.execute::<()>(encode_amo(LR_W, 12, 10, 0), None),
+                Err(CpuError::Trap(TrapCause::MisalignedAccess { .. }))
+            ));
+
+            // the code segment is not writable
+            cpu.regs[10] = 0;
+            assert!(matches!(
+                cpu.execute::<()>(encode_amo(AMOADD_W, 12, 10, 11), None),
+                Err(CpuError::Trap(TrapCause::AccessFault {
+                    address: 0,
+                    segment: Some(0)
+                }))
+            ));
+
+            // LR.W with rs2 != 0 is not a valid instruction
+            cpu.regs[10] = DATA_ADDR;
+            let inst = encode_amo(LR_W, 12, 10, 1);
+            assert!(matches!(
+                cpu.execute::<()>(inst, None),
+                Err(CpuError::Trap(TrapCause::IllegalInstruction { inst: i })) if i == inst
+            ));
+        });
+    }
+
+    #[test]
+    fn test_lr_sc() {
+        with_cpu(|cpu| {
+            cpu.write_u32::<()>(DATA_ADDR, 42).unwrap();
+            cpu.regs[10] = DATA_ADDR;
+            cpu.regs[11] = 7;
+
+            // SC.W without a reservation fails
+            cpu.execute::<()>(encode_amo(SC_W, 12, 10, 11), None)
+                .unwrap();
+            assert_eq!(cpu.regs[12], 1);
+            assert_eq!(cpu.read_u32::<()>(DATA_ADDR).unwrap(), 42);
+
+            // LR.W followed by SC.W on the same address succeeds
+            cpu.execute::<()>(encode_amo(LR_W, 13, 10, 0), None)
+                .unwrap();
+            assert_eq!(cpu.regs[13], 42);
+            cpu.execute::<()>(encode_amo(SC_W, 12, 10, 11), None)
+                .unwrap();
+            assert_eq!(cpu.regs[12], 0);
+            assert_eq!(cpu.read_u32::<()>(DATA_ADDR).unwrap(), 7);
+
+            // the reservation is consumed by the SC.W
+            cpu.regs[11] = 8;
+            cpu.execute::<()>(encode_amo(SC_W, 12, 10, 11), None)
+                .unwrap();
+            assert_eq!(cpu.regs[12], 1);
+            assert_eq!(cpu.read_u32::<()>(DATA_ADDR).unwrap(), 7);
+
+            // SC.W on a different address fails, and invalidates the reservation
+            cpu.execute::<()>(encode_amo(LR_W, 13, 10, 0), None)
+                .unwrap();
+            cpu.regs[14] = DATA_ADDR + 4;
+            cpu.execute::<()>(encode_amo(SC_W, 12, 14, 11), None)
+                .unwrap();
+            assert_eq!(cpu.regs[12], 1);
+            cpu.execute::<()>(encode_amo(SC_W, 12, 10, 11), None)
+                .unwrap();
+            assert_eq!(cpu.regs[12], 1);
+            assert_eq!(cpu.read_u32::<()>(DATA_ADDR).unwrap(), 7);
+        });
+    }
+
+    #[test]
+    fn test_fence() {
+        with_cpu(|cpu| {
+            // fence rw, rw
+            cpu.execute::<()>(0x0330000f, None).unwrap();
+            // fence.tso
+            cpu.execute::<()>(0x8330000f, None).unwrap();
+            assert_eq!(cpu.pc, 8);
+        });
+    }
+
+    // Encodes an R-type instruction
+    fn encode_r(funct7: u32, rs2: u8, rs1: u8, funct3: u32, rd: u8, opcode: u32) -> u32 {
+        (funct7 << 25)
+            | ((rs2 as u32) << 20)
+            | ((rs1 as u32) << 15)
+            | (funct3 << 12)
+            | ((rd as u32) << 7)
+            | opcode
+    }
+
+    const OP: u32 = 0x33;
+    const OP_IMM: u32 = 0x13;
+
+    // Executes the instruction with 10 and 11 as source registers (or 11 as immediate), and returns
+    // the value written in register 12
+    fn exec_bitmanip(funct7: u32, rs2: u8, funct3: u32, opcode: u32, a: u32, b: u32) -> u32 {
+        let mut result = 0;
+        with_cpu(|cpu| {
+            cpu.regs[10] = a;
+            cpu.regs[11] = b;
+            cpu.execute::<()>(encode_r(funct7, rs2, 10, funct3, 12, opcode), None)
+                .unwrap();
+            assert_eq!(cpu.pc, 4);
+            result = cpu.regs[12];
+        });
+        result
+    }
+
+    fn exec_r(funct7: u32, funct3: u32, a: u32, b: u32) -> u32 {
+        exec_bitmanip(funct7, 11, funct3, OP, a, b)
+    }
+
+    fn exec_unary(funct7: u32, rs2: u8, funct3: u32, opcode: u32, a: u32) -> u32 {
+        exec_bitmanip(funct7, rs2, funct3, opcode, a, 0)
+    }
+
+    #[test]
+    fn test_zba() {
+        assert_eq!(exec_r(0b0010000, 0b010, 3, 100), 106); // sh1add
+        assert_eq!(exec_r(0b0010000, 0b100, 3, 100), 112); // sh2add
+        assert_eq!(exec_r(0b0010000, 0b110, 3, 100), 124); // sh3add
+        assert_eq!(exec_r(0b0010000, 0b110, 0x20000000, 1), 1); // sh3add, overflow
+    }
+
+    #[test]
+    fn test_zbb() {
+        let a = 0x12f0_0080u32;
+        let b = 0xff00_ff00u32;
+        assert_eq!(exec_r(0b0100000, 0b111, a, b), a & !b); // andn
+        assert_eq!(exec_r(0b0100000, 0b110, a, b), a | !b); // orn
+        assert_eq!(exec_r(0b0100000, 0b100, a, b), !(a ^ b)); // xnor
+
+        assert_eq!(exec_r(0b0000101, 0b110, (-3i32) as u32, 2), 2); // max
+        assert_eq!(exec_r(0b0000101, 0b111, (-3i32) as u32, 2), (-3i32) as u32); // maxu
+        assert_eq!(exec_r(0b0000101, 0b100, (-3i32) as u32, 2), (-3i32) as u32); // min
+        assert_eq!(exec_r(0b0000101, 0b101, (-3i32) as u32, 2), 2); // minu
+
+        assert_eq!(exec_r(0b0110000, 0b001, 0x8000_0001, 4), 0x18); // rol
+        assert_eq!(exec_r(0b0110000, 0b001, 0x8000_0001, 36), 0x18); // rol, only 5 bits used
+        assert_eq!(exec_r(0b0110000, 0b101, 0x8000_0001, 4), 0x1800_0000); // ror
+        assert_eq!(
+            exec_unary(0b0110000, 4, 0b101, OP_IMM, 0x8000_0001),
+            0x1800_0000
+        ); // rori
+
+        assert_eq!(exec_unary(0b0110000, 0, 0b001, OP_IMM, 0x0001_0000), 15); // clz
+        assert_eq!(exec_unary(0b0110000, 0, 0b001, OP_IMM, 0), 32); // clz
+        assert_eq!(exec_unary(0b0110000, 1, 0b001, OP_IMM, 0x0001_0000), 16); // ctz
+        assert_eq!(exec_unary(0b0110000, 1, 0b001, OP_IMM, 0), 32); // ctz
+        assert_eq!(exec_unary(0b0110000, 2, 0b001, OP_IMM, 0xf0f0_0001), 9); // cpop
+        assert_eq!(
+            exec_unary(0b0110000, 4, 0b001, OP_IMM, 0x1234_5680),
+            0xffff_ff80
+        ); // sext.b
+        assert_eq!(
+            exec_unary(0b0110000, 5, 0b001, OP_IMM, 0x1234_8765),
+            0xffff_8765
+        ); // sext.h
+        assert_eq!(
+            exec_unary(0b0000100, 0, 0b100, OP, 0x1234_8765),
+            0x0000_8765
+        ); // zext.h
+
+        assert_eq!(
+            exec_unary(0b0010100, 7, 0b101, OP_IMM, 0x0010_8000),
+            0x00ff_ff00
+        ); // orc.b
+        assert_eq!(
+            exec_unary(0b0110100, 24, 0b101, OP_IMM, 0x1234_5678),
+            0x7856_3412
+        ); // rev8
+    }
+
+    #[test]
+    fn test_zbs() {
+        let a = 0x0000_00f0u32;
+        assert_eq!(exec_r(0b0100100, 0b001, a, 4), 0xe0); // bclr
+        assert_eq!(exec_r(0b0100100, 0b101, a, 4), 1); // bext
+        assert_eq!(exec_r(0b0100100, 0b101, a, 3), 0); // bext
+        assert_eq!(exec_r(0b0110100, 0b001, a, 4), 0xe0); // binv
+        assert_eq!(exec_r(0b0110100, 0b001, a, 35), 0xf8); // binv, only 5 bits used
+        assert_eq!(exec_r(0b0010100, 0b001, a, 31), 0x8000_00f0); // bset
+
+        assert_eq!(exec_unary(0b0100100, 4, 0b001, OP_IMM, a), 0xe0); // bclri
+        assert_eq!(exec_unary(0b0100100, 4, 0b101, OP_IMM, a), 1); // bexti
+        assert_eq!(exec_unary(0b0110100, 3, 0b001, OP_IMM, a), 0xf8); // binvi
+        assert_eq!(exec_unary(0b0010100, 31, 0b001, OP_IMM, a), 0x8000_00f0); // bseti
+    }
+
+    #[test]
+    fn test_invalid_bitmanip_encodings() {
+        with_cpu(|cpu| {
+            // zext.h with rs2 != 0 ("pack" is not supported)
+            assert!(cpu
+                .execute::<()>(encode_r(0b0000100, 1, 10, 0b100, 12, OP), None)
+                .is_err());
+            // unassigned unary operation
+            assert!(cpu
+                .execute::<()>(encode_r(0b0110000, 3, 10, 0b001, 12, OP_IMM), None)
+                .is_err());
+        });
+    }
+
+    // A VecMemory that also caches decoded instructions
+    #[derive(Debug)]
+    struct DecodingVecMemory {
+        memory: VecMemory,
+        decoded_pages: Vec<DecodedPage>,
+    }
+
+    impl DecodingVecMemory {
+        fn new(n_pages: usize) -> Self {
+            Self {
+                memory: VecMemory::new(n_pages),
+                decoded_pages: vec![DecodedPage::new(); n_pages],
+            }
+        }
+    }
+
+    impl PagedMemory for DecodingVecMemory {
+        type PageRef<'a>
+            = &'a mut Page
+        where
+            Self: 'a;
+
+        fn get_page(&mut self, page_index: u32) -> Result<Self::PageRef<'_>, MemoryError> {
+            self.memory.get_page(page_index)
+        }
+
+        fn get_decoded_instruction(&mut self, page_index: u32, offset: u32) -> Option<(Op, u32)> {
+            self.decoded_pages[page_index as usize].get(offset)
+        }
+
+        fn store_decoded_instruction(&mut self, page_index: u32, offset: u32, op: Op, size: u32) {
+            self.decoded_pages[page_index as usize].set(offset, op, size);
+        }
+    }
+
+    // Encodes addi rd, rs1, imm
+    fn encode_addi(rd: u8, rs1: u8, imm: i32) -> u32 {
+        ((imm as u32) << 20) | ((rs1 as u32) << 15) | ((rd as u32) << 7) | 0x13
+    }
+
+    #[test]
+    fn test_decoded_page() {
+        let mut decoded = DecodedPage::new();
+        assert!(decoded.get(4).is_none());
+
+        decoded.set(4, Op::Fence, 4);
+        decoded.set(8, Op::Ecall, 2);
+        assert!(matches!(decoded.get(4), Some((Op::Fence, 4))));
+        assert!(matches!(decoded.get(8), Some((Op::Ecall, 2))));
+        assert!(decoded.get(5).is_none());
+        assert!(decoded.get(PAGE_SIZE as u32).is_none());
+
+        // out of range or unaligned offsets are ignored
+        decoded.set(PAGE_SIZE as u32, Op::Fence, 4);
+        decoded.set(7, Op::Fence, 4);
+        assert!(decoded.get(6).is_none());
+
+        decoded.clear();
+        assert!(decoded.get(4).is_none());
+        assert!(decoded.get(8).is_none());
+    }
+
+    #[test]
+    fn test_fetch_op_uses_decoded_cache() {
+        let mut code_mem = DecodingVecMemory::new(2);
+        let mut data_mem = DecodingVecMemory::new(1);
+        let mut stack_mem = DecodingVecMemory::new(1);
+        let mut code_seg = MemorySegment::new(0, 2 * PAGE_SIZE as u32, &mut code_mem)
+            .unwrap()
+            .with_permissions(SegmentPermissions::RX);
+        code_seg.write_u32(0, encode_addi(1, 0, 5)).unwrap();
+        // the last instruction of the first page crosses the page boundary
+        let last = PAGE_SIZE as u32 - 2;
+        code_seg
+            .write_u16(last, encode_addi(1, 0, 9) as u16)
+            .unwrap();
+        code_seg
+            .write_u16(last + 2, (encode_addi(1, 0, 9) >> 16) as u16)
+            .unwrap();
+        let data_seg = MemorySegment::new(DATA_ADDR, PAGE_SIZE as u32, &mut data_mem).unwrap();
+        let stack_seg = MemorySegment::new(0x2000, PAGE_SIZE as u32, &mut stack_mem).unwrap();
+        let mut cpu = Cpu::new(0, vec![code_seg, data_seg, stack_seg]);
+
+        let (op, size) = cpu.fetch_op::<()>().unwrap();
+        assert!(matches!(
+            op,
+            Op::Addi {
+                rd: 1,
+                rs1: 0,
+                imm: 5
+            }
+        ));
+        assert_eq!(size, 4);
+        cpu.execute_op::<()>(op, size, None).unwrap();
+        assert_eq!(cpu.regs[1], 5);
+        assert_eq!(cpu.pc, 4);
+
+        // the decoded instruction is served from the cache, even if the memory changes
+        cpu.segments[0].write_u32(0, encode_addi(1, 0, 7)).unwrap();
+        cpu.pc = 0;
+        let (op, _) = cpu.fetch_op::<()>().unwrap();
+        assert!(matches!(op, Op::Addi { imm: 5, .. }));
+
+        // once the cache is cleared, the instruction is decoded again
+        cpu.segments[0].paged_memory.decoded_pages[0].clear();
+        let (op, _) = cpu.fetch_op::<()>().unwrap();
+        assert!(matches!(op, Op::Addi { imm: 7, .. }));
+
+        // instructions crossing a page boundary are not cached
+        cpu.pc = last;
+        let (op, size) = cpu.fetch_op::<()>().unwrap();
+        assert!(matches!(op, Op::Addi { imm: 9, .. }));
+        assert_eq!(size, 4);
+        assert!(cpu.segments[0].paged_memory.decoded_pages[0]
+            .get(last)
+            .is_none());
+    }
+
+    #[test]
+    fn test_segment_permissions() {
+        let mut code_mem = VecMemory::new(1);
+        let mut rodata_mem = VecMemory::new(1);
+        let mut code2_mem = VecMemory::new(1);
+        let mut data_mem = VecMemory::new(1);
+        let mut code_seg = MemorySegment::new(0, PAGE_SIZE as u32, &mut code_mem)
+            .unwrap()
+            .with_permissions(SegmentPermissions::RX);
+        // jump to the second executable segment
+        code_seg.write_u32(0, 0x0000406f).unwrap(); // jal x0, 0x4000
+        let mut rodata_seg = MemorySegment::new(0x3000, PAGE_SIZE as u32, &mut rodata_mem)
+            .unwrap()
+            .with_permissions(SegmentPermissions::R);
+        rodata_seg.write_u32(0x3000, 0x12345678).unwrap();
+        let mut code2_seg = MemorySegment::new(0x4000, PAGE_SIZE as u32, &mut code2_mem)
+            .unwrap()
+            .with_permissions(SegmentPermissions::RX);
+        code2_seg.write_u32(0x4000, encode_addi(1, 0, 5)).unwrap();
+        let data_seg = MemorySegment::new(DATA_ADDR, PAGE_SIZE as u32, &mut data_mem).unwrap();
+        let mut cpu = Cpu::new(0, vec![code_seg, rodata_seg, code2_seg, data_seg]);
+
+        // read-only data can be read, but not written nor executed
+        assert_eq!(cpu.read_u32::<()>(0x3000).unwrap(), 0x12345678);
+        assert!(matches!(
+            cpu.write_u32::<()>(0x3004, 0),
+            Err(CpuError::Trap(TrapCause::AccessFault {
+                address: 0x3004,
+                segment: Some(1)
+            }))
+        ));
+        cpu.pc = 0x3000;
+        assert!(matches!(
+            cpu.fetch_op::<()>(),
+            Err(CpuError::Trap(TrapCause::AccessFault {
+                address: 0x3000,
+                segment: Some(1)
+            }))
+        ));
+
+        // data can be written, but not executed
+        cpu.write_u32::<()>(DATA_ADDR, encode_addi(1, 0, 7))
+            .unwrap();
+        cpu.pc = DATA_ADDR;
+        assert!(matches!(
+            cpu.fetch_op::<()>(),
+            Err(CpuError::Trap(TrapCause::AccessFault {
+                segment: Some(3),
+                ..
+            }))
+        ));
+
+        // code can be executed from any executable segment
+        cpu.pc = 0;
+        let (op, size) = cpu.fetch_op::<()>().unwrap();
+        cpu.execute_op::<()>(op, size, None).unwrap();
+        assert_eq!(cpu.pc, 0x4000);
+        let (op, size) = cpu.fetch_op::<()>().unwrap();
+        cpu.execute_op::<()>(op, size, None).unwrap();
+        assert_eq!(cpu.regs[1], 5);
+    }
+
+    #[test]
+    fn test_instruction_budget() {
+        with_cpu(|cpu| {
+            // unlimited by default
+            for _ in 0..10 {
+                cpu.consume_instruction_budget::<()>().unwrap();
+            }
+
+            cpu.set_instruction_budget(Some(3));
+            for _ in 0..3 {
+                cpu.consume_instruction_budget::<()>().unwrap();
+            }
+            assert!(matches!(
+                cpu.consume_instruction_budget::<()>(),
+                Err(CpuError::InstructionBudgetExhausted)
+            ));
+
+            cpu.reset_instruction_budget();
+            assert_eq!(cpu.remaining_budget, 3);
+            cpu.consume_instruction_budget::<()>().unwrap();
+
+            cpu.set_instruction_budget(None);
+            for _ in 0..10 {
+                cpu.consume_instruction_budget::<()>().unwrap();
+            }
+        });
+    }
+
+    #[test]
+    fn test_traps() {
+        fn encode_lw(rd: u8, rs1: u8, imm: u32) -> u32 {
+            (imm << 20) | ((rs1 as u32) << 15) | (0b010 << 12) | ((rd as u32) << 7) | 0x03
+        }
+        fn encode_sw(rs1: u8, rs2: u8, imm: u32) -> u32 {
+            ((imm >> 5) << 25)
+                | ((rs2 as u32) << 20)
+                | ((rs1 as u32) << 15)
+                | (0b010 << 12)
+                | ((imm & 0x1f) << 7)
+                | 0x23
+        }
+
+        with_cpu(|cpu| {
+            cpu.regs[10] = DATA_ADDR + 2;
+            assert!(matches!(
+                cpu.execute::<()>(encode_lw(11, 10, 0), None),
+                Err(CpuError::Trap(TrapCause::MisalignedAccess { address })) if address == DATA_ADDR + 2
+            ));
+
+            // outside of any segment
+            cpu.regs[10] = 0x8000;
+            assert!(matches!(
+                cpu.execute::<()>(encode_lw(11, 10, 4), None),
+                Err(CpuError::Trap(TrapCause::AccessFault {
+                    address: 0x8004,
+                    segment: None
+                }))
+            ));
+
+            // misaligned store
+            cpu.regs[10] = DATA_ADDR + PAGE_SIZE as u32 - 2;
+            assert!(matches!(
+                cpu.execute::<()>(encode_sw(10, 11, 0), None),
+                Err(CpuError::Trap(TrapCause::MisalignedAccess { .. }))
+            ));
+
+            // writing to the code segment
+            cpu.regs[10] = 8;
+            assert!(matches!(
+                cpu.execute::<()>(encode_sw(10, 11, 4), None),
+                Err(CpuError::Trap(TrapCause::AccessFault {
+                    address: 12,
+                    segment: Some(0)
+                }))
+            ));
+
+            assert!(matches!(
+                cpu.execute::<()>(0x00100073, None), // ebreak
+                Err(CpuError::Trap(TrapCause::Breakpoint))
+            ));
+
+            cpu.regs[5] = 42;
+            assert!(matches!(
+                cpu.execute::<()>(0x00000073, None), // ecall
+                Err(CpuError::Trap(TrapCause::UnknownEcall { code: 42 }))
+            ));
+
+            assert!(matches!(
+                cpu.execute::<()>(0xffffffff, None),
+                Err(CpuError::Trap(TrapCause::IllegalInstruction {
+                    inst: 0xffffffff
+                }))
+            ));
+
+            // for an already decoded instruction, the raw bits are fetched from memory
+            cpu.segments[0].write_u32(0, 0xffffffff).unwrap();
+            assert!(matches!(
+                cpu.execute_op::<()>(Op::Unknown, 4, None),
+                Err(CpuError::Trap(TrapCause::IllegalInstruction {
+                    inst: 0xffffffff
+                }))
+            ));
+
+            // no instruction was executed
+            assert_eq!(cpu.pc, 0);
+
+            // fetching outside of the code segment
+            cpu.pc = DATA_ADDR;
+            assert!(matches!(
+                cpu.fetch_instruction::<()>(),
+                Err(CpuError::Trap(TrapCause::AccessFault {
+                    address: DATA_ADDR,
+                    segment: Some(1)
+                }))
+            ));
+        });
+    }
+
+    #[test]
+    fn test_trap_serialization() {
+        let trap = Trap {
+            pc: 0x1234,
+            cause: TrapCause::AccessFault {
+                address: 0x5678,
+                segment: Some(2),
+            },
+        };
+        let serialized = postcard::to_allocvec(&trap).unwrap();
+        assert_eq!(postcard::from_bytes::<Trap>(&serialized).unwrap(), trap);
+        assert_eq!(
+            alloc::string::ToString::to_string(&trap),
+            "access fault at address 0x00005678 in segment 2 at pc 0x00001234"
+        );
+    }
+
+    #[test]
+    fn test_debug_memory_access() {
+        with_cpu(|cpu| {
+            // unaligned accesses across the data segment are allowed
+            cpu.debug_write_memory(DATA_ADDR + 1, &[1, 2, 3, 4, 5])
+                .unwrap();
+            let mut buf = [0u8; 6];
+            cpu.debug_read_memory(DATA_ADDR, &mut buf).unwrap();
+            assert_eq!(buf, [0, 1, 2, 3, 4, 5]);
+
+            // the code segment is writable by the debugger
+            cpu.debug_write_memory(0, &0x00100073u32.to_le_bytes())
+                .unwrap();
+            assert_eq!(cpu.fetch_instruction::<()>().unwrap(), 0x00100073);
+
+            // accesses outside of the segments fail
+            assert!(cpu.debug_read_memory(0x8000, &mut buf).is_err());
+            assert!(cpu
+                .debug_write_memory(DATA_ADDR + PAGE_SIZE as u32 - 1, &[0, 0])
+                .is_err());
+        });
+    }
 }
@@ -0,0 +1,1111 @@
+use core::fmt;
+
+use bip32::{ChildNumber, XPrv};
+use hex_literal::hex;
+use hmac::{Hmac, Mac};
+use k256::{
+    ecdsa::{self, signature::hazmat::PrehashVerifier},
+    elliptic_curve::{
+        sec1::{FromEncodedPoint, ToEncodedPoint},
+        PrimeField,
+    },
+    schnorr, EncodedPoint, ProjectivePoint, Scalar,
+};
+use num_bigint::BigUint;
+use num_traits::Zero;
+use rand::TryRngCore;
+use sha2::Sha512;
+
+use common::{
+    client_commands::{
+        BufferType, CheckAbortMessage, Message, MessageDeserializationError, ReceiveBufferMessage,
+        ReceiveBufferResponse, SendBufferContinuedMessage, SendBufferMessage,
+    },
+    ecall_constants::{self, *},
+    ux::{Action, Deserializable, EventCode, NavInfo, Page, PageContent},
+    vm::{Cpu, CpuError, EcallHandler, MemoryError, VecMemory},
+};
+
+use crate::hash::{self, HashKind};
+use crate::transport::{ClientReply, VmComm};
+
+// Default seed used in Speculos, corresponding to the mnemonic "glory promote mansion idle axis
+// finger extra february uncover one trip resource lawn turtle enact monster seven myth punch hobby
+// comfort wild raise skin". It is the same seed used by the native target of the V-App SDK.
+const DEFAULT_SEED: [u8; 64] = hex!("b11997faff420a331bb4a4ffdc8bdc8ba7c01732a99a30d83dbbebd469666c84b47d09d3f5f472b3b9384ac634beba2a440ba36ec7661144132f35e206873564");
+
+const SLIP21_MAGIC: &str = "Symmetric key seed";
+
+// custom master seed used in Vanadium's version of SLIP-21 for compatibility with Bolos
+const SEED_MASTER_PATH: &str = "VANADIUM";
+
+// Same limits as the VM running on the device
+const MAX_BIP32_PATH: usize = 16;
+const MAX_UX_STEP_LEN: usize = 512;
+const MAX_UX_PAGE_LEN: usize = 512;
+
+// While the V-App waits for events, the client is asked every few tickers whether the request being
+// processed should be aborted, like in the VM
+const ABORT_CHECK_INTERVAL_TICKS: u32 = 5;
+
+// Register indices used by the ECALL calling convention
+const REG_T0: usize = 5;
+const REG_A0: usize = 10;
+
+/// Determines how the emulator reacts to the pages shown by the V-App, as there is no user to
+/// interact with the screen.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum UxPolicy {
+    /// Navigate through multi-page reviews, and confirm any confirmation page.
+    #[default]
+    Approve,
+    /// Reject (or quit) at the first page that allows it.
+    Reject,
+}
+
+#[derive(Debug)]
+pub enum HostEcallError {
+    Exit(i32),
+    Panic,
+    InvalidParameters(&'static str),
+    GenericError(&'static str),
+    Overflow,
+    MessageDeserializationError(MessageDeserializationError),
+    InvalidResponse(&'static str),
+    CpuError(String),
+    MemoryError(MemoryError),
+    Disconnected,
+    UnhandledEcall(u32),
+    /// The client asked to suspend the V-App instead of answering to xrecv
+    SuspendRequested,
+}
+
+impl fmt::Display for HostEcallError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HostEcallError::Exit(code) => write!(f, "Exit with code {}", code),
+            HostEcallError::Panic => write!(f, "Panic occurred"),
+            HostEcallError::InvalidParameters(msg) => write!(f, "Invalid parameters: {}", msg),
+            HostEcallError::GenericError(msg) => write!(f, "Error: {}", msg),
+            HostEcallError::Overflow => write!(f, "Buffer overflow"),
+            HostEcallError::MessageDeserializationError(e) => {
+                write!(f, "Message deserialization error: {}", e)
+            }
+            HostEcallError::InvalidResponse(msg) => {
+                write!(f, "Invalid response from host: {}", msg)
+            }
+            HostEcallError::CpuError(e) => write!(f, "Cpu error: {}", e),
+            HostEcallError::MemoryError(e) => write!(f, "Memory error: {}", e),
+            HostEcallError::Disconnected => write!(f, "The client disconnected"),
+            HostEcallError::UnhandledEcall(code) => write!(f, "Unhandled ecall: {}", code),
+            HostEcallError::SuspendRequested => write!(f, "Suspend requested"),
+        }
+    }
+}
+
+impl std::error::Error for HostEcallError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            HostEcallError::MemoryError(e) => Some(e),
+            HostEcallError::MessageDeserializationError(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl<E: fmt::Debug> From<CpuError<E>> for HostEcallError {
+    fn from(error: CpuError<E>) -> Self {
+        match error {
+            CpuError::MemoryError(e) => HostEcallError::MemoryError(e),
+            e => HostEcallError::CpuError(format!("{:?}", e)),
+        }
+    }
+}
+
+impl From<MemoryError> for HostEcallError {
+    fn from(error: MemoryError) -> Self {
+        HostEcallError::MemoryError(error)
+    }
+}
+
+impl From<MessageDeserializationError> for HostEcallError {
+    fn from(error: MessageDeserializationError) -> Self {
+        HostEcallError::MessageDeserializationError(error)
+    }
+}
+
+/// Implementation of all the ECALLs on the host, for V-Apps running in the emulator.
+///
+/// Communication ECALLs are forwarded to the client with the same client commands used by the
+/// VM on the device; everything else is computed locally.
+pub struct HostEcallHandler {
+    comm: VmComm,
+    ux_policy: UxPolicy,
+    // event to be returned at the next call to get_event, if any
+    pending_event: Option<(EventCode, [u8; 16])>,
+    ticks_since_abort_check: u32,
+}
+
+impl HostEcallHandler {
+    pub(crate) fn new(comm: VmComm, ux_policy: UxPolicy) -> Self {
+        Self {
+            comm,
+            ux_policy,
+            pending_event: None,
+            ticks_since_abort_check: 0,
+        }
+    }
+
+    pub(crate) fn into_comm(self) -> VmComm {
+        self.comm
+    }
+
+    fn read_guest(
+        cpu: &mut Cpu<'_, VecMemory>,
+        ptr: u32,
+        len: usize,
+    ) -> Result<Vec<u8>, HostEcallError> {
+        let mut buffer = vec![0u8; len];
+        // Rust uses a dangling pointer for empty buffers, so we must not access it
+        if len > 0 {
+            cpu.get_segment::<HostEcallError>(ptr)?
+                .read_buffer(ptr, &mut buffer)?;
+        }
+        Ok(buffer)
+    }
+
+    fn write_guest(
+        cpu: &mut Cpu<'_, VecMemory>,
+        ptr: u32,
+        data: &[u8],
+    ) -> Result<(), HostEcallError> {
+        if !data.is_empty() {
+            cpu.get_writable_segment::<HostEcallError>(ptr)?
+                .write_buffer(ptr, data)?;
+        }
+        Ok(())
+    }
+
+    fn handle_send_buffer(
+        &mut self,
+        cpu: &mut Cpu<'_, VecMemory>,
+        buffer: u32,
+        size: usize,
+        buffer_type: BufferType,
+    ) -> Result<(), HostEcallError> {
+        if buffer.checked_add(size as u32).is_none() {
+            return Err(HostEcallError::Overflow);
+        }
+
+        let data = Self::read_guest(cpu, buffer, size)?;
+
+        // same chunking as the VM: at most 249 bytes in the first chunk, 254 in the others
+        let first_len = core::cmp::min(size, 255 - 6);
+        self.comm.interrupt(
+            SendBufferMessage::new(size as u32, buffer_type, &data[..first_len]).serialize(),
+        )?;
+        for chunk in data[first_len..].chunks(255 - 1) {
+            self.comm
+                .interrupt(SendBufferContinuedMessage::new(chunk).serialize())?;
+        }
+        Ok(())
+    }
+
+    fn handle_xrecv(
+        &mut self,
+        cpu: &mut Cpu<'_, VecMemory>,
+        buffer: u32,
+        max_size: usize,
+    ) -> Result<usize, HostEcallError> {
+        let mut received: Vec<u8> = Vec::new();
+        let mut remaining_length = None;
+        while remaining_length != Some(0) {
+            let request = ReceiveBufferMessage::new().serialize();
+            let raw_response = if remaining_length.is_none() {
+                // The client can only suspend the V-App or abort its request before the message
+                // starts; when suspending, the ECALL is executed again when the V-App is resumed.
+                match self.comm.interrupt_with_reply(request)? {
+                    ClientReply::Continue(response) => response,
+                    ClientReply::Suspend => return Err(HostEcallError::SuspendRequested),
+                    ClientReply::Abort => return Ok(XRECV_ABORTED),
+                }
+            } else {
+                self.comm.interrupt(request)?
+            };
+            let response = ReceiveBufferResponse::deserialize(&raw_response)?;
+
+            let remaining = match remaining_length {
+                None => {
+                    // first chunk, check if the total length is acceptable
+                    if response.remaining_length > max_size as u32 {
+                        return Err(HostEcallError::InvalidResponse(
+                            "Received data is too large",
+                        ));
+                    }
+                    response.remaining_length
+                }
+                Some(remaining) => {
+                    if remaining != response.remaining_length {
+                        return Err(HostEcallError::InvalidResponse(
+                            "Mismatching remaining length",
+                        ));
+                    }
+                    remaining
+                }
+            };
+
+            let chunk_len = response.content.len() as u32;
+            if chunk_len > remaining {
+                return Err(HostEcallError::InvalidResponse(
+                    "Chunk exceeds declared remaining length",
+                ));
+            }
+            received.extend_from_slice(response.content);
+            remaining_length = Some(remaining - chunk_len);
+        }
+
+        Self::write_guest(cpu, buffer, &received)?;
+        Ok(received.len())
+    }
+
+    fn handle_get_event(
+        &mut self,
+        cpu: &mut Cpu<'_, VecMemory>,
+        event_data: u32,
+    ) -> Result<u32, HostEcallError> {
+        // Without a pending event, we return a ticker immediately: there is no reason to make the
+        // V-App wait in the emulator.
+        let (event_code, data) = match self.pending_event.take() {
+            Some(event) => event,
+            None if self.check_abort()? => (EventCode::Abort, [0u8; 16]),
+            None => (EventCode::Ticker, [0u8; 16]),
+        };
+        Self::write_guest(cpu, event_data, &data)?;
+        Ok(event_code as u32)
+    }
+
+    // Counts a ticker, and asks the client whether to abort the current request every
+    // ABORT_CHECK_INTERVAL_TICKS tickers
+    fn check_abort(&mut self) -> Result<bool, HostEcallError> {
+        self.ticks_since_abort_check += 1;
+        if self.ticks_since_abort_check < ABORT_CHECK_INTERVAL_TICKS {
+            return Ok(false);
+        }
+        self.ticks_since_abort_check = 0;
+
+        match self
+            .comm
+            .interrupt_with_reply(CheckAbortMessage::new().serialize())?
+        {
+            ClientReply::Continue(_) => Ok(false),
+            ClientReply::Abort => Ok(true),
+            ClientReply::Suspend => Err(HostEcallError::InvalidResponse(
+                "The V-App can only be suspended while waiting for a message",
+            )),
+        }
+    }
+
+    // Decides the action to take on a page, according to the UX policy
+    fn choose_action(&self, page: &Page) -> Option<Action> {
+        match (page, self.ux_policy) {
+            (Page::ConfirmReject { .. }, UxPolicy::Approve) => Some(Action::Confirm),
+            (Page::ConfirmReject { .. }, UxPolicy::Reject) => Some(Action::Reject),
+            (
+                Page::GenericPage {
+                    navigation_info,
+                    page_content_info,
+                },
+                policy,
+            ) => {
+                let is_confirmation = matches!(
+                    page_content_info.page_content,
+                    PageContent::ConfirmationButton { .. }
+                        | PageContent::ConfirmationLongPress { .. }
+                );
+                let can_quit = matches!(
+                    navigation_info.as_ref().map(|n| &n.nav_info),
+                    Some(NavInfo::NavWithButtons {
+                        quit_text: Some(_),
+                        ..
+                    })
+                );
+                let has_next_page = navigation_info
+                    .as_ref()
+                    .is_some_and(|n| n.active_page + 1 < n.n_pages);
+
+                match policy {
+                    UxPolicy::Approve if is_confirmation => Some(Action::Confirm),
+                    UxPolicy::Reject if can_quit => Some(Action::Quit),
+                    _ if has_next_page => Some(Action::NextPage),
+                    _ => None,
+                }
+            }
+            _ => None,
+        }
+    }
+
+    fn handle_show_page(
+        &mut self,
+        cpu: &mut Cpu<'_, VecMemory>,
+        page_ptr: u32,
+        page_len: usize,
+    ) -> Result<u32, HostEcallError> {
+        if page_len > MAX_UX_PAGE_LEN {
+            return Err(HostEcallError::InvalidParameters("page_len is too large"));
+        }
+        let page_raw = Self::read_guest(cpu, page_ptr, page_len)?;
+        let page = Page::deserialize_full(&page_raw)
+            .map_err(|_| HostEcallError::InvalidParameters("Failed to deserialize page"))?;
+
+        self.pending_event = self.choose_action(&page).map(|action| {
+            let mut data = [0u8; 16];
+            data[0] = action as u8;
+            (EventCode::Action, data)
+        });
+        Ok(1)
+    }
+
+    fn handle_show_step(
+        &mut self,
+        cpu: &mut Cpu<'_, VecMemory>,
+        step_ptr: u32,
+        step_len: usize,
+    ) -> Result<u32, HostEcallError> {
+        if step_len > MAX_UX_STEP_LEN {
+            return Err(HostEcallError::InvalidParameters("step_len is too large"));
+        }
+        let step_raw = Self::read_guest(cpu, step_ptr, step_len)?;
+        common::ux::Step::deserialize_full(&step_raw)
+            .map_err(|_| HostEcallError::InvalidParameters("Failed to deserialize step"))?;
+
+        // The emulator reports a device with the page UX model, like the native target
+        Err(HostEcallError::InvalidParameters(
+            "The emulator implements the page UX model, not the step UX model",
+        ))
+    }
+
+    fn handle_get_device_property(&self, property: u32) -> Result<u32, HostEcallError> {
+        match property {
+            // same values as the native target of the V-App SDK
+            DEVICE_PROPERTY_ID => Ok(0),
+            DEVICE_PROPERTY_SCREEN_SIZE => Ok(0),
+            DEVICE_PROPERTY_FEATURES => Ok(0),
+            _ => Err(HostEcallError::InvalidParameters("Unknown device property")),
+        }
+    }
+
+    // Reads a big-endian big number from the V-App memory
+    fn read_bignum(
+        cpu: &mut Cpu<'_, VecMemory>,
+        ptr: u32,
+        len: usize,
+    ) -> Result<BigUint, HostEcallError> {
+        Ok(BigUint::from_bytes_be(&Self::read_guest(cpu, ptr, len)?))
+    }
+
+    // Writes a big number to the V-App memory as a big-endian buffer of exactly `len` bytes
+    fn write_bignum(
+        cpu: &mut Cpu<'_, VecMemory>,
+        ptr: u32,
+        value: &BigUint,
+        len: usize,
+    ) -> Result<(), HostEcallError> {
+        let bytes = value.to_bytes_be();
+        if bytes.len() > len {
+            return Err(HostEcallError::Overflow);
+        }
+        let mut result = vec![0u8; len - bytes.len()];
+        result.extend_from_slice(&bytes);
+        Self::write_guest(cpu, ptr, &result)
+    }
+
+    fn handle_bn_modm(
+        &self,
+        cpu: &mut Cpu<'_, VecMemory>,
+        r: u32,
+        n: u32,
+        len: usize,
+        m: u32,
+        m_len: usize,
+    ) -> Result<(), HostEcallError> {
+        if len > MAX_BIGNUMBER_SIZE || m_len > MAX_BIGNUMBER_SIZE {
+            return Err(HostEcallError::InvalidParameters(
+                "len or m_len is too large",
+            ));
+        }
+        let n = Self::read_bignum(cpu, n, len)?;
+        let m = Self::read_bignum(cpu, m, m_len)?;
+        if m.is_zero() {
+            return Err(HostEcallError::GenericError("modm failed"));
+        }
+        Self::write_bignum(cpu, r, &(n % m), len)
+    }
+
+    // Reads the three operands of addm, subm and multm, validating them like the device does
+    fn read_modular_operands(
+        cpu: &mut Cpu<'_, VecMemory>,
+        a: u32,
+        b: u32,
+        m: u32,
+        len: usize,
+    ) -> Result<(BigUint, BigUint, BigUint), HostEcallError> {
+        if len > MAX_BIGNUMBER_SIZE {
+            return Err(HostEcallError::InvalidParameters("len is too large"));
+        }
+        let a = Self::read_bignum(cpu, a, len)?;
+        let b = Self::read_bignum(cpu, b, len)?;
+        let m = Self::read_bignum(cpu, m, len)?;
+        if m.is_zero() || a >= m || b >= m {
+            return Err(HostEcallError::InvalidParameters(
+                "operands must be smaller than a non-zero modulus",
+            ));
+        }
+        Ok((a, b, m))
+    }
+
+    fn handle_bn_addm(
+        &self,
+        cpu: &mut Cpu<'_, VecMemory>,
+        r: u32,
+        a: u32,
+        b: u32,
+        m: u32,
+        len: usize,
+    ) -> Result<(), HostEcallError> {
+        let (a, b, m) = Self::read_modular_operands(cpu, a, b, m, len)?;
+        Self::write_bignum(cpu, r, &((a + b) % m), len)
+    }
+
+    fn handle_bn_subm(
+        &self,
+        cpu: &mut Cpu<'_, VecMemory>,
+        r: u32,
+        a: u32,
+        b: u32,
+        m: u32,
+        len: usize,
+    ) -> Result<(), HostEcallError> {
+        let (a, b, m) = Self::read_modular_operands(cpu, a, b, m, len)?;
+        // the `+ &m` is to avoid negative numbers, since BigUints must be non-negative
+        Self::write_bignum(cpu, r, &((a + &m - b) % m), len)
+    }
+
+    fn handle_bn_multm(
+        &self,
+        cpu: &mut Cpu<'_, VecMemory>,
+        r: u32,
+        a: u32,
+        b: u32,
+        m: u32,
+        len: usize,
+    ) -> Result<(), HostEcallError> {
+        let (a, b, m) = Self::read_modular_operands(cpu, a, b, m, len)?;
+        Self::write_bignum(cpu, r, &((a * b) % m), len)
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn handle_bn_powm(
+        &self,
+        cpu: &mut Cpu<'_, VecMemory>,
+        r: u32,
+        a: u32,
+        e: u32,
+        len_e: usize,
+        m: u32,
+        len: usize,
+    ) -> Result<(), HostEcallError> {
+        if len_e > MAX_BIGNUMBER_SIZE {
+            return Err(HostEcallError::InvalidParameters("len_e is too large"));
+        }
+        if len > MAX_BIGNUMBER_SIZE {
+            return Err(HostEcallError::InvalidParameters("len is too large"));
+        }
+        let a = Self::read_bignum(cpu, a, len)?;
+        let e = Self::read_bignum(cpu, e, len_e)?;
+        let m = Self::read_bignum(cpu, m, len)?;
+        if m.is_zero() || a >= m {
+            return Err(HostEcallError::GenericError("powm failed"));
+        }
+        Self::write_bignum(cpu, r, &a.modpow(&e, &m), len)
+    }
+
+    fn handle_hash_init(
+        &self,
+        cpu: &mut Cpu<'_, VecMemory>,
+        hash_id: u32,
+        ctx: u32,
+    ) -> Result<(), HostEcallError> {
+        let kind = HashKind::try_from(hash_id)
+            .map_err(|_| HostEcallError::InvalidParameters("Unsupported hash id"))?;
+        Self::write_guest(cpu, ctx, &hash::init(kind))
+    }
+
+    fn handle_hash_update(
+        &self,
+        cpu: &mut Cpu<'_, VecMemory>,
+        hash_id: u32,
+        ctx: u32,
+        data: u32,
+        data_len: usize,
+    ) -> Result<(), HostEcallError> {
+        let kind = HashKind::try_from(hash_id)
+            .map_err(|_| HostEcallError::InvalidParameters("Unsupported hash id"))?;
+        if data_len == 0 {
+            return Ok(());
+        }
+        let mut ctx_local = Self::read_guest(cpu, ctx, kind.ctx_size())?;
+        let data_local = Self::read_guest(cpu, data, data_len)?;
+        hash::update(kind, &mut ctx_local, &data_local).map_err(HostEcallError::GenericError)?;
+        Self::write_guest(cpu, ctx, &ctx_local)
+    }
+
+    fn handle_hash_digest(
+        &self,
+        cpu: &mut Cpu<'_, VecMemory>,
+        hash_id: u32,
+        ctx: u32,
+        digest: u32,
+    ) -> Result<(), HostEcallError> {
+        let kind = HashKind::try_from(hash_id)
+            .map_err(|_| HostEcallError::InvalidParameters("Unsupported hash id"))?;
+        let mut ctx_local = Self::read_guest(cpu, ctx, kind.ctx_size())?;
+        let digest_local =
+            hash::digest(kind, &mut ctx_local).map_err(HostEcallError::GenericError)?;
+        Self::write_guest(cpu, digest, &digest_local)
+    }
+
+    fn handle_derive_hd_node(
+        &self,
+        cpu: &mut Cpu<'_, VecMemory>,
+        curve: u32,
+        path: u32,
+        path_len: usize,
+        private_key: u32,
+        chain_code: u32,
+    ) -> Result<(), HostEcallError> {
+        if curve != CurveKind::Secp256k1 as u32 {
+            return Err(HostEcallError::InvalidParameters("Unsupported curve"));
+        }
+        if path_len > MAX_BIP32_PATH {
+            return Err(HostEcallError::InvalidParameters("path_len is too large"));
+        }
+
+        let path_raw = Self::read_guest(cpu, path, path_len * 4)?;
+
+        let mut key = get_master_bip32_key();
+        for step in path_raw.chunks(4) {
+            let child = ChildNumber::from(u32::from_le_bytes(step.try_into().unwrap()));
+            key = key
+                .derive_child(child)
+                .map_err(|_| HostEcallError::GenericError("Key derivation failed"))?;
+        }
+
+        Self::write_guest(cpu, private_key, &key.private_key().to_bytes())?;
+        Self::write_guest(cpu, chain_code, &key.attrs().chain_code)
+    }
+
+    fn handle_get_master_fingerprint(&self, curve: u32) -> Result<u32, HostEcallError> {
+        if curve != CurveKind::Secp256k1 as u32 {
+            return Err(HostEcallError::InvalidParameters("Unsupported curve"));
+        }
+        Ok(u32::from_be_bytes(
+            get_master_bip32_key().public_key().fingerprint(),
+        ))
+    }
+
+    fn handle_derive_slip21_node(
+        &self,
+        cpu: &mut Cpu<'_, VecMemory>,
+        labels: u32,
+        labels_len: usize,
+        out: u32,
+    ) -> Result<u32, HostEcallError> {
+        if labels_len > 256 {
+            return Err(HostEcallError::InvalidParameters("labels_len is too large"));
+        }
+        let labels_local = Self::read_guest(cpu, labels, labels_len)?;
+
+        // Vanadium uses a custom seed for its SLIP-21 hierarchy, for compatibility with Bolos
+        let master = slip21_get_master_node(&DEFAULT_SEED);
+        let seed_node = slip21_derive_child_node(&master, SEED_MASTER_PATH.as_bytes());
+        let mut current_node = slip21_get_master_node(&seed_node[32..64]);
+
+        // `labels` is the concatenation of the labels, each prefixed by its length
+        let mut offset = 0;
+        while offset < labels_len {
+            let label_len = labels_local[offset] as usize;
+            offset += 1;
+            if label_len > 252 || offset + label_len > labels_len {
+                return Err(HostEcallError::InvalidParameters("Invalid labels format"));
+            }
+            current_node =
+                slip21_derive_child_node(&current_node, &labels_local[offset..offset + label_len]);
+            offset += label_len;
+        }
+
+        Self::write_guest(cpu, out, &current_node)?;
+        Ok(1)
+    }
+
+    fn read_point(
+        cpu: &mut Cpu<'_, VecMemory>,
+        ptr: u32,
+    ) -> Result<ProjectivePoint, HostEcallError> {
+        let raw = Self::read_guest(cpu, ptr, 65)?;
+        let encoded = EncodedPoint::from_bytes(&raw)
+            .map_err(|_| HostEcallError::InvalidParameters("Invalid point"))?;
+        Option::from(ProjectivePoint::from_encoded_point(&encoded))
+            .ok_or(HostEcallError::InvalidParameters("Invalid point"))
+    }
+
+    fn write_point(
+        cpu: &mut Cpu<'_, VecMemory>,
+        ptr: u32,
+        point: &ProjectivePoint,
+    ) -> Result<(), HostEcallError> {
+        let encoded = point.to_encoded_point(false);
+        if encoded.as_bytes().len() != 65 {
+            // the point at infinity has no uncompressed encoding
+            return Err(HostEcallError::GenericError("Point at infinity"));
+        }
+        Self::write_guest(cpu, ptr, encoded.as_bytes())
+    }
+
+    fn handle_ecfp_add_point(
+        &self,
+        cpu: &mut Cpu<'_, VecMemory>,
+        curve: u32,
+        r: u32,
+        p: u32,
+        q: u32,
+    ) -> Result<u32, HostEcallError> {
+        if curve != CurveKind::Secp256k1 as u32 {
+            return Err(HostEcallError::InvalidParameters("Unsupported curve"));
+        }
+        let p = Self::read_point(cpu, p)?;
+        let q = Self::read_point(cpu, q)?;
+        Self::write_point(cpu, r, &(p + q))?;
+        Ok(1)
+    }
+
+    fn handle_ecfp_scalar_mult(
+        &self,
+        cpu: &mut Cpu<'_, VecMemory>,
+        curve: u32,
+        r: u32,
+        p: u32,
+        k: u32,
+        k_len: usize,
+    ) -> Result<u32, HostEcallError> {
+        if curve != CurveKind::Secp256k1 as u32 {
+            return Err(HostEcallError::InvalidParameters("Unsupported curve"));
+        }
+        if k_len > 32 {
+            return Err(HostEcallError::InvalidParameters("k_len is too large"));
+        }
+        let p = Self::read_point(cpu, p)?;
+
+        let mut k_bytes = [0u8; 32];
+        k_bytes[32 - k_len..].copy_from_slice(&Self::read_guest(cpu, k, k_len)?);
+        let k: Option<Scalar> = Scalar::from_repr(k_bytes.into()).into();
+        let k = k.ok_or(HostEcallError::InvalidParameters("Invalid scalar"))?;
+
+        Self::write_point(cpu, r, &(p * k))?;
+        Ok(1)
+    }
+
+    fn handle_get_random_bytes(
+        &self,
+        cpu: &mut Cpu<'_, VecMemory>,
+        buffer: u32,
+        size: usize,
+    ) -> Result<u32, HostEcallError> {
+        if size > 256 {
+            return Err(HostEcallError::InvalidParameters(
+                "size is too large, must be <= 256",
+            ));
+        }
+        let mut random_bytes = vec![0u8; size];
+        rand::rngs::OsRng
+            .try_fill_bytes(&mut random_bytes)
+            .map_err(|_| HostEcallError::GenericError("Failed to generate random bytes"))?;
+        Self::write_guest(cpu, buffer, &random_bytes)?;
+        Ok(1)
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn handle_ecdsa_sign(
+        &self,
+        cpu: &mut Cpu<'_, VecMemory>,
+        curve: u32,
+        mode: u32,
+        hash_id: u32,
+        privkey: u32,
+        msg_hash: u32,
+        signature: u32,
+    ) -> Result<usize, HostEcallError> {
+        if curve != CurveKind::Secp256k1 as u32 {
+            return Err(HostEcallError::InvalidParameters("Unsupported curve"));
+        }
+        if mode != ecall_constants::EcdsaSignMode::RFC6979 as u32 {
+            return Err(HostEcallError::InvalidParameters(
+                "Invalid or unsupported ecdsa signing mode",
+            ));
+        }
+        if hash_id != ecall_constants::HashId::Sha256 as u32 {
+            return Err(HostEcallError::InvalidParameters(
+                "Invalid or unsupported hash id",
+            ));
+        }
+
+        let privkey_local: [u8; 32] = Self::read_guest(cpu, privkey, 32)?.try_into().unwrap();
+        let msg_hash_local = Self::read_guest(cpu, msg_hash, 32)?;
+
+        let signing_key = ecdsa::SigningKey::from_bytes(&privkey_local.into())
+            .map_err(|_| HostEcallError::InvalidParameters("Invalid private key"))?;
+        let (signature_local, _) = signing_key
+            .sign_prehash_recoverable(&msg_hash_local)
+            .map_err(|_| HostEcallError::GenericError("ecdsa signing failed"))?;
+        let signature_der = ecdsa::DerSignature::from(signature_local);
+
+        Self::write_guest(cpu, signature, signature_der.as_bytes())?;
+        Ok(signature_der.as_bytes().len())
+    }
+
+    fn handle_ecdsa_verify(
+        &self,
+        cpu: &mut Cpu<'_, VecMemory>,
+        curve: u32,
+        pubkey: u32,
+        msg_hash: u32,
+        signature: u32,
+        signature_len: usize,
+    ) -> Result<u32, HostEcallError> {
+        if curve != CurveKind::Secp256k1 as u32 {
+            return Err(HostEcallError::InvalidParameters("Unsupported curve"));
+        }
+        if signature_len > 72 {
+            return Err(HostEcallError::InvalidParameters(
+                "signature_len is too large",
+            ));
+        }
+
+        let pubkey_local = Self::read_guest(cpu, pubkey, 65)?;
+        let msg_hash_local = Self::read_guest(cpu, msg_hash, 32)?;
+        let signature_local = Self::read_guest(cpu, signature, signature_len)?;
+
+        let Ok(verifying_key) = ecdsa::VerifyingKey::from_sec1_bytes(&pubkey_local) else {
+            return Ok(0);
+        };
+        let Ok(signature) = ecdsa::DerSignature::from_bytes(&signature_local) else {
+            return Ok(0);
+        };
+        Ok(verifying_key
+            .verify_prehash(&msg_hash_local, &signature)
+            .is_ok() as u32)
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn handle_schnorr_sign(
+        &self,
+        cpu: &mut Cpu<'_, VecMemory>,
+        curve: u32,
+        mode: u32,
+        hash_id: u32,
+        privkey: u32,
+        msg: u32,
+        msg_len: usize,
+        signature: u32,
+        entropy: u32,
+    ) -> Result<usize, HostEcallError> {
+        if curve != CurveKind::Secp256k1 as u32 {
+            return Err(HostEcallError::InvalidParameters("Unsupported curve"));
+        }
+        if mode != ecall_constants::SchnorrSignMode::BIP340 as u32 {
+            return Err(HostEcallError::InvalidParameters(
+                "Invalid or unsupported schnorr signing mode",
+            ));
+        }
+        if msg_len > 128 {
+            return Err(HostEcallError::InvalidParameters("msg_len is too large"));
+        }
+        if hash_id != ecall_constants::HashId::Sha256 as u32 {
+            return Err(HostEcallError::InvalidParameters(
+                "Invalid or unsupported hash id",
+            ));
+        }
+
+        let privkey_local = Self::read_guest(cpu, privkey, 32)?;
+        let msg_local = Self::read_guest(cpu, msg, msg_len)?;
+        let aux_rand: [u8; 32] = if entropy == 0 {
+            let mut aux_rand = [0u8; 32];
+            rand::rngs::OsRng
+                .try_fill_bytes(&mut aux_rand)
+                .map_err(|_| HostEcallError::GenericError("Failed to generate random bytes"))?;
+            aux_rand
+        } else {
+            Self::read_guest(cpu, entropy, 32)?.try_into().unwrap()
+        };
+
+        let signing_key = schnorr::SigningKey::from_bytes(&privkey_local)
+            .map_err(|_| HostEcallError::InvalidParameters("Invalid private key"))?;
+        let signature_local = signing_key
+            .sign_raw(&msg_local, &aux_rand)
+            .map_err(|_| HostEcallError::GenericError("schnorr signing failed"))?
+            .to_bytes();
+
+        Self::write_guest(cpu, signature, &signature_local)?;
+        Ok(signature_local.len())
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn handle_schnorr_verify(
+        &self,
+        cpu: &mut Cpu<'_, VecMemory>,
+        curve: u32,
+        mode: u32,
+        hash_id: u32,
+        pubkey: u32,
+        msg: u32,
+        msg_len: usize,
+        signature: u32,
+        signature_len: usize,
+    ) -> Result<u32, HostEcallError> {
+        if curve != CurveKind::Secp256k1 as u32 {
+            return Err(HostEcallError::InvalidParameters("Unsupported curve"));
+        }
+        if mode != ecall_constants::SchnorrSignMode::BIP340 as u32 {
+            return Err(HostEcallError::InvalidParameters(
+                "Invalid or unsupported schnorr signing mode",
+            ));
+        }
+        if msg_len > 128 {
+            return Err(HostEcallError::InvalidParameters("msg_len is too large"));
+        }
+        if hash_id != ecall_constants::HashId::Sha256 as u32 {
+            return Err(HostEcallError::InvalidParameters(
+                "Invalid or unsupported hash id",
+            ));
+        }
+        if signature_len != 64 {
+            return Err(HostEcallError::InvalidParameters(
+                "Invalid signature length",
+            ));
+        }
+
+        let pubkey_local = Self::read_guest(cpu, pubkey, 65)?;
+        let msg_local = Self::read_guest(cpu, msg, msg_len)?;
+        let signature_local = Self::read_guest(cpu, signature, signature_len)?;
+
+        // BIP-340 public keys are the x coordinate of the point
+        let Ok(verifying_key) = schnorr::VerifyingKey::from_bytes(&pubkey_local[1..33]) else {
+            return Ok(0);
+        };
+        let Ok(signature) = schnorr::Signature::try_from(signature_local.as_slice()) else {
+            return Ok(0);
+        };
+        Ok(verifying_key.verify_raw(&msg_local, &signature).is_ok() as u32)
+    }
+}
+
+impl EcallHandler for HostEcallHandler {
+    type Memory = VecMemory;
+    type Error = HostEcallError;
+
+    fn handle_ecall(&mut self, cpu: &mut Cpu<'_, VecMemory>) -> Result<(), HostEcallError> {
+        macro_rules! reg {
+            ($idx:expr) => {
+                cpu.regs[REG_A0 + $idx]
+            };
+        }
+
+        let ecall_code = cpu.regs[REG_T0];
+
+        // The instruction budget limits the execution between interactions with the outside world
+        if matches!(
+            ecall_code,
+            ECALL_XSEND | ECALL_XRECV | ECALL_GET_EVENT | ECALL_SHOW_PAGE | ECALL_SHOW_STEP
+        ) {
+            cpu.reset_instruction_budget();
+        }
+
+        match ecall_code {
+            ECALL_EXIT => return Err(HostEcallError::Exit(reg!(0) as i32)),
+            ECALL_FATAL => {
+                self.handle_send_buffer(cpu, reg!(0), reg!(1) as usize, BufferType::Panic)?;
+                return Err(HostEcallError::Panic);
+            }
+            ECALL_XSEND => {
+                self.handle_send_buffer(cpu, reg!(0), reg!(1) as usize, BufferType::VAppMessage)?
+            }
+            ECALL_XRECV => {
+                reg!(0) = self.handle_xrecv(cpu, reg!(0), reg!(1) as usize)? as u32;
+            }
+            ECALL_PRINT => {
+                self.handle_send_buffer(cpu, reg!(0), reg!(1) as usize, BufferType::Print)?;
+                reg!(0) = 1;
+            }
+            ECALL_GET_EVENT => {
+                reg!(0) = self.handle_get_event(cpu, reg!(0))?;
+            }
+            ECALL_SHOW_PAGE => {
+                reg!(0) = self.handle_show_page(cpu, reg!(0), reg!(1) as usize)?;
+            }
+            ECALL_SHOW_STEP => {
+                reg!(0) = self.handle_show_step(cpu, reg!(0), reg!(1) as usize)?;
+            }
+            ECALL_GET_DEVICE_PROPERTY => {
+                reg!(0) = self.handle_get_device_property(reg!(0))?;
+            }
+            ECALL_MODM => {
+                self.handle_bn_modm(
+                    cpu,
+                    reg!(0),
+                    reg!(1),
+                    reg!(2) as usize,
+                    reg!(3),
+                    reg!(4) as usize,
+                )?;
+                reg!(0) = 1;
+            }
+            ECALL_ADDM => {
+                self.handle_bn_addm(cpu, reg!(0), reg!(1), reg!(2), reg!(3), reg!(4) as usize)?;
+                reg!(0) = 1;
+            }
+            ECALL_SUBM => {
+                self.handle_bn_subm(cpu, reg!(0), reg!(1), reg!(2), reg!(3), reg!(4) as usize)?;
+                reg!(0) = 1;
+            }
+            ECALL_MULTM => {
+                self.handle_bn_multm(cpu, reg!(0), reg!(1), reg!(2), reg!(3), reg!(4) as usize)?;
+                reg!(0) = 1;
+            }
+            ECALL_POWM => {
+                self.handle_bn_powm(
+                    cpu,
+                    reg!(0),
+                    reg!(1),
+                    reg!(2),
+                    reg!(3) as usize,
+                    reg!(4),
+                    reg!(5) as usize,
+                )?;
+                reg!(0) = 1;
+            }
+            ECALL_HASH_INIT => self.handle_hash_init(cpu, reg!(0), reg!(1))?,
+            ECALL_HASH_UPDATE => {
+                self.handle_hash_update(cpu, reg!(0), reg!(1), reg!(2), reg!(3) as usize)?;
+                reg!(0) = 1;
+            }
+            ECALL_HASH_DIGEST => {
+                self.handle_hash_digest(cpu, reg!(0), reg!(1), reg!(2))?;
+                reg!(0) = 1;
+            }
+            ECALL_DERIVE_HD_NODE => {
+                self.handle_derive_hd_node(
+                    cpu,
+                    reg!(0),
+                    reg!(1),
+                    reg!(2) as usize,
+                    reg!(3),
+                    reg!(4),
+                )?;
+                reg!(0) = 1;
+            }
+            ECALL_GET_MASTER_FINGERPRINT => {
+                reg!(0) = self.handle_get_master_fingerprint(reg!(0))?;
+            }
+            ECALL_DERIVE_SLIP21_KEY => {
+                reg!(0) =
+                    self.handle_derive_slip21_node(cpu, reg!(0), reg!(1) as usize, reg!(2))?;
+            }
+            ECALL_ECFP_ADD_POINT => {
+                reg!(0) = self.handle_ecfp_add_point(cpu, reg!(0), reg!(1), reg!(2), reg!(3))?;
+            }
+            ECALL_ECFP_SCALAR_MULT => {
+                reg!(0) = self.handle_ecfp_scalar_mult(
+                    cpu,
+                    reg!(0),
+                    reg!(1),
+                    reg!(2),
+                    reg!(3),
+                    reg!(4) as usize,
+                )?;
+            }
+            ECALL_GET_RANDOM_BYTES => {
+                reg!(0) = self.handle_get_random_bytes(cpu, reg!(0), reg!(1) as usize)?;
+            }
+            ECALL_ECDSA_SIGN => {
+                reg!(0) = self.handle_ecdsa_sign(
+                    cpu,
+                    reg!(0),
+                    reg!(1),
+                    reg!(2),
+                    reg!(3),
+                    reg!(4),
+                    reg!(5),
+                )? as u32;
+            }
+            ECALL_ECDSA_VERIFY => {
+                reg!(0) = self.handle_ecdsa_verify(
+                    cpu,
+                    reg!(0),
+                    reg!(1),
+                    reg!(2),
+                    reg!(3),
+                    reg!(4) as usize,
+                )?;
+            }
+            ECALL_SCHNORR_SIGN => {
+                reg!(0) = self.handle_schnorr_sign(
+                    cpu,
+                    reg!(0),
+                    reg!(1),
+                    reg!(2),
+                    reg!(3),
+                    reg!(4),
+                    reg!(5) as usize,
+                    reg!(6),
+                    reg!(7),
+                )? as u32;
+            }
+            ECALL_SCHNORR_VERIFY => {
+                reg!(0) = self.handle_schnorr_verify(
+                    cpu,
+                    reg!(0),
+                    reg!(1),
+                    reg!(2),
+                    reg!(3),
+                    reg!(4),
+                    reg!(5) as usize,
+                    reg!(6),
+                    reg!(7) as usize,
+                )?;
+            }
+            _ => return Err(HostEcallError::UnhandledEcall(ecall_code)),
+        }
+
+        Ok(())
+    }
+}
+
+fn get_master_bip32_key() -> XPrv {
+    XPrv::new(DEFAULT_SEED).expect("Failed to create master key from seed")
+}
+
+fn slip21_get_master_node(seed: &[u8]) -> [u8; 64] {
+    // compute HMAC-SHA512(key = SLIP21_MAGIC, msg = seed)
+    let mut mac = Hmac::<Sha512>::new_from_slice(SLIP21_MAGIC.as_bytes())
+        .expect("HMAC can take key of any size");
+    mac.update(seed);
+    mac.finalize().into_bytes().into()
+}
+
+fn slip21_derive_child_node(cur_node: &[u8; 64], label: &[u8]) -> [u8; 64] {
+    // compute HMAC-SHA512(key = cur_node[:32], msg = [0] + label)
+    let mut mac =
+        Hmac::<Sha512>::new_from_slice(&cur_node[..32]).expect("HMAC can take key of any size");
+    mac.update(&[0u8]);
+    mac.update(label);
+    mac.finalize().into_bytes().into()
+}
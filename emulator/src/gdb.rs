@@ -0,0 +1,426 @@
+//! A stub for the GDB Remote Serial Protocol, that allows debugging a V-App running in the
+//! emulator with `gdb`.
+//!
+//! The stub implements the [`DebugHook`] trait, and serves a single GDB connection for the
+//! duration of the execution of the V-App. It supports reading and writing registers and memory,
+//! software and hardware breakpoints (both implemented by checking the `pc` before each
+//! instruction, without patching the code), single-stepping and interrupting a running V-App.
+
+use std::collections::BTreeSet;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::TcpStream;
+
+use common::vm::{Cpu, DebugHook, TrapCause, VecMemory};
+
+// Signals reported to GDB in the stop replies
+const SIGILL: u8 = 4;
+const SIGTRAP: u8 = 5;
+const SIGBUS: u8 = 7;
+const SIGSEGV: u8 = 11;
+
+// Number of instructions executed between two checks for an interrupt request from GDB
+const INTERRUPT_POLL_INTERVAL: u32 = 0x10000;
+
+// Registers x0 to x31 in the ABI naming used by GDB, followed by pc as register 32
+const REGISTER_NAMES: [&str; 32] = [
+    "zero", "ra", "sp", "gp", "tp", "t0", "t1", "t2", "fp", "s1", "a0", "a1", "a2", "a3", "a4",
+    "a5", "a6", "a7", "s2", "s3", "s4", "s5", "s6", "s7", "s8", "s9", "s10", "s11", "t3", "t4",
+    "t5", "t6",
+];
+const PC_REGNUM: usize = 32;
+
+/// Errors that abort the execution of the V-App from the debugger.
+#[derive(Debug)]
+pub enum GdbStubError {
+    /// GDB killed the V-App.
+    Killed,
+}
+
+// What the V-App does once GDB resumes it
+enum Resume {
+    Continue,
+    Step,
+    Kill,
+}
+
+/// A [`DebugHook`] that lets GDB control the V-App over the Remote Serial Protocol.
+///
+/// The V-App is stopped before its first instruction, and the stub then waits for the commands of
+/// GDB. If GDB detaches or the connection is lost, the V-App continues running normally.
+pub struct GdbStub {
+    stream: Option<TcpStream>,
+    breakpoints: BTreeSet<u32>,
+    stop_requested: bool,
+    // false until the V-App first stops
+    started: bool,
+    // true right after resuming, so that the breakpoint at the current pc is not hit again
+    resuming: bool,
+    instructions_until_poll: u32,
+}
+
+impl GdbStub {
+    /// Creates a stub serving the GDB connection `stream`.
+    pub fn new(stream: TcpStream) -> Self {
+        // stop replies are short packets, better not to delay them
+        let _ = stream.set_nodelay(true);
+        Self {
+            stream: Some(stream),
+            breakpoints: BTreeSet::new(),
+            stop_requested: true,
+            started: false,
+            resuming: false,
+            instructions_until_poll: INTERRUPT_POLL_INTERVAL,
+        }
+    }
+
+    fn stream(&mut self) -> io::Result<&mut TcpStream> {
+        self.stream
+            .as_mut()
+            .ok_or_else(|| io::Error::from(ErrorKind::NotConnected))
+    }
+
+    fn read_byte(&mut self) -> io::Result<u8> {
+        let mut byte = [0u8; 1];
+        self.stream()?.read_exact(&mut byte)?;
+        Ok(byte[0])
+    }
+
+    // Returns true if GDB sent an interrupt (Ctrl-C) while the V-App was running
+    fn poll_interrupt(&mut self) -> io::Result<bool> {
+        let stream = self.stream()?;
+        stream.set_nonblocking(true)?;
+        let mut byte = [0u8; 1];
+        let result = stream.read(&mut byte);
+        stream.set_nonblocking(false)?;
+        match result {
+            Ok(0) => Err(ErrorKind::UnexpectedEof.into()),
+            Ok(_) => Ok(byte[0] == 0x03),
+            Err(e) if e.kind() == ErrorKind::WouldBlock => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    // Receives the next packet, acknowledging it. Stray acknowledgments and interrupts are
+    // ignored, as the V-App is already stopped.
+    fn receive_packet(&mut self) -> io::Result<String> {
+        loop {
+            while self.read_byte()? != b'$' {}
+
+            let mut data = Vec::new();
+            loop {
+                match self.read_byte()? {
+                    b'#' => break,
+                    b => data.push(b),
+                }
+            }
+            let checksum = [self.read_byte()?, self.read_byte()?];
+            let expected = std::str::from_utf8(&checksum)
+                .ok()
+                .and_then(|s| u8::from_str_radix(s, 16).ok());
+            if expected == Some(checksum_of(&data)) {
+                self.stream()?.write_all(b"+")?;
+                return String::from_utf8(data).map_err(|_| ErrorKind::InvalidData.into());
+            }
+            self.stream()?.write_all(b"-")?;
+        }
+    }
+
+    // Sends a packet, retransmitting it until GDB acknowledges it
+    fn send_packet(&mut self, data: &str) -> io::Result<()> {
+        let packet = format!("${}#{:02x}", data, checksum_of(data.as_bytes()));
+        loop {
+            self.stream()?.write_all(packet.as_bytes())?;
+            match self.read_byte()? {
+                b'+' => return Ok(()),
+                b'-' => continue,
+                _ => return Err(ErrorKind::InvalidData.into()),
+            }
+        }
+    }
+
+    // Answers the commands of GDB while the V-App is stopped, until it is resumed
+    fn serve(&mut self, cpu: &mut Cpu<'_, VecMemory>, signal: u8) -> io::Result<Resume> {
+        loop {
+            let packet = self.receive_packet()?;
+            let (command, args) = packet.split_at(packet.len().min(1));
+            let reply = match command {
+                "?" => format!("S{:02x}", signal),
+                "g" => {
+                    let mut regs = Vec::with_capacity(4 * (PC_REGNUM + 1));
+                    for reg in cpu.regs.iter().chain(core::iter::once(&cpu.pc)) {
+                        regs.extend_from_slice(&reg.to_le_bytes());
+                    }
+                    encode_hex(&regs)
+                }
+                "G" => match decode_hex(args) {
+                    Some(regs) if regs.len() >= 4 * (PC_REGNUM + 1) => {
+                        let mut values = regs
+                            .chunks_exact(4)
+                            .map(|c| u32::from_le_bytes(c.try_into().unwrap()));
+                        for reg in cpu.regs.iter_mut() {
+                            *reg = values.next().unwrap();
+                        }
+                        cpu.regs[0] = 0;
+                        cpu.pc = values.next().unwrap();
+                        "OK".into()
+                    }
+                    _ => "E01".into(),
+                },
+                "p" => match usize::from_str_radix(args, 16) {
+                    Ok(n) if n < 32 => encode_hex(&cpu.regs[n].to_le_bytes()),
+                    Ok(PC_REGNUM) => encode_hex(&cpu.pc.to_le_bytes()),
+                    _ => "E01".into(),
+                },
+                "P" => {
+                    let parsed = args.split_once('=').and_then(|(n, value)| {
+                        let n = usize::from_str_radix(n, 16).ok()?;
+                        let value: [u8; 4] = decode_hex(value)?.try_into().ok()?;
+                        Some((n, u32::from_le_bytes(value)))
+                    });
+                    match parsed {
+                        Some((0, _)) => "OK".into(),
+                        Some((n, value)) if n < 32 => {
+                            cpu.regs[n] = value;
+                            "OK".into()
+                        }
+                        Some((PC_REGNUM, value)) => {
+                            cpu.pc = value;
+                            "OK".into()
+                        }
+                        _ => "E01".into(),
+                    }
+                }
+                "m" => {
+                    let mut buf = Vec::new();
+                    let result = parse_address_length(args).and_then(|(address, length)| {
+                        buf.resize(length.min(0x1000), 0);
+                        cpu.debug_read_memory(address, &mut buf).ok()
+                    });
+                    match result {
+                        Some(()) => encode_hex(&buf),
+                        None => "E14".into(),
+                    }
+                }
+                "M" => {
+                    let result = args.split_once(':').and_then(|(range, data)| {
+                        let (address, length) = parse_address_length(range)?;
+                        let data = decode_hex(data).filter(|d| d.len() == length)?;
+                        cpu.debug_write_memory(address, &data).ok()
+                    });
+                    match result {
+                        Some(()) => "OK".into(),
+                        None => "E14".into(),
+                    }
+                }
+                "Z" | "z" => {
+                    let mut fields = args.split(',');
+                    let kind = fields.next();
+                    let address = fields.next().and_then(|a| u32::from_str_radix(a, 16).ok());
+                    match (kind, address) {
+                        (Some("0") | Some("1"), Some(address)) => {
+                            if command == "Z" {
+                                self.breakpoints.insert(address);
+                            } else {
+                                self.breakpoints.remove(&address);
+                            }
+                            "OK".into()
+                        }
+                        // watchpoints are not supported
+                        _ => String::new(),
+                    }
+                }
+                "c" | "s" | "C" | "S" => {
+                    // the signal passed by C and S is ignored, as the V-App has no handlers
+                    let address = match command {
+                        "c" | "s" => Some(args),
+                        _ => args.split_once(';').map(|(_, address)| address),
+                    };
+                    if let Some(Ok(address)) = address.map(|a| u32::from_str_radix(a, 16)) {
+                        cpu.pc = address;
+                    }
+                    return Ok(if command.eq_ignore_ascii_case("c") {
+                        Resume::Continue
+                    } else {
+                        Resume::Step
+                    });
+                }
+                "D" => {
+                    self.send_packet("OK")?;
+                    self.stream = None;
+                    return Ok(Resume::Continue);
+                }
+                "k" => return Ok(Resume::Kill),
+                "H" | "T" => "OK".into(),
+                "q" => match args {
+                    "Attached" => "1".into(),
+                    "fThreadInfo" => "m1".into(),
+                    "sThreadInfo" => "l".into(),
+                    "C" => "QC1".into(),
+                    _ if args.starts_with("Supported") => {
+                        "PacketSize=1000;qXfer:features:read+".into()
+                    }
+                    _ if args.starts_with("Xfer:features:read:target.xml:") => {
+                        let range = &args["Xfer:features:read:target.xml:".len()..];
+                        match parse_address_length(range) {
+                            Some((offset, length)) => {
+                                read_chunk(target_xml().as_bytes(), offset as usize, length)
+                            }
+                            None => "E01".into(),
+                        }
+                    }
+                    _ => String::new(),
+                },
+                "v" if args.starts_with("Kill") => {
+                    self.send_packet("OK")?;
+                    return Ok(Resume::Kill);
+                }
+                _ => String::new(),
+            };
+            self.send_packet(&reply)?;
+        }
+    }
+
+    // Lets GDB inspect the stopped V-App. If the connection with GDB is lost, the stub detaches
+    // and the V-App is resumed.
+    fn stop(&mut self, cpu: &mut Cpu<'_, VecMemory>, signal: u8) -> Resume {
+        match self.serve(cpu, signal) {
+            Ok(resume) => resume,
+            Err(_) => {
+                self.stream = None;
+                Resume::Continue
+            }
+        }
+    }
+}
+
+impl DebugHook for GdbStub {
+    type Memory = VecMemory;
+    type Error = GdbStubError;
+
+    fn before_instruction(&mut self, cpu: &mut Cpu<'_, VecMemory>) -> Result<(), GdbStubError> {
+        if self.stream.is_none() {
+            return Ok(());
+        }
+
+        self.instructions_until_poll -= 1;
+        if self.instructions_until_poll == 0 {
+            self.instructions_until_poll = INTERRUPT_POLL_INTERVAL;
+            match self.poll_interrupt() {
+                Ok(interrupted) => self.stop_requested |= interrupted,
+                Err(_) => {
+                    self.stream = None;
+                    return Ok(());
+                }
+            }
+        }
+
+        let at_breakpoint = !self.resuming && self.breakpoints.contains(&cpu.pc);
+        self.resuming = false;
+        if !self.stop_requested && !at_breakpoint {
+            return Ok(());
+        }
+
+        // the initial stop is reported when GDB asks for it, the other ones right away
+        if self.started && self.send_packet(&format!("S{:02x}", SIGTRAP)).is_err() {
+            self.stream = None;
+            return Ok(());
+        }
+        self.started = true;
+        match self.stop(cpu, SIGTRAP) {
+            Resume::Continue => self.stop_requested = false,
+            Resume::Step => self.stop_requested = true,
+            Resume::Kill => return Err(GdbStubError::Killed),
+        }
+        self.resuming = true;
+        Ok(())
+    }
+
+    fn on_trap(&mut self, cpu: &mut Cpu<'_, VecMemory>, cause: TrapCause) {
+        if self.stream.is_none() {
+            return;
+        }
+        let signal = match cause {
+            TrapCause::IllegalInstruction { .. } => SIGILL,
+            TrapCause::Breakpoint => SIGTRAP,
+            TrapCause::MisalignedAccess { .. } => SIGBUS,
+            TrapCause::AccessFault { .. } => SIGSEGV,
+            TrapCause::UnknownEcall { .. } => SIGILL,
+        };
+        if self.started && self.send_packet(&format!("S{:02x}", signal)).is_err() {
+            self.stream = None;
+            return;
+        }
+        self.started = true;
+        // the V-App cannot continue after a trap, whatever GDB asks
+        if !matches!(self.stop(cpu, signal), Resume::Kill) {
+            let _ = self.send_packet(&format!("X{:02x}", signal));
+        }
+        self.stream = None;
+    }
+
+    fn on_exit(&mut self, status: i32) {
+        if self.stream.is_some() {
+            let _ = self.send_packet(&format!("W{:02x}", status as u8));
+            self.stream = None;
+        }
+    }
+}
+
+fn checksum_of(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |sum, b| sum.wrapping_add(*b))
+}
+
+fn encode_hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn decode_hex(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+// Parses the "address,length" arguments of the memory commands
+fn parse_address_length(args: &str) -> Option<(u32, usize)> {
+    let (address, length) = args.split_once(',')?;
+    Some((
+        u32::from_str_radix(address, 16).ok()?,
+        usize::from_str_radix(length, 16).ok()?,
+    ))
+}
+
+// Replies to a qXfer read of `length` bytes at `offset` of `data`
+fn read_chunk(data: &[u8], offset: usize, length: usize) -> String {
+    let start = offset.min(data.len());
+    let end = start.saturating_add(length).min(data.len());
+    let marker = if end == data.len() { 'l' } else { 'm' };
+    format!("{}{}", marker, String::from_utf8_lossy(&data[start..end]))
+}
+
+// Describes the registers of the target, so that GDB does not need to guess the architecture
+fn target_xml() -> String {
+    let mut xml = String::from(
+        "<?xml version=\"1.0\"?><!DOCTYPE target SYSTEM \"gdb-target.dtd\"><target version=\"1.0\">\
+         <architecture>riscv:rv32</architecture><feature name=\"org.gnu.gdb.riscv.cpu\">",
+    );
+    for (regnum, name) in REGISTER_NAMES.iter().enumerate() {
+        let reg_type = match *name {
+            "ra" => "code_ptr",
+            "sp" | "fp" => "data_ptr",
+            _ => "int",
+        };
+        xml += &format!(
+            "<reg name=\"{}\" bitsize=\"32\" type=\"{}\" regnum=\"{}\"/>",
+            name, reg_type, regnum
+        );
+    }
+    xml += &format!(
+        "<reg name=\"pc\" bitsize=\"32\" type=\"code_ptr\" regnum=\"{}\"/></feature></target>",
+        PC_REGNUM
+    );
+    xml
+}
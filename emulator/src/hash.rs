@@ -0,0 +1,382 @@
+//! Hash contexts for the HASH_INIT/HASH_UPDATE/HASH_DIGEST ECALLs.
+//!
+//! The V-App SDK allocates hash contexts in the V-App's memory, with the same size as the
+//! corresponding context structs of the Ledger SDK, and treats them as opaque. As V-Apps can
+//! freely copy a context (for example, to compute the digest of a prefix and keep hashing), the
+//! whole state of the hash function must live in the guest context, rather than on the host.
+//!
+//! The layout used by the emulator mirrors the one of the Ledger SDK:
+//! - `hash_id: u32`
+//! - `counter: u32`, the number of blocks already compressed
+//! - `blen: u32`, the number of bytes in the partial block
+//! - `block: [u8; BLOCK_LEN]`, the partial block
+//! - `acc: [u8; STATE_LEN]`, the internal state of the compression function, as little-endian words
+
+use common::ecall_constants::HashId;
+
+const HEADER_LEN: usize = 12;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HashKind {
+    Ripemd160,
+    Sha256,
+    Sha512,
+}
+
+impl TryFrom<u32> for HashKind {
+    type Error = ();
+
+    fn try_from(hash_id: u32) -> Result<Self, Self::Error> {
+        match hash_id {
+            id if id == HashId::Ripemd160 as u32 => Ok(HashKind::Ripemd160),
+            id if id == HashId::Sha256 as u32 => Ok(HashKind::Sha256),
+            id if id == HashId::Sha512 as u32 => Ok(HashKind::Sha512),
+            _ => Err(()),
+        }
+    }
+}
+
+impl HashKind {
+    fn hash_id(&self) -> u32 {
+        match self {
+            HashKind::Ripemd160 => HashId::Ripemd160 as u32,
+            HashKind::Sha256 => HashId::Sha256 as u32,
+            HashKind::Sha512 => HashId::Sha512 as u32,
+        }
+    }
+
+    fn block_len(&self) -> usize {
+        match self {
+            HashKind::Ripemd160 | HashKind::Sha256 => 64,
+            HashKind::Sha512 => 128,
+        }
+    }
+
+    fn state_len(&self) -> usize {
+        match self {
+            HashKind::Ripemd160 => 20,
+            HashKind::Sha256 => 32,
+            HashKind::Sha512 => 64,
+        }
+    }
+
+    /// In-memory size of the hash context in the V-App's memory.
+    pub fn ctx_size(&self) -> usize {
+        HEADER_LEN + self.block_len() + self.state_len()
+    }
+
+    fn initial_state(&self) -> Vec<u8> {
+        match self {
+            HashKind::Ripemd160 => words32_to_le(&RIPEMD160_IV),
+            HashKind::Sha256 => words32_to_le(&SHA256_IV),
+            HashKind::Sha512 => SHA512_IV.iter().flat_map(|w| w.to_le_bytes()).collect(),
+        }
+    }
+
+    // Applies the compression function to a single block, updating the state in `acc`.
+    fn compress(&self, acc: &mut [u8], block: &[u8]) {
+        match self {
+            HashKind::Ripemd160 => {
+                let mut state: [u32; 5] = le_to_words32(acc).try_into().unwrap();
+                ripemd160_compress(&mut state, block.try_into().unwrap());
+                acc.copy_from_slice(&words32_to_le(&state));
+            }
+            HashKind::Sha256 => {
+                let mut state: [u32; 8] = le_to_words32(acc).try_into().unwrap();
+                let block: [u8; 64] = block.try_into().unwrap();
+                sha2::compress256(&mut state, &[block.into()]);
+                acc.copy_from_slice(&words32_to_le(&state));
+            }
+            HashKind::Sha512 => {
+                let mut state = [0u64; 8];
+                for (i, word) in state.iter_mut().enumerate() {
+                    *word = u64::from_le_bytes(acc[8 * i..8 * i + 8].try_into().unwrap());
+                }
+                let block: [u8; 128] = block.try_into().unwrap();
+                sha2::compress512(&mut state, &[block.into()]);
+                for (i, word) in state.iter().enumerate() {
+                    acc[8 * i..8 * i + 8].copy_from_slice(&word.to_le_bytes());
+                }
+            }
+        }
+    }
+}
+
+/// Returns a freshly initialized hash context.
+pub fn init(kind: HashKind) -> Vec<u8> {
+    let mut ctx = vec![0u8; kind.ctx_size()];
+    ctx[0..4].copy_from_slice(&kind.hash_id().to_le_bytes());
+    let acc_start = HEADER_LEN + kind.block_len();
+    ctx[acc_start..].copy_from_slice(&kind.initial_state());
+    ctx
+}
+
+/// Absorbs `data` into the hash context `ctx`.
+pub fn update(kind: HashKind, ctx: &mut [u8], mut data: &[u8]) -> Result<(), &'static str> {
+    check_ctx(kind, ctx)?;
+
+    let block_len = kind.block_len();
+    let (header, rest) = ctx.split_at_mut(HEADER_LEN);
+    let (block, acc) = rest.split_at_mut(block_len);
+
+    let mut counter = u32::from_le_bytes(header[4..8].try_into().unwrap());
+    let mut blen = u32::from_le_bytes(header[8..12].try_into().unwrap()) as usize;
+
+    while !data.is_empty() {
+        let n = core::cmp::min(block_len - blen, data.len());
+        block[blen..blen + n].copy_from_slice(&data[..n]);
+        blen += n;
+        data = &data[n..];
+
+        if blen == block_len {
+            kind.compress(acc, block);
+            counter = counter.checked_add(1).ok_or("Message too long")?;
+            blen = 0;
+        }
+    }
+
+    header[4..8].copy_from_slice(&counter.to_le_bytes());
+    header[8..12].copy_from_slice(&(blen as u32).to_le_bytes());
+    Ok(())
+}
+
+/// Computes the digest of the message absorbed in `ctx`. The context is left in an unspecified
+/// state.
+pub fn digest(kind: HashKind, ctx: &mut [u8]) -> Result<Vec<u8>, &'static str> {
+    check_ctx(kind, ctx)?;
+
+    let block_len = kind.block_len();
+    let counter = u32::from_le_bytes(ctx[4..8].try_into().unwrap()) as u128;
+    let blen = u32::from_le_bytes(ctx[8..12].try_into().unwrap()) as u128;
+    let bit_len = (counter * block_len as u128 + blen) * 8;
+
+    // The length is appended in 16 bytes for SHA-512, and in 8 bytes for the other hashes
+    let len_size = if kind == HashKind::Sha512 { 16 } else { 8 };
+    let mut padding = vec![0x80u8];
+    while (blen as usize + padding.len()) % block_len != block_len - len_size {
+        padding.push(0);
+    }
+    match kind {
+        HashKind::Ripemd160 => padding.extend_from_slice(&(bit_len as u64).to_le_bytes()),
+        HashKind::Sha256 => padding.extend_from_slice(&(bit_len as u64).to_be_bytes()),
+        HashKind::Sha512 => padding.extend_from_slice(&bit_len.to_be_bytes()),
+    }
+    update(kind, ctx, &padding)?;
+
+    let acc = &ctx[HEADER_LEN + block_len..];
+    let digest = match kind {
+        // RIPEMD-160 outputs its state words as little-endian
+        HashKind::Ripemd160 => acc.to_vec(),
+        HashKind::Sha256 => le_to_words32(acc)
+            .iter()
+            .flat_map(|w| w.to_be_bytes())
+            .collect(),
+        HashKind::Sha512 => acc
+            .chunks(8)
+            .flat_map(|w| u64::from_le_bytes(w.try_into().unwrap()).to_be_bytes())
+            .collect(),
+    };
+    Ok(digest)
+}
+
+fn check_ctx(kind: HashKind, ctx: &[u8]) -> Result<(), &'static str> {
+    if ctx.len() != kind.ctx_size() {
+        return Err("Invalid hash context size");
+    }
+    if u32::from_le_bytes(ctx[0..4].try_into().unwrap()) != kind.hash_id() {
+        return Err("Hash context does not match the hash id");
+    }
+    if u32::from_le_bytes(ctx[8..12].try_into().unwrap()) as usize >= kind.block_len() {
+        return Err("Corrupted hash context");
+    }
+    Ok(())
+}
+
+fn words32_to_le(words: &[u32]) -> Vec<u8> {
+    words.iter().flat_map(|w| w.to_le_bytes()).collect()
+}
+
+fn le_to_words32(bytes: &[u8]) -> Vec<u32> {
+    bytes
+        .chunks(4)
+        .map(|w| u32::from_le_bytes(w.try_into().unwrap()))
+        .collect()
+}
+
+const SHA256_IV: [u32; 8] = [
+    0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab, 0x5be0cd19,
+];
+
+const SHA512_IV: [u64; 8] = [
+    0x6a09e667f3bcc908,
+    0xbb67ae8584caa73b,
+    0x3c6ef372fe94f82b,
+    0xa54ff53a5f1d36f1,
+    0x510e527fade682d1,
+    0x9b05688c2b3e6c1f,
+    0x1f83d9abfb41bd6b,
+    0x5be0cd19137e2179,
+];
+
+const RIPEMD160_IV: [u32; 5] = [0x67452301, 0xefcdab89, 0x98badcfe, 0x10325476, 0xc3d2e1f0];
+
+// The ripemd crate does not expose its compression function, so we implement it here.
+
+#[rustfmt::skip]
+const RIPEMD160_R_LEFT: [usize; 80] = [
+    0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15,
+    7, 4, 13, 1, 10, 6, 15, 3, 12, 0, 9, 5, 2, 14, 11, 8,
+    3, 10, 14, 4, 9, 15, 8, 1, 2, 7, 0, 6, 13, 11, 5, 12,
+    1, 9, 11, 10, 0, 8, 12, 4, 13, 3, 7, 15, 14, 5, 6, 2,
+    4, 0, 5, 9, 7, 12, 2, 10, 14, 1, 3, 8, 11, 6, 15, 13,
+];
+
+#[rustfmt::skip]
+const RIPEMD160_R_RIGHT: [usize; 80] = [
+    5, 14, 7, 0, 9, 2, 11, 4, 13, 6, 15, 8, 1, 10, 3, 12,
+    6, 11, 3, 7, 0, 13, 5, 10, 14, 15, 8, 12, 4, 9, 1, 2,
+    15, 5, 1, 3, 7, 14, 6, 9, 11, 8, 12, 2, 10, 0, 4, 13,
+    8, 6, 4, 1, 3, 11, 15, 0, 5, 12, 2, 13, 9, 7, 10, 14,
+    12, 15, 10, 4, 1, 5, 8, 7, 6, 2, 13, 14, 0, 3, 9, 11,
+];
+
+#[rustfmt::skip]
+const RIPEMD160_S_LEFT: [u32; 80] = [
+    11, 14, 15, 12, 5, 8, 7, 9, 11, 13, 14, 15, 6, 7, 9, 8,
+    7, 6, 8, 13, 11, 9, 7, 15, 7, 12, 15, 9, 11, 7, 13, 12,
+    11, 13, 6, 7, 14, 9, 13, 15, 14, 8, 13, 6, 5, 12, 7, 5,
+    11, 12, 14, 15, 14, 15, 9, 8, 9, 14, 5, 6, 8, 6, 5, 12,
+    9, 15, 5, 11, 6, 8, 13, 12, 5, 12, 13, 14, 11, 8, 5, 6,
+];
+
+#[rustfmt::skip]
+const RIPEMD160_S_RIGHT: [u32; 80] = [
+    8, 9, 9, 11, 13, 15, 15, 5, 7, 7, 8, 11, 14, 14, 12, 6,
+    9, 13, 15, 7, 12, 8, 9, 11, 7, 7, 12, 7, 6, 15, 13, 11,
+    9, 7, 15, 11, 8, 6, 6, 14, 12, 13, 5, 14, 13, 13, 7, 5,
+    15, 5, 8, 11, 14, 14, 6, 14, 6, 9, 12, 9, 12, 5, 15, 8,
+    8, 5, 12, 9, 12, 5, 14, 6, 8, 13, 6, 5, 15, 13, 11, 11,
+];
+
+const RIPEMD160_K_LEFT: [u32; 5] = [0x00000000, 0x5a827999, 0x6ed9eba1, 0x8f1bbcdc, 0xa953fd4e];
+const RIPEMD160_K_RIGHT: [u32; 5] = [0x50a28be6, 0x5c4dd124, 0x6d703ef3, 0x7a6d76e9, 0x00000000];
+
+fn ripemd160_f(round: usize, x: u32, y: u32, z: u32) -> u32 {
+    match round {
+        0 => x ^ y ^ z,
+        1 => (x & y) | (!x & z),
+        2 => (x | !y) ^ z,
+        3 => (x & z) | (y & !z),
+        _ => x ^ (y | !z),
+    }
+}
+
+fn ripemd160_compress(state: &mut [u32; 5], block: &[u8; 64]) {
+    let mut x = [0u32; 16];
+    for (i, word) in x.iter_mut().enumerate() {
+        *word = u32::from_le_bytes(block[4 * i..4 * i + 4].try_into().unwrap());
+    }
+
+    let [mut al, mut bl, mut cl, mut dl, mut el] = *state;
+    let [mut ar, mut br, mut cr, mut dr, mut er] = *state;
+
+    for j in 0..80 {
+        let round = j / 16;
+
+        let t = al
+            .wrapping_add(ripemd160_f(round, bl, cl, dl))
+            .wrapping_add(x[RIPEMD160_R_LEFT[j]])
+            .wrapping_add(RIPEMD160_K_LEFT[round])
+            .rotate_left(RIPEMD160_S_LEFT[j])
+            .wrapping_add(el);
+        al = el;
+        el = dl;
+        dl = cl.rotate_left(10);
+        cl = bl;
+        bl = t;
+
+        let t = ar
+            .wrapping_add(ripemd160_f(4 - round, br, cr, dr))
+            .wrapping_add(x[RIPEMD160_R_RIGHT[j]])
+            .wrapping_add(RIPEMD160_K_RIGHT[round])
+            .rotate_left(RIPEMD160_S_RIGHT[j])
+            .wrapping_add(er);
+        ar = er;
+        er = dr;
+        dr = cr.rotate_left(10);
+        cr = br;
+        br = t;
+    }
+
+    let t = state[1].wrapping_add(cl).wrapping_add(dr);
+    state[1] = state[2].wrapping_add(dl).wrapping_add(er);
+    state[2] = state[3].wrapping_add(el).wrapping_add(ar);
+    state[3] = state[4].wrapping_add(al).wrapping_add(br);
+    state[4] = state[0].wrapping_add(bl).wrapping_add(cr);
+    state[0] = t;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sha2::Digest;
+
+    fn check_against<D: Digest>(kind: HashKind) {
+        for len in [
+            0usize, 1, 55, 56, 63, 64, 65, 111, 112, 127, 128, 129, 300, 1000,
+        ] {
+            let msg: Vec<u8> = (0..len).map(|i| (i * 7 + 3) as u8).collect();
+
+            // feed the message in irregular chunks to exercise partial blocks
+            let mut ctx = init(kind);
+            for chunk in msg.chunks(37) {
+                update(kind, &mut ctx, chunk).unwrap();
+            }
+
+            let expected = D::digest(&msg).to_vec();
+            assert_eq!(digest(kind, &mut ctx).unwrap(), expected, "length {}", len);
+        }
+    }
+
+    #[test]
+    fn test_sha256() {
+        check_against::<sha2::Sha256>(HashKind::Sha256);
+    }
+
+    #[test]
+    fn test_sha512() {
+        check_against::<sha2::Sha512>(HashKind::Sha512);
+    }
+
+    #[test]
+    fn test_ripemd160() {
+        check_against::<ripemd::Ripemd160>(HashKind::Ripemd160);
+    }
+
+    #[test]
+    fn test_copied_context() {
+        let kind = HashKind::Sha256;
+        let mut ctx = init(kind);
+        update(kind, &mut ctx, b"prefix").unwrap();
+
+        let mut ctx_copy = ctx.clone();
+        assert_eq!(
+            digest(kind, &mut ctx_copy).unwrap(),
+            sha2::Sha256::digest(b"prefix").to_vec()
+        );
+
+        update(kind, &mut ctx, b" and suffix").unwrap();
+        assert_eq!(
+            digest(kind, &mut ctx).unwrap(),
+            sha2::Sha256::digest(b"prefix and suffix").to_vec()
+        );
+    }
+
+    #[test]
+    fn test_ctx_sizes() {
+        // must match the sizes of the context structs in the V-App SDK
+        assert_eq!(HashKind::Ripemd160.ctx_size(), 96);
+        assert_eq!(HashKind::Sha256.ctx_size(), 108);
+        assert_eq!(HashKind::Sha512.ctx_size(), 204);
+    }
+}
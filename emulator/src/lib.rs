@@ -0,0 +1,35 @@
+//! Host-side emulator for Vanadium.
+//!
+//! This crate runs the RISC-V binary of a V-App directly on the host, using the same [`Cpu`]
+//! implementation as the Vanadium VM, with the whole memory of the V-App kept in plain
+//! [`VecMemory`] segments, and all the ECALLs implemented natively.
+//!
+//! The emulator is exposed as a [`Transport`](client_sdk::transport::Transport) that answers the
+//! APDUs exactly like the Vanadium app would, so it can be used as a drop-in replacement for a
+//! real device or Speculos in V-App clients, integration tests and benchmarks.
+//!
+//! Unlike the device, the emulator does not outsource the V-App's memory to the client: the
+//! only client commands it produces are the ones for the communication ECALLs.
+//!
+//! V-Apps running in the emulator can be debugged with GDB, see
+//! [`TransportEmulator::with_gdb_server`]. Loading the ELF file of the V-App in GDB gives
+//! source-level debugging, as long as it was compiled with debug information:
+//!
+//! ```text
+//! $ riscv64-unknown-elf-gdb target/riscv32imc-unknown-none-elf/debug/vnd-test
+//! (gdb) target remote localhost:1234
+//! (gdb) break main
+//! (gdb) continue
+//! ```
+//!
+//! [`Cpu`]: common::vm::Cpu
+//! [`VecMemory`]: common::vm::VecMemory
+
+mod ecall;
+mod gdb;
+mod hash;
+mod transport;
+
+pub use ecall::{HostEcallError, HostEcallHandler, UxPolicy};
+pub use gdb::{GdbStub, GdbStubError};
+pub use transport::TransportEmulator;
@@ -0,0 +1,1410 @@
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::net::TcpListener;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex as StdMutex};
+
+use async_trait::async_trait;
+use hmac::{Hmac, Mac};
+use rand::TryRngCore;
+use sha2::Sha256;
+use tokio::sync::{mpsc, Mutex};
+
+use client_sdk::apdu::{APDUCommand, StatusWord};
+use client_sdk::elf::VAppElfFile;
+use client_sdk::memory::MemorySegment as ClientMemorySegment;
+use client_sdk::transport::Transport;
+use common::constants::{page_start, PAGE_SIZE};
+use common::manifest::Manifest;
+use common::trace::{PageAccessRecorder, TraceEvent, MAX_EVENT_SIZE};
+use common::vm::{Cpu, CpuError, DebugHook, MemorySegment, Trap, TrapCause, VecMemory};
+
+use crate::ecall::{HostEcallError, HostEcallHandler, UxPolicy};
+use crate::gdb::{GdbStub, GdbStubError};
+
+const CLA: u8 = 0xE0;
+
+const INS_GET_VERSION: u8 = 0x00;
+const INS_GET_APP_NAME: u8 = 0x01;
+const INS_REGISTER_VAPP: u8 = 0x02;
+const INS_START_VAPP: u8 = 0x03;
+const INS_RESUME_VAPP: u8 = 0x04;
+const INS_CONTINUE: u8 = 0xff;
+
+// P1 of a Continue APDU that asks to suspend the V-App, instead of answering to ReceiveBuffer
+const P1_SUSPEND: u8 = 0x01;
+// P1 of a Continue APDU that asks to abort the request that the V-App is processing, answering to
+// ReceiveBuffer or CheckAbort
+const P1_ABORT: u8 = 0x02;
+
+// Name returned by the Vanadium app on the device
+const APP_NAME: &str = "app-vanadium";
+
+type Response = (StatusWord, Vec<u8>);
+
+/// The VM side of the channel between a running V-App and the client.
+///
+/// Each message from the VM is answered by the data of the next Continue APDU, exactly as it
+/// happens with the real device.
+pub(crate) struct VmComm {
+    to_client: mpsc::Sender<Response>,
+    // P1 and data of the Continue APDUs
+    from_client: mpsc::Receiver<(u8, Vec<u8>)>,
+}
+
+/// The reply of the client to a client command, if it can also ask to suspend the V-App or to
+/// abort its request instead of continuing.
+pub(crate) enum ClientReply {
+    Continue(Vec<u8>),
+    Suspend,
+    Abort,
+}
+
+impl VmComm {
+    /// Sends a client command to the client, and waits for its response.
+    pub(crate) fn interrupt(&mut self, command: Vec<u8>) -> Result<Vec<u8>, HostEcallError> {
+        match self.interrupt_with_reply(command)? {
+            ClientReply::Continue(response) => Ok(response),
+            ClientReply::Suspend => Err(HostEcallError::InvalidResponse(
+                "Unexpected suspend request",
+            )),
+            ClientReply::Abort => Err(HostEcallError::InvalidResponse("Unexpected abort request")),
+        }
+    }
+
+    /// Like [`VmComm::interrupt`], but the client can also ask to suspend the V-App, or to abort
+    /// the request that it is processing.
+    pub(crate) fn interrupt_with_reply(
+        &mut self,
+        command: Vec<u8>,
+    ) -> Result<ClientReply, HostEcallError> {
+        self.to_client
+            .blocking_send((StatusWord::InterruptedExecution, command))
+            .map_err(|_| HostEcallError::Disconnected)?;
+        match self.from_client.blocking_recv() {
+            Some((0, response)) => Ok(ClientReply::Continue(response)),
+            Some((P1_SUSPEND, _)) => Ok(ClientReply::Suspend),
+            Some((P1_ABORT, _)) => Ok(ClientReply::Abort),
+            Some(_) => Err(HostEcallError::InvalidResponse("Wrong P1")),
+            None => Err(HostEcallError::Disconnected),
+        }
+    }
+
+    // Sends the final response of the StartVApp or ResumeVApp command
+    fn finish(self, response: Response) {
+        // if the client is gone, there is nobody to tell
+        let _ = self.to_client.blocking_send(response);
+    }
+}
+
+// The client side of the channel with a running V-App
+struct Session {
+    to_vm: mpsc::Sender<(u8, Vec<u8>)>,
+    from_vm: mpsc::Receiver<Response>,
+}
+
+// The CPU registers and the memory of a V-App that is not running
+struct VAppState {
+    pc: u32,
+    regs: [u32; 32],
+    // The memory of each segment of the manifest, in the same order
+    mems: Vec<VecMemory>,
+}
+
+impl VAppState {
+    // The initial state of the V-App. Each segment is initialized with the content of the ELF
+    // segment at the same address, if any, or with zeros otherwise
+    fn new(manifest: &Manifest, elf: &VAppElfFile) -> Result<Self, &'static str> {
+        let mut regs = [0u32; 32];
+        // x2 is the stack pointer, that grows backwards from the end of the stack
+        // we make sure it's aligned to a multiple of 4
+        regs[2] = (manifest.stack_segment().end - 4) & !3;
+        // a0 and a1 are the start and the size of the heap
+        let heap = manifest.heap_segment();
+        regs[10] = heap.start;
+        regs[11] = heap.end - heap.start;
+        let mems = manifest
+            .segments
+            .iter()
+            .map(|descriptor| {
+                let content = elf
+                    .segments
+                    .iter()
+                    .find(|segment| segment.start == descriptor.start)
+                    .map_or(&[][..], |segment| &segment.data);
+                create_memory(descriptor.start, descriptor.end, content)
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Self {
+            pc: manifest.entrypoint,
+            regs,
+            mems,
+        })
+    }
+}
+
+// The state of the suspended V-Apps. Like on the device, only the last suspended V-App can be
+// resumed, and only once: the counter is incremented each time a V-App is suspended or resumed.
+#[derive(Default)]
+struct Snapshots {
+    counter: u32,
+    suspended: Option<([u8; 32], VAppState)>,
+}
+
+/// A [`Transport`] that runs the V-App on the host, instead of sending the APDUs to a device
+/// running the Vanadium app.
+///
+/// It answers the same APDUs as the Vanadium app, and executes the RISC-V code of the V-App with
+/// the same [`Cpu`] used on the device. All the memory is kept in the host, and the ECALLs are
+/// implemented by [`HostEcallHandler`].
+///
+/// The V-App is executed in a separate thread for the duration of the StartVApp command.
+///
+/// When the V-App is suspended, its whole state stays in the emulator, and the snapshot returned
+/// to the client is only a token to resume it.
+pub struct TransportEmulator {
+    elf: VAppElfFile,
+    registration_key: [u8; 32],
+    ux_policy: UxPolicy,
+    instruction_budget: Option<u64>,
+    gdb_listener: Option<Arc<TcpListener>>,
+    trace_path: Option<PathBuf>,
+    // if true, the trace only contains the page accesses
+    trace_page_accesses: bool,
+    session: Mutex<Option<Session>>,
+    snapshots: Arc<StdMutex<Snapshots>>,
+}
+
+impl TransportEmulator {
+    /// Creates an emulator for the V-App in the ELF file at `elf_path`.
+    pub fn new(elf_path: &Path) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        Ok(Self::from_elf(VAppElfFile::new(elf_path)?))
+    }
+
+    /// Creates an emulator for an already parsed ELF file.
+    pub fn from_elf(elf: VAppElfFile) -> Self {
+        let mut registration_key = [0u8; 32];
+        rand::rngs::OsRng
+            .try_fill_bytes(&mut registration_key)
+            .expect("Failed to generate random bytes");
+        Self {
+            elf,
+            registration_key,
+            ux_policy: UxPolicy::default(),
+            instruction_budget: None,
+            gdb_listener: None,
+            trace_path: None,
+            trace_page_accesses: false,
+            session: Mutex::new(None),
+            snapshots: Arc::new(StdMutex::new(Snapshots::default())),
+        }
+    }
+
+    /// Sets the policy used to answer the pages shown by the V-App, and the registration prompt.
+    pub fn with_ux_policy(mut self, ux_policy: UxPolicy) -> Self {
+        self.ux_policy = ux_policy;
+        self
+    }
+
+    /// Sets the maximum number of instructions that the V-App can execute between two
+    /// interactions with the client or the user, like the VM does. There is no limit by default.
+    pub fn with_instruction_budget(mut self, instruction_budget: Option<u64>) -> Self {
+        self.instruction_budget = instruction_budget;
+        self
+    }
+
+    /// Enables debugging the V-App with GDB. Each time the V-App is started, the emulator waits
+    /// for GDB to connect on `listener` (for example with `target remote localhost:1234`), and
+    /// the V-App is stopped before its first instruction.
+    ///
+    /// The StartVApp command does not complete until GDB is connected.
+    pub fn with_gdb_server(mut self, listener: TcpListener) -> Self {
+        self.gdb_listener = Some(Arc::new(listener));
+        self
+    }
+
+    /// Records a binary trace of the execution of the V-App in the file at `path`, in the format
+    /// defined in [`common::trace`]. The file is overwritten each time the V-App is started.
+    ///
+    /// As the emulator keeps the whole memory of the V-App, the trace has no page events.
+    pub fn with_trace(mut self, path: impl Into<PathBuf>) -> Self {
+        self.trace_path = Some(path.into());
+        self.trace_page_accesses = false;
+        self
+    }
+
+    /// Like [`TransportEmulator::with_trace`], but the trace only contains the page accesses of
+    /// each segment, as recorded by [`PageAccessRecorder`]. Such traces are much smaller, and can
+    /// be replayed by the page cache simulator in `tools/vcachesim`.
+    pub fn with_page_access_trace(mut self, path: impl Into<PathBuf>) -> Self {
+        self.trace_path = Some(path.into());
+        self.trace_page_accesses = true;
+        self
+    }
+
+    // Token returned as the snapshot of the suspended V-App: the counter, and a MAC binding it to
+    // the V-App
+    fn snapshot_token(registration_key: &[u8; 32], vapp_hash: &[u8; 32], counter: u32) -> Vec<u8> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(registration_key)
+            .expect("HMAC can take key of any size");
+        mac.update(b"VANADIUM_SNAPSHOT");
+        mac.update(vapp_hash);
+        mac.update(&counter.to_be_bytes());
+        let mut token = counter.to_be_bytes().to_vec();
+        token.extend_from_slice(&mac.finalize().into_bytes());
+        token
+    }
+
+    fn get_vapp_hmac(&self, manifest: &Manifest) -> [u8; 32] {
+        let vapp_hash = manifest.get_vapp_hash::<client_sdk::hash::Sha256, 32>();
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.registration_key)
+            .expect("HMAC can take key of any size");
+        mac.update(&vapp_hash);
+        mac.finalize().into_bytes().into()
+    }
+
+    fn handle_get_version(&self) -> Response {
+        let version: Vec<u8> = env!("CARGO_PKG_VERSION")
+            .split('.')
+            .map(|part| part.parse::<u8>().unwrap_or(0))
+            .collect();
+        (StatusWord::OK, version)
+    }
+
+    fn handle_register_vapp(&self, data: &[u8]) -> Response {
+        let manifest = match postcard::from_bytes::<Manifest>(data) {
+            Ok(manifest) => manifest,
+            Err(_) => return (StatusWord::IncorrectData, vec![]),
+        };
+        // postcard::from_bytes ignores trailing bytes, but the Vanadium app rejects them
+        if postcard::to_allocvec(&manifest).map(|m| m.len()) != Ok(data.len()) {
+            return (StatusWord::IncorrectData, vec![]);
+        }
+        if manifest.validate().is_err() {
+            return (StatusWord::IncorrectData, vec![]);
+        }
+
+        if self.ux_policy == UxPolicy::Reject {
+            return (StatusWord::Deny, vec![]);
+        }
+
+        (StatusWord::OK, self.get_vapp_hmac(&manifest).to_vec())
+    }
+
+    // Checks that the manifest describes the V-App in the ELF file: each segment of the ELF file
+    // must be in the manifest, and any other segment must be a zero-initialized writable one
+    fn check_manifest(&self, manifest: &Manifest) -> Result<(), &'static str> {
+        manifest.validate()?;
+        if manifest.entrypoint != self.elf.entrypoint {
+            return Err("The manifest does not match the layout of the ELF file");
+        }
+        for segment in self.elf.segments.iter() {
+            let Some(descriptor) = manifest.segments.iter().find(|d| d.start == segment.start)
+            else {
+                return Err("The manifest does not match the layout of the ELF file");
+            };
+            if (descriptor.end, descriptor.permissions) != (segment.end, segment.permissions) {
+                return Err("The manifest does not match the layout of the ELF file");
+            }
+            let root: [u8; 32] = ClientMemorySegment::new(segment.start, &segment.data)
+                .get_content_root()
+                .clone()
+                .into();
+            if root != descriptor.merkle_root {
+                return Err("The manifest does not match the content of the ELF file");
+            }
+            let zero_offset = (descriptor.zero_start - segment.start) as usize;
+            if segment.data[zero_offset..].iter().any(|&b| b != 0) {
+                return Err("The manifest does not match the content of the ELF file");
+            }
+        }
+        let is_in_elf = |start| self.elf.segments.iter().any(|s| s.start == start);
+        if manifest
+            .segments
+            .iter()
+            .any(|d| !is_in_elf(d.start) && !d.permissions.is_writable())
+        {
+            return Err("The manifest does not match the layout of the ELF file");
+        }
+        Ok(())
+    }
+
+    // Parses the manifest and its HMAC at the beginning of the data, and checks that the V-App is
+    // registered and matches the ELF file. Returns the manifest and the rest of the data.
+    fn parse_registered_manifest<'a>(
+        &self,
+        data: &'a [u8],
+    ) -> Result<(Manifest, &'a [u8]), StatusWord> {
+        let Ok((manifest, rest)) = postcard::take_from_bytes::<Manifest>(data) else {
+            return Err(StatusWord::IncorrectData);
+        };
+        if rest.len() < 32 {
+            return Err(StatusWord::IncorrectData);
+        }
+        let (provided_hmac, rest) = rest.split_at(32);
+
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.registration_key)
+            .expect("HMAC can take key of any size");
+        mac.update(&manifest.get_vapp_hash::<client_sdk::hash::Sha256, 32>());
+        if mac.verify_slice(provided_hmac).is_err() {
+            return Err(StatusWord::SignatureFail);
+        }
+
+        if self.check_manifest(&manifest).is_err() {
+            return Err(StatusWord::IncorrectData);
+        }
+        Ok((manifest, rest))
+    }
+
+    async fn handle_start_vapp(&self, data: &[u8]) -> Response {
+        let manifest = match self.parse_registered_manifest(data) {
+            Ok((manifest, [])) => manifest,
+            Ok(_) => return (StatusWord::IncorrectData, vec![]),
+            Err(status) => return (status, vec![]),
+        };
+
+        let state = match VAppState::new(&manifest, &self.elf) {
+            Ok(state) => state,
+            Err(_) => return (StatusWord::VMRuntimeError, vec![]),
+        };
+        self.spawn_vapp(manifest, state).await
+    }
+
+    async fn handle_resume_vapp(&self, data: &[u8]) -> Response {
+        let (manifest, snapshot) = match self.parse_registered_manifest(data) {
+            Ok(parsed) => parsed,
+            Err(status) => return (status, vec![]),
+        };
+        let vapp_hash = manifest.get_vapp_hash::<client_sdk::hash::Sha256, 32>();
+
+        let state = {
+            let mut snapshots = self.snapshots.lock().unwrap();
+            let expected =
+                Self::snapshot_token(&self.registration_key, &vapp_hash, snapshots.counter);
+            // the Merkle roots of the writable segments that follow the token are ignored, as
+            // the memory of the V-App never left the emulator
+            if !snapshot.starts_with(&expected) {
+                return (StatusWord::SignatureFail, vec![]);
+            }
+            match snapshots.suspended.take() {
+                Some((hash, state)) if hash == vapp_hash => {
+                    // the snapshot can only be resumed once
+                    snapshots.counter += 1;
+                    state
+                }
+                _ => return (StatusWord::SignatureFail, vec![]),
+            }
+        };
+        self.spawn_vapp(manifest, state).await
+    }
+
+    // Runs the V-App in a new thread, and returns its first response
+    async fn spawn_vapp(&self, manifest: Manifest, mut state: VAppState) -> Response {
+        let (to_vm, from_client) = mpsc::channel(1);
+        let (to_client, from_vm) = mpsc::channel(1);
+        let comm = VmComm {
+            to_client,
+            from_client,
+        };
+        let ux_policy = self.ux_policy;
+        let instruction_budget = self.instruction_budget;
+        let gdb_listener = self.gdb_listener.clone();
+        let trace_path = self.trace_path.clone();
+        let trace_page_accesses = self.trace_page_accesses;
+        let registration_key = self.registration_key;
+        let snapshots = self.snapshots.clone();
+        std::thread::spawn(move || {
+            let mut handler = HostEcallHandler::new(comm, ux_policy);
+            let mut gdb_stub = match gdb_listener.map(|listener| listener.accept()) {
+                None => None,
+                Some(Ok((stream, _))) => Some(GdbStub::new(stream)),
+                Some(Err(_)) => {
+                    handler
+                        .into_comm()
+                        .finish((StatusWord::VMRuntimeError, vec![]));
+                    return;
+                }
+            };
+            let tracer = match trace_path.map(|path| File::create(path).map(BufWriter::new)) {
+                None => None,
+                Some(Ok(file)) => Some(Tracer {
+                    file,
+                    page_accesses: trace_page_accesses.then(|| {
+                        PageAccessRecorder::new(
+                            manifest.segments.iter().map(|s| (s.start, s.end)).collect(),
+                        )
+                    }),
+                }),
+                Some(Err(_)) => {
+                    handler
+                        .into_comm()
+                        .finish((StatusWord::VMRuntimeError, vec![]));
+                    return;
+                }
+            };
+            let response = match run_vapp(
+                &manifest,
+                &mut state,
+                instruction_budget,
+                &mut handler,
+                gdb_stub.as_mut(),
+                tracer,
+            ) {
+                RunOutcome::Finished(response) => response,
+                RunOutcome::Suspended => {
+                    let vapp_hash = manifest.get_vapp_hash::<client_sdk::hash::Sha256, 32>();
+                    let mut snapshots = snapshots.lock().unwrap();
+                    snapshots.counter += 1;
+                    snapshots.suspended = Some((vapp_hash, state));
+                    let token =
+                        Self::snapshot_token(&registration_key, &vapp_hash, snapshots.counter);
+                    (StatusWord::VAppSuspended, token)
+                }
+            };
+            handler.into_comm().finish(response);
+        });
+
+        let mut session = Session { to_vm, from_vm };
+        let response = Self::next_response(&mut session).await;
+        if response.0 == StatusWord::InterruptedExecution {
+            *self.session.lock().await = Some(session);
+        }
+        response
+    }
+
+    async fn handle_continue(&self, p1: u8, data: &[u8]) -> Response {
+        let mut guard = self.session.lock().await;
+        let Some(session) = guard.as_mut() else {
+            return (StatusWord::InsNotSupported, vec![]);
+        };
+
+        let response = if session.to_vm.send((p1, data.to_vec())).await.is_err() {
+            (StatusWord::VMRuntimeError, vec![])
+        } else {
+            Self::next_response(session).await
+        };
+
+        // the session is over once the V-App stops asking for the client's response
+        if response.0 != StatusWord::InterruptedExecution {
+            *guard = None;
+        }
+        response
+    }
+
+    async fn next_response(session: &mut Session) -> Response {
+        session
+            .from_vm
+            .recv()
+            .await
+            .unwrap_or((StatusWord::VMRuntimeError, vec![]))
+    }
+}
+
+#[async_trait]
+impl Transport for TransportEmulator {
+    type Error = Box<dyn Error + Send + Sync>;
+
+    async fn exchange(&self, command: &APDUCommand) -> Result<(StatusWord, Vec<u8>), Self::Error> {
+        if command.cla != CLA {
+            return Ok((StatusWord::ClaNotSupported, vec![]));
+        }
+        if command.ins <= INS_RESUME_VAPP && (command.p1 != 0 || command.p2 != 0) {
+            return Ok((StatusWord::WrongP1P2, vec![]));
+        }
+
+        if command.ins != INS_CONTINUE {
+            // Any other command terminates the running V-App, if any; dropping the session
+            // disconnects the VM thread, which then stops.
+            self.session.lock().await.take();
+        }
+
+        let response = match command.ins {
+            INS_GET_VERSION => self.handle_get_version(),
+            INS_GET_APP_NAME => (StatusWord::OK, APP_NAME.as_bytes().to_vec()),
+            INS_REGISTER_VAPP => self.handle_register_vapp(&command.data),
+            INS_START_VAPP => self.handle_start_vapp(&command.data).await,
+            INS_RESUME_VAPP => self.handle_resume_vapp(&command.data).await,
+            INS_CONTINUE => self.handle_continue(command.p1, &command.data).await,
+            _ => (StatusWord::InsNotSupported, vec![]),
+        };
+        Ok(response)
+    }
+}
+
+// Creates the memory for a segment spanning from `start` to `end`, initialized with `content`
+fn create_memory(start: u32, end: u32, content: &[u8]) -> Result<VecMemory, &'static str> {
+    let n_pages = (page_start(end - 1) - page_start(start)) as usize / PAGE_SIZE + 1;
+    let mut memory = VecMemory::new(n_pages);
+    if !content.is_empty() {
+        MemorySegment::new(start, end - start, &mut memory)
+            .and_then(|mut segment| segment.write_buffer(start, content))
+            .map_err(|_| "Failed to initialize the memory")?;
+    }
+    Ok(memory)
+}
+
+// Builds the response reporting the trap that aborted the execution of the V-App
+fn trap_response(pc: u32, cause: TrapCause) -> Response {
+    let trap = Trap { pc, cause };
+    (
+        StatusWord::VAppTrap,
+        postcard::to_allocvec(&trap).expect("Failed to serialize the trap"),
+    )
+}
+
+// Writes the execution trace of a V-App
+struct Tracer {
+    file: BufWriter<File>,
+    // if set, only the page accesses are written
+    page_accesses: Option<PageAccessRecorder>,
+}
+
+impl Tracer {
+    fn write_header(&mut self) -> std::io::Result<()> {
+        self.file.write_all(&common::trace::header())
+    }
+
+    fn record_instruction(&mut self, cpu: &Cpu<'_, VecMemory>, inst: u32) -> std::io::Result<()> {
+        let (op, inst_size) = common::riscv::decode::decode(inst);
+        let Tracer {
+            file,
+            page_accesses,
+        } = self;
+        let mut result = Ok(());
+        let mut write_event = |event: &TraceEvent| {
+            if result.is_ok() {
+                result = Self::write_event(file, event);
+            }
+        };
+        common::trace::record_instruction(cpu.pc, inst, &op, inst_size, &cpu.regs, |event| {
+            match page_accesses.as_mut() {
+                Some(recorder) => recorder.record(event, &mut write_event),
+                None => write_event(event),
+            }
+        });
+        result
+    }
+
+    fn write_event(file: &mut BufWriter<File>, event: &TraceEvent) -> std::io::Result<()> {
+        let mut buf = [0u8; MAX_EVENT_SIZE];
+        let len = event.encode(&mut buf);
+        file.write_all(&buf[..len])
+    }
+}
+
+// How the execution of the V-App ended
+enum RunOutcome {
+    // The final response to StartVApp or ResumeVApp
+    Finished(Response),
+    // The client asked to suspend the V-App; its registers are saved in the state
+    Suspended,
+}
+
+// Executes the V-App from the given state until it exits, fails or is suspended
+fn run_vapp(
+    manifest: &Manifest,
+    state: &mut VAppState,
+    instruction_budget: Option<u64>,
+    handler: &mut HostEcallHandler,
+    debugger: Option<&mut GdbStub>,
+    tracer: Option<Tracer>,
+) -> RunOutcome {
+    let segments = manifest
+        .segments
+        .iter()
+        .zip(state.mems.iter_mut())
+        .map(|(descriptor, mem)| {
+            MemorySegment::new(descriptor.start, descriptor.end - descriptor.start, mem)
+                .map(|segment| segment.with_permissions(descriptor.permissions))
+        })
+        .collect::<Result<Vec<_>, _>>();
+    let Ok(segments) = segments else {
+        return RunOutcome::Finished((StatusWord::VMRuntimeError, vec![]));
+    };
+
+    let mut cpu = Cpu::new(state.pc, segments);
+    cpu.regs = state.regs;
+    if !cpu.pc.is_multiple_of(2) {
+        return RunOutcome::Finished((StatusWord::VMRuntimeError, vec![]));
+    }
+
+    match execute(&mut cpu, instruction_budget, handler, debugger, tracer) {
+        Some(response) => RunOutcome::Finished(response),
+        None => {
+            // The program counter is still at the ECALL, that is executed again on resume
+            state.pc = cpu.pc;
+            state.regs = cpu.regs;
+            RunOutcome::Suspended
+        }
+    }
+}
+
+// Runs the CPU until the V-App exits or fails, and returns the final response. Returns None if the
+// V-App is suspended.
+fn execute(
+    cpu: &mut Cpu<'_, VecMemory>,
+    instruction_budget: Option<u64>,
+    handler: &mut HostEcallHandler,
+    mut debugger: Option<&mut GdbStub>,
+    mut tracer: Option<Tracer>,
+) -> Option<Response> {
+    cpu.set_instruction_budget(instruction_budget);
+
+    if let Some(tracer) = tracer.as_mut() {
+        if tracer.write_header().is_err() {
+            return Some((StatusWord::VMRuntimeError, vec![]));
+        }
+    }
+
+    // the trace is flushed when the tracer is dropped
+    loop {
+        if let Some(debugger) = debugger.as_deref_mut() {
+            if let Err(GdbStubError::Killed) = debugger.before_instruction(cpu) {
+                return Some((StatusWord::VMRuntimeError, vec![]));
+            }
+        }
+
+        if let Some(tracer) = tracer.as_mut() {
+            // a failed fetch is reported as a trap below
+            if let Ok(instr) = cpu.fetch_instruction::<HostEcallError>() {
+                if tracer.record_instruction(cpu, instr).is_err() {
+                    return Some((StatusWord::VMRuntimeError, vec![]));
+                }
+            }
+        }
+
+        let result = cpu.fetch_instruction::<HostEcallError>().and_then(|instr| {
+            cpu.consume_instruction_budget()?;
+            cpu.execute(instr, Some(handler))
+        });
+
+        match result {
+            Ok(()) => {}
+            Err(CpuError::EcallError(HostEcallError::Exit(status))) => {
+                if let Some(debugger) = debugger {
+                    debugger.on_exit(status);
+                }
+                return Some((StatusWord::OK, status.to_be_bytes().to_vec()));
+            }
+            Err(CpuError::EcallError(HostEcallError::Panic)) => {
+                return Some((StatusWord::VAppPanic, vec![]));
+            }
+            Err(CpuError::InstructionBudgetExhausted) => {
+                return Some((StatusWord::InstructionBudgetExceeded, vec![]));
+            }
+            Err(CpuError::Trap(cause)) => {
+                if let Some(debugger) = debugger {
+                    debugger.on_trap(cpu, cause);
+                }
+                return Some(trap_response(cpu.pc, cause));
+            }
+            Err(CpuError::EcallError(HostEcallError::UnhandledEcall(code))) => {
+                let cause = TrapCause::UnknownEcall { code };
+                if let Some(debugger) = debugger {
+                    debugger.on_trap(cpu, cause);
+                }
+                return Some(trap_response(cpu.pc, cause));
+            }
+            Err(CpuError::EcallError(HostEcallError::SuspendRequested)) => return None,
+            Err(_) => return Some((StatusWord::VMRuntimeError, vec![])),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use client_sdk::apdu::{
+        apdu_abort_request, apdu_continue, apdu_register_vapp, apdu_resume_vapp, apdu_run_vapp,
+        apdu_suspend_vapp,
+    };
+    use client_sdk::elf::Segment;
+    use common::client_commands::{
+        ClientCommandCode, Message, ReceiveBufferResponse, SendBufferContinuedMessage,
+        SendBufferMessage,
+    };
+    use common::constants::{DEFAULT_HEAP_START, DEFAULT_STACK_START, MAX_HEAP_SIZE};
+    use common::ecall_constants::{ECALL_EXIT, ECALL_GET_EVENT, ECALL_XRECV, ECALL_XSEND};
+    use common::ux::EventCode;
+    use common::vm::SegmentPermissions;
+
+    const CODE_START: u32 = 0x00010000;
+    const DATA_START: u32 = 0x00020000;
+    const DATA_SIZE: u32 = 0x1000;
+    const STACK_SIZE: u32 = 0x1000;
+    // the heap is only allocated when used, so it can be very large
+    const HEAP_SIZE: u32 = MAX_HEAP_SIZE as u32;
+
+    const A0: u32 = 10;
+    const A1: u32 = 11;
+    const T0: u32 = 5;
+    const T1: u32 = 6;
+
+    const ECALL: u32 = 0x00000073;
+
+    fn addi(rd: u32, rs1: u32, imm: u32) -> u32 {
+        ((imm & 0xfff) << 20) | (rs1 << 15) | (rd << 7) | 0x13
+    }
+
+    fn lui(rd: u32, imm: u32) -> u32 {
+        (imm << 12) | (rd << 7) | 0x37
+    }
+
+    fn add(rd: u32, rs1: u32, rs2: u32) -> u32 {
+        (rs2 << 20) | (rs1 << 15) | (rd << 7) | 0x33
+    }
+
+    fn sb(rs2: u32, rs1: u32) -> u32 {
+        (rs2 << 20) | (rs1 << 15) | 0x23
+    }
+
+    fn lbu(rd: u32, rs1: u32) -> u32 {
+        (rs1 << 15) | (4 << 12) | (rd << 7) | 0x03
+    }
+
+    fn beq(rs1: u32, rs2: u32, offset: i32) -> u32 {
+        let imm = offset as u32;
+        (((imm >> 12) & 1) << 31)
+            | (((imm >> 5) & 0x3f) << 25)
+            | (rs2 << 20)
+            | (rs1 << 15)
+            | (((imm >> 1) & 0xf) << 8)
+            | (((imm >> 11) & 1) << 7)
+            | 0x63
+    }
+
+    // A V-App that receives a message, sends it back, and exits with the message length as status
+    fn echo_app() -> VAppElfFile {
+        let program = [
+            lui(A0, DATA_START >> 12),
+            lui(A1, DATA_SIZE >> 12),
+            addi(T0, 0, ECALL_XRECV),
+            ECALL,
+            addi(A1, A0, 0),
+            lui(A0, DATA_START >> 12),
+            addi(T0, 0, ECALL_XSEND),
+            ECALL,
+            addi(A0, A1, 0),
+            addi(T0, 0, ECALL_EXIT),
+            ECALL,
+        ];
+        let code: Vec<u8> = program.iter().flat_map(|i| i.to_le_bytes()).collect();
+        VAppElfFile {
+            segments: vec![
+                Segment {
+                    start: CODE_START,
+                    end: CODE_START + code.len() as u32,
+                    data: code,
+                    permissions: SegmentPermissions::RX,
+                },
+                Segment {
+                    data: vec![0; DATA_SIZE as usize],
+                    start: DATA_START,
+                    end: DATA_START + DATA_SIZE,
+                    permissions: SegmentPermissions::RW,
+                },
+            ],
+            entrypoint: CODE_START,
+            manifest: None,
+            compressed_pages: Default::default(),
+        }
+    }
+
+    fn make_manifest(elf: &VAppElfFile) -> Manifest {
+        let segments = elf.segment_table(
+            DEFAULT_STACK_START,
+            DEFAULT_STACK_START + STACK_SIZE,
+            DEFAULT_HEAP_START,
+            DEFAULT_HEAP_START + HEAP_SIZE,
+        );
+        let stack_segment = (segments.len() - 1) as u8;
+        let heap_segment = (segments.len() - 2) as u8;
+        Manifest::new(
+            0,
+            "Echo",
+            "0.1.0",
+            elf.entrypoint,
+            segments,
+            stack_segment,
+            heap_segment,
+        )
+        .unwrap()
+    }
+
+    async fn register_and_start(transport: &TransportEmulator, manifest: &Manifest) -> Response {
+        let serialized_manifest = postcard::to_allocvec(manifest).unwrap();
+        let (status, hmac) = transport
+            .exchange(&apdu_register_vapp(serialized_manifest.clone()))
+            .await
+            .unwrap();
+        assert_eq!(status, StatusWord::OK);
+        transport
+            .exchange(&apdu_run_vapp(
+                serialized_manifest,
+                hmac.try_into().unwrap(),
+            ))
+            .await
+            .unwrap()
+    }
+
+    // Plays the role of the client while the V-App receives `msg` and sends a message back.
+    // Returns the message sent by the V-App, and the response to the last APDU.
+    async fn exchange_message(
+        transport: &TransportEmulator,
+        mut response: Response,
+        msg: &[u8],
+    ) -> (Vec<u8>, Response) {
+        assert_eq!(response.0, StatusWord::InterruptedExecution);
+        assert_eq!(response.1, vec![ClientCommandCode::ReceiveBuffer as u8]);
+
+        let mut offset = 0;
+        loop {
+            let chunk = &msg[offset..core::cmp::min(offset + 250, msg.len())];
+            let data = ReceiveBufferResponse::new((msg.len() - offset) as u32, chunk).serialize();
+            response = transport.exchange(&apdu_continue(data)).await.unwrap();
+            offset += chunk.len();
+            if offset == msg.len() {
+                break;
+            }
+            assert_eq!(response.1, vec![ClientCommandCode::ReceiveBuffer as u8]);
+        }
+
+        assert_eq!(response.0, StatusWord::InterruptedExecution);
+        let first = SendBufferMessage::deserialize(&response.1).unwrap();
+        let total_size = first.total_size as usize;
+        let mut received = first.data.to_vec();
+        while received.len() < total_size {
+            response = transport.exchange(&apdu_continue(vec![])).await.unwrap();
+            assert_eq!(response.0, StatusWord::InterruptedExecution);
+            received.extend_from_slice(
+                SendBufferContinuedMessage::deserialize(&response.1)
+                    .unwrap()
+                    .data,
+            );
+        }
+        response = transport.exchange(&apdu_continue(vec![])).await.unwrap();
+        (received, response)
+    }
+
+    #[tokio::test]
+    async fn test_echo() {
+        let manifest = make_manifest(&echo_app());
+        let transport = TransportEmulator::from_elf(echo_app());
+
+        for len in [0usize, 1, 249, 250, 1000, DATA_SIZE as usize] {
+            let msg: Vec<u8> = (0..len).map(|i| i as u8).collect();
+            let response = register_and_start(&transport, &manifest).await;
+            let (received, response) = exchange_message(&transport, response, &msg).await;
+            assert_eq!(received, msg);
+
+            // the V-App exits after answering, with the message length as status
+            assert_eq!(
+                response,
+                (StatusWord::OK, (len as u32).to_be_bytes().to_vec())
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_message_too_large() {
+        let transport = TransportEmulator::from_elf(echo_app());
+        register_and_start(&transport, &make_manifest(&echo_app())).await;
+
+        let data = ReceiveBufferResponse::new(DATA_SIZE + 1, &[0u8; 100]).serialize();
+        let (status, _) = transport.exchange(&apdu_continue(data)).await.unwrap();
+        assert_eq!(status, StatusWord::VMRuntimeError);
+    }
+
+    #[tokio::test]
+    async fn test_start_vapp_wrong_hmac() {
+        let manifest = make_manifest(&echo_app());
+        let transport = TransportEmulator::from_elf(echo_app());
+
+        let command = apdu_run_vapp(postcard::to_allocvec(&manifest).unwrap(), [0u8; 32]);
+        let (status, _) = transport.exchange(&command).await.unwrap();
+        assert_eq!(status, StatusWord::SignatureFail);
+    }
+
+    #[tokio::test]
+    async fn test_start_vapp_mismatching_manifest() {
+        let mut manifest = make_manifest(&echo_app());
+        manifest.segments[1].merkle_root = [0u8; 32];
+        let transport = TransportEmulator::from_elf(echo_app());
+
+        let (status, _) = register_and_start(&transport, &manifest).await;
+        assert_eq!(status, StatusWord::IncorrectData);
+
+        // the code is not zero
+        let mut manifest = make_manifest(&echo_app());
+        manifest.segments[0].zero_start = manifest.segments[0].start;
+        let (status, _) = register_and_start(&transport, &manifest).await;
+        assert_eq!(status, StatusWord::IncorrectData);
+    }
+
+    #[tokio::test]
+    async fn test_continue_without_session() {
+        let transport = TransportEmulator::from_elf(echo_app());
+        let (status, _) = transport.exchange(&apdu_continue(vec![])).await.unwrap();
+        assert_eq!(status, StatusWord::InsNotSupported);
+    }
+
+    #[tokio::test]
+    async fn test_register_rejected() {
+        let manifest = make_manifest(&echo_app());
+        let transport = TransportEmulator::from_elf(echo_app()).with_ux_policy(UxPolicy::Reject);
+        let command = apdu_register_vapp(postcard::to_allocvec(&manifest).unwrap());
+        let (status, _) = transport.exchange(&command).await.unwrap();
+        assert_eq!(status, StatusWord::Deny);
+    }
+
+    #[tokio::test]
+    async fn test_instruction_budget() {
+        // the echo app runs at most 4 instructions between two ECALLs
+        let manifest = make_manifest(&echo_app());
+        let transport = TransportEmulator::from_elf(echo_app()).with_instruction_budget(Some(4));
+        let response = register_and_start(&transport, &manifest).await;
+        let (received, response) = exchange_message(&transport, response, b"hello").await;
+        assert_eq!(received, b"hello");
+        assert_eq!(response, (StatusWord::OK, 5u32.to_be_bytes().to_vec()));
+
+        let transport = TransportEmulator::from_elf(echo_app()).with_instruction_budget(Some(3));
+        let response = register_and_start(&transport, &manifest).await;
+        assert_eq!(response, (StatusWord::InstructionBudgetExceeded, vec![]));
+    }
+
+    #[tokio::test]
+    async fn test_suspend_resume() {
+        let manifest = make_manifest(&echo_app());
+        let serialized_manifest = postcard::to_allocvec(&manifest).unwrap();
+        let transport = TransportEmulator::from_elf(echo_app());
+
+        let response = register_and_start(&transport, &manifest).await;
+        assert_eq!(response.1, vec![ClientCommandCode::ReceiveBuffer as u8]);
+        let (status, snapshot) = transport.exchange(&apdu_suspend_vapp()).await.unwrap();
+        assert_eq!(status, StatusWord::VAppSuspended);
+
+        // the session is over, until the V-App is resumed
+        let response = transport.exchange(&apdu_continue(vec![])).await.unwrap();
+        assert_eq!(response.0, StatusWord::InsNotSupported);
+
+        let hmac: [u8; 32] = transport.get_vapp_hmac(&manifest);
+        let mut wrong_snapshot = snapshot.clone();
+        wrong_snapshot[0] ^= 1;
+        let response = transport
+            .exchange(&apdu_resume_vapp(
+                serialized_manifest.clone(),
+                hmac,
+                &wrong_snapshot,
+                &[],
+            ))
+            .await
+            .unwrap();
+        assert_eq!(response.0, StatusWord::SignatureFail);
+
+        // the V-App asks again for the message it was waiting for when it was suspended
+        let response = transport
+            .exchange(&apdu_resume_vapp(
+                serialized_manifest.clone(),
+                hmac,
+                &snapshot,
+                &[],
+            ))
+            .await
+            .unwrap();
+        let (received, response) = exchange_message(&transport, response, b"hello").await;
+        assert_eq!(received, b"hello");
+        assert_eq!(response, (StatusWord::OK, 5u32.to_be_bytes().to_vec()));
+
+        // a snapshot can only be resumed once
+        let response = transport
+            .exchange(&apdu_resume_vapp(serialized_manifest, hmac, &snapshot, &[]))
+            .await
+            .unwrap();
+        assert_eq!(response.0, StatusWord::SignatureFail);
+    }
+
+    #[tokio::test]
+    async fn test_trap() {
+        let mut elf = echo_app();
+        let code: Vec<u8> = [addi(A0, 0, 1), 0xffffffff]
+            .iter()
+            .flat_map(|i| i.to_le_bytes())
+            .collect();
+        elf.segments[0].end = elf.segments[0].start + code.len() as u32;
+        elf.segments[0].data = code;
+        let manifest = make_manifest(&elf);
+
+        let transport = TransportEmulator::from_elf(elf);
+        let (status, data) = register_and_start(&transport, &manifest).await;
+        assert_eq!(status, StatusWord::VAppTrap);
+        assert_eq!(
+            postcard::from_bytes::<Trap>(&data).unwrap(),
+            Trap {
+                pc: CODE_START + 4,
+                cause: TrapCause::IllegalInstruction { inst: 0xffffffff }
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_heap() {
+        // writes and reads back the last byte of the heap, whose bounds are in a0 and a1, and
+        // exits with the size of the heap plus the byte
+        let mut elf = echo_app();
+        let code: Vec<u8> = [
+            add(T0, A0, A1),
+            addi(T0, T0, 0xfff), // -1
+            addi(T1, 0, 42),
+            sb(T1, T0),
+            lbu(A0, T0),
+            add(A0, A0, A1),
+            addi(T0, 0, ECALL_EXIT),
+            ECALL,
+        ]
+        .iter()
+        .flat_map(|i| i.to_le_bytes())
+        .collect();
+        elf.segments[0].end = elf.segments[0].start + code.len() as u32;
+        elf.segments[0].data = code;
+        let manifest = make_manifest(&elf);
+        assert_eq!(manifest.heap_segment().start, DEFAULT_HEAP_START);
+
+        let transport = TransportEmulator::from_elf(elf);
+        let response = register_and_start(&transport, &manifest).await;
+        assert_eq!(
+            response,
+            (StatusWord::OK, (HEAP_SIZE + 42).to_be_bytes().to_vec())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_trap_unknown_ecall() {
+        let mut elf = echo_app();
+        let code: Vec<u8> = [addi(T0, 0, 0x7ff), ECALL]
+            .iter()
+            .flat_map(|i| i.to_le_bytes())
+            .collect();
+        elf.segments[0].end = elf.segments[0].start + code.len() as u32;
+        elf.segments[0].data = code;
+        let manifest = make_manifest(&elf);
+
+        let transport = TransportEmulator::from_elf(elf);
+        let (status, data) = register_and_start(&transport, &manifest).await;
+        assert_eq!(status, StatusWord::VAppTrap);
+        assert_eq!(
+            postcard::from_bytes::<Trap>(&data).unwrap(),
+            Trap {
+                pc: CODE_START + 4,
+                cause: TrapCause::UnknownEcall { code: 0x7ff }
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_abort() {
+        // waits for an event other than a ticker, and exits with its code
+        let elf = app_with_code(&[
+            lui(A0, DATA_START >> 12),
+            addi(T0, 0, ECALL_GET_EVENT),
+            ECALL,
+            beq(A0, 0, -12),
+            addi(T0, 0, ECALL_EXIT),
+            ECALL,
+        ]);
+        let manifest = make_manifest(&elf);
+        let transport = TransportEmulator::from_elf(elf);
+
+        // the client is asked regularly whether to abort
+        let response = register_and_start(&transport, &manifest).await;
+        assert_eq!(response.0, StatusWord::InterruptedExecution);
+        assert_eq!(response.1, vec![ClientCommandCode::CheckAbort as u8]);
+        let response = transport.exchange(&apdu_continue(vec![])).await.unwrap();
+        assert_eq!(response.1, vec![ClientCommandCode::CheckAbort as u8]);
+
+        let response = transport.exchange(&apdu_abort_request()).await.unwrap();
+        assert_eq!(
+            response,
+            (
+                StatusWord::OK,
+                (EventCode::Abort as u32).to_be_bytes().to_vec()
+            )
+        );
+
+        // the request can also be aborted instead of sending a message; the V-App exits with the
+        // value returned by xrecv
+        let elf = app_with_code(&[
+            lui(A0, DATA_START >> 12),
+            lui(A1, DATA_SIZE >> 12),
+            addi(T0, 0, ECALL_XRECV),
+            ECALL,
+            addi(T0, 0, ECALL_EXIT),
+            ECALL,
+        ]);
+        let manifest = make_manifest(&elf);
+        let transport = TransportEmulator::from_elf(elf);
+
+        let response = register_and_start(&transport, &manifest).await;
+        assert_eq!(response.1, vec![ClientCommandCode::ReceiveBuffer as u8]);
+        let response = transport.exchange(&apdu_abort_request()).await.unwrap();
+        assert_eq!(response, (StatusWord::OK, u32::MAX.to_be_bytes().to_vec()));
+    }
+
+    // A minimal GDB client, speaking the Remote Serial Protocol on `stream`
+    struct GdbClient {
+        stream: std::net::TcpStream,
+    }
+
+    impl GdbClient {
+        fn connect(port: u16) -> Self {
+            let stream = std::net::TcpStream::connect(("127.0.0.1", port)).unwrap();
+            stream.set_nodelay(true).unwrap();
+            Self { stream }
+        }
+
+        fn read_byte(&mut self) -> u8 {
+            use std::io::Read;
+            let mut byte = [0u8; 1];
+            self.stream.read_exact(&mut byte).unwrap();
+            byte[0]
+        }
+
+        fn receive(&mut self) -> String {
+            use std::io::Write;
+            assert_eq!(self.read_byte(), b'$');
+            let mut data = Vec::new();
+            loop {
+                match self.read_byte() {
+                    b'#' => break,
+                    b => data.push(b),
+                }
+            }
+            let checksum = [self.read_byte(), self.read_byte()];
+            let sum = data.iter().fold(0u8, |sum, b| sum.wrapping_add(*b));
+            assert_eq!(
+                std::str::from_utf8(&checksum).unwrap(),
+                format!("{:02x}", sum)
+            );
+            self.stream.write_all(b"+").unwrap();
+            String::from_utf8(data).unwrap()
+        }
+
+        fn send(&mut self, command: &str) {
+            use std::io::Write;
+            let sum = command.bytes().fold(0u8, |sum, b| sum.wrapping_add(b));
+            let packet = format!("${}#{:02x}", command, sum);
+            self.stream.write_all(packet.as_bytes()).unwrap();
+            assert_eq!(self.read_byte(), b'+');
+        }
+
+        fn request(&mut self, command: &str) -> String {
+            self.send(command);
+            self.receive()
+        }
+    }
+
+    fn hex_le(value: u32) -> String {
+        value
+            .to_le_bytes()
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect()
+    }
+
+    fn app_with_code(program: &[u32]) -> VAppElfFile {
+        let mut elf = echo_app();
+        let code: Vec<u8> = program.iter().flat_map(|i| i.to_le_bytes()).collect();
+        elf.segments[0].end = elf.segments[0].start + code.len() as u32;
+        elf.segments[0].data = code;
+        elf
+    }
+
+    #[tokio::test]
+    async fn test_gdb_server() {
+        let elf = app_with_code(&[
+            addi(A0, 0, 7),
+            addi(A0, A0, 1),
+            addi(A0, A0, 1),
+            addi(T0, 0, ECALL_EXIT),
+            ECALL,
+        ]);
+        let manifest = make_manifest(&elf);
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let transport = TransportEmulator::from_elf(elf).with_gdb_server(listener);
+
+        let gdb = std::thread::spawn(move || {
+            let mut gdb = GdbClient::connect(port);
+            assert!(gdb
+                .request("qSupported:multiprocess+")
+                .contains("qXfer:features:read+"));
+            assert!(gdb
+                .request("qXfer:features:read:target.xml:0,1000")
+                .starts_with("l<?xml"));
+
+            // stopped at the entrypoint
+            assert_eq!(gdb.request("?"), "S05");
+            assert_eq!(gdb.request("p20"), hex_le(CODE_START));
+            let regs = gdb.request("g");
+            assert_eq!(regs.len(), 33 * 8);
+            assert_eq!(&regs[32 * 8..], hex_le(CODE_START));
+
+            // breakpoint on the third instruction
+            let bp = format!("{:x}", CODE_START + 8);
+            assert_eq!(gdb.request(&format!("Z0,{},4", bp)), "OK");
+            assert_eq!(gdb.request("c"), "S05");
+            assert_eq!(gdb.request("p20"), hex_le(CODE_START + 8));
+            assert_eq!(gdb.request("p0a"), hex_le(8));
+            assert_eq!(gdb.request(&format!("z0,{},4", bp)), "OK");
+
+            // change a0, and single-step
+            assert_eq!(gdb.request(&format!("P0a={}", hex_le(41))), "OK");
+            assert_eq!(gdb.request("s"), "S05");
+            assert_eq!(gdb.request("p20"), hex_le(CODE_START + 12));
+            assert_eq!(gdb.request("p0a"), hex_le(42));
+
+            // memory accesses
+            let data = format!("{:x}", DATA_START + 1);
+            assert_eq!(gdb.request(&format!("m{},4", data)), "00000000");
+            assert_eq!(gdb.request(&format!("M{},4:01020304", data)), "OK");
+            assert_eq!(gdb.request(&format!("m{},4", data)), "01020304");
+            assert_eq!(gdb.request("m0,4"), "E14");
+
+            assert_eq!(gdb.request("c"), "W2a");
+        });
+
+        let response = register_and_start(&transport, &manifest).await;
+        gdb.join().unwrap();
+        assert_eq!(response, (StatusWord::OK, 42i32.to_be_bytes().to_vec()));
+    }
+
+    #[tokio::test]
+    async fn test_gdb_server_trap() {
+        let elf = app_with_code(&[addi(A0, 0, 1), 0xffffffff]);
+        let manifest = make_manifest(&elf);
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let transport = TransportEmulator::from_elf(elf).with_gdb_server(listener);
+
+        let gdb = std::thread::spawn(move || {
+            let mut gdb = GdbClient::connect(port);
+            assert_eq!(gdb.request("?"), "S05");
+            // SIGILL, with the pc at the faulting instruction
+            assert_eq!(gdb.request("c"), "S04");
+            assert_eq!(gdb.request("p20"), hex_le(CODE_START + 4));
+            assert_eq!(gdb.request("c"), "X04");
+        });
+
+        let (status, _) = register_and_start(&transport, &manifest).await;
+        gdb.join().unwrap();
+        assert_eq!(status, StatusWord::VAppTrap);
+    }
+
+    #[tokio::test]
+    async fn test_trace() {
+        let elf = app_with_code(&[addi(A0, 0, 3), addi(T0, 0, ECALL_EXIT), ECALL]);
+        let manifest = make_manifest(&elf);
+
+        let path = std::env::temp_dir().join(format!("vanadium-trace-{}.bin", std::process::id()));
+        let transport = TransportEmulator::from_elf(elf).with_trace(&path);
+        let response = register_and_start(&transport, &manifest).await;
+        assert_eq!(response, (StatusWord::OK, 3i32.to_be_bytes().to_vec()));
+
+        let trace = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(trace[..5], common::trace::header());
+        let mut events = Vec::new();
+        let mut offset = 5;
+        while offset < trace.len() {
+            let (event, size) = TraceEvent::decode(&trace[offset..]).unwrap();
+            events.push(event);
+            offset += size;
+        }
+        assert_eq!(
+            events,
+            [
+                TraceEvent::Instruction {
+                    pc: CODE_START,
+                    inst: addi(A0, 0, 3),
+                    size: 4
+                },
+                TraceEvent::Instruction {
+                    pc: CODE_START + 4,
+                    inst: addi(T0, 0, ECALL_EXIT),
+                    size: 4
+                },
+                TraceEvent::Instruction {
+                    pc: CODE_START + 8,
+                    inst: ECALL,
+                    size: 4
+                },
+                TraceEvent::Ecall { code: ECALL_EXIT },
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_page_access_trace() {
+        let elf = app_with_code(&[
+            addi(T1, 0, 5),
+            sb(T1, A0),
+            lbu(A0, A0),
+            addi(T0, 0, ECALL_EXIT),
+            ECALL,
+        ]);
+        let manifest = make_manifest(&elf);
+
+        let path =
+            std::env::temp_dir().join(format!("vanadium-page-trace-{}.bin", std::process::id()));
+        let transport = TransportEmulator::from_elf(elf).with_page_access_trace(&path);
+        let response = register_and_start(&transport, &manifest).await;
+        assert_eq!(response, (StatusWord::OK, 5i32.to_be_bytes().to_vec()));
+
+        let trace = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(trace[..5], common::trace::header());
+        let mut events = Vec::new();
+        let mut offset = 5;
+        while offset < trace.len() {
+            let (event, size) = TraceEvent::decode(&trace[offset..]).unwrap();
+            events.push(event);
+            offset += size;
+        }
+        // the code page, then the first page of the heap, that is written before being read
+        assert_eq!(
+            events,
+            [
+                TraceEvent::PageAccess {
+                    segment: 0,
+                    page_index: 0,
+                    write: false
+                },
+                TraceEvent::PageAccess {
+                    segment: manifest.heap_segment,
+                    page_index: 0,
+                    write: true
+                },
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_instruction_budget_infinite_loop() {
+        let mut elf = echo_app();
+        let jal_self = 0x0000006fu32; // jal x0, 0
+        elf.segments[0].data = jal_self.to_le_bytes().to_vec();
+        elf.segments[0].end = elf.segments[0].start + 4;
+        let manifest = make_manifest(&elf);
+
+        let transport = TransportEmulator::from_elf(elf).with_instruction_budget(Some(100_000));
+        let response = register_and_start(&transport, &manifest).await;
+        assert_eq!(response, (StatusWord::InstructionBudgetExceeded, vec![]));
+
+        // the session is over
+        let (status, _) = transport.exchange(&apdu_continue(vec![])).await.unwrap();
+        assert_eq!(status, StatusWord::InsNotSupported);
+    }
+}
@@ -1,8 +1,9 @@
 use proc_macro::TokenStream;
 use proc_macro_error2::proc_macro_error;
-use syn::{DeriveInput, parse_macro_input};
+use syn::{DeriveInput, ItemTrait, parse_macro_input};
 
 mod derive_serializable;
+mod rpc;
 
 #[proc_macro_derive(Serializable, attributes(maker, wrapped))]
 #[proc_macro_error]
@@ -10,3 +11,16 @@ pub fn serializable(input: TokenStream) -> TokenStream {
     let input = parse_macro_input!(input as DeriveInput);
     derive_serializable::derive_serializable(input).into()
 }
+
+/// Generates the request and response enums of a service trait, a `dispatch` method to handle the
+/// requests in the V-App, and an async client. See the `rpc` module of the `common` crate.
+///
+/// The arguments are the version of the service (`version = 1` by default), and the path of the
+/// `rpc` module of the `common` crate (`crate = ::common::rpc` by default).
+#[proc_macro_attribute]
+#[proc_macro_error]
+pub fn rpc(args: TokenStream, input: TokenStream) -> TokenStream {
+    let args = parse_macro_input!(args as rpc::RpcArgs);
+    let input = parse_macro_input!(input as ItemTrait);
+    rpc::rpc(args, input).into()
+}
@@ -0,0 +1,252 @@
+use proc_macro2::TokenStream;
+use proc_macro_error2::abort;
+use quote::{format_ident, quote, ToTokens};
+use syn::{
+    parse::{Parse, ParseStream},
+    parse_quote, FnArg, GenericArgument, Ident, ItemTrait, LitInt, Pat, Path, PathArguments,
+    ReturnType, Token, TraitItem, TraitItemFn, Type,
+};
+
+pub struct RpcArgs {
+    version: u32,
+    krate: Path,
+}
+
+impl Parse for RpcArgs {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let mut version = 1;
+        let mut krate = parse_quote!(::common::rpc);
+        while !input.is_empty() {
+            if input.peek(Token![crate]) {
+                input.parse::<Token![crate]>()?;
+                input.parse::<Token![=]>()?;
+                krate = input.parse()?;
+            } else {
+                let ident = input.parse::<Ident>()?;
+                if ident != "version" {
+                    return Err(syn::Error::new_spanned(
+                        ident,
+                        "only `version = $int` and `crate = $path` are allowed.",
+                    ));
+                }
+                input.parse::<Token![=]>()?;
+                version = input.parse::<LitInt>()?.base10_parse()?;
+            }
+            if !input.is_empty() {
+                input.parse::<Token![,]>()?;
+            }
+        }
+        Ok(RpcArgs { version, krate })
+    }
+}
+
+struct Method {
+    ident: Ident,
+    variant: Ident,
+    args: Vec<(Ident, Type)>,
+    output: Type,
+    docs: Vec<syn::Attribute>,
+}
+
+// add_numbers -> AddNumbers
+fn variant_name(ident: &Ident) -> Ident {
+    let name: String = ident
+        .to_string()
+        .split('_')
+        .map(|part| {
+            let mut chars = part.chars();
+            match chars.next() {
+                Some(first) => first.to_uppercase().chain(chars).collect(),
+                None => String::new(),
+            }
+        })
+        .collect();
+    Ident::new(&name, ident.span())
+}
+
+// Returns T, for a return type `Result<T, RpcError>`
+fn result_ok_type(output: &ReturnType) -> Option<Type> {
+    let ReturnType::Type(_, ty) = output else {
+        return None;
+    };
+    let Type::Path(type_path) = ty.as_ref() else {
+        return None;
+    };
+    let segment = type_path.path.segments.last()?;
+    if segment.ident != "Result" {
+        return None;
+    }
+    let PathArguments::AngleBracketed(args) = &segment.arguments else {
+        return None;
+    };
+    match args.args.first()? {
+        GenericArgument::Type(ty) => Some(ty.clone()),
+        _ => None,
+    }
+}
+
+fn parse_method(method: &TraitItemFn) -> Method {
+    let sig = &method.sig;
+    if !sig.generics.params.is_empty() {
+        abort!(sig.generics, "rpc methods cannot be generic");
+    }
+    if let Some(asyncness) = sig.asyncness {
+        abort!(asyncness, "rpc methods cannot be async");
+    }
+
+    let mut inputs = sig.inputs.iter();
+    match inputs.next() {
+        Some(FnArg::Receiver(receiver)) if receiver.reference.is_some() => {}
+        _ => abort!(sig, "rpc methods must take `&self` or `&mut self`"),
+    }
+    let args = inputs
+        .map(|arg| match arg {
+            FnArg::Typed(arg) => match arg.pat.as_ref() {
+                Pat::Ident(pat) if pat.by_ref.is_none() && pat.subpat.is_none() => {
+                    (pat.ident.clone(), arg.ty.as_ref().clone())
+                }
+                _ => abort!(arg.pat, "rpc arguments must be identifiers"),
+            },
+            FnArg::Receiver(receiver) => abort!(receiver, "unexpected receiver"),
+        })
+        .collect();
+
+    let Some(output) = result_ok_type(&sig.output) else {
+        abort!(
+            sig.output,
+            "rpc methods must return a `Result<T, RpcError>`"
+        );
+    };
+
+    Method {
+        ident: sig.ident.clone(),
+        variant: variant_name(&sig.ident),
+        args,
+        output,
+        docs: method
+            .attrs
+            .iter()
+            .filter(|attr| attr.path().is_ident("doc"))
+            .cloned()
+            .collect(),
+    }
+}
+
+pub fn rpc(args: RpcArgs, mut item: ItemTrait) -> TokenStream {
+    let RpcArgs { version, krate } = args;
+    if !item.generics.params.is_empty() {
+        abort!(item.generics, "rpc traits cannot be generic");
+    }
+
+    let methods: Vec<Method> = item
+        .items
+        .iter()
+        .filter_map(|item| match item {
+            TraitItem::Fn(method) => Some(parse_method(method)),
+            _ => None,
+        })
+        .collect();
+
+    let vis = &item.vis;
+    let trait_ident = &item.ident;
+    let request_ident = format_ident!("{}Request", trait_ident);
+    let response_ident = format_ident!("{}Response", trait_ident);
+    let client_ident = format_ident!("{}Client", trait_ident);
+    let serde_crate = quote!(#krate::__private::serde).to_string();
+
+    let request_variants = methods.iter().map(|m| {
+        let variant = &m.variant;
+        let fields = m.args.iter().map(|(ident, ty)| quote!(#ident: #ty));
+        quote!(#variant { #(#fields),* })
+    });
+    let response_variants = methods.iter().map(|m| {
+        let variant = &m.variant;
+        let output = &m.output;
+        quote!(#variant(#output))
+    });
+
+    let dispatch_arms = methods.iter().map(|m| {
+        let (ident, variant) = (&m.ident, &m.variant);
+        let args: Vec<&Ident> = m.args.iter().map(|(ident, _)| ident).collect();
+        quote! {
+            #request_ident::#variant { #(#args),* } => self.#ident(#(#args),*).map(#response_ident::#variant)
+        }
+    });
+    item.items.push(parse_quote! {
+        /// Deserializes a request, calls the corresponding method, and returns the serialized
+        /// response.
+        fn dispatch(&mut self, request: &[u8]) -> #krate::__private::Vec<u8> {
+            #krate::dispatch(#request_ident::VERSION, request, |request| match request {
+                #(#dispatch_arms,)*
+            })
+        }
+    });
+
+    let client_methods = methods.iter().map(|m| {
+        let Method { ident, variant, args, output, docs } = m;
+        let arg_idents: Vec<&Ident> = args.iter().map(|(ident, _)| ident).collect();
+        let arg_types = args.iter().map(|(_, ty)| ty);
+        quote! {
+            #(#docs)*
+            pub async fn #ident(&mut self, #(#arg_idents: #arg_types),*) -> Result<#output, #krate::RpcClientError<T::Error>> {
+                let request = #request_ident::#variant { #(#arg_idents),* };
+                let response: #response_ident =
+                    #krate::call(&mut self.transport, #request_ident::VERSION, &request).await?;
+                #[allow(unreachable_patterns)]
+                match response {
+                    #response_ident::#variant(response) => Ok(response),
+                    _ => Err(#krate::RpcClientError::InvalidResponse),
+                }
+            }
+        }
+    });
+
+    let request_doc = format!("The requests of the [`{}`] service.", trait_ident);
+    let response_doc = format!("The responses of the [`{}`] service.", trait_ident);
+    let client_doc = format!(
+        "A client of the [`{}`] service, over an `RpcTransport`.",
+        trait_ident
+    );
+
+    let mut output = item.to_token_stream();
+    output.extend(quote! {
+        #[doc = #request_doc]
+        #[derive(#krate::__private::serde::Serialize, #krate::__private::serde::Deserialize)]
+        #[serde(crate = #serde_crate)]
+        #vis enum #request_ident {
+            #(#request_variants,)*
+        }
+
+        impl #request_ident {
+            /// The version of the service.
+            pub const VERSION: u32 = #version;
+        }
+
+        #[doc = #response_doc]
+        #[derive(#krate::__private::serde::Serialize, #krate::__private::serde::Deserialize)]
+        #[serde(crate = #serde_crate)]
+        #vis enum #response_ident {
+            #(#response_variants,)*
+        }
+
+        #[doc = #client_doc]
+        #vis struct #client_ident<T> {
+            transport: T,
+        }
+
+        impl<T> #client_ident<T> {
+            pub fn new(transport: T) -> Self {
+                Self { transport }
+            }
+
+            pub fn into_inner(self) -> T {
+                self.transport
+            }
+        }
+
+        impl<T: #krate::RpcTransport> #client_ident<T> {
+            #(#client_methods)*
+        }
+    });
+    output
+}
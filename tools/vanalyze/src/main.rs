@@ -1,7 +1,10 @@
 use std::collections::HashMap;
 use std::env;
 use std::error::Error;
-use std::fs::read_to_string;
+
+use common::riscv::decode::decode;
+use common::riscv::op::Op;
+use vtrace::TraceEvent;
 
 /// Represents a node in the call hierarchy tree.
 struct Node {
@@ -52,51 +55,26 @@ impl Tree {
     }
 }
 
-/// Parsed RISC-V instruction.
+/// The instructions relevant to reconstruct the call stack.
 enum Instruction {
     Regular,
-    Jal { rd: u32, imm: i32 },
-    Jalr { rd: u32, rs1: u32, imm: i32 },
-}
-
-fn parse_field<T: std::str::FromStr>(field: &str) -> Result<T, &'static str> {
-    field
-        .split(':')
-        .nth(1)
-        .ok_or("Missing field value")
-        .and_then(|s| s.trim().parse::<T>().map_err(|_| "Failed to parse field"))
+    Jal { rd: u8 },
+    Jalr { rd: u8, rs1: u8 },
 }
 
-fn parse_instruction(s: &str) -> Result<Instruction, Box<dyn Error>> {
-    if s.starts_with("Jal ") {
-        let fields: Vec<&str> = s[4..]
-            .trim_start_matches('{')
-            .trim_end_matches('}')
-            .split(',')
-            .map(|f| f.trim())
-            .collect();
-        let rd: u32 = parse_field(fields.get(0).ok_or("Missing rd")?)?;
-        let imm: i32 = parse_field(fields.get(1).ok_or("Missing imm")?)?;
-        Ok(Instruction::Jal { rd, imm })
-    } else if s.starts_with("Jalr ") {
-        let fields: Vec<&str> = s[5..]
-            .trim_start_matches('{')
-            .trim_end_matches('}')
-            .split(',')
-            .map(|f| f.trim())
-            .collect();
-        let rd: u32 = parse_field(fields.get(0).ok_or("Missing rd")?)?;
-        let rs1: u32 = parse_field(fields.get(1).ok_or("Missing rs1")?)?;
-        let imm: i32 = parse_field(fields.get(2).ok_or("Missing imm")?)?;
-        Ok(Instruction::Jalr { rd, rs1, imm })
-    } else {
-        Ok(Instruction::Regular)
+impl From<Op> for Instruction {
+    fn from(op: Op) -> Self {
+        match op {
+            Op::Jal { rd, .. } => Instruction::Jal { rd },
+            Op::Jalr { rd, rs1, .. } => Instruction::Jalr { rd, rs1 },
+            _ => Instruction::Regular,
+        }
     }
 }
 
 fn is_call(inst: &Instruction) -> bool {
     match inst {
-        Instruction::Jal { rd, imm: _ } if *rd == 1 => true,
+        Instruction::Jal { rd } if *rd == 1 => true,
         Instruction::Jalr { rd, .. } if *rd == 1 => true,
         _ => false,
     }
@@ -161,24 +139,12 @@ fn main() -> Result<(), Box<dyn Error>> {
         std::process::exit(1);
     }
 
-    let trace = read_to_string(&args[1])?;
-    let lines: Vec<&str> = trace.lines().collect();
-
     let mut trace_data = Vec::new();
-    for line in lines {
-        let parts: Vec<&str> = line.split(" -> ").collect();
-        if parts.len() != 2 {
-            continue;
-        }
-        let pc_str = parts[0].split(": ").next().ok_or("Malformed trace line")?;
-        if pc_str.len() < 8 {
-            return Err("PC string too short".into());
+    for event in vtrace::open(&args[1])? {
+        if let TraceEvent::Instruction { pc, inst, .. } = event? {
+            let (op, _) = decode(inst);
+            trace_data.push((pc, Instruction::from(op)));
         }
-        let pc = u32::from_str_radix(&pc_str[pc_str.len() - 8..], 16)
-            .map_err(|_| "Failed to parse PC")?;
-        let decoded = parts[1];
-        let inst = parse_instruction(decoded)?;
-        trace_data.push((pc, inst));
     }
 
     let mut tree = Tree::new();
@@ -0,0 +1,144 @@
+mod sim;
+
+use std::collections::BTreeMap;
+use std::env;
+use std::error::Error;
+
+use common::trace::PageAccessRecorder;
+use vtrace::TraceEvent;
+
+use common::evict::EvictionPolicy;
+use sim::{simulate, Policy};
+
+const DEFAULT_SIZES: &[usize] = &[4, 8, 16, 32, 64];
+
+fn usage(program: &str) -> ! {
+    eprintln!(
+        "Usage: {} <trace_file> [--sizes <n>,...] [--policies <policy>,...] [--segments <start>-<end>,...]",
+        program
+    );
+    eprintln!();
+    eprintln!(
+        "  --sizes       number of pages in the cache of each segment (default: 4,8,16,32,64)"
+    );
+    eprintln!(
+        "  --policies    eviction policies among lru, 2q, clock, clock-pro, arc, 2q:<a1%>:<a1out%>"
+    );
+    eprintln!("                (default: all of them, except the custom 2q)");
+    eprintln!(
+        "  --segments    address ranges of the segments, in hexadecimal, in the order of the"
+    );
+    eprintln!("                manifest; only needed for full traces, without page accesses");
+    std::process::exit(1);
+}
+
+fn parse_list<T>(s: &str, parse: impl Fn(&str) -> Result<T, String>) -> Result<Vec<T>, String> {
+    s.split(',').map(|item| parse(item.trim())).collect()
+}
+
+fn parse_size(s: &str) -> Result<usize, String> {
+    match s.parse::<usize>() {
+        Ok(n) if n > 0 => Ok(n),
+        _ => Err(format!("invalid cache size: {}", s)),
+    }
+}
+
+fn parse_segment(s: &str) -> Result<(u32, u32), String> {
+    let parse_hex = |v: &str| u32::from_str_radix(v.trim_start_matches("0x"), 16).ok();
+    s.split_once('-')
+        .and_then(|(start, end)| Some((parse_hex(start)?, parse_hex(end)?)))
+        .filter(|(start, end)| start < end)
+        .ok_or_else(|| format!("invalid segment: {}", s))
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let args: Vec<String> = env::args().collect();
+    let mut trace_file = None;
+    let mut sizes = DEFAULT_SIZES.to_vec();
+    let mut policies: Vec<Policy> = EvictionPolicy::ALL.into_iter().map(Policy::Vm).collect();
+    let mut segments = None;
+
+    let mut i = 1;
+    while i < args.len() {
+        let value = || {
+            args.get(i + 1)
+                .map(String::as_str)
+                .unwrap_or_else(|| usage(&args[0]))
+        };
+        match args[i].as_str() {
+            "--sizes" => sizes = parse_list(value(), parse_size)?,
+            "--policies" => policies = parse_list(value(), Policy::parse)?,
+            "--segments" => segments = Some(parse_list(value(), parse_segment)?),
+            arg if !arg.starts_with("--") && trace_file.is_none() => {
+                trace_file = Some(arg.to_string());
+                i += 1;
+                continue;
+            }
+            _ => usage(&args[0]),
+        }
+        i += 2;
+    }
+    let Some(trace_file) = trace_file else {
+        usage(&args[0]);
+    };
+
+    // The page accesses of each segment. Traces recorded with page accesses only are already in
+    // the right form; full traces are converted using the address ranges of the segments.
+    let mut accesses: BTreeMap<u8, Vec<(u32, bool)>> = BTreeMap::new();
+    let mut recorder = segments.map(PageAccessRecorder::new);
+    for event in vtrace::open(&trace_file)? {
+        let event = event?;
+        let mut push = |event: &TraceEvent| {
+            if let TraceEvent::PageAccess {
+                segment,
+                page_index,
+                write,
+            } = *event
+            {
+                accesses
+                    .entry(segment)
+                    .or_default()
+                    .push((page_index, write));
+            }
+        };
+        match recorder.as_mut() {
+            Some(recorder) => recorder.record(&event, push),
+            None => push(&event),
+        }
+    }
+
+    if accesses.is_empty() {
+        eprintln!(
+            "No page accesses in the trace. For a full trace, the --segments option is required."
+        );
+        std::process::exit(1);
+    }
+
+    println!(
+        "{:>7} {:>12} {:>6} {:>10} {:>10} {:>10} {:>9}",
+        "segment", "policy", "size", "accesses", "misses", "commits", "miss rate"
+    );
+    for (segment, segment_accesses) in &accesses {
+        for policy in &policies {
+            for &size in &sizes {
+                let stats = simulate(segment_accesses, size, *policy);
+                let miss_rate = if stats.accesses == 0 {
+                    0.0
+                } else {
+                    100.0 * stats.misses as f64 / stats.accesses as f64
+                };
+                println!(
+                    "{:>7} {:>12} {:>6} {:>10} {:>10} {:>10} {:>8.2}%",
+                    segment,
+                    policy.to_string(),
+                    size,
+                    stats.accesses,
+                    stats.misses,
+                    stats.commits,
+                    miss_rate
+                );
+            }
+        }
+    }
+    Ok(())
+}
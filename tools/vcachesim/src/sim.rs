@@ -0,0 +1,259 @@
+//! Simulation of the page cache of a segment in the VM, replaying a sequence of page accesses.
+
+use std::fmt;
+
+use common::evict::{EvictionPolicy, PageEvictionStrategy, TwoQEvictionStrategy};
+
+/// An eviction policy, and its parameters.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Policy {
+    /// A policy with the same parameters as in the VM.
+    Vm(EvictionPolicy),
+    /// 2Q, with the maximum sizes of the A1 and A1out queues as fractions of the cache size.
+    TwoQ { a1: f64, a1out: f64 },
+}
+
+impl Policy {
+    /// Parses a policy: either the name of a policy of the VM, like `lru` or `arc`, or
+    /// `2q:<a1>:<a1out>` with the sizes of the queues of 2Q in percent of the cache size.
+    pub fn parse(s: &str) -> Result<Self, String> {
+        if let Some(policy) = EvictionPolicy::from_name(s) {
+            return Ok(Policy::Vm(policy));
+        }
+        let parts: Vec<&str> = s.split(':').collect();
+        match parts[..] {
+            ["2q", a1, a1out] => {
+                let percent = |v: &str| {
+                    v.parse::<f64>()
+                        .ok()
+                        .filter(|v| (0.0..=100.0).contains(v))
+                        .map(|v| v / 100.0)
+                        .ok_or_else(|| format!("invalid percentage: {}", v))
+                };
+                Ok(Policy::TwoQ {
+                    a1: percent(a1)?,
+                    a1out: percent(a1out)?,
+                })
+            }
+            _ => Err(format!("unknown policy: {}", s)),
+        }
+    }
+
+    /// Creates the eviction strategy for a cache of `n_slots` pages.
+    pub fn create(&self, n_slots: usize) -> Box<dyn PageEvictionStrategy> {
+        match *self {
+            Policy::Vm(policy) => policy.create(n_slots),
+            Policy::TwoQ { a1, a1out } => {
+                // the A1 queue must be smaller than the cache
+                let a1_max_size = ((n_slots as f64 * a1) as usize).min(n_slots - 1);
+                let a1out_max_size = (n_slots as f64 * a1out) as usize;
+                Box::new(TwoQEvictionStrategy::new(
+                    n_slots,
+                    a1_max_size,
+                    a1out_max_size,
+                ))
+            }
+        }
+    }
+}
+
+impl fmt::Display for Policy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Policy::Vm(policy) => write!(f, "{}", policy),
+            Policy::TwoQ { a1, a1out } => write!(f, "2q:{}:{}", a1 * 100.0, a1out * 100.0),
+        }
+    }
+}
+
+/// The outcome of a simulation.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Stats {
+    /// Number of accesses to a different page than the previous one.
+    pub accesses: u64,
+    /// Number of pages loaded from the client.
+    pub misses: u64,
+    /// Number of pages committed to the client, when a modified page is evicted.
+    pub commits: u64,
+}
+
+#[derive(Clone, Copy)]
+struct Slot {
+    page_index: u32,
+    modified: bool,
+}
+
+/// A page cache with the same behavior as the `OutsourcedMemory` of the VM.
+pub struct CacheSimulator {
+    slots: Vec<Option<Slot>>,
+    strategy: Box<dyn PageEvictionStrategy>,
+    last_accessed_slot: Option<usize>,
+    stats: Stats,
+}
+
+impl CacheSimulator {
+    pub fn new(n_slots: usize, policy: Policy) -> Self {
+        assert!(n_slots > 0, "The cache must have at least one slot");
+        Self {
+            slots: vec![None; n_slots],
+            strategy: policy.create(n_slots),
+            last_accessed_slot: None,
+            stats: Stats::default(),
+        }
+    }
+
+    pub fn stats(&self) -> Stats {
+        self.stats
+    }
+
+    /// Accesses a page, and writes it if `write` is true.
+    pub fn access(&mut self, page_index: u32, write: bool) {
+        // consecutive accesses to the same page are not reported to the eviction strategy
+        if let Some(slot) = self.last_accessed_slot {
+            if let Some(cached) = self.slots[slot].as_mut() {
+                if cached.page_index == page_index {
+                    cached.modified |= write;
+                    return;
+                }
+            }
+        }
+        self.stats.accesses += 1;
+
+        let cached_slot = self
+            .slots
+            .iter()
+            .position(|s| s.is_some_and(|s| s.page_index == page_index));
+        let slot = match cached_slot {
+            Some(slot) => {
+                self.strategy.on_access(slot, page_index);
+                slot
+            }
+            None => {
+                self.stats.misses += 1;
+                self.strategy.on_miss(page_index);
+                let slot = self.reserve_slot();
+                self.slots[slot] = Some(Slot {
+                    page_index,
+                    modified: false,
+                });
+                self.strategy.on_load(slot, page_index);
+                slot
+            }
+        };
+        if let Some(cached) = self.slots[slot].as_mut() {
+            cached.modified |= write;
+        }
+        self.last_accessed_slot = Some(slot);
+    }
+
+    // Returns a free slot, evicting a page if needed
+    fn reserve_slot(&mut self) -> usize {
+        if let Some(slot) = self.slots.iter().position(|s| s.is_none()) {
+            return slot;
+        }
+        let slot = self.strategy.choose_victim();
+        let evicted = self.slots[slot]
+            .take()
+            .expect("The victim is always a valid slot");
+        if evicted.modified {
+            self.stats.commits += 1;
+        }
+        self.strategy.on_invalidate(slot, evicted.page_index);
+        slot
+    }
+}
+
+/// Replays the page accesses of a segment in a cache of `n_slots` pages with the given policy.
+pub fn simulate(accesses: &[(u32, bool)], n_slots: usize, policy: Policy) -> Stats {
+    let mut simulator = CacheSimulator::new(n_slots, policy);
+    for &(page_index, write) in accesses {
+        simulator.access(page_index, write);
+    }
+    simulator.stats()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LRU: Policy = Policy::Vm(EvictionPolicy::Lru);
+
+    #[test]
+    fn test_parse_policy() {
+        assert_eq!(Policy::parse("lru"), Ok(LRU));
+        assert_eq!(Policy::parse("2q"), Ok(Policy::Vm(EvictionPolicy::TwoQ)));
+        assert_eq!(
+            Policy::parse("clock-pro"),
+            Ok(Policy::Vm(EvictionPolicy::ClockPro))
+        );
+        assert_eq!(
+            Policy::parse("2q:10:50"),
+            Ok(Policy::TwoQ {
+                a1: 0.1,
+                a1out: 0.5
+            })
+        );
+        assert!(Policy::parse("2q:10").is_err());
+        assert!(Policy::parse("2q:10:500").is_err());
+        assert!(Policy::parse("mru").is_err());
+    }
+
+    #[test]
+    fn test_simulate_lru() {
+        let reads = |pages: &[u32]| pages.iter().map(|&p| (p, false)).collect::<Vec<_>>();
+
+        // a loop over 3 pages fits in a cache of 3 pages, but thrashes a cache of 2 pages
+        let accesses = reads(&[0, 1, 2, 0, 1, 2, 0, 1, 2]);
+        let stats = simulate(&accesses, 3, LRU);
+        assert_eq!(
+            stats,
+            Stats {
+                accesses: 9,
+                misses: 3,
+                commits: 0
+            }
+        );
+        assert_eq!(simulate(&accesses, 2, LRU).misses, 9);
+
+        // consecutive accesses to the same page count once
+        assert_eq!(simulate(&reads(&[0, 0, 0, 1]), 1, LRU).accesses, 2);
+    }
+
+    #[test]
+    fn test_simulate_commits() {
+        // only the modified pages are committed when evicted, once per eviction
+        let accesses = [
+            (0, false),
+            (0, true),
+            (1, false),
+            (2, true),
+            (0, false),
+            (1, false),
+        ];
+        let stats = simulate(&accesses, 2, LRU);
+        assert_eq!(
+            stats,
+            Stats {
+                accesses: 5,
+                misses: 5,
+                commits: 2
+            }
+        );
+    }
+
+    #[test]
+    fn test_simulate_two_q() {
+        // a scan of pages used once does not evict the pages used repeatedly from Am
+        let mut accesses = Vec::new();
+        for i in 0..10 {
+            accesses.push((0, false));
+            accesses.push((1, false));
+            accesses.push((100 + i, false));
+        }
+        let lru = simulate(&accesses, 3, LRU);
+        let two_q = simulate(&accesses, 4, Policy::Vm(EvictionPolicy::TwoQ));
+        assert_eq!(lru.accesses, 30);
+        assert_eq!(two_q.accesses, 30);
+        assert!(two_q.misses <= lru.misses + 2);
+    }
+}
@@ -0,0 +1,247 @@
+//! Reader for the binary execution traces of V-Apps.
+//!
+//! See [`common::trace`] for the format of the traces.
+
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Cursor, Read};
+use std::path::Path;
+
+pub use common::trace::{TraceError, TraceEvent};
+use common::trace::{TRACE_MAGIC, TRACE_VERSION};
+
+/// Prefix of the console lines that contain a chunk of the trace produced by the VM.
+pub const LOG_PREFIX: &str = "[vtrace] ";
+
+const READ_CHUNK_SIZE: usize = 64 * 1024;
+
+#[derive(Debug)]
+pub enum ReadError {
+    Io(io::Error),
+    InvalidHeader,
+    UnsupportedVersion(u8),
+    InvalidLogLine(usize),
+    Event(TraceError),
+}
+
+impl fmt::Display for ReadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReadError::Io(e) => write!(f, "I/O error: {}", e),
+            ReadError::InvalidHeader => write!(f, "not a V-App trace"),
+            ReadError::UnsupportedVersion(v) => write!(f, "unsupported trace version {}", v),
+            ReadError::InvalidLogLine(n) => write!(f, "invalid trace chunk at line {}", n),
+            ReadError::Event(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for ReadError {}
+
+impl From<io::Error> for ReadError {
+    fn from(e: io::Error) -> Self {
+        ReadError::Io(e)
+    }
+}
+
+impl From<TraceError> for ReadError {
+    fn from(e: TraceError) -> Self {
+        ReadError::Event(e)
+    }
+}
+
+/// An iterator over the events of a binary trace.
+pub struct TraceReader<R: Read> {
+    reader: R,
+    buf: Vec<u8>,
+    pos: usize,
+    done: bool,
+}
+
+impl<R: Read> TraceReader<R> {
+    /// Creates a reader for the trace in `reader`, after checking its header.
+    pub fn new(mut reader: R) -> Result<Self, ReadError> {
+        let mut header = [0u8; 5];
+        reader.read_exact(&mut header).map_err(|e| match e.kind() {
+            io::ErrorKind::UnexpectedEof => ReadError::InvalidHeader,
+            _ => ReadError::Io(e),
+        })?;
+        if header[..4] != TRACE_MAGIC {
+            return Err(ReadError::InvalidHeader);
+        }
+        if header[4] != TRACE_VERSION {
+            return Err(ReadError::UnsupportedVersion(header[4]));
+        }
+        Ok(Self {
+            reader,
+            buf: Vec::new(),
+            pos: 0,
+            done: false,
+        })
+    }
+
+    // Reads more data from the underlying reader, and returns false at the end of the trace
+    fn fill_buf(&mut self) -> io::Result<bool> {
+        self.buf.drain(..self.pos);
+        self.pos = 0;
+        let len = self.buf.len();
+        self.buf.resize(len + READ_CHUNK_SIZE, 0);
+        loop {
+            match self.reader.read(&mut self.buf[len..]) {
+                Ok(n) => {
+                    self.buf.truncate(len + n);
+                    return Ok(n > 0);
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    self.buf.truncate(len);
+                    return Err(e);
+                }
+            }
+        }
+    }
+}
+
+impl<R: Read> Iterator for TraceReader<R> {
+    type Item = Result<TraceEvent, ReadError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let result = loop {
+            match TraceEvent::decode(&self.buf[self.pos..]) {
+                Ok((event, size)) => {
+                    self.pos += size;
+                    return Some(Ok(event));
+                }
+                Err(TraceError::Truncated) => match self.fill_buf() {
+                    Ok(true) => continue,
+                    // the end of the trace, unless it stops in the middle of an event
+                    Ok(false) if self.buf.is_empty() => break None,
+                    Ok(false) => break Some(Err(TraceError::Truncated.into())),
+                    Err(e) => break Some(Err(e.into())),
+                },
+                Err(e) => break Some(Err(e.into())),
+            }
+        };
+        self.done = true;
+        result
+    }
+}
+
+/// Extracts the binary trace from the console output of the VM, from the lines starting with
+/// [`LOG_PREFIX`]. Any other line is ignored.
+pub fn extract_from_log(log: impl BufRead) -> Result<Vec<u8>, ReadError> {
+    let mut trace = Vec::new();
+    for (i, line) in log.lines().enumerate() {
+        let line = line?;
+        let Some(start) = line.find(LOG_PREFIX) else {
+            continue;
+        };
+        let chunk = line[start + LOG_PREFIX.len()..].trim_end();
+        if !chunk.len().is_multiple_of(2) {
+            return Err(ReadError::InvalidLogLine(i + 1));
+        }
+        for j in (0..chunk.len()).step_by(2) {
+            let byte = chunk
+                .get(j..j + 2)
+                .and_then(|b| u8::from_str_radix(b, 16).ok())
+                .ok_or(ReadError::InvalidLogLine(i + 1))?;
+            trace.push(byte);
+        }
+    }
+    Ok(trace)
+}
+
+/// Opens the trace in the file at `path`, that is either a binary trace, or the console output
+/// of the VM.
+pub fn open(path: impl AsRef<Path>) -> Result<TraceReader<Box<dyn Read>>, ReadError> {
+    let mut file = BufReader::new(File::open(path)?);
+    if file.fill_buf()?.starts_with(&TRACE_MAGIC) {
+        return TraceReader::new(Box::new(file) as Box<dyn Read>);
+    }
+    let trace = extract_from_log(file)?;
+    TraceReader::new(Box::new(Cursor::new(trace)) as Box<dyn Read>)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::trace::{header, MAX_EVENT_SIZE};
+
+    fn events() -> Vec<TraceEvent> {
+        vec![
+            TraceEvent::Instruction {
+                pc: 0x10000,
+                inst: 0x00b14603,
+                size: 4,
+            },
+            TraceEvent::MemoryRead {
+                address: 0x2000b,
+                size: 1,
+            },
+            TraceEvent::PageLoad {
+                segment: 1,
+                page_index: 7,
+            },
+            TraceEvent::Ecall { code: 1 },
+        ]
+    }
+
+    fn encode(events: &[TraceEvent]) -> Vec<u8> {
+        let mut trace = header().to_vec();
+        for event in events {
+            let mut buf = [0u8; MAX_EVENT_SIZE];
+            let len = event.encode(&mut buf);
+            trace.extend_from_slice(&buf[..len]);
+        }
+        trace
+    }
+
+    #[test]
+    fn test_read_trace() {
+        let trace = encode(&events());
+        let read: Vec<TraceEvent> = TraceReader::new(Cursor::new(&trace))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(read, events());
+
+        // truncated in the middle of the last event
+        let mut reader = TraceReader::new(Cursor::new(&trace[..trace.len() - 1])).unwrap();
+        assert_eq!(reader.by_ref().take(3).count(), 3);
+        assert!(matches!(
+            reader.next(),
+            Some(Err(ReadError::Event(TraceError::Truncated)))
+        ));
+        assert!(reader.next().is_none());
+
+        assert!(matches!(
+            TraceReader::new(Cursor::new(b"VTRD\x01")),
+            Err(ReadError::InvalidHeader)
+        ));
+        assert!(matches!(
+            TraceReader::new(Cursor::new(b"VTRC\x02")),
+            Err(ReadError::UnsupportedVersion(2))
+        ));
+    }
+
+    #[test]
+    fn test_extract_from_log() {
+        let trace = encode(&events());
+        let hex: String = trace.iter().map(|b| format!("{:02x}", b)).collect();
+        let (first, second) = hex.split_at(12);
+        let log = format!(
+            "Some other output\n{}{}\n[page_load] segment: 0\n{}{}\n",
+            LOG_PREFIX, first, LOG_PREFIX, second
+        );
+        assert_eq!(extract_from_log(log.as_bytes()).unwrap(), trace);
+
+        let log = format!("{}0g\n", LOG_PREFIX);
+        assert!(matches!(
+            extract_from_log(log.as_bytes()),
+            Err(ReadError::InvalidLogLine(1))
+        ));
+    }
+}
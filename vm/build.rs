@@ -20,6 +20,25 @@ fn main() {
     )
     .unwrap();
     println!("cargo:rerun-if-env-changed=HEAP_SIZE");
+
+    // maximum number of instructions executed between two interactions of the V-App with the
+    // outside world; 0 means unlimited
+    let instruction_budget = env::var("INSTRUCTION_BUDGET")
+        .ok()
+        .map(|v| v.trim().parse::<u64>().expect("Invalid INSTRUCTION_BUDGET"))
+        .unwrap_or(0);
+    let instruction_budget = match instruction_budget {
+        0 => "None".to_string(),
+        n => format!("Some({n})"),
+    };
+
+    let out = PathBuf::from(env::var_os("OUT_DIR").unwrap()).join("instruction_budget.rs");
+    write(
+        &out,
+        format!("pub const INSTRUCTION_BUDGET: Option<u64> = {instruction_budget};\n"),
+    )
+    .unwrap();
+    println!("cargo:rerun-if-env-changed=INSTRUCTION_BUDGET");
 }
 
 fn parse_heap_size(raw: &str, target_os: &str) -> usize {
@@ -1,6 +1,7 @@
 use alloc::vec::Vec;
 use core::fmt;
 use ledger_device_sdk::sys::{cx_aes_enc_block, cx_aes_init_key_no_throw, cx_aes_key_t, CX_OK};
+use subtle::ConstantTimeEq;
 
 /// AES errors that can occur during cryptographic operations
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -11,6 +12,10 @@ pub enum AesError {
     InvalidInputLength,
     /// Encryption operation failed
     EncryptionFailed,
+    /// All the nonces have been used, and no more messages can be encrypted
+    NonceExhausted,
+    /// The tag of an authenticated message is invalid
+    AuthenticationFailed,
 }
 
 impl fmt::Display for AesError {
@@ -22,6 +27,8 @@ impl fmt::Display for AesError {
                 "Invalid input length (must be a multiple of 16 bytes for block operations)"
             ),
             AesError::EncryptionFailed => write!(f, "AES encryption operation failed"),
+            AesError::NonceExhausted => write!(f, "AES nonces exhausted"),
+            AesError::AuthenticationFailed => write!(f, "AES authentication failed"),
         }
     }
 }
@@ -29,6 +36,8 @@ impl fmt::Display for AesError {
 /// Wrapper for the AES key from ledger_device_sdk::sys
 pub struct AesKey {
     key: cx_aes_key_t,
+    /// The raw key material, kept to allow exporting the key
+    key_data: [u8; 16],
 }
 
 impl AesKey {
@@ -50,6 +59,19 @@ impl AesKey {
             }
         }
 
+        Self::from_bytes(&key_data)
+    }
+
+    /// Create a new AES-128 key from the provided 16 bytes of key material.
+    ///
+    /// # Arguments
+    ///
+    /// * `key_data` - The key material to use
+    ///
+    /// # Returns
+    ///
+    /// A new AesKey instance or an error if initialization fails
+    pub fn from_bytes(key_data: &[u8; 16]) -> Result<Self, AesError> {
         let mut key = unsafe { core::mem::zeroed::<cx_aes_key_t>() };
         let result = unsafe {
             cx_aes_init_key_no_throw(key_data.as_ptr(), key_data.len() as usize, &mut key)
@@ -59,7 +81,17 @@ impl AesKey {
             return Err(AesError::KeyInitFailed);
         }
 
-        Ok(Self { key })
+        Ok(Self {
+            key,
+            key_data: *key_data,
+        })
+    }
+
+    /// Returns the raw key material.
+    ///
+    /// SECURITY: the key must never leave the device unencrypted.
+    pub fn to_bytes(&self) -> [u8; 16] {
+        self.key_data
     }
 
     /// Create a new AES-128 key from the provided key data.
@@ -74,20 +106,11 @@ impl AesKey {
     #[cfg(feature = "run_tests")]
     pub fn from_slice(key_data: &[u8]) -> Result<Self, AesError> {
         // Only accept valid AES key sizes: 16 bytes (128 bits)
-        if key_data.len() != 16 {
-            return Err(AesError::InvalidInputLength);
-        }
+        let key_data: &[u8; 16] = key_data
+            .try_into()
+            .map_err(|_| AesError::InvalidInputLength)?;
 
-        let mut key = unsafe { core::mem::zeroed::<cx_aes_key_t>() };
-        let result = unsafe {
-            cx_aes_init_key_no_throw(key_data.as_ptr(), key_data.len() as usize, &mut key)
-        };
-
-        if result != 0 {
-            return Err(AesError::KeyInitFailed);
-        }
-
-        Ok(Self { key })
+        Self::from_bytes(key_data)
     }
 
     /// Encrypt a single 16-byte block
@@ -116,44 +139,155 @@ impl AesKey {
     }
 }
 
+/// Default maximum number of messages encrypted with the same key when the key is rotated. For
+/// pages, that is 2^36 blocks, far below the birthday bound of AES.
+pub const MAX_MESSAGES_PER_KEY: u64 = 1 << 32;
+
+// Prefix of the block encrypted with the main key to derive the key of an epoch
+const EPOCH_KEY_LABEL: &[u8; 12] = b"VND_PAGE_KEY";
+
+/// Size of the tag of the messages encrypted with AES-CCM.
+pub const CCM_TAG_SIZE: usize = 16;
+
+// Flags of the blocks of AES-CCM that contain the nonce: with a 12-byte nonce, 3 bytes are left
+// for the length of the message or the counter (the flags encode that size, minus 1)
+const CCM_COUNTER_FLAGS: u8 = 2;
+
+// The key of the current epoch, when the keys are rotated
+struct KeyRotation {
+    epoch_key: AesKey,
+    max_messages_per_key: u64,
+}
+
 /// AES-CTR mode implementation that encapsulates the state for Counter mode operations
+///
+/// With key rotation, the main key is only used to derive the key of each epoch, and the nonce is
+/// the epoch (4 bytes) followed by the number of messages encrypted in that epoch (8 bytes). Once
+/// the maximum number of messages per key is reached, the next epoch starts with a new key. The
+/// messages of any epoch can still be decrypted, as the key of their epoch is derived again.
 pub struct AesCtr {
     /// The AES key
     key: AesKey,
     /// A 12-byte nonce, incremented for each new message
     nonce: [u8; 12],
+    /// Set once all the nonces have been used
+    exhausted: bool,
+    rotation: Option<KeyRotation>,
 }
 
 impl AesCtr {
-    /// Create a new AES-CTR instance with the given key, and initialize the nonce to 0.
+    /// Create a new AES-CTR instance with the given key, and initialize the nonce to the given value
     ///
     /// # Arguments
     ///
     /// * `key` - The AES key to use
+    /// * `nonce` - The nonce to use
     ///
     /// # Returns
     ///
     /// A new AesCtr instance
-    pub fn new(key: AesKey) -> Self {
+    pub fn new_with_nonce(key: AesKey, nonce: [u8; 12]) -> Self {
         Self {
             key,
-            nonce: [0u8; 12],
+            nonce,
+            exhausted: false,
+            rotation: None,
         }
     }
 
-    /// Create a new AES-CTR instance with the given key, and initialize the nonce to the given value
+    /// Create a new AES-CTR instance that rotates the key every `MAX_MESSAGES_PER_KEY` messages.
+    /// The keys of the epochs are derived from `key`. The nonce is 0 for a new instance, or the
+    /// value returned by `nonce` to continue with the same keys.
     ///
     /// # Arguments
     ///
-    /// * `key` - The AES key to use
-    /// * `nonce` - The nonce to use
+    /// * `key` - The main key, that the keys of the epochs are derived from
+    /// * `nonce` - The nonce to use for the next message
     ///
     /// # Returns
     ///
-    /// A new AesCtr instance
+    /// A new AesCtr instance or an error if the key of the epoch cannot be derived
+    pub fn new_with_key_rotation(key: AesKey, nonce: [u8; 12]) -> Result<Self, AesError> {
+        let epoch_key = Self::derive_epoch_key(&key, Self::epoch_of(&nonce))?;
+        Ok(Self {
+            key,
+            nonce,
+            exhausted: false,
+            rotation: Some(KeyRotation {
+                epoch_key,
+                max_messages_per_key: MAX_MESSAGES_PER_KEY,
+            }),
+        })
+    }
+
+    /// Sets the maximum number of messages encrypted with the key of an epoch. It has no effect
+    /// without key rotation. Only used in tests, to rotate the key after a few messages.
     #[cfg(feature = "run_tests")]
-    pub fn new_with_nonce(key: AesKey, nonce: [u8; 12]) -> Self {
-        Self { key, nonce }
+    pub fn with_max_messages_per_key(mut self, max_messages_per_key: u64) -> Self {
+        if let Some(rotation) = self.rotation.as_mut() {
+            rotation.max_messages_per_key = max_messages_per_key.max(1);
+        }
+        self
+    }
+
+    /// Returns the key given when creating the instance; with key rotation, that is the main key
+    pub fn key(&self) -> &AesKey {
+        &self.key
+    }
+
+    /// Returns the epoch of the key that a message was encrypted with, if the key is rotated
+    #[inline(always)]
+    pub fn epoch_of(nonce: &[u8; 12]) -> u32 {
+        u32::from_be_bytes(nonce[0..4].try_into().unwrap())
+    }
+
+    fn derive_epoch_key(key: &AesKey, epoch: u32) -> Result<AesKey, AesError> {
+        let mut block = [0u8; 16];
+        block[0..12].copy_from_slice(EPOCH_KEY_LABEL);
+        block[12..16].copy_from_slice(&epoch.to_be_bytes());
+        let epoch_key = zeroize::Zeroizing::new(key.encrypt_block(&block)?);
+        AesKey::from_bytes(&epoch_key)
+    }
+
+    // Moves to the next epoch if the key of the current one has encrypted enough messages
+    fn rotate_if_needed(&mut self) -> Result<(), AesError> {
+        let Some(rotation) = self.rotation.as_mut() else {
+            return Ok(());
+        };
+        let n_messages = u64::from_be_bytes(self.nonce[4..12].try_into().unwrap());
+        if n_messages < rotation.max_messages_per_key {
+            return Ok(());
+        }
+        let epoch = Self::epoch_of(&self.nonce)
+            .checked_add(1)
+            .ok_or(AesError::NonceExhausted)?;
+        rotation.epoch_key = Self::derive_epoch_key(&self.key, epoch)?;
+        self.nonce[0..4].copy_from_slice(&epoch.to_be_bytes());
+        self.nonce[4..12].fill(0);
+        Ok(())
+    }
+
+    // Returns the key to use for a message with the given nonce
+    fn key_for(&self, nonce: &[u8; 12]) -> Result<Option<AesKey>, AesError> {
+        match &self.rotation {
+            Some(_) if Self::epoch_of(nonce) != Self::epoch_of(&self.nonce) => {
+                Self::derive_epoch_key(&self.key, Self::epoch_of(nonce)).map(Some)
+            }
+            _ => Ok(None),
+        }
+    }
+
+    // Returns the key used for the messages of the current epoch
+    fn current_key(&self) -> &AesKey {
+        match &self.rotation {
+            Some(rotation) => &rotation.epoch_key,
+            None => &self.key,
+        }
+    }
+
+    /// Returns the nonce that will be used for the next message
+    pub fn nonce(&self) -> [u8; 12] {
+        self.nonce
     }
 
     /// Increment a counter block by 1
@@ -178,7 +312,7 @@ impl AesCtr {
     ///
     /// The processed data or an error
     fn process_with_counter(
-        &self,
+        key: &AesKey,
         input: &[u8],
         counter: &mut [u8; 16],
     ) -> Result<Vec<u8>, AesError> {
@@ -191,7 +325,7 @@ impl AesCtr {
         // Process input in blocks (or partial blocks)
         for chunk in input.chunks(16) {
             // Encrypt the counter to create keystream
-            let keystream = self.key.encrypt_block(counter)?;
+            let keystream = key.encrypt_block(counter)?;
 
             // XOR input with keystream to produce output
             for i in 0..chunk.len() {
@@ -219,17 +353,23 @@ impl AesCtr {
         plaintext: &[u8],
         initial_counter: u32,
     ) -> Result<([u8; 12], Vec<u8>), AesError> {
+        self.rotate_if_needed()?;
+        if self.exhausted {
+            return Err(AesError::NonceExhausted);
+        }
+
         // Copy the nonce to return it with the cyphertext
         let nonce = self.nonce.clone();
 
-        // Increment the nonce for the next message
+        // Increment the nonce for the next message; the nonce is never reused, even if it wraps
         Self::increment_be_slice(&mut self.nonce);
+        self.exhausted = self.nonce == [0u8; 12];
 
         let mut counter_block = Self::create_counter_block(&nonce, initial_counter);
 
         Ok((
             nonce,
-            self.process_with_counter(plaintext, &mut counter_block)?,
+            Self::process_with_counter(self.current_key(), plaintext, &mut counter_block)?,
         ))
     }
 
@@ -255,7 +395,9 @@ impl AesCtr {
         initial_counter: u32,
     ) -> Result<Vec<u8>, AesError> {
         let mut counter_block = Self::create_counter_block(&nonce, initial_counter);
-        self.process_with_counter(ciphertext, &mut counter_block)
+        let epoch_key = self.key_for(nonce)?;
+        let key = epoch_key.as_ref().unwrap_or(self.current_key());
+        Self::process_with_counter(key, ciphertext, &mut counter_block)
     }
 
     /// Decrypt data using AES in Counter (CTR) mode
@@ -273,6 +415,129 @@ impl AesCtr {
         self._decrypt_with_initial_counter(nonce, ciphertext, 0)
     }
 
+    // Block of AES-CCM with the given flags, the nonce, and a 3-byte value (a length or a counter)
+    #[inline(always)]
+    fn create_ccm_block(flags: u8, nonce: &[u8; 12], value: u32) -> [u8; 16] {
+        let mut block = [0u8; 16];
+        block[0] = flags;
+        block[1..13].copy_from_slice(nonce);
+        block[13..16].copy_from_slice(&value.to_be_bytes()[1..]);
+        block
+    }
+
+    // Computes the CBC-MAC of AES-CCM over the associated data and the plaintext, and returns the
+    // tag once encrypted with the first block of the keystream
+    fn ccm_tag(
+        key: &AesKey,
+        nonce: &[u8; 12],
+        aad: &[u8],
+        plaintext: &[u8],
+    ) -> Result<[u8; CCM_TAG_SIZE], AesError> {
+        // the length of the message must fit in 3 bytes, and the associated data in 2 bytes
+        if plaintext.len() >= 1 << 24 || aad.len() >= 0xff00 {
+            return Err(AesError::InvalidInputLength);
+        }
+        let adata_flag = if aad.is_empty() { 0 } else { 0x40 };
+        let tag_flags = (((CCM_TAG_SIZE - 2) / 2) as u8) << 3;
+        let b0 = Self::create_ccm_block(
+            adata_flag | tag_flags | CCM_COUNTER_FLAGS,
+            nonce,
+            plaintext.len() as u32,
+        );
+        let mut mac = key.encrypt_block(&b0)?;
+
+        // Each block is xored into the MAC before encrypting it; the last block of the associated
+        // data and of the plaintext are padded with zeros
+        let absorb = |mac: &mut [u8; 16], block: &[u8]| -> Result<(), AesError> {
+            for (m, b) in mac.iter_mut().zip(block) {
+                *m ^= b;
+            }
+            *mac = key.encrypt_block(&mac[..])?;
+            Ok(())
+        };
+        if !aad.is_empty() {
+            // the associated data is prefixed with its length
+            let mut encoded_aad = Vec::with_capacity(2 + aad.len());
+            encoded_aad.extend_from_slice(&(aad.len() as u16).to_be_bytes());
+            encoded_aad.extend_from_slice(aad);
+            for block in encoded_aad.chunks(16) {
+                absorb(&mut mac, block)?;
+            }
+        }
+        for block in plaintext.chunks(16) {
+            absorb(&mut mac, block)?;
+        }
+
+        let s0 = key.encrypt_block(&Self::create_ccm_block(CCM_COUNTER_FLAGS, nonce, 0))?;
+        for (m, s) in mac.iter_mut().zip(s0) {
+            *m ^= s;
+        }
+        Ok(mac)
+    }
+
+    /// Encrypt and authenticate data using AES in CCM mode (NIST SP 800-38C), with the same nonces
+    /// as the CTR mode. The same key must not be used with both modes.
+    ///
+    /// # Arguments
+    ///
+    /// * `plaintext` - The plaintext data to encrypt
+    /// * `aad` - The associated data, that is authenticated but not encrypted
+    ///
+    /// # Returns
+    ///
+    /// The nonce, the ciphertext and the tag, or an error
+    pub fn encrypt_authenticated(
+        &mut self,
+        plaintext: &[u8],
+        aad: &[u8],
+    ) -> Result<([u8; 12], Vec<u8>, [u8; CCM_TAG_SIZE]), AesError> {
+        self.rotate_if_needed()?;
+        if self.exhausted {
+            return Err(AesError::NonceExhausted);
+        }
+        let nonce = self.nonce;
+        Self::increment_be_slice(&mut self.nonce);
+        self.exhausted = self.nonce == [0u8; 12];
+
+        let key = self.current_key();
+        let tag = Self::ccm_tag(key, &nonce, aad, plaintext)?;
+        let mut counter_block = Self::create_ccm_block(CCM_COUNTER_FLAGS, &nonce, 1);
+        let ciphertext = Self::process_with_counter(key, plaintext, &mut counter_block)?;
+        Ok((nonce, ciphertext, tag))
+    }
+
+    /// Decrypt data encrypted with `encrypt_authenticated`, and check its tag.
+    ///
+    /// # Arguments
+    ///
+    /// * `nonce` - The nonce the ciphertext was generated with
+    /// * `ciphertext` - The ciphertext data to decrypt
+    /// * `aad` - The associated data that was given when encrypting
+    /// * `tag` - The tag of the message
+    ///
+    /// # Returns
+    ///
+    /// The decrypted plaintext, or an error if the tag is invalid
+    pub fn decrypt_authenticated(
+        &self,
+        nonce: &[u8; 12],
+        ciphertext: &[u8],
+        aad: &[u8],
+        tag: &[u8; CCM_TAG_SIZE],
+    ) -> Result<Vec<u8>, AesError> {
+        let epoch_key = self.key_for(nonce)?;
+        let key = epoch_key.as_ref().unwrap_or(self.current_key());
+        let mut counter_block = Self::create_ccm_block(CCM_COUNTER_FLAGS, nonce, 1);
+        let plaintext = Self::process_with_counter(key, ciphertext, &mut counter_block)?;
+
+        let expected_tag = Self::ccm_tag(key, nonce, aad, &plaintext)?;
+        // constant time comparison, not to leak how much of the tag is correct
+        if expected_tag[..].ct_ne(&tag[..]).into() {
+            return Err(AesError::AuthenticationFailed);
+        }
+        Ok(plaintext)
+    }
+
     /// We make these function public only for tests, so we can use test vectors with an arbitrary counter block
     #[cfg(feature = "run_tests")]
     pub fn encrypt_with_initial_counter(
@@ -290,7 +555,6 @@ impl AesCtr {
         ciphertext: &[u8],
         initial_counter: u32,
     ) -> Result<Vec<u8>, AesError> {
-        let mut counter_block = Self::create_counter_block(&nonce, initial_counter);
-        self.process_with_counter(ciphertext, &mut counter_block)
+        self._decrypt_with_initial_counter(nonce, ciphertext, initial_counter)
     }
 }
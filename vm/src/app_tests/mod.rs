@@ -2,5 +2,8 @@ mod test_aes;
 
 pub fn run_tests() {
     test_aes::test_aes();
+    test_aes::test_aes_nonce_exhaustion();
+    test_aes::test_aes_key_rotation();
+    test_aes::test_aes_ccm();
     crate::println!("All test passed!");
 }
@@ -32,3 +32,121 @@ pub fn test_aes() {
         "Decrypted plaintext does not match the original plaintext"
     );
 }
+
+pub fn test_aes_nonce_exhaustion() {
+    let aes_key = aes::AesKey::from_slice(&[7u8; 16]).unwrap();
+    let mut aesctr = AesCtr::new_with_nonce(aes_key, [0xff; 12]);
+
+    // the last nonce can be used once, but the nonce never wraps around
+    let (nonce, _) = aesctr.encrypt(&[0u8; 32]).expect("Encryption failed");
+    assert_eq!(nonce, [0xff; 12]);
+    assert_eq!(
+        aesctr.encrypt(&[0u8; 32]),
+        Err(aes::AesError::NonceExhausted)
+    );
+}
+
+pub fn test_aes_key_rotation() {
+    let aes_key_raw = [42u8; 16];
+    let plaintext = [0x5au8; 64];
+    let mut aesctr =
+        AesCtr::new_with_key_rotation(aes::AesKey::from_slice(&aes_key_raw).unwrap(), [0; 12])
+            .unwrap()
+            .with_max_messages_per_key(2);
+
+    let messages: alloc::vec::Vec<_> = (0..5)
+        .map(|_| aesctr.encrypt(&plaintext).expect("Encryption failed"))
+        .collect();
+
+    // two messages per epoch
+    let epochs: alloc::vec::Vec<u32> = messages
+        .iter()
+        .map(|(nonce, _)| AesCtr::epoch_of(nonce))
+        .collect();
+    assert_eq!(epochs, [0, 0, 1, 1, 2]);
+    assert_eq!(messages[2].0[4..], [0u8; 8]);
+    assert_eq!(messages[3].0[4..], [0, 0, 0, 0, 0, 0, 0, 1]);
+
+    // the key changes with the epoch, and is not the main key
+    let plain = AesCtr::new_with_nonce(aes::AesKey::from_slice(&aes_key_raw).unwrap(), [0; 12]);
+    assert_ne!(
+        plain.decrypt(&messages[0].0, &messages[0].1).unwrap(),
+        plaintext
+    );
+    assert_ne!(messages[0].1, messages[2].1);
+
+    // messages of previous epochs can still be decrypted, also after resuming with the same nonce
+    for (nonce, ciphertext) in messages.iter() {
+        assert_eq!(aesctr.decrypt(nonce, ciphertext).unwrap(), plaintext);
+    }
+    let resumed = AesCtr::new_with_key_rotation(
+        aes::AesKey::from_slice(&aes_key_raw).unwrap(),
+        aesctr.nonce(),
+    )
+    .unwrap();
+    for (nonce, ciphertext) in messages.iter() {
+        assert_eq!(resumed.decrypt(nonce, ciphertext).unwrap(), plaintext);
+    }
+
+    // no epoch after the last one
+    let mut last_nonce = [0xffu8; 12];
+    last_nonce[4..].copy_from_slice(&2u64.to_be_bytes());
+    let mut aesctr =
+        AesCtr::new_with_key_rotation(aes::AesKey::from_slice(&aes_key_raw).unwrap(), last_nonce)
+            .unwrap()
+            .with_max_messages_per_key(2);
+    assert_eq!(
+        aesctr.encrypt(&plaintext),
+        Err(aes::AesError::NonceExhausted)
+    );
+}
+
+pub fn test_aes_ccm() {
+    // test vector computed with the AES-CCM implementation of the Python cryptography package
+    let aes_key_raw = hex!("2b7e151628aed2a6abf7158809cf4f3c");
+    let initial_nonce = hex!("101112131415161718191a1b");
+    let aad = [1u8, 0, 0, 0, 5, 0, 0, 0, 7];
+    let plaintext: alloc::vec::Vec<u8> = (0..40).map(|i| (i * 3) as u8).collect();
+
+    let mut aesctr = AesCtr::new_with_nonce(
+        aes::AesKey::from_slice(&aes_key_raw).unwrap(),
+        initial_nonce,
+    );
+    let (nonce, ciphertext, tag) = aesctr
+        .encrypt_authenticated(&plaintext, &aad)
+        .expect("Encryption failed");
+    assert_eq!(nonce, initial_nonce, "Nonce does not match");
+    assert_eq!(
+        ciphertext,
+        hex!("1d024a8cd9ad0f895c3210c1acb77db74c2f018590ac1caa5116538861568271fe1d364cd909136a")
+            .to_vec()
+    );
+    assert_eq!(tag, hex!("46ef4b2feb299eff69a07bcea4fdf7ba"));
+
+    assert_eq!(
+        aesctr
+            .decrypt_authenticated(&nonce, &ciphertext, &aad, &tag)
+            .expect("Decryption failed"),
+        plaintext
+    );
+
+    // any change of the ciphertext, the associated data or the tag is detected
+    let mut tampered = ciphertext.clone();
+    tampered[39] ^= 1;
+    assert_eq!(
+        aesctr.decrypt_authenticated(&nonce, &tampered, &aad, &tag),
+        Err(aes::AesError::AuthenticationFailed)
+    );
+    let mut other_aad = aad;
+    other_aad[8] = 8;
+    assert_eq!(
+        aesctr.decrypt_authenticated(&nonce, &ciphertext, &other_aad, &tag),
+        Err(aes::AesError::AuthenticationFailed)
+    );
+    let mut other_tag = tag;
+    other_tag[0] ^= 0x80;
+    assert_eq!(
+        aesctr.decrypt_authenticated(&nonce, &ciphertext, &aad, &other_tag),
+        Err(aes::AesError::AuthenticationFailed)
+    );
+}
@@ -7,7 +7,7 @@ use core::{
 use alloc::{format, rc::Rc, string::String, vec, vec::Vec};
 use common::{
     client_commands::{
-        BufferType, Message, MessageDeserializationError, ReceiveBufferMessage,
+        BufferType, CheckAbortMessage, Message, MessageDeserializationError, ReceiveBufferMessage,
         ReceiveBufferResponse, SendBufferContinuedMessage, SendBufferMessage,
     },
     ecall_constants::{self, *},
@@ -19,7 +19,7 @@ use ledger_device_sdk::sys::{
 };
 use ledger_device_sdk::{hash::HashInit, io::DecodedEventType};
 
-use crate::io::interrupt;
+use crate::io::{interrupt, interrupt_with_reply, ClientReply};
 
 use super::{outsourced_mem::OutsourcedMemory, SerializeToComm};
 
@@ -35,6 +35,10 @@ use ux_handler::*;
 
 const VENDOR_ID: u16 = 0x2C97; // Ledger vendor ID
 
+// While the V-App waits for events, the client is asked every few tickers whether the request being
+// processed should be aborted
+const ABORT_CHECK_INTERVAL_TICKS: u32 = 5;
+
 #[cfg(target_os = "nanox")]
 mod device_props {
     pub const PRODUCT_ID: u16 = 0x40;
@@ -277,7 +281,9 @@ pub enum CommEcallError {
     InvalidResponse(&'static str),
     CpuError(String),
     MemoryError(MemoryError),
-    UnhandledEcall,
+    UnhandledEcall(u32),
+    /// The client asked to suspend the V-App while it was waiting for a message
+    SuspendRequested,
 }
 
 impl core::fmt::Display for CommEcallError {
@@ -299,7 +305,8 @@ impl core::fmt::Display for CommEcallError {
             }
             CommEcallError::CpuError(e) => write!(f, "Cpu error: {:?}", e),
             CommEcallError::MemoryError(e) => write!(f, "Memory error: {:?}", e),
-            CommEcallError::UnhandledEcall => write!(f, "Unhandled ecall"),
+            CommEcallError::UnhandledEcall(code) => write!(f, "Unhandled ecall: {}", code),
+            CommEcallError::SuspendRequested => write!(f, "Suspend requested"),
         }
     }
 }
@@ -355,6 +362,7 @@ impl core::error::Error for CommEcallError {
 pub struct CommEcallHandler<'a, const N: usize> {
     comm: Rc<RefCell<&'a mut ledger_device_sdk::io::Comm<N>>>,
     ux_handler: &'static mut UxHandler,
+    ticks_since_abort_check: u32,
 }
 
 impl<'a, const N: usize> CommEcallHandler<'a, N> {
@@ -362,6 +370,7 @@ impl<'a, const N: usize> CommEcallHandler<'a, N> {
         Self {
             comm,
             ux_handler: init_ux_handler(),
+            ticks_since_abort_check: 0,
         }
     }
 
@@ -452,7 +461,7 @@ impl<'a, const N: usize> CommEcallHandler<'a, N> {
     ) -> Result<usize, CommEcallError> {
         let mut g_ptr = buffer.0;
 
-        let segment = cpu.get_segment::<E>(g_ptr)?;
+        let segment = cpu.get_writable_segment::<E>(g_ptr)?;
 
         let mut remaining_length = None;
         let mut total_received: usize = 0;
@@ -462,7 +471,18 @@ impl<'a, const N: usize> CommEcallHandler<'a, N> {
                 let mut resp = comm.begin_response();
                 ReceiveBufferMessage::new().serialize_to_comm(&mut resp);
 
-                let command = interrupt(resp)?;
+                // The client can only suspend the V-App or abort its request before sending a
+                // message. When suspending, the ECALL has no effect yet, so it is executed again once
+                // the V-App is resumed.
+                let command = if remaining_length.is_none() {
+                    match interrupt_with_reply(resp)? {
+                        ClientReply::Continue(command) => command,
+                        ClientReply::Suspend => return Err(CommEcallError::SuspendRequested),
+                        ClientReply::Abort => return Ok(XRECV_ABORTED),
+                    }
+                } else {
+                    interrupt(resp)?
+                };
 
                 let raw_data = command.get_data();
                 let response = ReceiveBufferResponse::deserialize(raw_data)?;
@@ -550,7 +570,7 @@ impl<'a, const N: usize> CommEcallHandler<'a, N> {
         }
 
         // copy r_local to r
-        let segment = cpu.get_segment::<E>(r.0)?;
+        let segment = cpu.get_writable_segment::<E>(r.0)?;
         segment.write_buffer(r.0, &r_local[0..len])?;
         Ok(())
     }
@@ -594,7 +614,7 @@ impl<'a, const N: usize> CommEcallHandler<'a, N> {
         }
 
         // copy r_local to r
-        let segment = cpu.get_segment::<E>(r.0)?;
+        let segment = cpu.get_writable_segment::<E>(r.0)?;
         segment.write_buffer(r.0, &r_local[0..len])?;
         Ok(())
     }
@@ -638,7 +658,7 @@ impl<'a, const N: usize> CommEcallHandler<'a, N> {
         }
 
         // copy r_local to r
-        let segment = cpu.get_segment::<E>(r.0)?;
+        let segment = cpu.get_writable_segment::<E>(r.0)?;
         segment.write_buffer(r.0, &r_local[0..len])?;
         Ok(())
     }
@@ -682,7 +702,7 @@ impl<'a, const N: usize> CommEcallHandler<'a, N> {
         }
 
         // copy r_local to r
-        let segment = cpu.get_segment::<E>(r.0)?;
+        let segment = cpu.get_writable_segment::<E>(r.0)?;
         segment.write_buffer(r.0, &r_local[0..len])?;
         Ok(())
     }
@@ -731,7 +751,7 @@ impl<'a, const N: usize> CommEcallHandler<'a, N> {
         }
 
         // copy r_local to r
-        let segment = cpu.get_segment::<E>(r.0)?;
+        let segment = cpu.get_writable_segment::<E>(r.0)?;
         segment.write_buffer(r.0, &r_local[0..len])?;
         Ok(())
     }
@@ -760,7 +780,7 @@ impl<'a, const N: usize> CommEcallHandler<'a, N> {
         }
 
         // copy context back to V-App memory
-        let segment = cpu.get_segment::<E>(ctx.0)?;
+        let segment = cpu.get_writable_segment::<E>(ctx.0)?;
         segment.write_buffer(ctx.0, &ctx_local[0..ctx_size])?;
 
         Ok(())
@@ -813,7 +833,7 @@ impl<'a, const N: usize> CommEcallHandler<'a, N> {
         }
 
         // copy context back to V-App memory
-        cpu.get_segment::<E>(ctx.0)?
+        cpu.get_writable_segment::<E>(ctx.0)?
             .write_buffer(ctx.0, &ctx_local[0..ctx_size])?;
 
         Ok(())
@@ -852,7 +872,7 @@ impl<'a, const N: usize> CommEcallHandler<'a, N> {
         // actual length of the digest
         let digest_len = LedgerHashContext::get_digest_len_from_id(hash_id)?;
         // copy digest to V-App memory
-        let segment = cpu.get_segment::<E>(digest.0)?;
+        let segment = cpu.get_writable_segment::<E>(digest.0)?;
         segment.write_buffer(digest.0, &digest_local[0..digest_len])?;
 
         Ok(())
@@ -904,9 +924,9 @@ impl<'a, const N: usize> CommEcallHandler<'a, N> {
         }
 
         // copy private_key and chain_code to V-App memory
-        cpu.get_segment::<E>(private_key.0)?
+        cpu.get_writable_segment::<E>(private_key.0)?
             .write_buffer(private_key.0, &private_key_local[..])?;
-        cpu.get_segment::<E>(chain_code.0)?
+        cpu.get_writable_segment::<E>(chain_code.0)?
             .write_buffer(chain_code.0, &chain_code_local)?;
 
         Ok(())
@@ -1006,8 +1026,8 @@ impl<'a, const N: usize> CommEcallHandler<'a, N> {
         let out_node = slip21::get_custom_slip21_node(&slices);
 
         // copy the result to the V-App memory
-        let segment = cpu.get_segment::<E>(out.0).unwrap();
-        segment.write_buffer(out.0, &out_node).unwrap();
+        let segment = cpu.get_writable_segment::<E>(out.0)?;
+        segment.write_buffer(out.0, &out_node)?;
 
         Ok(1)
     }
@@ -1051,7 +1071,7 @@ impl<'a, const N: usize> CommEcallHandler<'a, N> {
         }
 
         // copy r_local to r
-        let segment = cpu.get_segment::<E>(r.0)?;
+        let segment = cpu.get_writable_segment::<E>(r.0)?;
         segment.write_buffer(r.0, &r_local.W)?;
 
         Ok(1)
@@ -1099,7 +1119,7 @@ impl<'a, const N: usize> CommEcallHandler<'a, N> {
         }
 
         // copy r_local to r
-        let segment = cpu.get_segment::<E>(r.0)?;
+        let segment = cpu.get_writable_segment::<E>(r.0)?;
         segment.write_buffer(r.0, &r_local.W)?;
 
         Ok(1)
@@ -1124,7 +1144,7 @@ impl<'a, const N: usize> CommEcallHandler<'a, N> {
             return Err(CommEcallError::Overflow);
         }
 
-        let segment = cpu.get_segment::<E>(buffer.0)?;
+        let segment = cpu.get_writable_segment::<E>(buffer.0)?;
 
         // generate random bytes
         let mut random_bytes = vec![0u8; size];
@@ -1206,7 +1226,7 @@ impl<'a, const N: usize> CommEcallHandler<'a, N> {
         }
 
         // copy signature to V-App memory
-        cpu.get_segment::<E>(signature.0)?
+        cpu.get_writable_segment::<E>(signature.0)?
             .write_buffer(signature.0, &signature_local[0..signature_len as usize])?;
 
         Ok(signature_len)
@@ -1353,7 +1373,7 @@ impl<'a, const N: usize> CommEcallHandler<'a, N> {
         }
 
         // copy signature to V-App memory
-        cpu.get_segment::<E>(signature.0)?
+        cpu.get_writable_segment::<E>(signature.0)?
             .write_buffer(signature.0, &signature_local[0..signature_len as usize])?;
 
         Ok(signature_len)
@@ -1429,7 +1449,7 @@ impl<'a, const N: usize> CommEcallHandler<'a, N> {
     }
 
     fn handle_get_event<E: fmt::Debug>(
-        &self,
+        &mut self,
         cpu: &mut Cpu<OutsourcedMemory<'_, N>>,
         event_data_ptr: GuestPointer,
     ) -> Result<u32, CommEcallError> {
@@ -1443,7 +1463,7 @@ impl<'a, const N: usize> CommEcallHandler<'a, N> {
             };
 
             // copy event data to guest pointer
-            cpu.get_segment::<E>(event_data_ptr.0)?
+            cpu.get_writable_segment::<E>(event_data_ptr.0)?
                 .write_buffer(event_data_ptr.0, &event_data_raw)?;
 
             Ok(event_code as u32)
@@ -1453,6 +1473,23 @@ impl<'a, const N: usize> CommEcallHandler<'a, N> {
 
             wait_for_ticker(&mut comm);
 
+            self.ticks_since_abort_check += 1;
+            if self.ticks_since_abort_check >= ABORT_CHECK_INTERVAL_TICKS {
+                self.ticks_since_abort_check = 0;
+
+                let mut resp = comm.begin_response();
+                CheckAbortMessage::new().serialize_to_comm(&mut resp);
+                match interrupt_with_reply(resp)? {
+                    ClientReply::Continue(_) => {}
+                    ClientReply::Abort => return Ok(common::ux::EventCode::Abort as u32),
+                    ClientReply::Suspend => {
+                        return Err(CommEcallError::InvalidResponse(
+                            "The V-App can only be suspended while waiting for a message",
+                        ))
+                    }
+                }
+            }
+
             Ok(common::ux::EventCode::Ticker as u32)
         }
     }
@@ -1589,6 +1626,14 @@ impl<'a, const N: usize> EcallHandler for CommEcallHandler<'a, N> {
             get_ecall_name(ecall_code)
         );
 
+        // The instruction budget limits the execution between interactions with the outside world
+        if matches!(
+            ecall_code,
+            ECALL_XSEND | ECALL_XRECV | ECALL_GET_EVENT | ECALL_SHOW_PAGE | ECALL_SHOW_STEP
+        ) {
+            cpu.reset_instruction_budget();
+        }
+
         match ecall_code {
             ECALL_EXIT => return Err(CommEcallError::Exit(reg!(A0) as i32)),
             ECALL_FATAL => {
@@ -1602,7 +1647,10 @@ impl<'a, const N: usize> EcallHandler for CommEcallHandler<'a, N> {
             ECALL_XRECV => {
                 let ret = self
                     .handle_xrecv::<CommEcallError>(cpu, GPreg!(A0), reg!(A1) as usize)
-                    .map_err(|_| CommEcallError::GenericError("xrecv failed"))?;
+                    .map_err(|e| match e {
+                        CommEcallError::SuspendRequested => e,
+                        _ => CommEcallError::GenericError("xrecv failed"),
+                    })?;
                 reg!(A0) = ret as u32;
             }
             ECALL_PRINT => {
@@ -1808,7 +1856,7 @@ impl<'a, const N: usize> EcallHandler for CommEcallHandler<'a, N> {
 
             // Any other ecall is unhandled and will case the CPU to abort
             _ => {
-                return Err(CommEcallError::UnhandledEcall);
+                return Err(CommEcallError::UnhandledEcall(ecall_code));
             }
         }
 
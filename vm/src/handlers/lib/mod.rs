@@ -1,8 +1,8 @@
 use common::client_commands::Message;
 
 pub mod ecall;
-pub mod evict;
 pub mod outsourced_mem;
+pub mod snapshot;
 pub mod vapp;
 
 trait SerializeToComm<const N: usize> {
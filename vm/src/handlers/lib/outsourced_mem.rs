@@ -2,24 +2,27 @@ use core::cell::RefCell;
 
 use alloc::{boxed::Box, rc::Rc, vec, vec::Vec};
 use common::accumulator::{
-    HashOutput, Hasher, InclusionProofVerifier, MerkleAccumulator, ResettableHasher,
-    StreamingVectorAccumulator, UpdateProofVerifier,
+    top_nodes_count, HashOutput, Hasher, InclusionProofVerifier, MerkleTreeTop, ResettableHasher,
+    UpdateProofVerifier,
 };
-use common::vm::{Page, PagedMemory};
+use common::riscv::op::Op;
+use common::vm::{DecodedPage, Page, PagedMemory};
 use ledger_device_sdk::io;
 
 use common::client_commands::{
     CommitPageMessage, CommitPageProofContinuedMessage, CommitPageProofContinuedResponse,
     CommitPageProofResponse, GetPageMessage, GetPageProofContinuedMessage,
-    GetPageProofContinuedResponse, GetPageResponse, Message, SectionKind,
+    GetPageProofContinuedResponse, GetPageResponse, GetPagesContinuedMessage, GetPagesMessage,
+    GetPagesResponse, GetTreeNodesMessage, GetTreeNodesResponse, Message, PageContent,
 };
 use common::constants::PAGE_SIZE;
+use common::evict::PageEvictionStrategy;
+use common::ranges::PageRangeSet;
 
-use crate::aes::AesCtr;
+use crate::aes::{AesCtr, AesError};
 use crate::hash::Sha256Hasher;
 
 use super::SerializeToComm;
-use crate::handlers::lib::evict::PageEvictionStrategy;
 use crate::io::interrupt;
 
 #[derive(Clone, Debug)]
@@ -29,6 +32,14 @@ struct CachedPage {
     page_hash: HashOutput<32>, // Hash of the page data when loaded (before any changes)
     valid: bool,               // Indicates if the slot contains a valid page
     modified: bool,            // Indicates if the page has been modified since it was loaded
+    version: u32,              // Version of the page when loaded, for authenticated pages
+}
+
+// Decoded instructions of the page in one of the slots of the cache
+#[derive(Clone, Debug, Default)]
+struct DecodedCacheEntry {
+    slot: Option<usize>, // Slot of the cached page, if the entry is in use
+    decoded: DecodedPage,
 }
 
 impl Default for CachedPage {
@@ -41,6 +52,7 @@ impl Default for CachedPage {
             page_hash: [0; 32].into(),
             valid: false,
             modified: false,
+            version: 0,
         }
     }
 }
@@ -49,17 +61,37 @@ pub struct OutsourcedMemory<'c, const N: usize> {
     comm: Rc<RefCell<&'c mut io::Comm<N>>>,
     cached_pages: Vec<CachedPage>,
     n_pages: u32,
-    merkle_root: HashOutput<32>,
+    // The root and the top levels of the Merkle tree; the proofs only go up to the cached levels
+    tree_top: MerkleTreeTop<Sha256Hasher, 32>,
+    cached_tree_levels: u8, // Number of levels below the root to cache, once fetched
     aes_ctr: Rc<RefCell<AesCtr>>,
     hasher: Sha256Hasher,
     is_readonly: bool,
-    section_kind: SectionKind,
+    segment_index: u8,
     eviction_strategy: Box<dyn PageEvictionStrategy + 'c>,
+    max_batch_pages: usize, // Maximum number of consecutive pages fetched on a cache miss
+    // The pages from first_zero_page onwards are known to be all zeros in the client's copy, except
+    // the ones in committed_zero_pages, that were committed since the start of the V-App. The heap
+    // can be much larger than the pages that are actually used, so the committed pages are kept
+    // in a bounded number of ranges; the pages that the ranges cover without having been committed
+    // are just fetched from the client.
+    first_zero_page: u32,
+    committed_zero_pages: PageRangeSet,
+    zero_page_hash: HashOutput<32>,
     last_accessed_page: Option<(u32, usize)>,
+    decoded_pages: Vec<DecodedCacheEntry>,
+    decoded_page_of_slot: Vec<Option<usize>>, // For each slot, the index in decoded_pages, if any
+    next_decoded_victim: usize,
     #[cfg(feature = "metrics")]
     pub n_page_loads: usize,
     #[cfg(feature = "metrics")]
     pub n_page_commits: usize,
+    #[cfg(feature = "metrics")]
+    pub n_zero_page_loads: usize,
+    #[cfg(feature = "metrics")]
+    pub n_decoded_hits: usize,
+    #[cfg(feature = "metrics")]
+    pub n_decoded_misses: usize,
 }
 
 impl<'c, const N: usize> core::fmt::Debug for OutsourcedMemory<'c, N> {
@@ -68,38 +100,134 @@ impl<'c, const N: usize> core::fmt::Debug for OutsourcedMemory<'c, N> {
             .field("comm", &"...")
             .field("cached_pages", &self.cached_pages)
             .field("is_readonly", &self.is_readonly)
-            .field("section_kind", &self.section_kind)
+            .field("segment_index", &self.segment_index)
             .finish()
     }
 }
 
-/// Computes the hash of a page as a MerkleAccumulator element.
+// With the `authenticated_pages` feature, the pages are encrypted with AES-CCM rather than
+// AES-CTR, and the tag of each page authenticates its position and its version.
+const AUTHENTICATED_PAGES: bool = cfg!(feature = "authenticated_pages");
+
+// Maximum number of ranges of committed pages in the zero area of a segment. The heap and the
+// stack are mostly used contiguously, so that a few ranges are enough.
+const MAX_COMMITTED_ZERO_RANGES: usize = 8;
+
+/// Computes the hash of a page as a MerkleAccumulator element, from its serialized content, that
+/// must not be compressed.
 /// Note that this assumes that a 0 byte is prepended to the hash of the serialized content of the page.
 /// Therefore, it would be incorrect if an accumulator different than the MerkleAccumulator is used.
-fn get_page_hash(
-    hasher: &mut Sha256Hasher,
-    data: &[u8],
-    nonce: Option<&[u8; 12]>,
-) -> HashOutput<32> {
+fn get_page_hash(hasher: &mut Sha256Hasher, content: &PageContent) -> HashOutput<32> {
     hasher.reset();
     hasher.update(&[0x0u8]); // leaves in the Merkle tree have the 0x00 prefix
-    match nonce {
-        Some(nonce) => {
-            hasher.update(&[0x1u8]);
-            hasher.update(nonce)
+    content.serialize_with(|chunk| {
+        hasher.update(chunk);
+    });
+    hasher.finalize_inplace().into()
+}
+
+// Returns the hash of a plaintext page
+fn get_plaintext_page_hash(hasher: &mut Sha256Hasher, data: &[u8; PAGE_SIZE]) -> HashOutput<32> {
+    let content = PageContent::Raw {
+        is_encrypted: false,
+        nonce: [0; 12],
+        data,
+    };
+    get_page_hash(hasher, &content)
+}
+
+// The associated data of an authenticated page: its position in the memory, and its version
+fn get_page_aad(segment_index: u8, page_index: u32, version: u32) -> [u8; 9] {
+    let mut aad = [0u8; 9];
+    aad[0] = segment_index;
+    aad[1..5].copy_from_slice(&page_index.to_be_bytes());
+    aad[5..9].copy_from_slice(&version.to_be_bytes());
+    aad
+}
+
+// Decompresses a page sent compressed by the client. Compressed pages are never encrypted.
+fn decompress_page(data: &[u8]) -> Result<Page, common::vm::MemoryError> {
+    let mut page = Page {
+        data: [0u8; PAGE_SIZE],
+    };
+    common::compression::decompress(data, &mut page.data)
+        .map_err(|_| common::vm::MemoryError::GenericError("Invalid compressed page"))?;
+    Ok(page)
+}
+
+// Returns the plaintext content of a page sent by the client, its hash and its version. The tag
+// of an authenticated page is checked first, but the page is only trusted once the Merkle proof of
+// its hash is verified. Only the pages encrypted with the mode of the VM are accepted.
+fn open_page(
+    hasher: &mut Sha256Hasher,
+    aes_ctr: &AesCtr,
+    segment_index: u8,
+    page_index: u32,
+    content: &PageContent,
+) -> Result<(Page, HashOutput<32>, u32), common::vm::MemoryError> {
+    let (plaintext, version) = match *content {
+        PageContent::Raw {
+            is_encrypted: false,
+            data,
+            ..
+        } => {
+            return Ok((
+                Page { data: *data },
+                get_plaintext_page_hash(hasher, data),
+                0,
+            ))
+        }
+        PageContent::Compressed(data) => {
+            let page = decompress_page(data)?;
+            let page_hash = get_plaintext_page_hash(hasher, &page.data);
+            return Ok((page, page_hash, 0));
+        }
+        PageContent::Raw {
+            is_encrypted: true,
+            nonce,
+            data,
+        } if !AUTHENTICATED_PAGES => {
+            let plaintext = aes_ctr
+                .decrypt(&nonce, data)
+                .map_err(|_| common::vm::MemoryError::GenericError("AES decryption failed"))?;
+            (plaintext, 0)
+        }
+        PageContent::Authenticated {
+            nonce,
+            version,
+            tag,
+            data,
+        } if AUTHENTICATED_PAGES => {
+            let aad = get_page_aad(segment_index, page_index, version);
+            let plaintext = aes_ctr
+                .decrypt_authenticated(&nonce, data, &aad, &tag)
+                .map_err(|_| common::vm::MemoryError::GenericError("Page authentication failed"))?;
+            (plaintext, version)
+        }
+        _ => {
+            return Err(common::vm::MemoryError::GenericError(
+                "Unexpected page encryption mode",
+            ))
         }
-        None => hasher.update(&[0x0u8; 13]),
     };
-    hasher.update(data);
-    hasher.finalize_inplace().into()
+
+    // validate decrypted size matches expected page size
+    let data: [u8; PAGE_SIZE] = plaintext
+        .try_into()
+        .map_err(|_| common::vm::MemoryError::GenericError("Decrypted page size mismatch"))?;
+    Ok((Page { data }, get_page_hash(hasher, content), version))
 }
 
 impl<'c, const N: usize> OutsourcedMemory<'c, N> {
+    /// Creates a new `OutsourcedMemory` caching up to `max_pages_in_cache` pages.
+    /// For up to `max_decoded_pages` of the cached pages, the decoded instructions are also
+    /// cached; this should be 0 unless the memory contains code.
     pub fn new(
         comm: Rc<RefCell<&'c mut io::Comm<N>>>,
         max_pages_in_cache: usize,
+        max_decoded_pages: usize,
         is_readonly: bool,
-        section_kind: SectionKind,
+        segment_index: u8,
         n_pages: u32,
         merkle_root: HashOutput<32>,
         aes_ctr: Rc<RefCell<AesCtr>>,
@@ -109,38 +237,166 @@ impl<'c, const N: usize> OutsourcedMemory<'c, N> {
             comm,
             cached_pages: vec![CachedPage::default(); max_pages_in_cache],
             n_pages,
-            merkle_root,
+            tree_top: MerkleTreeTop::new(merkle_root, n_pages as usize),
+            cached_tree_levels: 0,
             aes_ctr,
             is_readonly,
-            section_kind,
+            segment_index,
             eviction_strategy,
+            max_batch_pages: 1,
+            first_zero_page: n_pages,
+            committed_zero_pages: PageRangeSet::new(MAX_COMMITTED_ZERO_RANGES),
+            zero_page_hash: [0; 32].into(),
             hasher: Sha256Hasher::new(),
             last_accessed_page: None,
+            decoded_pages: vec![DecodedCacheEntry::default(); max_decoded_pages],
+            decoded_page_of_slot: vec![None; max_pages_in_cache],
+            next_decoded_victim: 0,
             #[cfg(feature = "metrics")]
             n_page_loads: 0,
             #[cfg(feature = "metrics")]
             n_page_commits: 0,
+            #[cfg(feature = "metrics")]
+            n_zero_page_loads: 0,
+            #[cfg(feature = "metrics")]
+            n_decoded_hits: 0,
+            #[cfg(feature = "metrics")]
+            n_decoded_misses: 0,
+        }
+    }
+
+    /// Sets the maximum number of consecutive pages that are fetched at once on a cache miss, with
+    /// a single Merkle multiproof. The pages following the missing one are only fetched if they
+    /// are not already in the cache. The default is 1, that is, only the missing page is fetched.
+    pub fn with_batch_size(mut self, max_batch_pages: usize) -> Self {
+        self.max_batch_pages = max_batch_pages.clamp(1, u8::MAX as usize);
+        self
+    }
+
+    /// Marks the pages from `first_zero_page` to the end of the memory as known to be all zeros
+    /// in the client's copy. Until they are first committed, they are served from the VM without
+    /// asking the client.
+    pub fn with_zero_pages(mut self, first_zero_page: u32) -> Self {
+        self.first_zero_page = first_zero_page.min(self.n_pages);
+        self.zero_page_hash = get_plaintext_page_hash(&mut self.hasher, &[0u8; PAGE_SIZE]);
+        self
+    }
+
+    /// Caches as many levels of the Merkle tree below the root as fit in `max_size` bytes, so that
+    /// the proofs sent by the client stop at the cached levels. The nodes are fetched from the
+    /// client the first time that a proof is needed. The default is to only keep the root.
+    pub fn with_tree_cache(mut self, max_size: usize) -> Self {
+        let max_nodes = max_size / core::mem::size_of::<HashOutput<32>>();
+        let size = self.n_pages as usize;
+        let mut levels = 0u8;
+        while levels < u8::MAX
+            && top_nodes_count(size, levels + 1) <= max_nodes
+            && top_nodes_count(size, levels + 1) > top_nodes_count(size, levels)
+        {
+            levels += 1;
         }
+        self.cached_tree_levels = levels;
+        self
     }
 
     // Return the number of bytes used by a each additional cached page
     pub const fn size_per_page() -> usize {
-        core::mem::size_of::<CachedPage>()
+        core::mem::size_of::<CachedPage>() + core::mem::size_of::<Option<usize>>()
+    }
+
+    // Return the number of bytes used by each page of decoded instructions
+    pub const fn size_per_decoded_page() -> usize {
+        core::mem::size_of::<DecodedCacheEntry>()
+    }
+
+    /// Returns the current Merkle root of the content of the memory.
+    pub fn merkle_root(&self) -> &HashOutput<32> {
+        self.tree_top.root()
+    }
+
+    /// Commits all the modified pages to the host, so that the Merkle root reflects the whole
+    /// content of the memory. The cache is emptied.
+    pub fn commit_all(&mut self) -> Result<(), common::vm::MemoryError> {
+        for slot in 0..self.cached_pages.len() {
+            if !self.cached_pages[slot].valid {
+                continue;
+            }
+            if !self.is_readonly && self.cached_pages[slot].modified {
+                self.commit_page_at(slot)?;
+            }
+            let page_index = self.cached_pages[slot].idx;
+            self.cached_pages[slot].valid = false;
+            self.invalidate_decoded(slot);
+            self.eviction_strategy.on_invalidate(slot, page_index);
+        }
+        self.last_accessed_page = None;
+        Ok(())
+    }
+
+    // Discards the decoded instructions of the page in the given slot, if any
+    fn invalidate_decoded(&mut self, slot: usize) {
+        if let Some(entry_index) = self.decoded_page_of_slot[slot].take() {
+            self.decoded_pages[entry_index].slot = None;
+        }
+    }
+
+    // Fetches the top levels of the Merkle tree from the client, if not done yet. They are only
+    // accepted if they are consistent with the current root.
+    fn fetch_tree_top(&mut self) -> Result<(), common::vm::MemoryError> {
+        if self.tree_top.levels() == self.cached_tree_levels {
+            return Ok(());
+        }
+        let n_nodes = top_nodes_count(self.n_pages as usize, self.cached_tree_levels);
+        let mut nodes: Vec<HashOutput<32>> = Vec::with_capacity(n_nodes);
+
+        let mut comm = self.comm.borrow_mut();
+        while nodes.len() < n_nodes {
+            let count = (n_nodes - nodes.len()).min(GetTreeNodesResponse::max_nodes());
+            let mut resp = comm.begin_response();
+            GetTreeNodesMessage::new(self.segment_index, nodes.len() as u32, count as u8)
+                .serialize_to_comm(&mut resp);
+
+            let command = interrupt(resp)?;
+
+            let response = GetTreeNodesResponse::deserialize(command.get_data())
+                .map_err(|_| common::vm::MemoryError::GenericError("Invalid tree nodes data"))?;
+            if response.t as usize != count {
+                return Err(common::vm::MemoryError::GenericError(
+                    "Wrong number of tree nodes",
+                ));
+            }
+            nodes.extend(response.nodes.iter().map(|node| HashOutput(*node)));
+        }
+
+        if !self.tree_top.set_nodes(self.cached_tree_levels, nodes) {
+            return Err(common::vm::MemoryError::GenericError(
+                "Tree nodes do not match the Merkle root",
+            ));
+        }
+        Ok(())
     }
 
     fn commit_page_at(&mut self, index: usize) -> Result<(), common::vm::MemoryError> {
+        self.fetch_tree_top()?;
+
         #[cfg(feature = "trace_pages")]
         crate::trace!(
             "page_commit",
             "light_green",
-            "section: {:?}, page_index: {}",
-            self.section_kind,
+            "segment: {}, page_index: {}",
+            self.segment_index,
             index
         );
 
         let cached_page = &self.cached_pages[index];
         assert!(cached_page.valid, "Trying to commit an invalid page");
 
+        #[cfg(feature = "trace_binary")]
+        crate::trace::record(&common::trace::TraceEvent::PageCommit {
+            segment: self.segment_index,
+            page_index: cached_page.idx,
+        });
+
         let page_hash_old = &cached_page.page_hash;
 
         #[cfg(feature = "metrics")]
@@ -150,10 +406,34 @@ impl<'c, const N: usize> OutsourcedMemory<'c, N> {
 
         let mut aes_ctr = self.aes_ctr.borrow_mut();
 
-        let (nonce, payload) = aes_ctr
-            .encrypt(&cached_page.page.data)
-            .map_err(|_| common::vm::MemoryError::GenericError("AES encryption failed"))?;
-        let new_page_hash = get_page_hash(&mut self.hasher, &payload, Some(&nonce));
+        // Authenticated pages get a new version, so that the previous ones are not valid at its
+        // position anymore
+        let new_version = if AUTHENTICATED_PAGES {
+            cached_page
+                .version
+                .checked_add(1)
+                .ok_or(common::vm::MemoryError::GenericError(
+                    "Page version exhausted",
+                ))?
+        } else {
+            cached_page.version
+        };
+        let encrypted = if AUTHENTICATED_PAGES {
+            let aad = get_page_aad(self.segment_index, cached_page.idx, new_version);
+            aes_ctr
+                .encrypt_authenticated(&cached_page.page.data, &aad)
+                .map(|(nonce, payload, tag)| (nonce, payload, Some(tag)))
+        } else {
+            aes_ctr
+                .encrypt(&cached_page.page.data)
+                .map(|(nonce, payload)| (nonce, payload, None))
+        };
+        let (nonce, payload, tag) = encrypted.map_err(|e| match e {
+            AesError::NonceExhausted => {
+                common::vm::MemoryError::GenericError("AES nonces exhausted")
+            }
+            _ => common::vm::MemoryError::GenericError("AES encryption failed"),
+        })?;
 
         assert!(payload.len() == PAGE_SIZE);
 
@@ -162,14 +442,28 @@ impl<'c, const N: usize> OutsourcedMemory<'c, N> {
             .try_into()
             .expect("The payload is the correct size");
 
+        let content = match tag {
+            Some(tag) => PageContent::Authenticated {
+                nonce,
+                version: new_version,
+                tag,
+                data: payload_array,
+            },
+            None => PageContent::Raw {
+                is_encrypted: true,
+                nonce,
+                data: payload_array,
+            },
+        };
+        let new_page_hash = get_page_hash(&mut self.hasher, &content);
+
         let mut comm = self.comm.borrow_mut();
         let mut resp = comm.begin_response();
         CommitPageMessage::new(
-            self.section_kind,
+            self.segment_index,
             cached_page.idx,
-            true,
-            nonce,
-            payload_array,
+            self.tree_top.levels(),
+            content,
         )
         .serialize_to_comm(&mut resp);
 
@@ -182,11 +476,6 @@ impl<'c, const N: usize> OutsourcedMemory<'c, N> {
             .map_err(|_| common::vm::MemoryError::GenericError("Invalid proof data"))?;
 
         let n = proof_response.n; // Total number of elements in the proof
-        if n == 0 {
-            return Err(common::vm::MemoryError::GenericError(
-                "Proof must contain at least one element",
-            ));
-        }
         if proof_response.t as usize != proof_response.proof.len() {
             return Err(common::vm::MemoryError::GenericError(
                 "Proof fragment size does not match the expected number of elements",
@@ -195,14 +484,11 @@ impl<'c, const N: usize> OutsourcedMemory<'c, N> {
 
         let new_root = HashOutput::<32>::as_hash_output(proof_response.new_root).clone();
 
-        // Verify the Merkle update proof using streaming verification
-        let mut verifier = MerkleAccumulator::<Sha256Hasher, Vec<u8>, 32>::begin_update_proof(
-            &self.merkle_root,
-            &new_root,
+        // Verify the Merkle update proof using streaming verification, up to the cached levels
+        let mut verifier = self.tree_top.begin_update_proof(
             page_hash_old,
             &new_page_hash,
             cached_page.idx as usize,
-            self.n_pages as usize,
         );
 
         for el in proof_response.proof.iter() {
@@ -233,22 +519,32 @@ impl<'c, const N: usize> OutsourcedMemory<'c, N> {
             n_processed_elements += continued_response.t as usize;
         }
 
-        if !verifier.verified() {
+        // Update the cached nodes and the root; the page is no longer zero in the client's copy
+        if !self.tree_top.apply_update(&verifier) {
             return Err(common::vm::MemoryError::GenericError(
                 "Merkle update verification failed",
             ));
         }
-
-        // Update the root to the new root
-        self.merkle_root = new_root;
+        if self.tree_top.root() != &new_root {
+            return Err(common::vm::MemoryError::GenericError(
+                "The new Merkle root does not match",
+            ));
+        }
+        let page_index = cached_page.idx;
+        if page_index >= self.first_zero_page {
+            self.committed_zero_pages.insert(page_index);
+        }
 
         Ok(())
     }
 
+    // Loads a page, and returns its content, its hash and its version
     fn load_page(
         &mut self,
         page_index: u32,
-    ) -> Result<(Page, HashOutput<32>), common::vm::MemoryError> {
+    ) -> Result<(Page, HashOutput<32>, u32), common::vm::MemoryError> {
+        self.fetch_tree_top()?;
+
         #[cfg(feature = "metrics")]
         {
             self.n_page_loads += 1;
@@ -258,29 +554,35 @@ impl<'c, const N: usize> OutsourcedMemory<'c, N> {
         crate::trace!(
             "page_load",
             "light_green",
-            "section: {:?}, page_index: {}",
-            self.section_kind,
+            "segment: {}, page_index: {}",
+            self.segment_index,
             page_index
         );
 
+        #[cfg(feature = "trace_binary")]
+        crate::trace::record(&common::trace::TraceEvent::PageLoad {
+            segment: self.segment_index,
+            page_index,
+        });
+
         let mut comm = self.comm.borrow_mut();
         let mut resp = comm.begin_response();
-        GetPageMessage::new(self.section_kind, page_index).serialize_to_comm(&mut resp);
+        GetPageMessage::new(self.segment_index, page_index, self.tree_top.levels())
+            .serialize_to_comm(&mut resp);
 
         let command = interrupt(resp)?;
 
         let page_response = GetPageResponse::deserialize(command.get_data())
             .map_err(|_| common::vm::MemoryError::GenericError("Invalid page data"))?;
 
-        let page_hash = if page_response.is_encrypted {
-            get_page_hash(
-                &mut self.hasher,
-                page_response.page_data,
-                Some(&page_response.nonce),
-            )
-        } else {
-            get_page_hash(&mut self.hasher, page_response.page_data, None)
-        };
+        // The page is not trusted until the proof is verified
+        let (page, page_hash, version) = open_page(
+            &mut self.hasher,
+            &self.aes_ctr.borrow(),
+            self.segment_index,
+            page_index,
+            &page_response.page,
+        )?;
 
         let n = page_response.n; // Total number of elements in the proof
         if page_response.t as usize != page_response.proof.len() {
@@ -289,23 +591,16 @@ impl<'c, const N: usize> OutsourcedMemory<'c, N> {
             ));
         }
 
-        // Verify the Merkle inclusion proof using streaming verification
-        let mut verifier = MerkleAccumulator::<Sha256Hasher, Vec<u8>, 32>::begin_inclusion_proof(
-            &self.merkle_root,
-            &page_hash,
-            page_index as usize,
-            self.n_pages as usize,
-        );
+        // Verify the Merkle inclusion proof using streaming verification, up to the cached levels
+        let mut verifier = self
+            .tree_top
+            .begin_inclusion_proof(&page_hash, page_index as usize);
 
         for el in page_response.proof.iter() {
             verifier.feed(&mut self.hasher, HashOutput::<32>::as_hash_output(el));
         }
 
-        let nonce = page_response.nonce.clone();
-        let is_page_encrypted = page_response.is_encrypted;
-
         let mut n_processed_elements = page_response.t as usize;
-        let data = *page_response.page_data;
 
         // If we need more elements, request them
         while n_processed_elements < n as usize {
@@ -337,25 +632,137 @@ impl<'c, const N: usize> OutsourcedMemory<'c, N> {
             ));
         }
 
-        if is_page_encrypted {
-            // Decrypt the page data
-            let aes_ctr = self.aes_ctr.borrow();
-            let decrypted_data = aes_ctr
-                .decrypt(&nonce, &data)
-                .map_err(|_| common::vm::MemoryError::GenericError("AES decryption failed"))?;
+        Ok((page, page_hash, version))
+    }
+
+    // Loads the consecutive pages starting at `page_index` in the given slots, verifying them with
+    // a single Merkle multiproof. The slots are only marked as valid once the proof is verified.
+    fn load_pages(
+        &mut self,
+        page_index: u32,
+        slots: &[usize],
+    ) -> Result<(), common::vm::MemoryError> {
+        self.fetch_tree_top()?;
+        let n_pages = slots.len();
+
+        #[cfg(feature = "metrics")]
+        {
+            self.n_page_loads += n_pages;
+        }
 
-            // validate decrypted size matches expected page size
-            if decrypted_data.len() != PAGE_SIZE {
-                return Err(common::vm::MemoryError::GenericError("Decrypted page size mismatch"));
+        #[cfg(feature = "trace_pages")]
+        crate::trace!(
+            "page_load",
+            "light_green",
+            "segment: {}, page_index: {}, n_pages: {}",
+            self.segment_index,
+            page_index,
+            n_pages
+        );
+
+        #[cfg(feature = "trace_binary")]
+        for i in 0..n_pages as u32 {
+            crate::trace::record(&common::trace::TraceEvent::PageLoad {
+                segment: self.segment_index,
+                page_index: page_index + i,
+            });
+        }
+
+        let mut page_hashes: Vec<HashOutput<32>> = Vec::with_capacity(n_pages);
+        let mut verifier = None;
+        let mut n_processed_elements = 0;
+
+        let mut comm = self.comm.borrow_mut();
+        loop {
+            let mut resp = comm.begin_response();
+            if page_hashes.is_empty() && verifier.is_none() {
+                GetPagesMessage::new(
+                    self.segment_index,
+                    page_index,
+                    n_pages as u8,
+                    self.tree_top.levels(),
+                )
+                .serialize_to_comm(&mut resp);
+            } else {
+                GetPagesContinuedMessage::new().serialize_to_comm(&mut resp);
             }
 
-            // safe conversion since we just validated the length
-            let page_data: [u8; PAGE_SIZE] = decrypted_data.try_into().unwrap();
+            let command = interrupt(resp)?;
 
-            Ok((Page { data: page_data }, page_hash))
-        } else {
-            Ok((Page { data }, page_hash))
+            let response = GetPagesResponse::deserialize(command.get_data())
+                .map_err(|_| common::vm::MemoryError::GenericError("Invalid pages data"))?;
+
+            if response.n_pages == 0 && response.t == 0 {
+                return Err(common::vm::MemoryError::GenericError(
+                    "Empty pages response",
+                ));
+            }
+            if page_hashes.len() + response.n_pages as usize > n_pages {
+                return Err(common::vm::MemoryError::GenericError(
+                    "More pages than requested",
+                ));
+            }
+
+            for content in response.pages() {
+                let idx = page_index + page_hashes.len() as u32;
+                let (page, page_hash, version) = open_page(
+                    &mut self.hasher,
+                    &self.aes_ctr.borrow(),
+                    self.segment_index,
+                    idx,
+                    &content,
+                )?;
+                let slot = slots[page_hashes.len()];
+                // The content is not trusted until the proof is verified, but the slot is not
+                // valid until then
+                self.cached_pages[slot] = CachedPage {
+                    idx,
+                    page,
+                    page_hash: page_hash.clone(),
+                    valid: false,
+                    modified: false,
+                    version,
+                };
+                page_hashes.push(page_hash);
+            }
+
+            // The proof can only be verified once the hashes of all the pages are known
+            if verifier.is_none() && page_hashes.len() == n_pages {
+                verifier = Some(
+                    self.tree_top
+                        .begin_range_proof(&page_hashes, page_index as usize),
+                );
+            }
+            match verifier.as_mut() {
+                Some(verifier) => {
+                    for el in response.proof.iter() {
+                        verifier.feed(&mut self.hasher, HashOutput::<32>::as_hash_output(el));
+                    }
+                }
+                None if response.t > 0 => {
+                    return Err(common::vm::MemoryError::GenericError(
+                        "Proof elements received before all the pages",
+                    ));
+                }
+                None => {}
+            }
+            n_processed_elements += response.t as usize;
+
+            if page_hashes.len() == n_pages && n_processed_elements >= response.n as usize {
+                break;
+            }
+        }
+
+        if !verifier.is_some_and(|verifier| verifier.verified()) {
+            return Err(common::vm::MemoryError::GenericError(
+                "Merkle multiproof verification failed",
+            ));
         }
+
+        for &slot in slots {
+            self.cached_pages[slot].valid = true;
+        }
+        Ok(())
     }
 }
 
@@ -379,6 +786,89 @@ impl<'a> core::ops::DerefMut for CachedPageRef<'a> {
 }
 
 impl<'c, const N: usize> OutsourcedMemory<'c, N> {
+    #[inline]
+    /// Returns the slot containing the page with the given index, if it is in the cache.
+    /// Informs the eviction strategy of the access and updates the last accessed page.
+    fn find_cached_slot(&mut self, page_index: u32) -> Option<usize> {
+        // Check if this is the same page as the last accessed one; if so, return immediately.
+        // For the purpose of cache strategies, we do not want to count consecutive accesses
+        // as separate ones. Therefore, here we return without informing the eviction strategy.
+        if let Some((last_page_index, last_slot)) = self.last_accessed_page {
+            if page_index == last_page_index {
+                return Some(last_slot);
+            }
+        }
+
+        // Search for the page in cache
+        for i in 0..self.cached_pages.len() {
+            if self.cached_pages[i].valid && self.cached_pages[i].idx == page_index {
+                self.eviction_strategy.on_access(i, page_index);
+                self.last_accessed_page = Some((page_index, i));
+                return Some(i);
+            }
+        }
+        None
+    }
+
+    // Returns true if the page with the given index is in the cache, without informing the
+    // eviction strategy
+    fn is_cached(&self, page_index: u32) -> bool {
+        self.cached_pages
+            .iter()
+            .any(|cached_page| cached_page.valid && cached_page.idx == page_index)
+    }
+
+    // Returns true if the page with the given index is known to be all zeros in the client's copy
+    fn is_zero_page(&self, page_index: u32) -> bool {
+        page_index >= self.first_zero_page
+            && page_index < self.n_pages
+            && !self.committed_zero_pages.contains(page_index)
+    }
+
+    /// Returns a slot for a page that is about to be loaded, other than the `reserved` ones.
+    /// If there is no free slot, a page is evicted from the cache, and committed if needed.
+    fn reserve_slot(&mut self, reserved: &[usize]) -> Result<usize, common::vm::MemoryError> {
+        // Find a free slot
+        for i in 0..self.cached_pages.len() {
+            if !self.cached_pages[i].valid && !reserved.contains(&i) {
+                return Ok(i);
+            }
+        }
+
+        // If no free slot, evict a page. The reserved slots are invalidated, and the eviction
+        // strategies never choose them; two pages of a batch in the same slot would be wrong.
+        let evict_index = self.eviction_strategy.choose_victim();
+        if reserved.contains(&evict_index) {
+            return Err(common::vm::MemoryError::GenericError(
+                "Reserved slot chosen for eviction",
+            ));
+        }
+
+        // Commit the page if this memory is not readonly and the page was modified
+        if !self.is_readonly && self.cached_pages[evict_index].modified {
+            self.commit_page_at(evict_index)?;
+        }
+
+        // Invalidate the evicted page, and its decoded instructions
+        let evicted_page_index = self.cached_pages[evict_index].idx;
+        self.cached_pages[evict_index].valid = false;
+        self.invalidate_decoded(evict_index);
+        self.eviction_strategy
+            .on_invalidate(evict_index, evicted_page_index);
+
+        #[cfg(feature = "trace_pages")]
+        crate::trace!(
+            "page_evict",
+            "light_green",
+            "segment: {}, page_index: {}, slot: {}",
+            self.segment_index,
+            evicted_page_index,
+            evict_index
+        );
+
+        Ok(evict_index)
+    }
+
     #[inline]
     /// Returns a mutable reference to the page in the cache, updating the last accessed page.
     fn get_cached_page_ref(&mut self, page_index: u32, slot: usize) -> CachedPageRef<'_> {
@@ -396,74 +886,130 @@ impl<'c, const N: usize> PagedMemory for OutsourcedMemory<'c, N> {
         Self: 'a;
 
     fn get_page(&mut self, page_index: u32) -> Result<Self::PageRef<'_>, common::vm::MemoryError> {
-        // Check if this is the same page as the last accessed one; if so, return immediately.
-        // For the purpose of cache strategies, we do not want to count consecutive accesses
-        // as separate ones. Therefore, here we return without informing the eviction strategy.
-        if let Some((last_page_index, last_slot)) = self.last_accessed_page {
-            if page_index == last_page_index {
-                return Ok(self.get_cached_page_ref(page_index, last_slot));
-            }
+        if let Some(slot) = self.find_cached_slot(page_index) {
+            return Ok(self.get_cached_page_ref(page_index, slot));
         }
 
-        // Search for the page in cache
-        for i in 0..self.cached_pages.len() {
-            if self.cached_pages[i].valid && self.cached_pages[i].idx == page_index {
-                self.eviction_strategy.on_access(i, page_index);
+        self.eviction_strategy.on_miss(page_index);
 
-                return Ok(self.get_cached_page_ref(page_index, i));
+        // Page not found in cache. If it was never committed, it's still all zeros, and there is
+        // no need to ask the client.
+        if self.is_zero_page(page_index) {
+            #[cfg(feature = "metrics")]
+            {
+                self.n_zero_page_loads += 1;
             }
+
+            let slot = self.reserve_slot(&[])?;
+            self.cached_pages[slot] = CachedPage {
+                idx: page_index,
+                page: Page {
+                    data: [0; PAGE_SIZE],
+                },
+                page_hash: self.zero_page_hash.clone(),
+                valid: true,
+                modified: false,
+                version: 0,
+            };
+            self.eviction_strategy.on_load(slot, page_index);
+            return Ok(self.get_cached_page_ref(page_index, slot));
         }
 
-        // Page not found in cache
-        // Find a free slot
-        let mut slot: Option<usize> = None;
-        for i in 0..self.cached_pages.len() {
-            if !self.cached_pages[i].valid {
-                slot = Some(i);
-                break;
-            }
+        // The following pages are fetched together with it, as long as they are not in the cache
+        // already, nor known to be zero
+        let max_batch_pages = self.max_batch_pages.min(self.cached_pages.len());
+        let mut n_pages = 1;
+        while n_pages < max_batch_pages
+            && page_index + (n_pages as u32) < self.n_pages
+            && !self.is_cached(page_index + n_pages as u32)
+            && !self.is_zero_page(page_index + n_pages as u32)
+        {
+            n_pages += 1;
+        }
+
+        let mut slots: Vec<usize> = Vec::with_capacity(n_pages);
+        for _ in 0..n_pages {
+            let slot = self.reserve_slot(&slots)?;
+            slots.push(slot);
+        }
+
+        if n_pages == 1 {
+            // Load the page into the slot
+            let (page_data, page_hash, version) = self.load_page(page_index)?;
+            self.cached_pages[slots[0]] = CachedPage {
+                idx: page_index,
+                page: page_data,
+                page_hash,
+                valid: true,
+                modified: false,
+                version,
+            };
+        } else {
+            self.load_pages(page_index, &slots)?;
+        }
+
+        // The requested page is loaded last, so that it's the most recent one for the eviction
+        // strategy
+        for (i, &slot) in slots.iter().enumerate().rev() {
+            self.eviction_strategy.on_load(slot, page_index + i as u32);
+        }
+
+        Ok(self.get_cached_page_ref(page_index, slots[0]))
+    }
+
+    #[inline]
+    fn get_decoded_instruction(&mut self, page_index: u32, offset: u32) -> Option<(Op, u32)> {
+        if self.decoded_pages.is_empty() {
+            return None;
         }
 
-        // If no free slot, evict a page
-        if slot.is_none() {
-            let evict_index = self.eviction_strategy.choose_victim();
+        let result = self
+            .find_cached_slot(page_index)
+            .and_then(|slot| self.decoded_page_of_slot[slot])
+            .and_then(|entry_index| self.decoded_pages[entry_index].decoded.get(offset));
 
-            // Commit the page if this memory is not readonly and the page was modified
-            if !self.is_readonly && self.cached_pages[evict_index].modified {
-                self.commit_page_at(evict_index)?;
+        #[cfg(feature = "metrics")]
+        {
+            if result.is_some() {
+                self.n_decoded_hits += 1;
+            } else {
+                self.n_decoded_misses += 1;
             }
+        }
 
-            // Invalidate the evicted page
-            let evicted_page_index = self.cached_pages[evict_index].idx;
-            self.cached_pages[evict_index].valid = false;
-            self.eviction_strategy
-                .on_invalidate(evict_index, evicted_page_index);
-            slot = Some(evict_index);
-
-            #[cfg(feature = "trace_pages")]
-            crate::trace!(
-                "page_evict",
-                "light_green",
-                "section: {:?}, page_index: {}, slot: {}",
-                self.section_kind,
-                evicted_page_index,
-                evict_index
-            );
-        }
-
-        let slot = slot.unwrap();
-
-        // Load the page into the slot
-        let (page_data, page_hash) = self.load_page(page_index)?;
-        self.cached_pages[slot] = CachedPage {
-            idx: page_index,
-            page: page_data,
-            page_hash,
-            valid: true,
-            modified: false,
+        result
+    }
+
+    #[inline]
+    fn store_decoded_instruction(&mut self, page_index: u32, offset: u32, op: Op, size: u32) {
+        if self.decoded_pages.is_empty() {
+            return;
+        }
+
+        // The page was just fetched, so it should be in the cache
+        let Some(slot) = self.find_cached_slot(page_index) else {
+            return;
+        };
+
+        let entry_index = match self.decoded_page_of_slot[slot] {
+            Some(entry_index) => entry_index,
+            None => {
+                // Reuse the entries in round-robin order
+                let entry_index = self.next_decoded_victim;
+                self.next_decoded_victim = (entry_index + 1) % self.decoded_pages.len();
+                if let Some(old_slot) = self.decoded_pages[entry_index].slot {
+                    self.decoded_page_of_slot[old_slot] = None;
+                }
+                let entry = &mut self.decoded_pages[entry_index];
+                entry.slot = Some(slot);
+                entry.decoded.clear();
+                self.decoded_page_of_slot[slot] = Some(entry_index);
+                entry_index
+            }
         };
-        self.eviction_strategy.on_load(slot, page_index);
 
-        Ok(self.get_cached_page_ref(page_index, slot))
+        self.decoded_pages[entry_index]
+            .decoded
+            .set(offset, op, size);
     }
 }
@@ -0,0 +1,196 @@
+use alloc::vec::Vec;
+use ledger_device_sdk::hmac::{self, HMACInit};
+use ledger_device_sdk::nvm::*;
+use ledger_device_sdk::NVMData;
+use subtle::ConstantTimeEq;
+use zeroize::Zeroizing;
+
+use super::vapp::VappRegistrationKey;
+use crate::aes::{AesCtr, AesKey};
+
+// Size of the serialized state: pc, x1 to x31, and the AES key and nonce used to encrypt the pages.
+const STATE_SIZE: usize = 4 + 31 * 4 + 16 + 12;
+const MAC_SIZE: usize = 16;
+
+/// Size of a snapshot: the counter, the encrypted state, and the truncated MAC. It fits in the
+/// response to a single APDU.
+pub const SNAPSHOT_SIZE: usize = 4 + STATE_SIZE + MAC_SIZE;
+
+const ENCRYPTION_KEY_LABEL: &[u8] = b"VANADIUM_SNAPSHOT_ENCRYPTION_KEY";
+const MAC_KEY_LABEL: &[u8] = b"VANADIUM_SNAPSHOT_MAC_KEY";
+
+/// The state of a suspended V-App that is not already stored by the client.
+///
+/// The Merkle roots of the writable segments are not part of the state: the client provides them
+/// when resuming, and they are authenticated by the MAC of the snapshot. The read-only segments
+/// never change, so their Merkle roots are the ones in the manifest.
+pub struct VAppState {
+    pub pc: u32,
+    pub regs: [u32; 32],
+    pub aes_key: [u8; 16],
+    pub aes_nonce: [u8; 12],
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SnapshotError {
+    InvalidSize,
+    InvalidMac,
+    /// The snapshot is authentic, but it's not the latest one
+    Rollback,
+    CounterExhausted,
+    CryptoError,
+}
+
+impl VAppState {
+    fn serialize(&self) -> Zeroizing<[u8; STATE_SIZE]> {
+        let mut out = Zeroizing::new([0u8; STATE_SIZE]);
+        out[0..4].copy_from_slice(&self.pc.to_be_bytes());
+        for (i, reg) in self.regs[1..].iter().enumerate() {
+            out[4 + 4 * i..8 + 4 * i].copy_from_slice(&reg.to_be_bytes());
+        }
+        let offset = 4 + 31 * 4;
+        out[offset..offset + 16].copy_from_slice(&self.aes_key);
+        out[offset + 16..offset + 28].copy_from_slice(&self.aes_nonce);
+        out
+    }
+
+    fn deserialize(data: &[u8; STATE_SIZE]) -> Self {
+        let u32_at = |i: usize| u32::from_be_bytes(data[i..i + 4].try_into().unwrap());
+        let mut regs = [0u32; 32];
+        for (i, reg) in regs[1..].iter_mut().enumerate() {
+            *reg = u32_at(4 + 4 * i);
+        }
+        let offset = 4 + 31 * 4;
+        VAppState {
+            pc: u32_at(0),
+            regs,
+            aes_key: data[offset..offset + 16].try_into().unwrap(),
+            aes_nonce: data[offset + 16..offset + 28].try_into().unwrap(),
+        }
+    }
+}
+
+// Anti-rollback counter. It is incremented every time a snapshot is created or resumed, and only
+// the snapshot with the current value of the counter can be resumed. Therefore, a snapshot can be
+// resumed at most once, and only if no other V-App was suspended after it.
+
+#[link_section = ".nvm_data"]
+static mut SNAPSHOT_COUNTER: NVMData<AtomicStorage<[u8; 4]>> =
+    NVMData::new(AtomicStorage::new(&[0u8; 4]));
+
+fn read_counter() -> u32 {
+    let counter = &raw const SNAPSHOT_COUNTER;
+    unsafe { u32::from_be_bytes(*(*counter).get_ref().get_ref()) }
+}
+
+fn increment_counter() -> Result<u32, SnapshotError> {
+    let new_value = read_counter()
+        .checked_add(1)
+        .ok_or(SnapshotError::CounterExhausted)?;
+    let counter = &raw mut SNAPSHOT_COUNTER;
+    unsafe {
+        (*counter).get_mut().update(&new_value.to_be_bytes());
+    }
+    Ok(new_value)
+}
+
+// Derives a key from the device-held V-App registration key
+fn derive_key(label: &[u8]) -> Zeroizing<[u8; 32]> {
+    let mut registration_key = VappRegistrationKey;
+    let mut hmac = hmac::sha2::Sha2_256::new(registration_key.get_key());
+    hmac.update(label).expect("Should never fail");
+    let mut key = Zeroizing::new([0u8; 32]);
+    hmac.finalize(key.as_mut()).expect("Should never fail");
+    key
+}
+
+// The snapshots are encrypted with a fixed key, therefore the nonce is derived from the counter,
+// that is different for each snapshot.
+fn get_cipher(counter: u32) -> Result<AesCtr, SnapshotError> {
+    let encryption_key = derive_key(ENCRYPTION_KEY_LABEL);
+    let aes_key = AesKey::from_bytes(encryption_key[..16].try_into().unwrap())
+        .map_err(|_| SnapshotError::CryptoError)?;
+    let mut nonce = [0u8; 12];
+    nonce[8..].copy_from_slice(&counter.to_be_bytes());
+    Ok(AesCtr::new_with_nonce(aes_key, nonce))
+}
+
+fn compute_mac(
+    vapp_hash: &[u8; 32],
+    counter: u32,
+    segment_roots: &[[u8; 32]],
+    ciphertext: &[u8],
+) -> [u8; 32] {
+    let mac_key = derive_key(MAC_KEY_LABEL);
+    let mut hmac = hmac::sha2::Sha2_256::new(mac_key.as_ref());
+    hmac.update(vapp_hash).expect("Should never fail");
+    hmac.update(&counter.to_be_bytes())
+        .expect("Should never fail");
+    for root in segment_roots {
+        hmac.update(root).expect("Should never fail");
+    }
+    hmac.update(ciphertext).expect("Should never fail");
+    let mut mac = [0u8; 32];
+    hmac.finalize(&mut mac).expect("Should never fail");
+    mac
+}
+
+/// Creates the encrypted and authenticated snapshot of the state of the V-App with the given
+/// hash, bound to the Merkle roots of its writable segments. Any previous snapshot is invalidated.
+pub fn seal_snapshot(
+    vapp_hash: &[u8; 32],
+    state: &VAppState,
+    segment_roots: &[[u8; 32]],
+) -> Result<Vec<u8>, SnapshotError> {
+    let counter = increment_counter()?;
+
+    let (_, ciphertext) = get_cipher(counter)?
+        .encrypt(state.serialize().as_ref())
+        .map_err(|_| SnapshotError::CryptoError)?;
+    let mac = compute_mac(vapp_hash, counter, segment_roots, &ciphertext);
+
+    let mut snapshot = Vec::with_capacity(SNAPSHOT_SIZE);
+    snapshot.extend_from_slice(&counter.to_be_bytes());
+    snapshot.extend_from_slice(&ciphertext);
+    snapshot.extend_from_slice(&mac[..MAC_SIZE]);
+    Ok(snapshot)
+}
+
+/// Verifies and decrypts a snapshot of the V-App with the given hash, and checks that the Merkle
+/// roots of the writable segments are the ones it was created with. On success, the snapshot is
+/// invalidated, so that it can never be resumed again.
+pub fn open_snapshot(
+    vapp_hash: &[u8; 32],
+    snapshot: &[u8],
+    segment_roots: &[[u8; 32]],
+) -> Result<VAppState, SnapshotError> {
+    if snapshot.len() != SNAPSHOT_SIZE {
+        return Err(SnapshotError::InvalidSize);
+    }
+    let counter = u32::from_be_bytes(snapshot[0..4].try_into().unwrap());
+    let ciphertext = &snapshot[4..4 + STATE_SIZE];
+    let mac = &snapshot[4 + STATE_SIZE..];
+
+    // It's critical to use a constant time comparison to prevent timing attacks
+    let expected_mac = compute_mac(vapp_hash, counter, segment_roots, ciphertext);
+    if mac.ct_ne(&expected_mac[..MAC_SIZE]).into() {
+        return Err(SnapshotError::InvalidMac);
+    }
+
+    if counter != read_counter() {
+        return Err(SnapshotError::Rollback);
+    }
+    increment_counter()?;
+
+    let cipher = get_cipher(counter)?;
+    let plaintext = Zeroizing::new(
+        cipher
+            .decrypt(&cipher.nonce(), ciphertext)
+            .map_err(|_| SnapshotError::CryptoError)?,
+    );
+    let plaintext: &[u8; STATE_SIZE] = plaintext
+        .as_slice()
+        .try_into()
+        .map_err(|_| SnapshotError::CryptoError)?;
+    Ok(VAppState::deserialize(plaintext))
+}
@@ -19,6 +19,9 @@ pub fn handler_register_vapp(
         return Err(AppSW::IncorrectData); // extra data
     }
 
+    // the segment table of a manifest received from the client might be invalid
+    manifest.validate().map_err(|_| AppSW::IncorrectData)?;
+
     #[cfg(any(target_os = "stax", target_os = "flex"))]
     const VANADIUM_ICON: NbglGlyph =
         NbglGlyph::from_include(include_gif!("icons/vanadium_64x64.gif", NBGL));
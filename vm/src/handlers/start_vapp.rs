@@ -4,62 +4,206 @@ use alloc::vec::Vec;
 use alloc::{boxed::Box, rc::Rc};
 use subtle::ConstantTimeEq;
 
-use common::client_commands::SectionKind;
+use common::evict::EvictionPolicy;
 use common::manifest::Manifest;
-use common::vm::{Cpu, MemorySegment};
+use common::vm::{Cpu, CpuError, MemorySegment, Trap, TrapCause};
 
 use super::lib::{
     ecall::{CommEcallError, CommEcallHandler},
-    evict::{LruEvictionStrategy, TwoQEvictionStrategy},
     outsourced_mem::OutsourcedMemory,
+    snapshot::{open_snapshot, seal_snapshot, SnapshotError, VAppState, SNAPSHOT_SIZE},
     vapp::get_vapp_hmac,
 };
 use crate::aes::{AesCtr, AesKey};
-use crate::{println, AppSW, COMM_BUFFER_SIZE};
+use crate::hash::Sha256Hasher;
+use crate::{println, AppSW, ErrorResponse, COMM_BUFFER_SIZE};
 
-pub fn handler_start_vapp(
-    command: ledger_device_sdk::io::Command<COMM_BUFFER_SIZE>,
-) -> Result<Vec<u8>, AppSW> {
-    let data_raw = command.get_data();
+// Builds the response reporting the trap that aborted the execution of the V-App
+fn trap_response(pc: u32, cause: TrapCause) -> ErrorResponse {
+    let trap = Trap { pc, cause };
+    println!("V-App trap: {}", trap);
+    ErrorResponse {
+        sw: AppSW::VAppTrap,
+        data: postcard::to_allocvec(&trap).unwrap_or_default(),
+    }
+}
 
-    let (manifest, provided_hmac) =
-        postcard::take_from_bytes::<Manifest>(data_raw).map_err(|_| AppSW::IncorrectData)?;
+// Parses the manifest and its HMAC at the beginning of the data, and checks that the V-App is
+// registered. Returns the manifest and the rest of the data.
+fn parse_registered_manifest(data: &[u8]) -> Result<(Manifest, &[u8]), ErrorResponse> {
+    let (manifest, rest) =
+        postcard::take_from_bytes::<Manifest>(data).map_err(|_| AppSW::IncorrectData)?;
 
-    if provided_hmac.len() != 32 {
-        return Err(AppSW::IncorrectData);
+    if rest.len() < 32 {
+        return Err(AppSW::IncorrectData.into());
     }
+    let (provided_hmac, rest) = rest.split_at(32);
 
     let vapp_hmac = get_vapp_hmac(&manifest);
 
     // It's critical to use a constant time comparison to prevent timing attacks
     if provided_hmac.ct_ne(&vapp_hmac).into() {
-        return Err(AppSW::SignatureFail);
+        return Err(AppSW::SignatureFail.into());
     }
 
     println!("Running app with Manifest: {:?}", manifest);
     println!("hmac: {:?}", provided_hmac);
 
-    let comm = command.into_comm();
+    Ok((manifest, rest))
+}
+
+// Commits all the pages of the writable segments, and builds the response that contains the
+// snapshot of the suspended V-App. The read-only segments never need to be committed.
+fn suspend(
+    manifest: &Manifest,
+    pc: u32,
+    regs: [u32; 32],
+    aes_ctr: &RefCell<AesCtr>,
+    mems: &mut [OutsourcedMemory<'_, COMM_BUFFER_SIZE>],
+) -> ErrorResponse {
+    let mut segment_roots = Vec::new();
+    for (descriptor, mem) in manifest.segments.iter().zip(mems.iter_mut()) {
+        if !descriptor.permissions.is_writable() {
+            continue;
+        }
+        if let Err(e) = mem.commit_all() {
+            println!("Failed to commit the pages while suspending: {}", e);
+            return AppSW::VMRuntimeError.into();
+        }
+        segment_roots.push(mem.merkle_root().0);
+    }
+
+    let (aes_key, aes_nonce) = {
+        let aes_ctr = aes_ctr.borrow();
+        (aes_ctr.key().to_bytes(), aes_ctr.nonce())
+    };
+    let state = VAppState {
+        pc,
+        regs,
+        aes_key,
+        aes_nonce,
+    };
+    let vapp_hash: [u8; 32] = manifest.get_vapp_hash::<Sha256Hasher, 32>();
+    match seal_snapshot(&vapp_hash, &state, &segment_roots) {
+        Ok(snapshot) => {
+            println!("V-App suspended");
+            ErrorResponse {
+                sw: AppSW::VAppSuspended,
+                data: snapshot,
+            }
+        }
+        Err(e) => {
+            println!("Failed to create the snapshot: {:?}", e);
+            AppSW::VMRuntimeError.into()
+        }
+    }
+}
+
+pub fn handler_start_vapp(
+    command: ledger_device_sdk::io::Command<COMM_BUFFER_SIZE>,
+) -> Result<Vec<u8>, ErrorResponse> {
+    let (manifest, rest) = parse_registered_manifest(command.get_data())?;
+    if !rest.is_empty() {
+        return Err(AppSW::IncorrectData.into());
+    }
+
+    run_vapp(command.into_comm(), &manifest, None)
+}
+
+/// Resumes a V-App from the snapshot created when it was suspended. The data of the command is
+/// the manifest, its HMAC, the snapshot, and the current Merkle roots of the writable segments,
+/// in the order of the segment table.
+pub fn handler_resume_vapp(
+    command: ledger_device_sdk::io::Command<COMM_BUFFER_SIZE>,
+) -> Result<Vec<u8>, ErrorResponse> {
+    let (manifest, rest) = parse_registered_manifest(command.get_data())?;
+
+    let n_writable = manifest
+        .segments
+        .iter()
+        .filter(|segment| segment.permissions.is_writable())
+        .count();
+    if rest.len() != SNAPSHOT_SIZE + 32 * n_writable {
+        return Err(AppSW::IncorrectData.into());
+    }
+    let (snapshot, roots) = rest.split_at(SNAPSHOT_SIZE);
+    let segment_roots: Vec<[u8; 32]> = roots
+        .chunks_exact(32)
+        .map(|root| root.try_into().unwrap())
+        .collect();
+
+    let vapp_hash: [u8; 32] = manifest.get_vapp_hash::<Sha256Hasher, 32>();
+    let state = open_snapshot(&vapp_hash, snapshot, &segment_roots).map_err(|e| {
+        println!("Invalid snapshot: {:?}", e);
+        match e {
+            SnapshotError::InvalidSize => AppSW::IncorrectData,
+            SnapshotError::InvalidMac | SnapshotError::Rollback => AppSW::SignatureFail,
+            SnapshotError::CounterExhausted | SnapshotError::CryptoError => AppSW::VMRuntimeError,
+        }
+    })?;
+
+    run_vapp(command.into_comm(), &manifest, Some((state, segment_roots)))
+}
+
+// Runs the V-App until it exits or fails. If `resume_state` is given, the execution continues
+// from the state of a suspended V-App and the Merkle roots of its writable segments, instead of
+// starting from the entrypoint.
+fn run_vapp(
+    comm: &mut ledger_device_sdk::io::Comm<COMM_BUFFER_SIZE>,
+    manifest: &Manifest,
+    resume_state: Option<(VAppState, Vec<[u8; 32]>)>,
+) -> Result<Vec<u8>, ErrorResponse> {
     let comm = Rc::new(RefCell::new(comm));
 
-    let aes_ctr = Rc::new(RefCell::new(AesCtr::new(
-        AesKey::new_random().map_err(|_| AppSW::VMRuntimeError)?,
-    )));
+    // When resuming, the pages stored by the client are encrypted with the key of the previous
+    // session, so we need to keep using it. The key of the pages is rotated during long sessions;
+    // the pages encrypted with the key of a previous epoch are re-encrypted with the current one
+    // the next time they are committed.
+    let (aes_key, aes_nonce) = match &resume_state {
+        Some((state, _)) => (
+            AesKey::from_bytes(&state.aes_key).map_err(|_| AppSW::VMRuntimeError)?,
+            state.aes_nonce,
+        ),
+        None => (
+            AesKey::new_random().map_err(|_| AppSW::VMRuntimeError)?,
+            [0u8; 12],
+        ),
+    };
+    let aes_ctr =
+        AesCtr::new_with_key_rotation(aes_key, aes_nonce).map_err(|_| AppSW::VMRuntimeError)?;
+    let aes_ctr = Rc::new(RefCell::new(aes_ctr));
 
     // Base number of pages for code, data and stack, computed based on the BASE_HEAP_SIZE of Nano X
     const BASE_CODE_PAGES: usize = 24;
     const BASE_DATA_PAGES: usize = 8;
     const BASE_STACK_PAGES: usize = 8;
 
+    // Code is mostly executed sequentially, so on a miss in the code cache the following pages
+    // are fetched too, in a single exchange with the client
+    const CODE_BATCH_PAGES: usize = 4;
+
     // Based on the total available heap size, we allocate more pages to the caches
     let base_heap_size = crate::BASE_HEAP_SIZE; // smallest heap size, tailored for Nano X
 
     assert!(crate::HEAP_SIZE >= base_heap_size);
-    let additional_heap = crate::HEAP_SIZE - base_heap_size;
+    let mut additional_heap = crate::HEAP_SIZE - base_heap_size;
 
-    // compute how many additional pages we can allocate with the extra available heap
-    const CACHED_PAGE_SIZE: usize = OutsourcedMemory::<COMM_BUFFER_SIZE>::size_per_page()
-        + TwoQEvictionStrategy::size_per_page();
+    // A quarter of the additional heap is used to cache decoded instructions for some of the
+    // code pages, so that hot loops don't need to decode the same instructions over and over.
+    const DECODED_PAGE_SIZE: usize = OutsourcedMemory::<COMM_BUFFER_SIZE>::size_per_decoded_page();
+    let n_decoded_code_pages = (additional_heap / 4) / DECODED_PAGE_SIZE;
+    additional_heap -= n_decoded_code_pages * DECODED_PAGE_SIZE;
+
+    // An eighth of the additional heap caches the top levels of the Merkle tree of each segment,
+    // so that the proofs sent by the client are shorter. Without additional heap, only the roots
+    // are kept, and the proofs go all the way up to them.
+    let tree_cache_size = (additional_heap / 8) / manifest.segments.len();
+    additional_heap -= tree_cache_size * manifest.segments.len();
+
+    // compute how many additional pages we can allocate with the extra available heap; the
+    // eviction policy of each segment is chosen by the manifest, so we budget for the largest one
+    const CACHED_PAGE_SIZE: usize =
+        OutsourcedMemory::<COMM_BUFFER_SIZE>::size_per_page() + EvictionPolicy::max_size_per_page();
     let n_additional_pages = additional_heap / CACHED_PAGE_SIZE;
 
     // Divide the additional pages among code, data and stack; we privilege the code cache.
@@ -69,71 +213,124 @@ pub fn handler_start_vapp(
     let n_additional_code_pages =
         n_additional_pages - n_additional_data_pages - n_additional_stack_pages;
 
-    let n_code_cache_pages = BASE_CODE_PAGES + n_additional_code_pages;
-    let n_data_cache_pages = BASE_DATA_PAGES + n_additional_data_pages;
-    let n_stack_cache_pages = BASE_STACK_PAGES + n_additional_stack_pages;
-
-    let mut code_mem = OutsourcedMemory::new(
-        comm.clone(),
-        n_code_cache_pages,
-        true,
-        SectionKind::Code,
-        manifest.n_code_pages(),
-        manifest.code_merkle_root.into(),
-        aes_ctr.clone(),
-        Box::new(TwoQEvictionStrategy::new(
-            n_code_cache_pages,
-            n_code_cache_pages / 4,
-            n_code_cache_pages / 2,
-        )),
-    );
-    let code_seg = MemorySegment::<OutsourcedMemory<'_, COMM_BUFFER_SIZE>>::new(
-        manifest.code_start,
-        manifest.code_end - manifest.code_start,
-        &mut code_mem,
-    )
-    .unwrap();
-
-    let mut data_mem = OutsourcedMemory::new(
-        comm.clone(),
-        n_data_cache_pages,
-        false,
-        SectionKind::Data,
-        manifest.n_data_pages(),
-        manifest.data_merkle_root.into(),
-        aes_ctr.clone(),
-        Box::new(LruEvictionStrategy::new(n_data_cache_pages)),
-    );
-    let data_seg = MemorySegment::<OutsourcedMemory<'_, COMM_BUFFER_SIZE>>::new(
-        manifest.data_start,
-        manifest.data_end - manifest.data_start,
-        &mut data_mem,
-    )
-    .unwrap();
-
-    let mut stack_mem = OutsourcedMemory::new(
-        comm.clone(),
-        n_stack_cache_pages,
-        false,
-        SectionKind::Stack,
-        manifest.n_stack_pages(),
-        manifest.stack_merkle_root.into(),
-        aes_ctr.clone(),
-        Box::new(LruEvictionStrategy::new(n_stack_cache_pages)),
-    );
-    let stack_seg = MemorySegment::<OutsourcedMemory<'_, COMM_BUFFER_SIZE>>::new(
-        manifest.stack_start,
-        manifest.stack_end - manifest.stack_start,
-        &mut stack_mem,
-    )
-    .unwrap();
+    // The code pages are divided among the executable segments, and the data and stack pages
+    // among all the other segments, with at least one page for each segment. A valid manifest has
+    // at least one executable segment and one writable segment.
+    let n_code_segments = manifest
+        .segments
+        .iter()
+        .filter(|segment| segment.permissions.is_executable())
+        .count();
+    let n_other_segments = manifest.segments.len() - n_code_segments;
+    let n_code_pages = BASE_CODE_PAGES + n_additional_code_pages;
+    let n_other_pages =
+        BASE_DATA_PAGES + BASE_STACK_PAGES + n_additional_data_pages + n_additional_stack_pages;
+    let n_code_cache_pages = (n_code_pages / n_code_segments).max(1);
+    let n_decoded_pages_per_segment = n_decoded_code_pages / n_code_segments;
+    let n_other_cache_pages = (n_other_pages / n_other_segments).max(1);
+
+    // The writable segments start from the Merkle roots given when resuming, if any
+    let mut resumed_roots = resume_state.as_ref().map(|(_, roots)| roots.iter());
+
+    let mut mems = Vec::with_capacity(manifest.segments.len());
+    for (index, segment) in manifest.segments.iter().enumerate() {
+        let mut merkle_root = segment.merkle_root;
+        if segment.permissions.is_writable() {
+            if let Some(roots) = resumed_roots.as_mut() {
+                merkle_root = *roots.next().ok_or(AppSW::VMRuntimeError)?;
+            }
+        }
+        // Unless the manifest chooses otherwise, the code segments use 2Q, so that the pages of
+        // hot loops are not evicted by a long sequence of calls, and the others use LRU
+        let default_policy = if segment.permissions.is_executable() {
+            EvictionPolicy::TwoQ
+        } else {
+            EvictionPolicy::Lru
+        };
+        let eviction_policy = segment.eviction_policy.unwrap_or(default_policy);
+        // Only the writable segments need to be encrypted and committed
+        let mem = if segment.permissions.is_executable() {
+            OutsourcedMemory::new(
+                comm.clone(),
+                n_code_cache_pages,
+                n_decoded_pages_per_segment,
+                true,
+                index as u8,
+                segment.n_pages(),
+                merkle_root.into(),
+                aes_ctr.clone(),
+                eviction_policy.create(n_code_cache_pages),
+            )
+            .with_batch_size(CODE_BATCH_PAGES)
+        } else {
+            OutsourcedMemory::new(
+                comm.clone(),
+                n_other_cache_pages,
+                0,
+                !segment.permissions.is_writable(),
+                index as u8,
+                segment.n_pages(),
+                merkle_root.into(),
+                aes_ctr.clone(),
+                eviction_policy.create(n_other_cache_pages),
+            )
+        };
+        // When resuming, the writable segments might have been committed since they were zero
+        let mem = if resume_state.is_none() || !segment.permissions.is_writable() {
+            mem.with_zero_pages(segment.first_zero_page())
+        } else {
+            mem
+        };
+        mems.push(mem.with_tree_cache(tree_cache_size));
+    }
+
+    let segments = manifest
+        .segments
+        .iter()
+        .zip(mems.iter_mut())
+        .map(|(segment, mem)| {
+            MemorySegment::<OutsourcedMemory<'_, COMM_BUFFER_SIZE>>::new(
+                segment.start,
+                segment.end - segment.start,
+                mem,
+            )
+            .map(|seg| seg.with_permissions(segment.permissions))
+        })
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|_| AppSW::VMRuntimeError)?;
+
+    let mut cpu = Cpu::new(manifest.entrypoint, segments);
 
-    let mut cpu = Cpu::new(manifest.entrypoint, code_seg, data_seg, stack_seg);
+    match &resume_state {
+        Some((state, _)) => {
+            cpu.pc = state.pc;
+            cpu.regs = state.regs;
+        }
+        None => {
+            // x2 is the stack pointer, that grows backwards from the end of the stack
+            // we make sure it's aligned to a multiple of 4
+            cpu.regs[2] = (manifest.stack_segment().end - 4) & !3;
+            // a0 and a1 are the start and the size of the heap, passed to the entrypoint
+            let heap = manifest.heap_segment();
+            cpu.regs[10] = heap.start;
+            cpu.regs[11] = heap.end - heap.start;
+            assert!(cpu.pc % 2 == 0, "Unaligned entrypoint");
+        }
+    }
+
+    cpu.set_instruction_budget(crate::INSTRUCTION_BUDGET);
 
-    // x2 is the stack pointer, that grows backwards from the end of the stack
-    // we make sure it's aligned to a multiple of 4
-    cpu.regs[2] = (manifest.stack_end - 4) & !3;
-    assert!(cpu.pc % 2 == 0, "Unaligned entrypoint");
+    #[cfg(any(feature = "trace_binary", feature = "trace_page_accesses"))]
+    let _trace_guard = crate::trace::start();
+
+    #[cfg(feature = "trace_page_accesses")]
+    let mut page_access_recorder = common::trace::PageAccessRecorder::new(
+        manifest
+            .segments
+            .iter()
+            .map(|segment| (segment.start, segment.end))
+            .collect(),
+    );
 
     let mut ecall_handler = CommEcallHandler::new(comm.clone());
 
@@ -142,11 +339,12 @@ pub fn handler_start_vapp(
 
     loop {
         // Handle instruction fetch errors
-        let instr = match cpu.fetch_instruction::<CommEcallError>() {
-            Ok(instr) => instr,
+        let (op, inst_size) = match cpu.fetch_op::<CommEcallError>() {
+            Ok(decoded) => decoded,
+            Err(CpuError::Trap(cause)) => return Err(trap_response(cpu.pc, cause)),
             Err(e) => {
                 println!("Error fetching instruction: {:?}", e);
-                return Err(AppSW::VMRuntimeError);
+                return Err(AppSW::VMRuntimeError.into());
             }
         };
 
@@ -155,19 +353,43 @@ pub fn handler_start_vapp(
 
         #[cfg(feature = "trace")]
         {
-            // Print the instruction, but check if it's compressed
-            let (decoded_op, len) = common::riscv::decode::decode(instr);
-            let instruction = if len == 2 {
+            // Print the instruction, but check if it's compressed. The raw instruction is fetched
+            // again, as the decoded one might come from the cache.
+            let instr = cpu.fetch_instruction::<CommEcallError>().unwrap_or(0);
+            let instruction = if inst_size == 2 {
                 let instr_lo = (instr & 0xffffu32) as u16;
-                alloc::format!("{:08x?}: {:04x?} -> {:?}", cpu.pc, instr_lo, decoded_op)
+                alloc::format!("{:08x?}: {:04x?} -> {:?}", cpu.pc, instr_lo, op)
             } else {
-                alloc::format!("{:08x?}: {:08x?} -> {:?}", cpu.pc, instr, decoded_op)
+                alloc::format!("{:08x?}: {:08x?} -> {:?}", cpu.pc, instr, op)
             };
 
             crate::trace!("Instruction", "green", "{}", instruction);
         }
 
-        let result = cpu.execute(instr, Some(&mut ecall_handler));
+        #[cfg(feature = "trace_binary")]
+        {
+            let instr = cpu.fetch_instruction::<CommEcallError>().unwrap_or(0);
+            common::trace::record_instruction(
+                cpu.pc,
+                instr,
+                &op,
+                inst_size,
+                &cpu.regs,
+                crate::trace::record,
+            );
+        }
+
+        #[cfg(feature = "trace_page_accesses")]
+        {
+            let instr = cpu.fetch_instruction::<CommEcallError>().unwrap_or(0);
+            common::trace::record_instruction(cpu.pc, instr, &op, inst_size, &cpu.regs, |event| {
+                page_access_recorder.record(event, crate::trace::record)
+            });
+        }
+
+        let result = cpu
+            .consume_instruction_budget()
+            .and_then(|()| cpu.execute_op(op, inst_size, Some(&mut ecall_handler)));
 
         #[cfg(feature = "metrics")]
         {
@@ -176,42 +398,63 @@ pub fn handler_start_vapp(
 
         match result {
             Ok(_) => {}
-            Err(common::vm::CpuError::EcallError(e)) => match e {
+            Err(CpuError::EcallError(e)) => match e {
                 CommEcallError::Exit(status) => {
                     #[cfg(feature = "metrics")]
                     {
-                        let n_loads =
-                            code_mem.n_page_loads + data_mem.n_page_loads + stack_mem.n_page_loads;
-                        let n_commits = code_mem.n_page_commits
-                            + data_mem.n_page_commits
-                            + stack_mem.n_page_commits;
+                        drop(cpu);
+                        let n_loads: usize = mems.iter().map(|mem| mem.n_page_loads).sum();
+                        let n_commits: usize = mems.iter().map(|mem| mem.n_page_commits).sum();
+                        let n_zero_loads: usize =
+                            mems.iter().map(|mem| mem.n_zero_page_loads).sum();
+                        let n_decoded_hits: usize = mems.iter().map(|mem| mem.n_decoded_hits).sum();
+                        let n_decoded_misses: usize =
+                            mems.iter().map(|mem| mem.n_decoded_misses).sum();
                         println!("Vanadium ran {} instructions", instr_count);
                         println!("Number of page loads:   {}", n_loads);
                         println!("Number of page commits: {}", n_commits);
+                        println!("Number of zero pages:   {}", n_zero_loads);
+                        println!(
+                            "Decoded instruction cache: {} hits, {} misses",
+                            n_decoded_hits, n_decoded_misses
+                        );
                     }
                     println!("Exiting with status {}", status);
                     return Ok(status.to_be_bytes().to_vec());
                 }
                 CommEcallError::Panic => {
                     println!("V-App panicked");
-                    return Err(AppSW::VAppPanic);
+                    return Err(AppSW::VAppPanic.into());
                 }
                 CommEcallError::GenericError(e) => {
                     println!("Runtime error: {}", e);
-                    return Err(AppSW::VMRuntimeError);
+                    return Err(AppSW::VMRuntimeError.into());
+                }
+                CommEcallError::SuspendRequested => {
+                    // The program counter is still at the ECALL, that is executed again on resume
+                    let (pc, regs) = (cpu.pc, cpu.regs);
+                    // release the memories borrowed by the segments of the CPU
+                    drop(cpu);
+                    return Err(suspend(manifest, pc, regs, &aes_ctr, &mut mems));
+                }
+                CommEcallError::UnhandledEcall(code) => {
+                    return Err(trap_response(cpu.pc, TrapCause::UnknownEcall { code }));
                 }
                 e => {
                     println!("CommEcallError: {:?}", e);
-                    return Err(AppSW::VMRuntimeError);
+                    return Err(AppSW::VMRuntimeError.into());
                 }
             },
-            Err(common::vm::CpuError::MemoryError(e)) => {
+            Err(CpuError::MemoryError(e)) => {
                 println!("Memory error: {}", e);
-                return Err(AppSW::VMRuntimeError);
+                return Err(AppSW::VMRuntimeError.into());
+            }
+            Err(CpuError::InstructionBudgetExhausted) => {
+                println!("V-App exceeded its instruction budget");
+                return Err(AppSW::InstructionBudgetExceeded.into());
             }
-            Err(common::vm::CpuError::GenericError(e)) => {
-                println!("Error executing instruction: {}", e);
-                return Err(AppSW::VMRuntimeError);
+            Err(CpuError::Trap(cause)) => {
+                return Err(trap_response(cpu.pc, cause));
             }
         }
     }
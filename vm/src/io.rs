@@ -2,10 +2,26 @@ use ledger_device_sdk::io;
 
 use crate::{AppSW, Instruction};
 
-// Helper function to send the InterruptedExecution response, and make sure the next command is 'Continue'
-pub fn interrupt<'a, const N: usize>(
+/// P1 of a Continue APDU answering a ReceiveBuffer client command, that asks the VM to suspend the
+/// V-App instead of sending it a message.
+pub const P1_SUSPEND: u8 = 0x01;
+
+/// P1 of a Continue APDU answering a ReceiveBuffer or CheckAbort client command, that asks the VM
+/// to abort the request that the V-App is processing.
+pub const P1_ABORT: u8 = 0x02;
+
+/// The reply of the client to a client command, if it can also ask to suspend the V-App or to abort
+/// its request instead of continuing.
+pub enum ClientReply<'a, const N: usize> {
+    Continue(io::Command<'a, N>),
+    Suspend,
+    Abort,
+}
+
+// Sends the InterruptedExecution response, and returns the next command if it's 'Continue'
+fn interrupt_with_p1<'a, const N: usize>(
     response: io::CommandResponse<'a, N>,
-) -> Result<io::Command<'a, N>, common::vm::MemoryError> {
+) -> Result<(io::Command<'a, N>, u8), common::vm::MemoryError> {
     let comm = response.send(AppSW::InterruptedExecution).unwrap();
     let command = comm.next_command();
 
@@ -17,9 +33,34 @@ pub fn interrupt<'a, const N: usize>(
         // expected "Continue"
         return Err(common::vm::MemoryError::GenericError("INS not supported"));
     };
-    if (p1, p2) != (0, 0) {
+    if p2 != 0 {
+        return Err(common::vm::MemoryError::GenericError("Wrong P1/P2"));
+    }
+
+    Ok((command, p1))
+}
+
+// Helper function to send the InterruptedExecution response, and make sure the next command is 'Continue'
+pub fn interrupt<'a, const N: usize>(
+    response: io::CommandResponse<'a, N>,
+) -> Result<io::Command<'a, N>, common::vm::MemoryError> {
+    let (command, p1) = interrupt_with_p1(response)?;
+    if p1 != 0 {
         return Err(common::vm::MemoryError::GenericError("Wrong P1/P2"));
     }
 
     Ok(command)
 }
+
+// Like `interrupt`, but the client can also ask to suspend the V-App, or to abort the request that
+// the V-App is processing
+pub fn interrupt_with_reply<'a, const N: usize>(
+    response: io::CommandResponse<'a, N>,
+) -> Result<ClientReply<'a, N>, common::vm::MemoryError> {
+    match interrupt_with_p1(response)? {
+        (command, 0) => Ok(ClientReply::Continue(command)),
+        (_, P1_SUSPEND) => Ok(ClientReply::Suspend),
+        (_, P1_ABORT) => Ok(ClientReply::Abort),
+        _ => Err(common::vm::MemoryError::GenericError("Wrong P1/P2")),
+    }
+}
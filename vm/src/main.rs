@@ -23,6 +23,8 @@ mod app_ui;
 mod handlers;
 mod hash;
 mod io;
+#[cfg(any(feature = "trace_binary", feature = "trace_page_accesses"))]
+mod trace;
 
 #[cfg(feature = "run_tests")]
 mod app_tests;
@@ -30,8 +32,9 @@ mod app_tests;
 use alloc::{string::ToString, vec::Vec};
 use app_ui::menu::ui_menu_main;
 use handlers::{
-    get_version::handler_get_version, register_vapp::handler_register_vapp,
-    start_vapp::handler_start_vapp,
+    get_version::handler_get_version,
+    register_vapp::handler_register_vapp,
+    start_vapp::{handler_resume_vapp, handler_start_vapp},
 };
 use ledger_device_sdk::{
     io::{ApduHeader, Comm, Command, Reply, StatusWords},
@@ -41,6 +44,7 @@ use ledger_device_sdk::{
 extern crate alloc;
 
 include!(concat!(env!("OUT_DIR"), "/heap_size.rs"));
+include!(concat!(env!("OUT_DIR"), "/instruction_budget.rs"));
 
 pub const COMM_BUFFER_SIZE: usize = 600;
 
@@ -144,12 +148,30 @@ pub enum AppSW {
 
     VMRuntimeError = 0xB020,
     VAppPanic = 0xB021,
+    InstructionBudgetExceeded = 0xB022,
+    VAppTrap = 0xB023,
+    VAppSuspended = 0xB024,
 
     Unknown = 0xCCCC,
 
     Ok = 0x9000,
 }
 
+/// An error status word, with some data that is sent to the client together with it.
+pub struct ErrorResponse {
+    pub sw: AppSW,
+    pub data: Vec<u8>,
+}
+
+impl From<AppSW> for ErrorResponse {
+    fn from(sw: AppSW) -> Self {
+        ErrorResponse {
+            sw,
+            data: Vec::new(),
+        }
+    }
+}
+
 impl From<AppSW> for Reply {
     fn from(sw: AppSW) -> Reply {
         Reply(sw as u16)
@@ -178,6 +200,9 @@ impl From<Reply> for AppSW {
             x if x == AppSW::WrongApduLength as u16 => AppSW::WrongApduLength,
             x if x == AppSW::VMRuntimeError as u16 => AppSW::VMRuntimeError,
             x if x == AppSW::VAppPanic as u16 => AppSW::VAppPanic,
+            x if x == AppSW::InstructionBudgetExceeded as u16 => AppSW::InstructionBudgetExceeded,
+            x if x == AppSW::VAppTrap as u16 => AppSW::VAppTrap,
+            x if x == AppSW::VAppSuspended as u16 => AppSW::VAppSuspended,
             x if x == AppSW::Ok as u16 => AppSW::Ok,
             _ => AppSW::Unknown,
         }
@@ -191,6 +216,7 @@ pub enum Instruction {
     GetAppName,
     RegisterVApp,
     StartVApp,
+    ResumeVApp,
     Continue(u8, u8), // client response to a request from the VM
 }
 
@@ -214,7 +240,8 @@ impl TryFrom<ApduHeader> for Instruction {
             (1, 0, 0) => Ok(Instruction::GetAppName),
             (2, 0, 0) => Ok(Instruction::RegisterVApp),
             (3, 0, 0) => Ok(Instruction::StartVApp),
-            (0..=3, _, _) => Err(AppSW::WrongP1P2),
+            (4, 0, 0) => Ok(Instruction::ResumeVApp),
+            (0..=4, _, _) => Err(AppSW::WrongP1P2),
             (0xff, p1, p2) => Ok(Instruction::Continue(p1, p2)),
             (_, _, _) => Err(AppSW::InsNotSupported),
         }
@@ -239,8 +266,8 @@ extern "C" fn sample_main() {
             Ok(data) => {
                 let _ = comm.send(&data, AppSW::Ok);
             }
-            Err(sw) => {
-                let _ = comm.send(&[], sw);
+            Err(err) => {
+                let _ = comm.send(&err.data, err.sw);
             }
         };
         home.show_and_return();
@@ -254,15 +281,16 @@ extern "C" fn sample_main() {
     ledger_device_sdk::exit_app(0x00);
 }
 
-fn handle_apdu(command: Command<COMM_BUFFER_SIZE>) -> Result<Vec<u8>, AppSW> {
+fn handle_apdu(command: Command<COMM_BUFFER_SIZE>) -> Result<Vec<u8>, ErrorResponse> {
     let ins: Instruction = command
         .decode::<Instruction>()
         .map_err(|sw| AppSW::from(sw))?;
     match ins {
         Instruction::GetAppName => Ok(env!("CARGO_PKG_NAME").as_bytes().to_vec()),
-        Instruction::GetVersion => handler_get_version(command),
-        Instruction::RegisterVApp => handler_register_vapp(command),
+        Instruction::GetVersion => Ok(handler_get_version(command)?),
+        Instruction::RegisterVApp => Ok(handler_register_vapp(command)?),
         Instruction::StartVApp => handler_start_vapp(command),
-        Instruction::Continue(_, _) => Err(AppSW::InsNotSupported), // 'Continue' command is only allowed when requested by the VM
+        Instruction::ResumeVApp => handler_resume_vapp(command),
+        Instruction::Continue(_, _) => Err(AppSW::InsNotSupported.into()), // 'Continue' command is only allowed when requested by the VM
     }
 }
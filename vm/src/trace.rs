@@ -0,0 +1,71 @@
+//! Binary execution trace of the V-App, in the format defined in `common::trace`.
+//!
+//! The trace is printed to the console in hexadecimal chunks, on lines starting with `[vtrace]`,
+//! that the `vtrace` library in `tools/` extracts from the output of Speculos. Encoding the events
+//! in a buffer and printing them in chunks is a lot faster than printing each instruction.
+
+use common::trace::{TraceEvent, MAX_EVENT_SIZE};
+
+const CHUNK_SIZE: usize = 256;
+
+static mut BUFFER: [u8; CHUNK_SIZE] = [0; CHUNK_SIZE];
+static mut BUFFER_LEN: usize = 0;
+
+/// Flushes the trace when dropped, so that the end of the trace is not lost however the execution
+/// of the V-App terminates.
+pub struct TraceGuard;
+
+impl Drop for TraceGuard {
+    fn drop(&mut self) {
+        flush();
+    }
+}
+
+/// Starts a new trace, beginning with the trace header.
+pub fn start() -> TraceGuard {
+    // Safe in a single-threaded environment
+    unsafe {
+        BUFFER_LEN = 0;
+    }
+    push(&common::trace::header());
+    TraceGuard
+}
+
+pub fn record(event: &TraceEvent) {
+    let mut buf = [0u8; MAX_EVENT_SIZE];
+    let len = event.encode(&mut buf);
+    push(&buf[..len]);
+}
+
+fn push(data: &[u8]) {
+    // Safe in a single-threaded environment
+    #[allow(static_mut_refs)]
+    unsafe {
+        if BUFFER_LEN + data.len() > CHUNK_SIZE {
+            flush();
+        }
+        BUFFER[BUFFER_LEN..BUFFER_LEN + data.len()].copy_from_slice(data);
+        BUFFER_LEN += data.len();
+    }
+}
+
+fn flush() {
+    // Safe in a single-threaded environment
+    #[allow(static_mut_refs)]
+    unsafe {
+        if BUFFER_LEN == 0 {
+            return;
+        }
+        let mut line = [0u8; 2 * CHUNK_SIZE];
+        let len = 2 * BUFFER_LEN;
+        hex::encode_to_slice(&BUFFER[..BUFFER_LEN], &mut line[..len])
+            .expect("The buffer has the right size");
+        BUFFER_LEN = 0;
+        crate::trace!(
+            "vtrace",
+            "",
+            "{}",
+            core::str::from_utf8_unchecked(&line[..len])
+        );
+    }
+}